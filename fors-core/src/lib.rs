@@ -0,0 +1,10 @@
+//! The parts of fors that do no I/O: recognizing provider URLs, building
+//! provider API requests and URLs, and parsing the bodies that come back.
+//!
+//! Callers fetch the data themselves, which keeps this crate usable from
+//! WebAssembly or behind a C FFI in GUI frontends.
+
+pub mod playlist;
+pub mod provider;
+pub mod twitch;
+pub mod youtube;
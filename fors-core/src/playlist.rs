@@ -0,0 +1,934 @@
+use anyhow::{Context, Result, bail};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::info;
+use url::Url;
+
+#[cfg(test)]
+mod tests;
+pub mod twitch_policy;
+use twitch_policy::{AdDateRange, TwitchHlsPolicy};
+
+#[derive(Debug, Clone)]
+pub struct StreamVariant {
+    pub label: String,
+    pub aliases: Vec<String>,
+    pub bandwidth: u64,
+    pub resolution: Option<(u64, u64)>,
+    pub frame_rate: Option<f64>,
+    pub uri: Url,
+    /// The same stream on redundant servers, tried in order when `uri` fails.
+    pub backups: Vec<Url>,
+    pub is_audio_only: bool,
+    /// The untranscoded rendition (Twitch "source"/"chunked").
+    pub is_source: bool,
+    /// GROUP-ID of the alternative audio tracks that go with this variant.
+    pub audio_group: Option<String>,
+    /// Closed captions declared as carried in the variant's video.
+    pub captions: Vec<CaptionTrack>,
+}
+
+impl StreamVariant {
+    /// How `best` and `worst` order variants: the source rendition above any
+    /// transcode, then by bandwidth.
+    pub fn rank(&self) -> (bool, u64) {
+        (self.is_source, self.bandwidth)
+    }
+}
+
+/// A CEA-608/708 caption service from `EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS`.
+/// The captions travel inside the video stream, not as a playlist of their own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptionTrack {
+    /// `CC1`-`CC4` for CEA-608, `SERVICE1`-`SERVICE63` for CEA-708.
+    pub instream_id: String,
+    pub name: String,
+    pub language: Option<String>,
+}
+
+/// An alternative audio track from `EXT-X-MEDIA:TYPE=AUDIO`, such as a dub.
+#[derive(Debug, Clone)]
+pub struct AudioRendition {
+    pub group: String,
+    pub name: String,
+    pub language: Option<String>,
+    pub is_default: bool,
+    /// Missing when the track is muxed into the variants themselves.
+    pub uri: Option<Url>,
+}
+
+#[derive(Debug)]
+pub struct MediaPlaylist {
+    pub target_duration: f64,
+    pub end_list: bool,
+    pub segments: Vec<MediaSegment>,
+    pub ads_active: bool,
+    pub ad_dateranges: Vec<AdDateRange>,
+    /// For LL-HLS playlists with `CAN-BLOCK-RELOAD=YES`, the `_HLS_msn` and
+    /// `_HLS_part` to ask for so the next reload waits for new media.
+    pub blocking_reload: Option<(u64, Option<u64>)>,
+}
+
+#[derive(Debug)]
+pub struct MediaSegment {
+    pub uri: Url,
+    /// Shared by every segment after the same `EXT-X-MAP`.
+    pub init: Option<Arc<Url>>,
+    /// `BYTERANGE` of the `EXT-X-MAP` as offset and length into `init`.
+    pub init_range: Option<(u64, u64)>,
+    pub sequence: u64,
+    pub duration: f64,
+    /// `EXT-X-BYTERANGE` as offset and length into `uri`.
+    pub byte_range: Option<(u64, u64)>,
+    /// Shared by every segment until the next `EXT-X-KEY`.
+    pub key: Option<Arc<SegmentKey>>,
+    /// `EXT-X-BITRATE` in kbit/s, shared by every segment until the next one.
+    pub bitrate: Option<u64>,
+    /// `EXT-X-PROGRAM-DATE-TIME` in seconds since the Unix epoch, carried
+    /// forward from the last segment that had one.
+    pub program_date_time: Option<f64>,
+    pub prefetch: bool,
+    pub ad: bool,
+    pub discontinuity: bool,
+}
+
+impl MediaSegment {
+    /// The time this segment adds to a recording, which skips ads.
+    pub fn effective_duration(&self) -> f64 {
+        if self.ad { 0.0 } else { self.duration }
+    }
+}
+
+impl MediaPlaylist {
+    /// The advertised length of the current ad break, if any.
+    pub fn ad_break_duration(&self) -> Option<f64> {
+        twitch_policy::total_duration(&self.ad_dateranges)
+    }
+}
+
+/// A playlist of keyframes only, from `EXT-X-I-FRAME-STREAM-INF`, meant for
+/// trick play and previews.
+#[derive(Debug, Clone)]
+pub struct IFramePlaylist {
+    pub bandwidth: u64,
+    pub resolution: Option<(u64, u64)>,
+    pub uri: Url,
+}
+
+/// An `EXT-X-KEY` with `METHOD=AES-128`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SegmentKey {
+    pub uri: Url,
+    /// Without one, the IV is the segment's media sequence number.
+    pub iv: Option<[u8; 16]>,
+}
+
+impl SegmentKey {
+    pub fn iv_for(&self, sequence: u64) -> [u8; 16] {
+        self.iv
+            .unwrap_or_else(|| u128::from(sequence).to_be_bytes())
+    }
+}
+
+/// What the URLs a playlist gives relative to itself do with the query of
+/// the playlist's own URL. Some origins only serve segments that carry the
+/// master playlist's signed parameters, others reject unknown ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryPassthrough {
+    /// Resolve them as written, which drops the playlist's query.
+    #[default]
+    Off,
+    /// Add the playlist's query parameters they do not set themselves.
+    Inherit,
+    /// Drop every query parameter, including their own.
+    Strip,
+}
+
+impl FromStr for QueryPassthrough {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "off" => Ok(QueryPassthrough::Off),
+            "inherit" => Ok(QueryPassthrough::Inherit),
+            "strip" => Ok(QueryPassthrough::Strip),
+            _ => Err(format!(
+                "invalid query passthrough '{value}' (expected off, inherit or strip)"
+            )),
+        }
+    }
+}
+
+/// Parses an `EXT-X-KEY` tag; `None` for `METHOD=NONE`.
+fn parse_key(base_url: &Url, value: &str, query: QueryPassthrough) -> Result<Option<SegmentKey>> {
+    let attrs = parse_attribute_line(value);
+    let attr = |key: &str| attrs.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v);
+    match attr("METHOD") {
+        Some("NONE") | None => return Ok(None),
+        Some("AES-128") => {}
+        Some(method) => bail!("Segments are encrypted with {method}, which is not supported"),
+    }
+    let uri = attr("URI").context("Encryption key without a URI")?;
+    let iv = attr("IV")
+        .map(|iv| {
+            let hex = iv.trim_start_matches("0x").trim_start_matches("0X");
+            u128::from_str_radix(hex, 16)
+                .map(u128::to_be_bytes)
+                .with_context(|| format!("Invalid IV {iv}"))
+        })
+        .transpose()?;
+    Ok(Some(SegmentKey {
+        uri: resolve_url(base_url, uri, query)
+            .with_context(|| format!("Resolving encryption key URL: {uri}"))?,
+        iv,
+    }))
+}
+
+/// Parses `<length>[@<offset>]`.
+fn parse_byte_range(value: &str) -> Option<(Option<u64>, u64)> {
+    let (length, offset) = match value.trim().split_once('@') {
+        Some((length, offset)) => (length, Some(offset.parse().ok()?)),
+        None => (value.trim(), None),
+    };
+    Some((offset, length.parse().ok()?))
+}
+
+/// Lists the I-frame playlists declared in a master playlist.
+pub fn parse_iframe_playlists(
+    base_url: &Url,
+    body: &str,
+    query: QueryPassthrough,
+) -> Result<Vec<IFramePlaylist>> {
+    let mut playlists = Vec::new();
+    for line in body.lines().map(str::trim) {
+        let Some(value) = line.strip_prefix("#EXT-X-I-FRAME-STREAM-INF:") else {
+            continue;
+        };
+        let attrs = parse_attribute_line(value);
+        let attr = |key: &str| attrs.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v);
+        let Some(uri) = attr("URI") else {
+            continue;
+        };
+        playlists.push(IFramePlaylist {
+            bandwidth: attr("BANDWIDTH")
+                .and_then(|bandwidth| bandwidth.parse().ok())
+                .unwrap_or(0),
+            resolution: attr("RESOLUTION").and_then(parse_resolution),
+            uri: resolve_url(base_url, uri, query)
+                .with_context(|| format!("Resolving I-frame playlist URI: {uri}"))?,
+        });
+    }
+    Ok(playlists)
+}
+
+/// Lists the alternative audio tracks declared in a master playlist.
+pub fn parse_audio_renditions(
+    base_url: &Url,
+    body: &str,
+    query: QueryPassthrough,
+) -> Result<Vec<AudioRendition>> {
+    let mut renditions = Vec::new();
+    for line in body.lines().map(str::trim) {
+        let Some(value) = line.strip_prefix("#EXT-X-MEDIA:") else {
+            continue;
+        };
+        let attrs = parse_attribute_line(value);
+        let attr = |key: &str| attrs.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v);
+        if attr("TYPE") != Some("AUDIO") {
+            continue;
+        }
+        let Some(group) = attr("GROUP-ID") else {
+            continue;
+        };
+        let uri = attr("URI")
+            .map(|uri| {
+                resolve_url(base_url, uri, query).with_context(|| {
+                    format!("Resolving audio track URI from master playlist: {uri}")
+                })
+            })
+            .transpose()?;
+        let language = attr("LANGUAGE");
+        renditions.push(AudioRendition {
+            group: group.to_string(),
+            name: attr("NAME").or(language).unwrap_or("audio").to_string(),
+            language: language.map(str::to_string),
+            is_default: attr("DEFAULT") == Some("YES"),
+            uri,
+        });
+    }
+    Ok(renditions)
+}
+
+pub fn parse_master_playlist(
+    base_url: &Url,
+    body: &str,
+    query: QueryPassthrough,
+) -> Result<Vec<StreamVariant>> {
+    let mut variants: Vec<StreamVariant> = Vec::new();
+    let mut pending_attrs: Option<Vec<(&str, &str)>> = None;
+    // GROUP-ID -> NAME of the EXT-X-MEDIA video renditions
+    let mut media_names: Vec<(&str, &str)> = Vec::new();
+    // Attributes of each variant, to spot the redundant copies of one.
+    let mut variant_attrs: Vec<Vec<(&str, &str)>> = Vec::new();
+    // GROUP-ID -> the EXT-X-MEDIA caption services, and each variant's group.
+    let mut caption_tracks: Vec<(&str, CaptionTrack)> = Vec::new();
+    let mut caption_groups: Vec<Option<&str>> = Vec::new();
+
+    for line in body.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("#EXT-X-MEDIA:") {
+            let attrs = parse_attribute_line(value);
+            let attr = |key: &str| attrs.iter().find(|&&(k, _)| k == key).map(|&(_, v)| v);
+            if attr("TYPE") == Some("VIDEO")
+                && let (Some(group), Some(name)) = (attr("GROUP-ID"), attr("NAME"))
+            {
+                media_names.push((group, name));
+            }
+            if attr("TYPE") == Some("CLOSED-CAPTIONS")
+                && let (Some(group), Some(instream_id)) = (attr("GROUP-ID"), attr("INSTREAM-ID"))
+            {
+                let language = attr("LANGUAGE");
+                caption_tracks.push((
+                    group,
+                    CaptionTrack {
+                        instream_id: instream_id.to_string(),
+                        name: attr("NAME").or(language).unwrap_or(instream_id).to_string(),
+                        language: language.map(str::to_string),
+                    },
+                ));
+            }
+            continue;
+        }
+
+        if line.starts_with("#EXT-X-STREAM-INF:") {
+            let attrs = parse_attribute_line(line.trim_start_matches("#EXT-X-STREAM-INF:"));
+            pending_attrs = Some(attrs);
+            continue;
+        }
+
+        if let Some(attrs) = pending_attrs.take() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let uri = resolve_url(base_url, line, query)
+                .with_context(|| format!("Resolving stream URI from master playlist: {line}"))?;
+            if let Some(primary) = variant_attrs.iter().position(|other| *other == attrs) {
+                variants[primary].backups.push(uri);
+                continue;
+            }
+
+            let mut bandwidth = 0;
+            let mut resolution = None;
+            let mut frame_rate = None;
+            let mut name = None;
+            let mut audio_only = false;
+            let mut is_source = false;
+            let mut audio_group = None;
+            let mut caption_group = None;
+
+            for &(key, value) in &attrs {
+                match key {
+                    "BANDWIDTH" => bandwidth = value.parse().unwrap_or(0),
+                    "AVERAGE-BANDWIDTH" if bandwidth == 0 => bandwidth = value.parse().unwrap_or(0),
+                    "RESOLUTION" => resolution = parse_resolution(value),
+                    "FRAME-RATE" => frame_rate = value.parse().ok(),
+                    "NAME" => name = Some(value),
+                    "VIDEO" if name.is_none() => name = Some(value),
+                    // Names the group of its audio renditions, which says
+                    // nothing about whether the variant has video.
+                    "AUDIO" => audio_group = Some(value.to_string()),
+                    "CODECS" if !has_video_codec(value) => audio_only = true,
+                    "CLOSED-CAPTIONS" if value != "NONE" => caption_group = Some(value),
+                    _ => {}
+                }
+            }
+
+            if resolution.is_none() && name == Some("audio_only") {
+                audio_only = true;
+            }
+
+            // Twitch's source rendition has the group "chunked" and a media
+            // name like "1080p60 (source)"; label it by that name instead.
+            let media_name = name.and_then(|group| {
+                media_names
+                    .iter()
+                    .find(|&&(g, _)| g == group)
+                    .map(|&(_, n)| n)
+            });
+            if let Some(source_name) = media_name.and_then(|n| n.strip_suffix(" (source)")) {
+                is_source = true;
+                name = Some(source_name);
+            } else if name == Some("chunked") {
+                is_source = true;
+                name = None;
+            }
+
+            let (label, mut aliases) = build_labels(name, resolution, frame_rate, audio_only);
+            if is_source {
+                aliases.push("source".into());
+                aliases.push("chunked".into());
+            }
+            if bandwidth == 0 && !audio_only {
+                // fall back to rough estimate based on height
+                if let Some((_, h)) = resolution {
+                    bandwidth = h * 1000;
+                }
+            }
+
+            aliases.sort();
+            aliases.dedup();
+
+            variants.push(StreamVariant {
+                label,
+                aliases,
+                bandwidth,
+                resolution,
+                frame_rate,
+                uri,
+                backups: Vec::new(),
+                is_audio_only: audio_only,
+                is_source,
+                audio_group,
+                captions: Vec::new(),
+            });
+            variant_attrs.push(attrs);
+            caption_groups.push(caption_group);
+        }
+    }
+
+    if variants.is_empty() {
+        bail!("No playable variants found in playlist");
+    }
+    // EXT-X-MEDIA lines may come after the variants that use them.
+    for (variant, group) in variants.iter_mut().zip(caption_groups) {
+        variant.captions = caption_tracks
+            .iter()
+            .filter(|&&(g, _)| Some(g) == group)
+            .map(|(_, track)| track.clone())
+            .collect();
+    }
+
+    add_audio_aliases(&mut variants);
+    add_quality_aliases(&mut variants);
+    Ok(variants)
+}
+
+/// Gives every provider the same aliases: `<height>p` and
+/// `<height>p<fps>` from the metadata, whatever the variants are named, and
+/// `best`, `worst` and `audio` on the variants those pick.
+fn add_quality_aliases(variants: &mut [StreamVariant]) {
+    for variant in variants.iter_mut() {
+        if variant.is_audio_only {
+            variant.aliases.push("audio".into());
+        } else if let Some((_, height)) = variant.resolution
+            && let Some(fps) = variant.frame_rate.filter(|fps| *fps > 0.0)
+        {
+            variant.aliases.push(format!("{height}p{}", fps.round()));
+        }
+    }
+
+    // As `best` and `worst` are picked without constraints: among the video
+    // variants, unless there are none.
+    let has_video = variants.iter().any(|v| !v.is_audio_only);
+    let candidates: Vec<usize> = (0..variants.len())
+        .filter(|&i| !(has_video && variants[i].is_audio_only))
+        .collect();
+    let best = candidates
+        .iter()
+        .copied()
+        .max_by_key(|&i| variants[i].rank());
+    let worst = candidates
+        .iter()
+        .copied()
+        .min_by_key(|&i| variants[i].rank());
+    // A bare `<height>p` names the best variant of that height only.
+    let heights: Vec<Option<String>> = variants
+        .iter()
+        .map(|v| match v.resolution {
+            Some((_, height)) if !v.is_audio_only => Some(format!("{height}p")),
+            _ => None,
+        })
+        .collect();
+    let height_picks: Vec<bool> = (0..variants.len())
+        .map(|i| {
+            heights[i].is_some()
+                && (0..variants.len())
+                    .filter(|&j| heights[j] == heights[i])
+                    .max_by_key(|&j| (variants[j].rank(), std::cmp::Reverse(j)))
+                    == Some(i)
+        })
+        .collect();
+    if let Some(i) = best {
+        variants[i].aliases.push("best".into());
+    }
+    if let Some(i) = worst {
+        variants[i].aliases.push("worst".into());
+    }
+
+    for ((variant, height), pick) in variants.iter_mut().zip(heights).zip(height_picks) {
+        if let Some(height) = height {
+            variant.aliases.retain(|alias| *alias != height);
+            if pick {
+                variant.aliases.push(height);
+            }
+        }
+        variant.aliases.sort();
+        variant.aliases.dedup();
+    }
+}
+
+fn has_video_codec(codecs: &str) -> bool {
+    codecs.split(',').map(str::trim).any(|codec| {
+        ["avc1", "avc3", "hvc1", "hev1", "vp09", "vp8", "av01"]
+            .iter()
+            .any(|prefix| codec.starts_with(prefix))
+    })
+}
+
+/// Gives audio-only variants bitrate aliases such as `128k`, plus
+/// `audio_best`/`audio_worst` when there is more than one.
+fn add_audio_aliases(variants: &mut [StreamVariant]) {
+    let mut audio: Vec<&mut StreamVariant> =
+        variants.iter_mut().filter(|v| v.is_audio_only).collect();
+    for variant in &mut audio {
+        if variant.bandwidth > 0 {
+            variant
+                .aliases
+                .push(format!("{}k", (variant.bandwidth + 500) / 1000));
+        }
+    }
+    audio.sort_by_key(|v| v.bandwidth);
+    if let Some(worst) = audio.first_mut() {
+        worst.aliases.push("audio_worst".into());
+    }
+    if let Some(best) = audio.last_mut() {
+        best.aliases.push("audio_best".into());
+    }
+    for variant in audio {
+        variant.aliases.sort();
+        variant.aliases.dedup();
+    }
+}
+
+pub fn parse_media_playlist(
+    base_url: &Url,
+    body: &str,
+    query: QueryPassthrough,
+    low_latency: bool,
+    debug_ads: bool,
+) -> Result<MediaPlaylist> {
+    let mut target_duration = 4.0;
+    let mut media_sequence: u64 = 0;
+    let mut end_list = false;
+    let mut segments = Vec::new();
+    let mut pending_duration: Option<f64> = None;
+    let mut pending_title: Option<&str> = None;
+    let mut last_duration: Option<f64> = None;
+    let mut discontinuity_next = false;
+    let mut pending_range: Option<(Option<u64>, u64)> = None;
+    let mut pending_date_time: Option<f64> = None;
+    let mut current_init: Option<Arc<Url>> = None;
+    let mut current_init_range = None;
+    let mut current_key: Option<Arc<SegmentKey>> = None;
+    let mut current_bitrate: Option<u64> = None;
+    // Without program date times (VODs), an ad daterange covers the segments
+    // after it: seconds of it left and whether it has begun. Those segments
+    // are only marked once the playlist turns out to be a finished one.
+    let mut inline_ad: Option<(f64, bool)> = None;
+    let mut inline_ad_segments = Vec::new();
+    let mut policy = TwitchHlsPolicy::new();
+    let mut can_block_reload = false;
+    let mut has_parts = false;
+    // Parts of the segment that is still being produced.
+    let mut trailing_parts = 0;
+
+    for line in body.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("#EXT-X-SERVER-CONTROL:") {
+            can_block_reload = parse_attribute_line(value)
+                .iter()
+                .any(|&(key, value)| key == "CAN-BLOCK-RELOAD" && value == "YES");
+        } else if line.starts_with("#EXT-X-PART:") {
+            has_parts = true;
+            trailing_parts += 1;
+        } else if line.starts_with("#EXT-X-TARGETDURATION:") {
+            if let Some(value) = line.split_once(':').map(|(_, v)| v)
+                && let Ok(parsed) = value.parse::<f64>()
+            {
+                target_duration = parsed;
+            }
+        } else if line.starts_with("#EXT-X-MEDIA-SEQUENCE:") {
+            if let Some(value) = line.split_once(':').map(|(_, v)| v)
+                && let Ok(parsed) = value.parse::<u64>()
+            {
+                media_sequence = parsed;
+            }
+        } else if line.starts_with("#EXTINF:") {
+            let value = line.trim_start_matches("#EXTINF:");
+            let mut parts = value.splitn(2, ',');
+            if let Some(duration_part) = parts.next() {
+                pending_duration = duration_part.parse::<f64>().ok();
+            }
+            pending_title = parts.next().map(str::trim).filter(|s| !s.is_empty());
+            last_duration = pending_duration;
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+            pending_range = parse_byte_range(value);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+            pending_date_time = parse_date_time(value);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-KEY:") {
+            current_key = parse_key(base_url, value, query)?.map(Arc::new);
+        } else if let Some(value) = line.strip_prefix("#EXT-X-BITRATE:") {
+            current_bitrate = value.parse().ok();
+        } else if line.starts_with("#EXT-X-DISCONTINUITY") {
+            discontinuity_next = true;
+            if inline_ad.is_some_and(|(_, begun)| begun) {
+                inline_ad = None;
+            }
+        } else if line.starts_with("#EXT-X-TWITCH-PREFETCH:") {
+            if !low_latency {
+                continue;
+            }
+            let uri = resolve_url(
+                base_url,
+                line.trim_start_matches("#EXT-X-TWITCH-PREFETCH:"),
+                query,
+            )
+            .with_context(|| format!("Resolving prefetch segment URL: {line}"))?;
+            let sequence = media_sequence + segments.len() as u64;
+            let duration = last_duration.unwrap_or(target_duration);
+            let program_date_time = next_date_time(&mut pending_date_time, &segments);
+            let after_ad = segments.last().is_some_and(|s: &MediaSegment| s.ad);
+            let ad_flag = policy.classify_prefetch(&uri, program_date_time, after_ad);
+            if debug_ads {
+                info!(
+                    "[ads] segment={} classified={} prefetch=true",
+                    sequence,
+                    if ad_flag { "AD" } else { "CONTENT" }
+                );
+            }
+            segments.push(MediaSegment {
+                uri,
+                init: current_init.clone(),
+                init_range: current_init_range,
+                sequence,
+                duration,
+                byte_range: None,
+                key: current_key.clone(),
+                bitrate: None,
+                program_date_time,
+                prefetch: true,
+                ad: ad_flag,
+                discontinuity: discontinuity_next,
+            });
+            if discontinuity_next {
+                discontinuity_next = false;
+            }
+            continue;
+        } else if line.starts_with("#EXT-X-DATERANGE:") {
+            let attrs = parse_attribute_line(line.trim_start_matches("#EXT-X-DATERANGE:"));
+            let Some(range) = policy.on_daterange(&attrs) else {
+                continue;
+            };
+            // Without a duration there is no telling where the ad ends.
+            if let Some(duration) = range.duration {
+                inline_ad = Some((duration, false));
+            }
+            if debug_ads {
+                let id = range.id.as_deref().unwrap_or("unknown");
+                match range.duration {
+                    Some(d) => info!(
+                        "[ads] playlist contains stitched ad daterange id={} duration={:.0}",
+                        id, d
+                    ),
+                    None => info!(
+                        "[ads] playlist contains stitched ad daterange id={} duration=unknown",
+                        id
+                    ),
+                }
+            }
+        } else if line.starts_with("#EXT-X-ENDLIST") {
+            end_list = true;
+        } else if line.starts_with("#EXT-X-MAP:") {
+            let attrs = parse_attribute_line(line.trim_start_matches("#EXT-X-MAP:"));
+            if let Some(&(_, uri_value)) = attrs.iter().find(|&&(k, _)| k == "URI") {
+                let map_url = resolve_url(base_url, uri_value, query)
+                    .with_context(|| format!("Resolving init segment URL: {uri_value}"))?;
+                current_init = Some(Arc::new(map_url));
+                current_init_range = attrs
+                    .iter()
+                    .find(|&&(k, _)| k == "BYTERANGE")
+                    .and_then(|&(_, range)| parse_byte_range(range))
+                    .map(|(offset, length)| (offset.unwrap_or(0), length));
+            }
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(duration) = pending_duration.take() {
+            let uri = resolve_url(base_url, line, query)
+                .with_context(|| format!("Resolving segment URL: {line}"))?;
+            let sequence = media_sequence + segments.len() as u64;
+            let title = pending_title.take();
+            // Without an offset, a range follows the previous one of the same resource.
+            let byte_range = pending_range.take().map(|(offset, length)| {
+                let follows = segments
+                    .last()
+                    .filter(|last: &&MediaSegment| last.uri == uri)
+                    .and_then(|last| last.byte_range)
+                    .map_or(0, |(offset, length)| offset + length);
+                (offset.unwrap_or(follows), length)
+            });
+            let program_date_time = next_date_time(&mut pending_date_time, &segments);
+            let ad_flag = policy.classify_segment(&uri, title, false);
+            if program_date_time.is_none()
+                && let Some((left, begun)) = &mut inline_ad
+            {
+                inline_ad_segments.push(segments.len());
+                *begun = true;
+                *left -= duration;
+                if *left <= 0.0 {
+                    inline_ad = None;
+                }
+            }
+            if debug_ads {
+                info!(
+                    "[ads] segment={} classified={} prefetch=false",
+                    sequence,
+                    if ad_flag { "AD" } else { "CONTENT" }
+                );
+            }
+            segments.push(MediaSegment {
+                uri,
+                init: current_init.clone(),
+                init_range: current_init_range,
+                sequence,
+                duration,
+                byte_range,
+                key: current_key.clone(),
+                bitrate: current_bitrate,
+                program_date_time,
+                prefetch: false,
+                ad: ad_flag,
+                discontinuity: discontinuity_next,
+            });
+            if discontinuity_next {
+                discontinuity_next = false;
+            }
+            trailing_parts = 0;
+        }
+    }
+
+    if segments.is_empty() {
+        bail!("No segments found in media playlist");
+    }
+
+    if end_list {
+        for &index in &inline_ad_segments {
+            let segment = &mut segments[index];
+            if !segment.ad {
+                segment.ad = true;
+                if debug_ads {
+                    info!(
+                        "[ads] segment={} classified=AD by daterange",
+                        segment.sequence
+                    );
+                }
+            }
+        }
+    }
+
+    // Dateranges may be declared after the segments they cover.
+    for segment in &mut segments {
+        if !segment.ad
+            && segment
+                .program_date_time
+                .is_some_and(|time| policy.covers(time))
+        {
+            segment.ad = true;
+            if debug_ads {
+                info!(
+                    "[ads] segment={} classified=AD by daterange",
+                    segment.sequence
+                );
+            }
+        }
+    }
+
+    let ads_active = segments.iter().any(|s| s.ad);
+    let blocking_reload = (can_block_reload && !end_list).then(|| {
+        let next_msn = media_sequence + segments.len() as u64;
+        (next_msn, has_parts.then_some(trailing_parts))
+    });
+
+    Ok(MediaPlaylist {
+        target_duration,
+        end_list,
+        segments,
+        ads_active,
+        ad_dateranges: policy.dateranges,
+        blocking_reload,
+    })
+}
+
+/// The program date time of the next segment: its own tag, or the previous
+/// segment's continued.
+fn next_date_time(pending: &mut Option<f64>, segments: &[MediaSegment]) -> Option<f64> {
+    pending.take().or_else(|| {
+        let last = segments.last()?;
+        Some(last.program_date_time? + last.duration)
+    })
+}
+
+/// Parses an ISO 8601 date and time such as `2024-05-01T12:00:00.500Z` into
+/// seconds since the Unix epoch.
+fn parse_date_time(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let (date, time) = value.split_once(['T', ' '])?;
+    let mut date = date.splitn(3, '-');
+    let year: i64 = date.next()?.parse().ok()?;
+    let month: i64 = date.next()?.parse().ok()?;
+    let day: i64 = date.next()?.parse().ok()?;
+
+    let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+        (clock, 0)
+    } else if let Some(at) = time.rfind(['+', '-']) {
+        let (clock, zone) = time.split_at(at);
+        let sign = if zone.starts_with('-') { -1 } else { 1 };
+        let zone = zone[1..].replace(':', "");
+        let hours: i64 = zone.get(..2)?.parse().ok()?;
+        let minutes: i64 = zone
+            .get(2..)
+            .filter(|m| !m.is_empty())
+            .map_or(Ok(0), str::parse)
+            .ok()?;
+        (clock, sign * (hours * 3600 + minutes * 60))
+    } else {
+        (time, 0)
+    };
+    let mut clock = clock.splitn(3, ':');
+    let hour: i64 = clock.next()?.parse().ok()?;
+    let minute: i64 = clock.next()?.parse().ok()?;
+    let second: f64 = clock.next().unwrap_or("0").parse().ok()?;
+
+    // Days from the civil calendar, after Howard Hinnant's algorithm.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    Some((days * 86_400 + hour * 3600 + minute * 60 - offset) as f64 + second)
+}
+
+fn resolve_url(base: &Url, input: &str, query: QueryPassthrough) -> Result<Url> {
+    if let Ok(url) = Url::parse(input) {
+        return Ok(url);
+    }
+
+    let mut url = base.join(input).context("Failed to resolve relative URL")?;
+    match (query, base.query()) {
+        (QueryPassthrough::Strip, _) => url.set_query(None),
+        (QueryPassthrough::Inherit, Some(inherited)) => {
+            // Copied as written: signed parameters may not survive re-encoding.
+            let key = |pair: &str| pair.split('=').next().unwrap_or_default().to_string();
+            let own: Vec<&str> = url
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .collect();
+            let own_keys: Vec<String> = own.iter().map(|pair| key(pair)).collect();
+            let merged: Vec<&str> = own
+                .iter()
+                .copied()
+                .chain(
+                    inherited
+                        .split('&')
+                        .filter(|pair| !pair.is_empty() && !own_keys.contains(&key(pair))),
+                )
+                .collect();
+            let merged = merged.join("&");
+            url.set_query(Some(&merged));
+        }
+        _ => {}
+    }
+    Ok(url)
+}
+
+/// Splits `KEY=VALUE,KEY="VALUE"` attribute lists without copying them.
+fn parse_attribute_line(value: &str) -> Vec<(&str, &str)> {
+    let mut pairs = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+
+    for (i, ch) in value.char_indices() {
+        match ch {
+            ',' if !in_quotes => {
+                pairs.push(&value[start..i]);
+                start = i + 1;
+            }
+            '"' => in_quotes = !in_quotes,
+            _ => {}
+        }
+    }
+    pairs.push(&value[start..]);
+
+    pairs
+        .into_iter()
+        .filter_map(|pair| {
+            pair.split_once('=')
+                .map(|(k, v)| (k.trim(), v.trim().trim_matches('"')))
+        })
+        .collect()
+}
+
+fn parse_resolution(value: &str) -> Option<(u64, u64)> {
+    let (w, h) = value.split_once('x')?;
+    let width = w.parse().ok()?;
+    let height = h.parse().ok()?;
+    Some((width, height))
+}
+
+fn build_labels(
+    name: Option<&str>,
+    resolution: Option<(u64, u64)>,
+    frame_rate: Option<f64>,
+    audio_only: bool,
+) -> (String, Vec<String>) {
+    let mut aliases = Vec::new();
+
+    if let Some(name) = name {
+        aliases.push(name.to_lowercase());
+    }
+
+    let resolution_label = resolution.map(|(_, height)| {
+        let suffix = if frame_rate.map(|fr| fr >= 59.5).unwrap_or(false) {
+            "60"
+        } else {
+            ""
+        };
+        let label = format!("{height}p{suffix}");
+        aliases.push(label.to_lowercase());
+        label
+    });
+
+    if audio_only {
+        aliases.push("audio_only".into());
+        aliases.push("audio".into());
+    }
+
+    let primary = name
+        .map(|n| n.to_string())
+        .or(resolution_label)
+        .unwrap_or_else(|| {
+            if audio_only {
+                "audio_only".into()
+            } else {
+                "unknown".into()
+            }
+        });
+
+    aliases.push(primary.to_lowercase());
+    aliases.sort();
+    aliases.dedup();
+
+    (primary, aliases)
+}
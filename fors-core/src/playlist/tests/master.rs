@@ -0,0 +1,212 @@
+use crate::playlist::{
+    QueryPassthrough, parse_audio_renditions, parse_iframe_playlists, parse_master_playlist,
+};
+use url::Url;
+
+const TWITCH_MASTER: &str = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="chunked",NAME="1080p60 (source)",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=6000000,RESOLUTION=1920x1080,VIDEO="chunked",FRAME-RATE=60.000
+source.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="720p30",NAME="720p",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=6500000,RESOLUTION=1280x720,VIDEO="720p30",FRAME-RATE=30.000
+720p30.m3u8
+"#;
+
+#[test]
+fn twitch_source_is_detected_and_labelled() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let variants = parse_master_playlist(&base, TWITCH_MASTER, QueryPassthrough::Off).unwrap();
+
+    assert_eq!(variants[0].label, "1080p60");
+    assert!(variants[0].is_source);
+    assert!(variants[0].aliases.contains(&"source".to_string()));
+
+    assert_eq!(variants[1].label, "720p30");
+    assert!(!variants[1].is_source);
+}
+
+#[test]
+fn unnamed_variants_get_the_same_quality_aliases() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=4000000,CODECS=\"avc1.640020\",RESOLUTION=1280x720,FRAME-RATE=59.94
+hd.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,CODECS=\"avc1.4d401e\",RESOLUTION=640x360,FRAME-RATE=29.97
+sd.m3u8
+";
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    let aliases = |i: usize| variants[i].aliases.join(" ");
+    assert_eq!(aliases(0), "720p 720p60 best");
+    assert_eq!(aliases(1), "360p 360p30 worst");
+}
+
+#[test]
+fn redundant_variants_become_backups() {
+    let base = Url::parse("https://a.example.com/master.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=4000000,RESOLUTION=1280x720
+hd.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,RESOLUTION=640x360
+sd.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=4000000,RESOLUTION=1280x720
+https://b.example.com/hd.m3u8
+";
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    assert_eq!(variants.len(), 2);
+    assert_eq!(variants[0].uri.as_str(), "https://a.example.com/hd.m3u8");
+    assert_eq!(
+        variants[0].backups,
+        [Url::parse("https://b.example.com/hd.m3u8").unwrap()]
+    );
+    assert!(variants[1].backups.is_empty());
+}
+
+#[test]
+fn closed_captions_are_linked_to_variants() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = r#"#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=4000000,RESOLUTION=1280x720,CLOSED-CAPTIONS="cc"
+hd.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=1000000,RESOLUTION=640x360,CLOSED-CAPTIONS=NONE
+sd.m3u8
+#EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID="cc",NAME="English",LANGUAGE="en",INSTREAM-ID="CC1"
+#EXT-X-MEDIA:TYPE=CLOSED-CAPTIONS,GROUP-ID="cc",LANGUAGE="es",INSTREAM-ID="SERVICE2"
+"#;
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    let captions = &variants[0].captions;
+    assert_eq!(captions.len(), 2);
+    assert_eq!(captions[0].instream_id, "CC1");
+    assert_eq!(captions[0].name, "English");
+    assert_eq!(captions[1].instream_id, "SERVICE2");
+    assert_eq!(captions[1].name, "es");
+    assert!(variants[1].captions.is_empty());
+}
+
+const DUBBED_MASTER: &str = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="234",NAME="English (original)",LANGUAGE="en",DEFAULT=YES,URI="audio/en.m3u8"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="234",NAME="Español",LANGUAGE="es-419",DEFAULT=NO,URI="audio/es.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,CODECS="avc1.4d401f",RESOLUTION=1280x720,AUDIO="234"
+video/720.m3u8
+"#;
+
+#[test]
+fn audio_renditions_are_linked_to_variants() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let variants = parse_master_playlist(&base, DUBBED_MASTER, QueryPassthrough::Off).unwrap();
+    let tracks = parse_audio_renditions(&base, DUBBED_MASTER, QueryPassthrough::Off).unwrap();
+
+    assert_eq!(variants[0].audio_group.as_deref(), Some("234"));
+    assert!(!variants[0].is_audio_only);
+    assert_eq!(tracks.len(), 2);
+    assert!(tracks[0].is_default);
+    assert_eq!(tracks[1].language.as_deref(), Some("es-419"));
+    assert_eq!(
+        tracks[1].uri.as_ref().map(Url::as_str),
+        Some("https://example.com/audio/es.m3u8")
+    );
+}
+
+#[test]
+fn audio_group_names_do_not_make_variants_audio_only() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID="audio-aac",NAME="English",LANGUAGE="en",URI="audio/en.m3u8"
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,RESOLUTION=1280x720,AUDIO="audio-aac"
+video/720.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=96000,CODECS="mp4a.40.2",AUDIO="audio-aac"
+audio/en.m3u8
+"#;
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    assert!(!variants[0].is_audio_only);
+    assert_eq!(variants[0].audio_group.as_deref(), Some("audio-aac"));
+    assert!(variants[1].is_audio_only);
+}
+
+#[test]
+fn iframe_playlists_are_listed_apart_from_variants() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=4000000,RESOLUTION=1280x720
+hd.m3u8
+#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=200000,RESOLUTION=1280x720,URI=\"hd-iframes.m3u8\"
+";
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+    let iframes = parse_iframe_playlists(&base, body, QueryPassthrough::Off).unwrap();
+
+    assert_eq!(variants.len(), 1);
+    let [iframe] = &iframes[..] else {
+        panic!("expected one I-frame playlist");
+    };
+    assert_eq!(iframe.uri.as_str(), "https://example.com/hd-iframes.m3u8");
+    assert_eq!(
+        (iframe.bandwidth, iframe.resolution),
+        (200000, Some((1280, 720)))
+    );
+}
+
+#[test]
+fn worst_is_never_the_audio_only_variant() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=3000000,CODECS=\"avc1.4d401f,mp4a.40.2\",RESOLUTION=1280x720,FRAME-RATE=30.000
+720p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=700000,CODECS=\"avc1.4d401e,mp4a.40.2\",RESOLUTION=640x360,FRAME-RATE=30.000
+360p.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=160000,CODECS=\"mp4a.40.2\"
+audio.m3u8
+";
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    let named = |alias: &str| {
+        variants
+            .iter()
+            .filter(|v| v.aliases.iter().any(|a| a == alias))
+            .map(|v| v.uri.path())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(named("best"), ["/720p.m3u8"]);
+    assert_eq!(named("worst"), ["/360p.m3u8"]);
+    assert_eq!(named("audio"), ["/audio.m3u8"]);
+}
+
+#[test]
+fn audio_only_playlists_still_have_best_and_worst() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=64000,CODECS=\"mp4a.40.2\"
+low.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=192000,CODECS=\"mp4a.40.2\"
+high.m3u8
+";
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    assert!(variants[0].aliases.contains(&"worst".to_string()));
+    assert!(variants[1].aliases.contains(&"best".to_string()));
+}
+
+#[test]
+fn bare_height_names_the_best_variant_of_that_height() {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=3000000,CODECS=\"avc1.4d401f\",RESOLUTION=1280x720,FRAME-RATE=30.000
+720p30.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=4500000,CODECS=\"avc1.4d401f\",RESOLUTION=1280x720,FRAME-RATE=60.000
+720p60.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,CODECS=\"avc1.4d401e\",RESOLUTION=852x480,FRAME-RATE=30.000
+480p-a.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2500000,CODECS=\"avc1.4d401e\",RESOLUTION=852x480,FRAME-RATE=30.000,NAME=\"other\"
+480p-b.m3u8
+";
+    let variants = parse_master_playlist(&base, body, QueryPassthrough::Off).unwrap();
+
+    let aliases = |i: usize| variants[i].aliases.join(" ");
+    assert_eq!(aliases(0), "720p30");
+    assert_eq!(aliases(1), "720p 720p60 best");
+    // Equal variants leave the bare height to the first one.
+    assert!(variants[2].aliases.contains(&"480p".to_string()));
+    assert!(!variants[3].aliases.contains(&"480p".to_string()));
+}
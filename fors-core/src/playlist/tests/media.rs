@@ -0,0 +1,167 @@
+use crate::playlist::{QueryPassthrough, parse_media_playlist};
+use std::sync::Arc;
+use url::Url;
+
+#[test]
+fn blocking_reload_requests_the_next_part() {
+    let base = Url::parse("https://example.com/live/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.0
+#EXT-X-PART-INF:PART-TARGET=0.5
+#EXT-X-MEDIA-SEQUENCE:100
+#EXTINF:4.0,
+seg100.ts
+#EXT-X-PART:DURATION=0.5,URI=\"seg101.0.ts\"
+#EXT-X-PART:DURATION=0.5,URI=\"seg101.1.ts\"
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+    assert_eq!(playlist.segments.len(), 1);
+    assert_eq!(playlist.blocking_reload, Some((101, Some(2))));
+
+    let without_control = body.replace(
+        "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,",
+        "#EXT-X-SERVER-CONTROL:",
+    );
+    let playlist =
+        parse_media_playlist(&base, &without_control, QueryPassthrough::Off, false, false).unwrap();
+    assert_eq!(playlist.blocking_reload, None);
+}
+
+#[test]
+fn segments_share_their_init_segment() {
+    let base = Url::parse("https://example.com/vod/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MAP:URI=\"init.mp4\",BYTERANGE=\"720@0\"
+#EXTINF:2.0,
+seg0.m4s
+#EXTINF:2.0,Amazon Ad
+seg1.m4s
+#EXT-X-ENDLIST
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+    let [first, second] = &playlist.segments[..] else {
+        panic!("expected two segments");
+    };
+    let init = first.init.as_ref().unwrap();
+    assert_eq!(init.as_str(), "https://example.com/vod/init.mp4");
+    assert_eq!(first.init_range, Some((0, 720)));
+    assert!(Arc::ptr_eq(init, second.init.as_ref().unwrap()));
+    assert!(!first.ad && second.ad);
+    assert_eq!(second.duration, 2.0);
+    assert_eq!(second.effective_duration(), 0.0);
+}
+
+#[test]
+fn byte_ranges_continue_from_the_previous_range() {
+    let base = Url::parse("https://example.com/vod/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXTINF:2.0,
+#EXT-X-BYTERANGE:1000@500
+all.ts
+#EXTINF:2.0,
+#EXT-X-BYTERANGE:800
+all.ts
+#EXTINF:2.0,
+other.ts
+#EXT-X-ENDLIST
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+    let ranges: Vec<_> = playlist.segments.iter().map(|s| s.byte_range).collect();
+    assert_eq!(ranges, [Some((500, 1000)), Some((1500, 800)), None]);
+}
+
+#[test]
+fn keys_apply_until_the_next_key_tag() {
+    let base = Url::parse("https://example.com/live/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:5
+#EXT-X-KEY:METHOD=AES-128,URI=\"k1.key\"
+#EXTINF:2.0,
+seg5.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"k2.key\",IV=0x0000000000000000000000000000002A
+#EXTINF:2.0,
+seg6.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:2.0,
+seg7.ts
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+    let [first, second, third] = &playlist.segments[..] else {
+        panic!("expected three segments");
+    };
+
+    let key = first.key.as_ref().unwrap();
+    assert_eq!(key.uri.as_str(), "https://example.com/live/k1.key");
+    assert_eq!(key.iv_for(first.sequence)[15], 5);
+    assert_eq!(second.key.as_ref().unwrap().iv_for(6)[15], 42);
+    assert!(third.key.is_none());
+}
+
+#[test]
+fn bitrates_apply_until_the_next_bitrate_tag() {
+    let base = Url::parse("https://example.com/live/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXTINF:2.0,
+seg0.ts
+#EXT-X-BITRATE:6000
+#EXTINF:2.0,
+seg1.ts
+#EXTINF:2.0,
+seg2.ts
+#EXT-X-BITRATE:3000
+#EXTINF:2.0,
+seg3.ts
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+    let bitrates: Vec<_> = playlist.segments.iter().map(|s| s.bitrate).collect();
+    assert_eq!(bitrates, [None, Some(6000), Some(6000), Some(3000)]);
+}
+
+#[test]
+fn relative_segments_inherit_or_strip_the_playlist_query() {
+    let base = Url::parse("https://cdn.example.com/live/index.m3u8?token=a%2Fb&exp=1").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"
+#EXTINF:2.0,
+seg0.ts?exp=2
+#EXTINF:2.0,
+https://other.example.com/seg1.ts?sig=x
+";
+    let uris = |query| {
+        let playlist = parse_media_playlist(&base, body, query, false, false).unwrap();
+        let mut uris = vec![playlist.segments[0].key.as_ref().unwrap().uri.to_string()];
+        uris.extend(playlist.segments.iter().map(|s| s.uri.to_string()));
+        uris
+    };
+
+    assert_eq!(
+        uris(QueryPassthrough::Off),
+        [
+            "https://cdn.example.com/live/key.bin",
+            "https://cdn.example.com/live/seg0.ts?exp=2",
+            "https://other.example.com/seg1.ts?sig=x",
+        ]
+    );
+    assert_eq!(
+        uris(QueryPassthrough::Inherit),
+        [
+            "https://cdn.example.com/live/key.bin?token=a%2Fb&exp=1",
+            "https://cdn.example.com/live/seg0.ts?exp=2&token=a%2Fb",
+            "https://other.example.com/seg1.ts?sig=x",
+        ]
+    );
+    assert_eq!(
+        uris(QueryPassthrough::Strip),
+        [
+            "https://cdn.example.com/live/key.bin",
+            "https://cdn.example.com/live/seg0.ts",
+            "https://other.example.com/seg1.ts?sig=x",
+        ]
+    );
+}
@@ -0,0 +1,3 @@
+mod master;
+mod media;
+mod twitch_ads;
@@ -0,0 +1,151 @@
+use crate::playlist::twitch_policy::TwitchHlsPolicy;
+use crate::playlist::{QueryPassthrough, parse_media_playlist};
+use url::Url;
+
+#[test]
+fn daterange_is_recorded_for_logging() {
+    let mut policy = TwitchHlsPolicy::new();
+
+    policy.on_daterange(&[("CLASS", "twitch-stitched-ad"), ("ID", "stitched-ad-1")]);
+
+    assert_eq!(policy.dateranges.len(), 1);
+    assert_eq!(policy.dateranges[0].id.as_deref(), Some("stitched-ad-1"));
+
+    let is_ad = policy.classify_segment(
+        &Url::parse("https://example.com/seg.ts").unwrap(),
+        Some("Amazon Ad"),
+        false,
+    );
+    assert!(is_ad);
+}
+
+#[test]
+fn prefetch_classification_does_not_mutate_state() {
+    let policy = TwitchHlsPolicy::new();
+
+    let is_ad = policy.classify_segment(
+        &Url::parse("https://example.com/stitched-ad-prefetch.ts").unwrap(),
+        None,
+        true,
+    );
+
+    assert!(is_ad);
+    assert!(policy.dateranges.is_empty());
+}
+
+#[test]
+fn title_detection_marks_ad_without_daterange() {
+    let policy = TwitchHlsPolicy::new();
+
+    let is_ad = policy.classify_segment(
+        &Url::parse("https://example.com/seg.ts").unwrap(),
+        Some("Amazon Ad Spot"),
+        false,
+    );
+
+    assert!(is_ad);
+}
+
+#[test]
+fn overlapping_dateranges_classify_segments_by_program_date_time() {
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-MEDIA-SEQUENCE:10
+#EXT-X-DATERANGE:ID=\"stitched-ad-pre\",CLASS=\"twitch-stitched-ad\",START-DATE=\"2024-05-01T12:00:02.000Z\",DURATION=4
+#EXT-X-DATERANGE:ID=\"stitched-ad-mid\",CLASS=\"twitch-stitched-ad\",START-DATE=\"2024-05-01T14:00:05+02:00\",DURATION=3
+#EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:00.000Z
+#EXTINF:2.000,live
+seg10.ts
+#EXTINF:2.000,live
+seg11.ts
+#EXTINF:2.000,live
+seg12.ts
+#EXTINF:2.000,live
+seg13.ts
+#EXTINF:2.000,live
+seg14.ts
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+
+    let ads: Vec<bool> = playlist.segments.iter().map(|s| s.ad).collect();
+    assert_eq!(ads, [false, true, true, true, false]);
+    assert_eq!(playlist.ad_dateranges.len(), 2);
+    // 12:00:02 to 12:00:08, the midroll overlapping the preroll.
+    assert_eq!(playlist.ad_break_duration(), Some(6.0));
+}
+
+#[test]
+fn prefetch_during_an_ad_daterange_is_an_ad() {
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:2
+#EXT-X-DATERANGE:ID=\"stitched-ad-1\",CLASS=\"twitch-stitched-ad\",DURATION=30
+#EXTINF:2.000,live
+seg0.ts
+#EXTINF:2.000,Amazon
+seg1.ts
+#EXT-X-TWITCH-PREFETCH:https://example.com/seg2.ts
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, true, false).unwrap();
+
+    let prefetch = playlist.segments.last().unwrap();
+    assert!(prefetch.prefetch && prefetch.ad);
+}
+
+#[test]
+fn vod_ad_daterange_covers_segments_until_discontinuity() {
+    let base = Url::parse("https://example.com/vod/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXTINF:10.000,
+0.ts
+#EXT-X-DATERANGE:ID=\"stitched-ad-7\",CLASS=\"twitch-stitched-ad\",DURATION=60
+#EXT-X-DISCONTINUITY
+#EXTINF:10.000,
+1.ts
+#EXTINF:10.000,
+2.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:10.000,
+3.ts
+#EXT-X-ENDLIST
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+
+    let ads: Vec<bool> = playlist.segments.iter().map(|s| s.ad).collect();
+    assert_eq!(ads, [false, true, true, false]);
+}
+
+#[test]
+fn ad_daterange_without_date_times_only_marks_finished_playlists() {
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-DATERANGE:ID=\"stitched-ad-7\",CLASS=\"twitch-stitched-ad\",DURATION=20
+#EXTINF:10.000,
+0.ts
+#EXTINF:10.000,
+1.ts
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+
+    assert!(playlist.segments.iter().all(|s| !s.ad));
+}
+
+#[test]
+fn vod_ad_daterange_without_duration_is_ignored() {
+    let base = Url::parse("https://example.com/vod/index.m3u8").unwrap();
+    let body = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXT-X-DATERANGE:ID=\"stitched-ad-7\",CLASS=\"twitch-stitched-ad\"
+#EXTINF:10.000,
+0.ts
+#EXTINF:10.000,
+1.ts
+#EXT-X-ENDLIST
+";
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+
+    assert!(playlist.segments.iter().all(|s| !s.ad));
+}
@@ -0,0 +1,152 @@
+use url::Url;
+
+use super::parse_date_time;
+
+/// A `twitch-stitched-ad` `EXT-X-DATERANGE`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdDateRange {
+    pub id: Option<String>,
+    /// `START-DATE` in seconds since the Unix epoch.
+    pub start: Option<f64>,
+    pub duration: Option<f64>,
+}
+
+impl AdDateRange {
+    /// Whether the media at `time` (seconds since the Unix epoch) is inside
+    /// this ad.
+    pub fn covers(&self, time: f64) -> bool {
+        match (self.start, self.duration) {
+            (Some(start), Some(duration)) => time >= start && time < start + duration,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TwitchHlsPolicy {
+    /// Every ad daterange in the playlist, such as a preroll followed by a
+    /// midroll.
+    pub dateranges: Vec<AdDateRange>,
+}
+
+impl TwitchHlsPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the daterange if it is an ad and returns it.
+    pub fn on_daterange(&mut self, attrs: &[(&str, &str)]) -> Option<&AdDateRange> {
+        let mut class = None;
+        let mut id = None;
+        let mut start = None;
+        let mut duration = None;
+
+        for &(k, v) in attrs {
+            match k {
+                "CLASS" => class = Some(v),
+                "ID" => id = Some(v),
+                "START-DATE" => start = parse_date_time(v),
+                "DURATION" => duration = v.parse::<f64>().ok(),
+                _ => {}
+            }
+        }
+
+        let is_ad = class == Some("twitch-stitched-ad")
+            || id.map(|v| v.starts_with("stitched-ad-")).unwrap_or(false);
+        if !is_ad {
+            return None;
+        }
+
+        let range = AdDateRange {
+            id: id.map(str::to_string),
+            start,
+            duration,
+        };
+        // A daterange may be repeated with more attributes filled in.
+        let existing = self
+            .dateranges
+            .iter()
+            .position(|known| range.id.is_some() && known.id == range.id);
+        let index = match existing {
+            Some(index) => {
+                self.dateranges[index] = range;
+                index
+            }
+            None => {
+                self.dateranges.push(range);
+                self.dateranges.len() - 1
+            }
+        };
+        self.dateranges.get(index)
+    }
+
+    pub fn classify_segment(&self, uri: &Url, title: Option<&str>, _is_prefetch: bool) -> bool {
+        if let Some(t) = title
+            && (contains_ignore_case(t, "amazon") || contains_ignore_case(t, "stitched-ad"))
+        {
+            return true;
+        }
+
+        uri.as_str().contains("stitched-ad")
+    }
+
+    /// Prefetch URIs do not always carry the `stitched-ad` marker during a
+    /// break, so while an ad daterange is active they are classified by
+    /// time. Without a program date time, a prefetch segment right after an
+    /// ad is taken to continue it.
+    pub fn classify_prefetch(
+        &self,
+        uri: &Url,
+        program_date_time: Option<f64>,
+        after_ad: bool,
+    ) -> bool {
+        if self.classify_segment(uri, None, true) {
+            return true;
+        }
+        match program_date_time {
+            Some(time) => self.covers(time),
+            None => after_ad && !self.dateranges.is_empty(),
+        }
+    }
+
+    /// Whether the media at `program_date_time` falls inside an ad daterange,
+    /// for ad segments without a telltale URI or title.
+    pub fn covers(&self, program_date_time: f64) -> bool {
+        self.dateranges
+            .iter()
+            .any(|range| range.covers(program_date_time))
+    }
+}
+
+/// The advertised length of `ranges` together. Overlapping ranges are only
+/// counted once.
+pub fn total_duration(ranges: &[AdDateRange]) -> Option<f64> {
+    let mut spans: Vec<(f64, f64)> = Vec::new();
+    let mut unplaced = None;
+    for range in ranges {
+        match (range.start, range.duration) {
+            (Some(start), Some(duration)) => spans.push((start, start + duration)),
+            (None, Some(duration)) => *unplaced.get_or_insert(0.0) += duration,
+            _ => {}
+        }
+    }
+    spans.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut total = unplaced;
+    let mut covered_until = f64::NEG_INFINITY;
+    for (start, end) in spans {
+        let from = start.max(covered_until);
+        if end > from {
+            *total.get_or_insert(0.0) += end - from;
+        }
+        covered_until = covered_until.max(end);
+    }
+    total
+}
+
+/// `needle` must be lowercase.
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
@@ -0,0 +1,255 @@
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+use std::fmt;
+use url::Url;
+
+#[cfg(test)]
+mod tests;
+
+/// What a supported URL points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    TwitchLive {
+        channel: String,
+    },
+    /// A VOD or highlight, optionally starting `start` seconds in (`?t=1h2m3s`).
+    TwitchVod {
+        id: String,
+        start: Option<u64>,
+    },
+    /// A collection of VODs, to be expanded with
+    /// [`collection_request`](crate::twitch::collection_request).
+    TwitchCollection {
+        id: String,
+    },
+    YouTube {
+        video_id: String,
+    },
+}
+
+impl Target {
+    pub fn provider(&self) -> &'static str {
+        match self {
+            Target::TwitchLive { .. }
+            | Target::TwitchVod { .. }
+            | Target::TwitchCollection { .. } => "twitch",
+            Target::YouTube { .. } => "youtube",
+        }
+    }
+
+    /// Channel name or video id.
+    pub fn id(&self) -> &str {
+        match self {
+            Target::TwitchLive { channel } => channel,
+            Target::TwitchVod { id, .. } | Target::TwitchCollection { id } => id,
+            Target::YouTube { video_id } => video_id,
+        }
+    }
+}
+
+/// What the platform says about a stream, for listings and frontends.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StreamMetadata {
+    pub title: Option<String>,
+    /// Channel or uploader name.
+    pub author: Option<String>,
+    /// Current viewers of a live stream, or the views of a video.
+    pub viewers: Option<u64>,
+    /// When the broadcast started or the video was published, as given by
+    /// the platform (ISO 8601).
+    pub started_at: Option<String>,
+    /// Twitch category or YouTube genre.
+    pub category: Option<String>,
+    /// URL of the stream's preview image or the video's thumbnail.
+    pub thumbnail: Option<String>,
+    /// Chapters of a VOD, in order.
+    pub chapters: Vec<Chapter>,
+}
+
+/// A section of a VOD, such as one game of a multi-game stream.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Chapter {
+    /// Seconds from the start of the VOD.
+    pub start: f64,
+    pub duration: f64,
+    pub title: String,
+}
+
+/// A stream that exists but is not live, or a channel or video that does not
+/// exist. Kept apart from other errors so callers can report them plainly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unavailable {
+    /// The channel exists but is not streaming.
+    Offline(String),
+    /// Describes what was looked up, e.g. `channel foo` or `VOD 123`.
+    NotFound(String),
+    /// An upcoming premiere or live event, with its scheduled start as a Unix
+    /// timestamp when known.
+    Scheduled(Option<u64>),
+    /// Blocked for this viewer, e.g. by region or subscription; holds why.
+    Restricted(String),
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unavailable::Offline(channel) => write!(f, "{channel} is offline"),
+            Unavailable::NotFound(what) => write!(f, "{what} does not exist"),
+            Unavailable::Scheduled(_) => write!(f, "The stream has not started yet"),
+            Unavailable::Restricted(reason) => write!(f, "The stream cannot be played: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for Unavailable {}
+
+/// Returns the name of the provider that would handle `input`, if any.
+pub fn provider_name_for(input: &str) -> Option<&'static str> {
+    let url = Url::parse(input).ok()?;
+    if is_twitch_url(&url) {
+        Some("twitch")
+    } else if is_youtube_url(&url) {
+        Some("youtube")
+    } else {
+        None
+    }
+}
+
+pub fn resolve(input: &str) -> Result<Target> {
+    let url = Url::parse(input)?;
+
+    if is_twitch_url(&url) {
+        resolve_twitch(&url)
+    } else if is_youtube_url(&url) {
+        let video_id = youtube_video_id(&url).ok_or_else(|| anyhow!("Unsupported YouTube URL"))?;
+        Ok(Target::YouTube { video_id })
+    } else {
+        bail!("Unsupported URL: {input}");
+    }
+}
+
+fn is_twitch_url(url: &Url) -> bool {
+    url.host_str()
+        .map(|host| host == "twitch.tv" || host.ends_with(".twitch.tv"))
+        .unwrap_or(false)
+}
+
+fn is_youtube_url(url: &Url) -> bool {
+    url.host_str()
+        .map(|host| host.contains("youtube.com") || host == "youtu.be")
+        .unwrap_or(false)
+}
+
+fn resolve_twitch(url: &Url) -> Result<Target> {
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|p| !p.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let query = |key: &str| {
+        url.query_pairs()
+            .find_map(|(k, v)| (k == key && !v.is_empty()).then(|| v.to_string()))
+    };
+
+    // A VOD opened from a collection carries the collection id; the whole
+    // collection is what the link points at.
+    if let Some(id) = query("collection") {
+        return Ok(Target::TwitchCollection { id });
+    }
+
+    match segments
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["collections", id, ..] => {
+            return Ok(Target::TwitchCollection { id: id.to_string() });
+        }
+        // Old style links: twitch.tv/<channel>/v/<id> and /<channel>/video/<id>
+        ["videos", id, ..] | [_, "v" | "video", id, ..] => {
+            let start = match query("t") {
+                Some(t) => Some(
+                    parse_timestamp(&t)
+                        .ok_or_else(|| anyhow!("Invalid start time '{t}' in URL"))?,
+                ),
+                None => None,
+            };
+            return Ok(Target::TwitchVod {
+                id: id.to_string(),
+                start,
+            });
+        }
+        ["videos"] => bail!("Missing VOD id in URL"),
+        _ => {}
+    }
+
+    if let Some(channel) = segments.first() {
+        Ok(Target::TwitchLive {
+            channel: channel.clone(),
+        })
+    } else {
+        bail!("Invalid Twitch URL: {}", url);
+    }
+}
+
+/// Parses Twitch's `t` parameter: `1h2m3s`, `90s` or plain seconds.
+fn parse_timestamp(value: &str) -> Option<u64> {
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+    let mut total = 0;
+    let mut number = String::new();
+    for c in value.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'h' | 'm' | 's' => {
+                let unit = match c {
+                    'h' => 3600,
+                    'm' => 60,
+                    _ => 1,
+                };
+                total += number.parse::<u64>().ok()? * unit;
+                number.clear();
+            }
+            _ => return None,
+        }
+    }
+    number.is_empty().then_some(total)
+}
+
+fn youtube_video_id(url: &Url) -> Option<String> {
+    let host = url.host_str()?.to_lowercase();
+    if host == "youtu.be" {
+        return url.path_segments()?.next().map(String::from);
+    }
+
+    if let Some(id) = url
+        .query_pairs()
+        .find_map(|(k, v)| if k == "v" { Some(v.to_string()) } else { None })
+    {
+        return Some(id);
+    }
+
+    let segments: Vec<String> = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    match segments.as_slice() {
+        [prefix, id] if prefix == "live" || prefix == "embed" || prefix == "shorts" => {
+            Some(id.to_string())
+        }
+        _ => None,
+    }
+}
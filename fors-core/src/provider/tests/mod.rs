@@ -0,0 +1,2 @@
+mod twitch;
+mod youtube;
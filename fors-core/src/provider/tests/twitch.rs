@@ -0,0 +1,159 @@
+use crate::provider::{Target, Unavailable, resolve};
+use crate::twitch::{
+    TwitchTarget, parse_metadata, parse_stream_status, parse_stream_status_batch,
+    stream_status_batch_request, usher_error,
+};
+
+#[test]
+fn vod_links_keep_start_time() {
+    for url in [
+        "https://www.twitch.tv/videos/123?t=1h2m3s",
+        "https://www.twitch.tv/somechannel/v/123?t=3723",
+    ] {
+        assert_eq!(
+            resolve(url).unwrap(),
+            Target::TwitchVod {
+                id: "123".into(),
+                start: Some(3723),
+            }
+        );
+    }
+}
+
+#[test]
+fn collection_links_resolve_to_collection() {
+    for url in [
+        "https://www.twitch.tv/collections/abcDEF",
+        "https://www.twitch.tv/videos/123?collection=abcDEF",
+    ] {
+        assert_eq!(
+            resolve(url).unwrap(),
+            Target::TwitchCollection {
+                id: "abcDEF".into()
+            }
+        );
+    }
+}
+
+#[test]
+fn usher_errors_distinguish_offline_and_missing_channels() {
+    let live = TwitchTarget::Live {
+        channel: "somechannel".into(),
+    };
+    let offline = r#"[{"error":"twirp error not_found: transcode does not exist","error_code":"transcode_does_not_exist","type":"error"}]"#;
+    let missing =
+        r#"[{"error":"Can not find channel","error_code":"does_not_exist","type":"error"}]"#;
+
+    let reason = |status, body| {
+        usher_error(&live, status, body).and_then(|err| err.downcast::<Unavailable>().ok())
+    };
+    assert_eq!(
+        reason(404, offline),
+        Some(Unavailable::Offline("somechannel".into()))
+    );
+    assert_eq!(
+        reason(404, missing),
+        Some(Unavailable::NotFound("Twitch channel somechannel".into()))
+    );
+    assert!(usher_error(&live, 500, "").is_none());
+    assert!(usher_error(&live, 200, "#EXTM3U\n").is_none());
+}
+
+#[test]
+fn usher_restrictions_are_reported() {
+    let live = TwitchTarget::Live {
+        channel: "somechannel".into(),
+    };
+    let err = usher_error(&live, 200, r#"{"error":"content_restricted_in_region"}"#).unwrap();
+    assert!(matches!(
+        err.downcast::<Unavailable>(),
+        Ok(Unavailable::Restricted(_))
+    ));
+}
+
+#[test]
+fn stream_status_tells_live_from_offline() {
+    let live = serde_json::json!({ "data": { "user": { "stream": { "id": "4242" } } } });
+    let offline = serde_json::json!({ "data": { "user": { "stream": null } } });
+    let missing = serde_json::json!({ "data": { "user": null } });
+
+    assert_eq!(parse_stream_status(&live).unwrap().as_deref(), Some("4242"));
+    assert_eq!(parse_stream_status(&offline).unwrap(), None);
+    assert!(parse_stream_status(&missing).is_err());
+}
+
+#[test]
+fn batched_stream_status_answers_each_channel() {
+    let request = stream_status_batch_request(&["one", "two", "three"]);
+    assert_eq!(request.as_array().map(Vec::len), Some(3));
+    assert_eq!(request[1]["variables"]["login"], "two");
+
+    let answers = serde_json::json!([
+        { "data": { "user": { "stream": { "id": "4242" } } } },
+        { "data": { "user": { "stream": null } } },
+        { "data": { "user": null } },
+    ]);
+    let statuses = parse_stream_status_batch(&answers).unwrap();
+    assert_eq!(statuses[0].as_ref().unwrap().as_deref(), Some("4242"));
+    assert_eq!(statuses[1].as_ref().unwrap(), &None);
+    assert!(statuses[2].is_err());
+
+    let failed = serde_json::json!({ "errors": [{ "message": "service timeout" }] });
+    assert!(parse_stream_status_batch(&failed).is_err());
+}
+
+#[test]
+fn live_metadata_comes_from_the_channel_and_stream() {
+    let live = TwitchTarget::Live {
+        channel: "somechannel".into(),
+    };
+    let value = serde_json::json!({ "data": { "user": {
+        "displayName": "SomeChannel",
+        "broadcastSettings": { "title": "Speedruns" },
+        "stream": {
+            "viewersCount": 1234,
+            "createdAt": "2024-05-01T12:00:00Z",
+            "previewImageURL": "https://example.com/preview-1280x720.jpg",
+            "game": { "name": "Celeste" },
+        },
+    } } });
+
+    let metadata = parse_metadata(&live, &value).unwrap();
+    assert_eq!(metadata.title.as_deref(), Some("Speedruns"));
+    assert_eq!(metadata.author.as_deref(), Some("SomeChannel"));
+    assert_eq!(metadata.viewers, Some(1234));
+    assert_eq!(metadata.category.as_deref(), Some("Celeste"));
+    assert_eq!(
+        metadata.thumbnail.as_deref(),
+        Some("https://example.com/preview-1280x720.jpg")
+    );
+}
+
+#[test]
+fn vod_chapters_are_titled_and_ordered() {
+    let vod = TwitchTarget::Vod { id: "123".into() };
+    let value = serde_json::json!({ "data": { "video": {
+        "title": "Marathon",
+        "moments": { "edges": [
+            { "node": {
+                "description": "",
+                "positionMilliseconds": 3_600_000,
+                "durationMilliseconds": 1_800_000,
+                "details": { "game": { "displayName": "Hades" } },
+            } },
+            { "node": {
+                "description": "Just Chatting",
+                "positionMilliseconds": 0,
+                "durationMilliseconds": 3_600_000,
+                "details": {},
+            } },
+        ] },
+    } } });
+
+    let chapters = parse_metadata(&vod, &value).unwrap().chapters;
+    assert_eq!(chapters.len(), 2);
+    assert_eq!(chapters[0].title, "Just Chatting");
+    assert_eq!(chapters[1].title, "Hades");
+    assert_eq!(chapters[1].start, 3600.0);
+    assert_eq!(chapters[1].duration, 1800.0);
+}
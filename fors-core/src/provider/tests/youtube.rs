@@ -0,0 +1,50 @@
+use crate::provider::Unavailable;
+use crate::youtube::{extract_manifest_url, extract_player_response, nsig_script, parse_formats};
+
+#[test]
+fn upcoming_premiere_reports_scheduled_start() {
+    let page = r#"{"videoDetails":{"isUpcoming":true},"liveStreamOfflineSlateRenderer":{"scheduledStartTime":"1760000000"}}"#;
+    let err = extract_manifest_url(page).unwrap_err();
+    assert_eq!(
+        err.downcast::<Unavailable>().unwrap(),
+        Unavailable::Scheduled(Some(1760000000))
+    );
+}
+
+#[test]
+fn formats_are_read_from_the_player_response() {
+    let page = r#"<script>var ytInitialPlayerResponse = {"streamingData":{"formats":[{"itag":18,"url":"https://rr1.googlevideo.com/videoplayback?itag=18","mimeType":"video/mp4; codecs=\"avc1.42001E, mp4a.40.2\"","width":640,"height":360,"fps":30,"bitrate":500000}],"adaptiveFormats":[{"itag":140,"signatureCipher":"s=abc","mimeType":"audio/mp4; codecs=\"mp4a.40.2\"","bitrate":130000}]}};var meta = {};</script>"#;
+    let player = extract_player_response(page).unwrap();
+    let formats = parse_formats(&player);
+
+    assert_eq!(formats.len(), 2);
+    assert!(formats[0].has_video() && formats[0].has_audio());
+    assert_eq!(formats[0].resolution, Some((640, 360)));
+    assert!(formats[1].has_audio() && !formats[1].has_video());
+    assert_eq!(formats[1].codecs(), "mp4a.40.2");
+    assert!(formats[1].url.is_none());
+}
+
+#[test]
+fn only_the_nsig_function_is_taken_from_the_player() {
+    let player = r#"var _yt_player={};(function(g){var window=this;fetch("https://example.com");var Ab=function(a){var b={c:"}"};return a.split("").reverse().join("")};var Xy=[Ab];g.tk=function(a){var b;(b=a.get("n"))&&(b=Xy[0](b),a.set("n",b))};})(_yt_player);"#;
+    let script = nsig_script(player, &["abc".to_string()]).unwrap();
+
+    assert_eq!(
+        script,
+        concat!(
+            "var __fors_n=function(a){var b={c:\"}\"};return a.split(\"\").reverse().join(\"\")};\n",
+            "console.log(JSON.stringify([\"abc\"].map(function(n){return __fors_n(n)})));\n",
+        )
+    );
+}
+
+#[test]
+fn nsig_function_keeps_the_global_array_and_drops_the_scope_guard() {
+    let player = r#"'use strict';var Zq="split;reverse;join".split(";"),Wt=this;(function(g){function Ab(a){var b=a[Zq[0]]("");if(typeof Wt==="undefined")return a;return b[Zq[1]]()[Zq[2]]("")}g.tk=function(a){var b;(b=a.get("n"))&&(b=Ab(b),a.set("n",b))};})(_yt_player);"#;
+    let script = nsig_script(player, &["abc".to_string()]).unwrap();
+
+    assert!(script.starts_with(
+        "var Zq=\"split;reverse;join\".split(\";\");\nvar __fors_n=function(a){var b=a[Zq[0]](\"\");return b"
+    ));
+}
@@ -0,0 +1,358 @@
+use anyhow::{Context, Result, anyhow, bail};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use url::Url;
+
+use crate::provider::{Chapter, StreamMetadata, Unavailable};
+
+pub const CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
+pub const GQL_ENDPOINT: &str = "https://gql.twitch.tv/gql";
+/// Most operations Twitch answers in one batched GQL request.
+pub const GQL_BATCH_LIMIT: usize = 35;
+// Persisted query hash used by Twitch web player (2024-12)
+const PLAYBACK_HASH: &str = "ed230aa1e33e07eebb8928504583da78a5173989fadfb1ac94be06a04f3cdbe9";
+
+pub enum TwitchTarget {
+    Live { channel: String },
+    Vod { id: String },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccessToken {
+    pub signature: String,
+    pub value: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Deserialize)]
+struct PlaybackData {
+    #[serde(rename = "streamPlaybackAccessToken")]
+    streamPlaybackAccessToken: Option<AccessToken>,
+    #[serde(rename = "videoPlaybackAccessToken")]
+    videoPlaybackAccessToken: Option<AccessToken>,
+}
+
+/// GQL request body for a playback access token.
+pub fn access_token_request(target: &TwitchTarget) -> Value {
+    let variables = match target {
+        TwitchTarget::Live { channel } => json!({
+            "isLive": true,
+            "login": channel,
+            "isVod": false,
+            "vodID": "",
+            "playerType": "embed",
+            "platform": "site",
+        }),
+        TwitchTarget::Vod { id } => json!({
+            "isLive": false,
+            "login": "",
+            "isVod": true,
+            "vodID": id,
+            "playerType": "embed",
+            "platform": "site",
+        }),
+    };
+
+    json!({
+        "operationName": "PlaybackAccessToken",
+        "extensions": { "persistedQuery": { "version": 1, "sha256Hash": PLAYBACK_HASH } },
+        "variables": variables,
+    })
+}
+
+/// Extracts the access token from a GQL response, turning API errors into
+/// errors.
+pub fn parse_access_token(target: &TwitchTarget, value: &Value) -> Result<AccessToken> {
+    if let Some(errors) = value.get("errors").and_then(|v| v.as_array())
+        && let Some(msg) = errors
+            .first()
+            .and_then(|err| err.get("message").and_then(|m| m.as_str()))
+    {
+        bail!("Twitch API error: {msg}");
+    }
+
+    if let (Some(error), Some(message)) = (
+        value.get("error").and_then(|v| v.as_str()),
+        value.get("message").and_then(|v| v.as_str()),
+    ) {
+        bail!("Twitch API error: {error}: {message}");
+    }
+
+    let data: PlaybackData = serde_json::from_value(
+        value
+            .get("data")
+            .cloned()
+            .unwrap_or_else(|| serde_json::json!({})),
+    )
+    .context("Malformed Twitch access token response")?;
+
+    match target {
+        TwitchTarget::Live { channel } => {
+            let token = data
+                .streamPlaybackAccessToken
+                .ok_or_else(|| anyhow!("No access token returned for live channel"))?;
+            // Unknown logins still get a token, just without a channel id.
+            let claims: Value = serde_json::from_str(&token.value).unwrap_or_default();
+            if claims.get("channel_id").is_some_and(Value::is_null) {
+                return Err(Unavailable::NotFound(format!("Twitch channel {channel}")).into());
+            }
+            Ok(token)
+        }
+        TwitchTarget::Vod { .. } => data
+            .videoPlaybackAccessToken
+            .ok_or_else(|| anyhow!("No access token returned for VOD")),
+    }
+}
+
+/// GQL request body asking whether `channel` is broadcasting.
+pub fn stream_status_request(channel: &str) -> Value {
+    json!({
+        "query": "query($login: String!) { user(login: $login) { stream { id } } }",
+        "variables": { "login": channel },
+    })
+}
+
+/// The id of the channel's current broadcast, or `None` when it is offline.
+pub fn parse_stream_status(value: &Value) -> Result<Option<String>> {
+    if let Some(msg) = value.pointer("/errors/0/message").and_then(|m| m.as_str()) {
+        bail!("Twitch API error: {msg}");
+    }
+    let user = value
+        .pointer("/data/user")
+        .filter(|user| !user.is_null())
+        .ok_or_else(|| anyhow!("Channel not found"))?;
+    Ok(user
+        .pointer("/stream/id")
+        .and_then(|id| id.as_str())
+        .map(String::from))
+}
+
+/// One GQL request asking whether each of `channels` is broadcasting. Twitch
+/// takes a list of operations and answers with a list in the same order.
+pub fn stream_status_batch_request(channels: &[&str]) -> Value {
+    Value::Array(
+        channels
+            .iter()
+            .map(|channel| stream_status_request(channel))
+            .collect(),
+    )
+}
+
+/// The answers to [`stream_status_batch_request`], one per channel.
+pub fn parse_stream_status_batch(value: &Value) -> Result<Vec<Result<Option<String>>>> {
+    // A batch that fails as a whole gets a single error object.
+    if let Some(msg) = value.pointer("/errors/0/message").and_then(|m| m.as_str()) {
+        bail!("Twitch API error: {msg}");
+    }
+    let answers = value
+        .as_array()
+        .ok_or_else(|| anyhow!("Twitch did not answer the batched request with a list"))?;
+    Ok(answers.iter().map(parse_stream_status).collect())
+}
+
+/// GQL request body for the title, channel, viewers and category of a
+/// stream or VOD, and the chapters of a VOD.
+pub fn metadata_request(target: &TwitchTarget) -> Value {
+    match target {
+        TwitchTarget::Live { channel } => json!({
+            "query": "query($login: String!) { user(login: $login) { displayName broadcastSettings { title } stream { viewersCount createdAt previewImageURL(width: 1280, height: 720) game { name } } } }",
+            "variables": { "login": channel },
+        }),
+        TwitchTarget::Vod { id } => json!({
+            "query": "query($id: ID!) { video(id: $id) { title viewCount createdAt previewThumbnailURL(width: 1280, height: 720) owner { displayName } game { name } moments(first: 100, momentRequestType: VIDEO_CHAPTER_MARKERS) { edges { node { description positionMilliseconds durationMilliseconds details { ... on GameChangeMomentDetails { game { displayName } } } } } } } }",
+            "variables": { "id": id },
+        }),
+    }
+}
+
+pub fn parse_metadata(target: &TwitchTarget, value: &Value) -> Result<StreamMetadata> {
+    if let Some(msg) = value.pointer("/errors/0/message").and_then(|m| m.as_str()) {
+        bail!("Twitch API error: {msg}");
+    }
+    let text = |node: &Value, pointer: &str| {
+        node.pointer(pointer)
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    Ok(match target {
+        TwitchTarget::Live { .. } => {
+            let user = value
+                .pointer("/data/user")
+                .filter(|user| !user.is_null())
+                .ok_or_else(|| anyhow!("Channel not found"))?;
+            StreamMetadata {
+                title: text(user, "/broadcastSettings/title"),
+                author: text(user, "/displayName"),
+                viewers: user
+                    .pointer("/stream/viewersCount")
+                    .and_then(|v| v.as_u64()),
+                started_at: text(user, "/stream/createdAt"),
+                category: text(user, "/stream/game/name"),
+                thumbnail: text(user, "/stream/previewImageURL"),
+                chapters: Vec::new(),
+            }
+        }
+        TwitchTarget::Vod { .. } => {
+            let video = value
+                .pointer("/data/video")
+                .filter(|video| !video.is_null())
+                .ok_or_else(|| anyhow!("VOD not found"))?;
+            StreamMetadata {
+                title: text(video, "/title"),
+                author: text(video, "/owner/displayName"),
+                viewers: video.pointer("/viewCount").and_then(|v| v.as_u64()),
+                started_at: text(video, "/createdAt"),
+                category: text(video, "/game/name"),
+                thumbnail: text(video, "/previewThumbnailURL"),
+                chapters: parse_chapters(video),
+            }
+        }
+    })
+}
+
+/// A VOD's chapter markers, titled by their description or else the game.
+fn parse_chapters(video: &Value) -> Vec<Chapter> {
+    let Some(edges) = video.pointer("/moments/edges").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    let mut chapters: Vec<Chapter> = edges
+        .iter()
+        .filter_map(|edge| {
+            let node = edge.get("node")?;
+            let millis = |key: &str| node.get(key).and_then(Value::as_f64);
+            let title = [
+                node.get("description"),
+                node.pointer("/details/game/displayName"),
+            ]
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .find(|title| !title.is_empty())
+            .unwrap_or("Chapter");
+            Some(Chapter {
+                start: millis("positionMilliseconds")? / 1000.0,
+                duration: millis("durationMilliseconds").unwrap_or(0.0) / 1000.0,
+                title: title.to_string(),
+            })
+        })
+        .collect();
+    chapters.sort_by(|a, b| a.start.total_cmp(&b.start));
+    chapters
+}
+
+/// GQL request body listing the VODs of a collection.
+pub fn collection_request(id: &str) -> Value {
+    json!({
+        "query": "query($id: ID!) { collection(id: $id) { title items(first: 100) { edges { node { ... on Video { id } } } } } }",
+        "variables": { "id": id },
+    })
+}
+
+/// A collection's title and VOD ids, in collection order.
+pub struct Collection {
+    pub title: String,
+    pub video_ids: Vec<String>,
+}
+
+pub fn parse_collection(value: &Value) -> Result<Collection> {
+    if let Some(msg) = value.pointer("/errors/0/message").and_then(|m| m.as_str()) {
+        bail!("Twitch API error: {msg}");
+    }
+    let collection = value
+        .pointer("/data/collection")
+        .filter(|c| !c.is_null())
+        .ok_or_else(|| anyhow!("Collection not found"))?;
+
+    let video_ids = collection
+        .pointer("/items/edges")
+        .and_then(|edges| edges.as_array())
+        .map(|edges| {
+            edges
+                .iter()
+                .filter_map(|edge| edge.pointer("/node/id").and_then(|id| id.as_str()))
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Collection {
+        title: collection
+            .get("title")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string(),
+        video_ids,
+    })
+}
+
+/// Recognizes usher's JSON error payloads, which can come back instead of a
+/// playlist even with a success status, and turns them into errors that say
+/// why. Offline channels and missing channels or VODs become [`Unavailable`].
+pub fn usher_error(target: &TwitchTarget, status: u16, body: &str) -> Option<anyhow::Error> {
+    let value: Value = serde_json::from_str(body.trim()).unwrap_or_default();
+    let error = value.get(0).unwrap_or(&value);
+    let message = error.get("error").and_then(|e| e.as_str());
+    let code = error
+        .get("error_code")
+        .and_then(|c| c.as_str())
+        .or(message)
+        .unwrap_or_default();
+
+    let not_found = || match target {
+        TwitchTarget::Live { channel } => {
+            Unavailable::NotFound(format!("Twitch channel {channel}"))
+        }
+        TwitchTarget::Vod { id } => Unavailable::NotFound(format!("Twitch VOD {id}")),
+    };
+    let unavailable = match (target, code) {
+        (_, "does_not_exist" | "vod_manifest_does_not_exist") => not_found(),
+        (TwitchTarget::Live { channel }, "transcode_does_not_exist") => {
+            Unavailable::Offline(channel.clone())
+        }
+        (_, "content_restricted_in_region" | "content_geoblocked") => {
+            Unavailable::Restricted("it is not available in your region".into())
+        }
+        (_, "vod_manifest_restricted" | "unauthorized_entitlements") => {
+            Unavailable::Restricted("it is for subscribers only".into())
+        }
+        (_, "content_classification_gate" | "content_warning") => Unavailable::Restricted(
+            "it has a content warning that must be accepted while logged in".into(),
+        ),
+        (_, code) if code.contains("restricted") || code.contains("geoblock") => {
+            Unavailable::Restricted(message.unwrap_or(code).to_string())
+        }
+        _ => {
+            if let Some(message) = message {
+                return Some(anyhow!("Twitch playlist error: {message}"));
+            }
+            match (target, status) {
+                (TwitchTarget::Live { channel }, 404) => Unavailable::Offline(channel.clone()),
+                (TwitchTarget::Vod { .. }, 404 | 410) => not_found(),
+                _ => return None,
+            }
+        }
+    };
+    Some(unavailable.into())
+}
+
+/// The usher URL of the master playlist.
+pub fn manifest_url(target: &TwitchTarget, token: &AccessToken, low_latency: bool) -> Result<Url> {
+    let encoded = urlencoding::encode(&token.value);
+    let url = match target {
+        TwitchTarget::Live { channel } => format!(
+            "https://usher.ttvnw.net/api/channel/hls/{channel}.m3u8?sig={sig}&token={token}&allow_source=true&allow_audio_only=true&allow_spectre=true&player=twitchweb&client_id={client}{fast_bread}",
+            sig = token.signature,
+            token = encoded,
+            client = CLIENT_ID,
+            fast_bread = if low_latency { "&fast_bread=true" } else { "" },
+        ),
+        TwitchTarget::Vod { id } => format!(
+            "https://usher.ttvnw.net/vod/{id}.m3u8?sig={sig}&token={token}&allow_source=true&allow_spectre=true&player=twitchweb&client_id={client}",
+            sig = token.signature,
+            token = encoded,
+            client = CLIENT_ID,
+        ),
+    };
+
+    Url::parse(&url).context("Failed to build Twitch manifest URL")
+}
@@ -0,0 +1,406 @@
+use anyhow::{Context, Result, anyhow};
+use regex::Regex;
+use url::Url;
+
+use crate::provider::{StreamMetadata, Unavailable};
+
+pub fn watch_url(video_id: &str) -> Result<Url> {
+    Url::parse(&format!("https://www.youtube.com/watch?v={video_id}"))
+        .context("Invalid YouTube video id")
+}
+
+/// Cookies that record the consent choice EU visitors are otherwise asked
+/// for, accepting only the essential ones.
+pub const CONSENT_COOKIES: &str = "SOCS=CAI; CONSENT=PENDING+999";
+
+/// Whether a watch page request ended up on the cookie consent page.
+pub fn is_consent_redirect(url: &Url) -> bool {
+    url.host_str()
+        .map(|h| h.contains("consent.youtube.com"))
+        .unwrap_or(false)
+}
+
+/// Finds the HLS master playlist URL in a watch page. Premieres and
+/// scheduled streams that have not started yet give
+/// [`Unavailable::Scheduled`].
+pub fn extract_manifest_url(body: &str) -> Result<Url> {
+    if let Some(start) = upcoming_start(body) {
+        return Err(Unavailable::Scheduled(start).into());
+    }
+
+    let re = Regex::new(r#""hlsManifestUrl":"(?P<url>[^"]+)""#).unwrap();
+    let captures = re
+        .captures(body)
+        .ok_or_else(|| anyhow!("No HLS manifest URL found on the page (stream may be offline)"))?;
+
+    let raw_url = captures.name("url").unwrap().as_str();
+    // Decode JSON-style escaping inside the string
+    let decoded: String = serde_json::from_str(&format!("\"{raw_url}\""))
+        .context("Failed to decode manifest URL from page data")?;
+
+    Url::parse(&decoded).context("Invalid YouTube manifest URL")
+}
+
+/// For an upcoming premiere or live event, its scheduled start as a Unix
+/// timestamp if the page has one.
+fn upcoming_start(body: &str) -> Option<Option<u64>> {
+    if !body.contains(r#""isUpcoming":true"#) {
+        return None;
+    }
+    let re = Regex::new(r#""scheduledStartTime":"(\d+)""#).unwrap();
+    Some(
+        re.captures(body)
+            .and_then(|captures| captures[1].parse().ok()),
+    )
+}
+
+/// One entry of the player response's `formats`/`adaptiveFormats`.
+#[derive(Debug, Clone)]
+pub struct Format {
+    pub itag: u32,
+    /// e.g. `video/mp4; codecs="avc1.640028"`
+    pub mime_type: String,
+    pub resolution: Option<(u64, u64)>,
+    pub fps: Option<u64>,
+    pub bitrate: u64,
+    /// Missing when the URL is behind a `signatureCipher`.
+    pub url: Option<Url>,
+}
+
+impl Format {
+    pub fn has_video(&self) -> bool {
+        self.mime_type.starts_with("video/")
+    }
+
+    pub fn has_audio(&self) -> bool {
+        // Progressive formats list a video and an audio codec.
+        self.mime_type.starts_with("audio/") || self.codecs().contains(',')
+    }
+
+    /// The `codecs` parameter of the MIME type.
+    pub fn codecs(&self) -> &str {
+        self.mime_type
+            .split_once("codecs=")
+            .map(|(_, codecs)| codecs.trim_matches('"'))
+            .unwrap_or_default()
+    }
+}
+
+/// Extracts `ytInitialPlayerResponse` from a watch page.
+pub fn extract_player_response(body: &str) -> Result<serde_json::Value> {
+    let start = body
+        .find("ytInitialPlayerResponse = ")
+        .map(|i| i + "ytInitialPlayerResponse = ".len())
+        .ok_or_else(|| anyhow!("No player response found on the page"))?;
+    serde_json::Deserializer::from_str(&body[start..])
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Empty player response"))?
+        .context("Failed to parse the player response")
+}
+
+/// Lists the progressive and adaptive formats of a player response.
+pub fn parse_formats(player: &serde_json::Value) -> Vec<Format> {
+    let streaming = &player["streamingData"];
+    ["formats", "adaptiveFormats"]
+        .iter()
+        .filter_map(|key| streaming[key].as_array())
+        .flatten()
+        .filter_map(|format| {
+            let number = |key: &str| format[key].as_u64();
+            Some(Format {
+                itag: number("itag")? as u32,
+                mime_type: format["mimeType"].as_str()?.to_string(),
+                resolution: number("width").zip(number("height")),
+                fps: number("fps"),
+                bitrate: number("averageBitrate")
+                    .or_else(|| number("bitrate"))
+                    .unwrap_or(0),
+                url: format["url"].as_str().and_then(|url| Url::parse(url).ok()),
+            })
+        })
+        .collect()
+}
+
+/// The URL of the player JavaScript referenced by a watch page.
+pub fn player_js_url(body: &str) -> Option<Url> {
+    let re = Regex::new(r#""jsUrl":"([^"]+)""#).unwrap();
+    let path = re.captures(body)?.get(1)?.as_str();
+    Url::parse("https://www.youtube.com").ok()?.join(path).ok()
+}
+
+/// Builds a script that prints the player's `n` parameter transform of each
+/// value as a JSON array. Stream URLs whose `n` is not transformed are
+/// throttled to roughly real-time speed.
+///
+/// Only the transform function is taken from the player, along with the
+/// global array of names recent players have it look things up in, so none
+/// of the rest of the player runs.
+pub fn nsig_script(player_js: &str, values: &[String]) -> Result<String> {
+    let name = nsig_function_name(player_js)?;
+    let function = function_source(player_js, &name)?;
+    // Players bail out early when a variable of the player's scope is
+    // missing, which it is once the function is taken out of it.
+    let guard = Regex::new(
+        r#";\s*if\s*\(\s*typeof\s+[a-zA-Z0-9_$]+\s*===?\s*(?:"undefined"|'undefined'|[a-zA-Z0-9_$]+\[\d+\])\s*\)\s*return\s+[a-zA-Z0-9_$]+;"#,
+    )
+    .unwrap();
+    let function = guard.replace_all(&function, ";");
+
+    let mut script = String::new();
+    if let Some(global) = global_array(player_js) {
+        script.push_str(global);
+        script.push_str(";\n");
+    }
+    script.push_str(&format!("var __fors_n={function};\n"));
+    script.push_str(&format!(
+        "console.log(JSON.stringify({}.map(function(n){{return __fors_n(n)}})));\n",
+        serde_json::to_string(values)?
+    ));
+    Ok(script)
+}
+
+/// The `function(...){...}` that `name` is defined as in the player script.
+fn function_source(player_js: &str, name: &str) -> Result<String> {
+    let name = regex::escape(name);
+    let start = Regex::new(&format!(
+        r"(?:function\s+{name}|[{{;,\s]{name}\s*=\s*function)\s*\(([^)]*)\)\s*\{{"
+    ))
+    .unwrap()
+    .captures(player_js)
+    .ok_or_else(|| anyhow!("Could not find the n parameter function {name}"))?;
+    let params = &start[1];
+    let body_start = start.get(0).unwrap().end();
+    let body_len = block_len(&player_js[body_start..])
+        .ok_or_else(|| anyhow!("The n parameter function {name} does not end"))?;
+    Ok(format!(
+        "function({params}){{{}",
+        &player_js[body_start..body_start + body_len]
+    ))
+}
+
+/// The length of a block up to and including the `}` that closes it, with
+/// `code` starting right after its `{`.
+fn block_len(code: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        if let Some(q) = quote {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                _ if c == q => quote = None,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some(i + 1),
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The `var Xy="...".split(";")` declaration at the top of recent players.
+fn global_array(player_js: &str) -> Option<&str> {
+    Regex::new(
+        r#"'use strict';\s*(var\s+[a-zA-Z0-9_$]+\s*=\s*(?:"[^"]*"|'[^']*')\.split\((?:"[^"]*"|'[^']*')\))"#,
+    )
+    .unwrap()
+    .captures(player_js)
+    .and_then(|captures| captures.get(1))
+    .map(|m| m.as_str())
+}
+
+/// Finds the name of the `n` transform function in the player script.
+fn nsig_function_name(player_js: &str) -> Result<String> {
+    let patterns = [
+        r#"\.get\("n"\)\)&&\(b=([a-zA-Z0-9_$]+)(?:\[(\d+)\])?\([a-zA-Z0-9_$]\)"#,
+        r#"b=String\.fromCharCode\(110\),c=a\.get\(b\)\)&&\(c=([a-zA-Z0-9_$]+)(?:\[(\d+)\])?\([a-zA-Z0-9_$]\)"#,
+    ];
+    let captures = patterns
+        .iter()
+        .find_map(|pattern| Regex::new(pattern).unwrap().captures(player_js))
+        .ok_or_else(|| anyhow!("Could not find the n parameter function in the player"))?;
+    let name = &captures[1];
+
+    let Some(index) = captures.get(2) else {
+        return Ok(name.to_string());
+    };
+    // The call goes through an array such as `var Xy=[Ab];`.
+    let index: usize = index.as_str().parse()?;
+    let array = Regex::new(&format!(
+        r"[,;\s]{}\s*=\s*\[([^\]]+)\]",
+        regex::escape(name)
+    ))
+    .unwrap()
+    .captures(player_js)
+    .ok_or_else(|| anyhow!("Could not find the n parameter function array {name}"))?;
+    array[1]
+        .split(',')
+        .nth(index)
+        .map(|name| name.trim().to_string())
+        .ok_or_else(|| anyhow!("The n parameter function array {name} is too short"))
+}
+
+pub const INNERTUBE_PLAYER_URL: &str =
+    "https://www.youtube.com/youtubei/v1/player?prettyPrint=false";
+/// The Innertube client a player request claims to be. The app clients are
+/// often still served when the web client gets a bot check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InnertubeClient {
+    Web,
+    Android,
+    Ios,
+}
+
+impl InnertubeClient {
+    pub fn name(self) -> &'static str {
+        match self {
+            InnertubeClient::Web => "WEB",
+            InnertubeClient::Android => "ANDROID",
+            InnertubeClient::Ios => "IOS",
+        }
+    }
+
+    pub fn version(self) -> &'static str {
+        match self {
+            InnertubeClient::Web => "2.20250312.04.00",
+            InnertubeClient::Android => "19.44.38",
+            InnertubeClient::Ios => "19.45.4",
+        }
+    }
+
+    /// Value of the `X-YouTube-Client-Name` header.
+    pub fn id(self) -> &'static str {
+        match self {
+            InnertubeClient::Web => "1",
+            InnertubeClient::Android => "3",
+            InnertubeClient::Ios => "5",
+        }
+    }
+
+    /// The app's own user agent; the web client keeps the configured one.
+    pub fn user_agent(self) -> Option<&'static str> {
+        match self {
+            InnertubeClient::Web => None,
+            InnertubeClient::Android => {
+                Some("com.google.android.youtube/19.44.38 (Linux; U; Android 11) gzip")
+            }
+            InnertubeClient::Ios => Some(
+                "com.google.ios.youtube/19.45.4 (iPhone16,2; U; CPU iOS 18_1_0 like Mac OS X;)",
+            ),
+        }
+    }
+}
+
+/// Innertube `player` request body. `visitor_data` and `po_token` are what
+/// YouTube increasingly requires from clients that are not a browser.
+pub fn innertube_player_request(
+    profile: InnertubeClient,
+    video_id: &str,
+    visitor_data: Option<&str>,
+    po_token: Option<&str>,
+) -> serde_json::Value {
+    let mut client = serde_json::json!({
+        "clientName": profile.name(),
+        "clientVersion": profile.version(),
+        "hl": "en",
+    });
+    match profile {
+        InnertubeClient::Web => {}
+        InnertubeClient::Android => {
+            client["androidSdkVersion"] = 30.into();
+            client["osName"] = "Android".into();
+            client["osVersion"] = "11".into();
+        }
+        InnertubeClient::Ios => {
+            client["deviceMake"] = "Apple".into();
+            client["deviceModel"] = "iPhone16,2".into();
+            client["osName"] = "iPhone".into();
+            client["osVersion"] = "18.1.0.22B83".into();
+        }
+    }
+    if let Some(visitor_data) = visitor_data {
+        client["visitorData"] = visitor_data.into();
+    }
+    let mut request = serde_json::json!({
+        "context": { "client": client },
+        "videoId": video_id,
+        "contentCheckOk": true,
+        "racyCheckOk": true,
+    });
+    if let Some(po_token) = po_token {
+        request["serviceIntegrityDimensions"] = serde_json::json!({ "poToken": po_token });
+    }
+    request
+}
+
+/// The title, channel, views and start of the video in a player response.
+pub fn parse_metadata(player: &serde_json::Value) -> StreamMetadata {
+    let text = |pointer: &str| {
+        player
+            .pointer(pointer)
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    let microformat = "/microformat/playerMicroformatRenderer";
+    StreamMetadata {
+        title: text("/videoDetails/title"),
+        author: text("/videoDetails/author"),
+        // Strings in the player response.
+        viewers: text("/videoDetails/viewCount").and_then(|views| views.parse().ok()),
+        started_at: text(&format!(
+            "{microformat}/liveBroadcastDetails/startTimestamp"
+        ))
+        .or_else(|| text(&format!("{microformat}/publishDate"))),
+        category: text(&format!("{microformat}/category")),
+        // Listed from smallest to largest.
+        thumbnail: player
+            .pointer("/videoDetails/thumbnail/thumbnails")
+            .and_then(|thumbnails| thumbnails.as_array()?.last()?.get("url")?.as_str())
+            .map(String::from),
+        chapters: Vec::new(),
+    }
+}
+
+/// Whether the live broadcast of a player response is still going, if it
+/// says. Ended broadcasts carry an end time, running ones `isLive`.
+pub fn broadcast_live(player: &serde_json::Value) -> Option<bool> {
+    let details = "/microformat/playerMicroformatRenderer/liveBroadcastDetails";
+    if let Some(details) = player.pointer(details) {
+        if details.get("endTimestamp").is_some() {
+            return Some(false);
+        }
+        if let Some(live) = details.get("isLiveNow").and_then(|live| live.as_bool()) {
+            return Some(live);
+        }
+    }
+    player
+        .pointer("/videoDetails/isLive")
+        .and_then(|live| live.as_bool())
+}
+
+/// Whether a watch page or player response is YouTube's "Sign in to confirm
+/// you're not a bot" wall.
+pub fn is_bot_check(text: &str) -> bool {
+    text.contains("confirm you\u{2019}re not a bot") || text.contains("confirm you're not a bot")
+}
+
+/// Adds a PO token to a stream URL: as a path parameter for HLS manifests and
+/// as the `pot` query parameter for format URLs.
+pub fn with_po_token(url: &Url, po_token: &str) -> Url {
+    let mut url = url.clone();
+    if url.path().contains("/hls_variant/") || url.path().contains("/hls_playlist/") {
+        let path = format!("{}/pot/{po_token}", url.path().trim_end_matches('/'));
+        url.set_path(&path);
+    } else {
+        url.query_pairs_mut().append_pair("pot", po_token);
+    }
+    url
+}
@@ -0,0 +1,68 @@
+//! Sidecar files listing where skipped ads were cut out of a recording, so it
+//! can be lined up with chat logs and other recordings of the same stream.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hls::AdGap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SidecarFormat {
+    /// `<output>.ads.json` with output, stream and wall-clock times
+    Json,
+    /// `<output>.edl` with a Kodi/MPlayer scene marker at each gap
+    Edl,
+}
+
+/// Where the sidecar for `output` goes.
+pub fn sidecar_path(output: &Path, format: SidecarFormat) -> PathBuf {
+    let suffix = match format {
+        SidecarFormat::Json => "ads.json",
+        SidecarFormat::Edl => "edl",
+    };
+    let mut name = output.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+pub fn write(output: &Path, format: SidecarFormat, gaps: &[AdGap]) -> Result<PathBuf> {
+    let path = sidecar_path(output, format);
+    let contents = match format {
+        SidecarFormat::Json => to_json(gaps)?,
+        SidecarFormat::Edl => to_edl(gaps),
+    };
+    fs::write(&path, contents)
+        .with_context(|| format!("Failed to write ad gaps to {}", path.display()))?;
+    Ok(path)
+}
+
+fn to_json(gaps: &[AdGap]) -> Result<String> {
+    let gaps: Vec<_> = gaps
+        .iter()
+        .map(|gap| {
+            let stream_start = gap.output_time + gap.skipped_before;
+            json!({
+                "output_time": gap.output_time,
+                "stream_start": stream_start,
+                "stream_end": stream_start + gap.duration,
+                "duration": gap.duration,
+                "started_at": DateTime::<Local>::from(gap.started_at).to_rfc3339(),
+                "ended_at": DateTime::<Local>::from(gap.ended_at).to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&json!({ "ad_gaps": gaps }))?)
+}
+
+/// Nothing is left of an ad in the output, so each gap is a zero-length
+/// scene marker (action 2) rather than a cut.
+fn to_edl(gaps: &[AdGap]) -> String {
+    gaps.iter()
+        .map(|gap| format!("{0:.3}\t{0:.3}\t2\n", gap.output_time))
+        .collect()
+}
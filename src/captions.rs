@@ -0,0 +1,295 @@
+//! `--probe-captions`: reports whether a variant carries CEA-608/708 closed
+//! captions, from what the master playlist declares and from the caption
+//! data in the video of a few of its segments.
+//!
+//! The captions ride in the SEI messages of the video frames they belong
+//! to, so skipping ad segments and writing across discontinuities drops or
+//! keeps them together with their video and cannot shift them. fors never
+//! rewrites the video of the segments it keeps.
+
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+use std::collections::BTreeSet;
+use std::io::Read;
+
+use crate::hls::{StreamVariant, fetch_media_playlist};
+use crate::http::Retry;
+use crate::output::ts::{Demuxer, STREAM_TYPE_H264, STREAM_TYPE_HEVC};
+
+/// Number of segments whose video is searched.
+const SAMPLE_SEGMENTS: usize = 3;
+/// SEI payload type of registered user data, which carries the captions.
+const USER_DATA_REGISTERED: u32 = 4;
+/// ITU-T T.35 country code (USA), provider code (ATSC) and user identifier
+/// in front of ATSC A/53 caption data.
+const ATSC_CAPTIONS: [u8; 7] = [0xb5, 0x00, 0x31, b'G', b'A', b'9', b'4'];
+
+/// What was found in the video of one or more segments.
+#[derive(Default)]
+struct Captions {
+    /// CEA-608 channels, `CC1`-`CC4`.
+    channels: BTreeSet<&'static str>,
+    /// CEA-708 service numbers.
+    services: BTreeSet<u8>,
+    /// Caption byte pairs, including the padding sent between captions.
+    pairs: usize,
+    /// The CEA-708 packet being put together.
+    dtvcc: Vec<u8>,
+    /// The CEA-608 channel of each field, which only control codes name.
+    field_channel: [Option<&'static str>; 2],
+}
+
+pub fn run(client: &Client, variant: &StreamVariant) -> Result<()> {
+    println!("Captions of {}", variant.label);
+    if variant.captions.is_empty() {
+        println!("  The master playlist declares none");
+    }
+    for track in &variant.captions {
+        println!(
+            "  Declared: {} ({}{})",
+            track.instream_id,
+            track.name,
+            track
+                .language
+                .as_deref()
+                .map(|language| format!(", {language}"))
+                .unwrap_or_default()
+        );
+    }
+    if variant.is_audio_only {
+        bail!("{} has no video to carry captions", variant.label);
+    }
+
+    let playlist = fetch_media_playlist(client, &variant.uri)?;
+    let content: Vec<_> = playlist.segments.iter().filter(|s| !s.ad).collect();
+    let segments = &content[content.len().saturating_sub(SAMPLE_SEGMENTS)..];
+    if segments.is_empty() {
+        bail!("The playlist has no segments to probe");
+    }
+
+    let mut total = Captions::default();
+    for segment in segments {
+        if segment.key.is_some() || segment.init.is_some() {
+            bail!("Only unencrypted MPEG-TS segments can be probed for captions");
+        }
+        let mut data = Vec::new();
+        Retry::SEGMENT
+            .send(client.get(segment.uri.clone()))
+            .with_context(|| format!("Requesting segment {}", segment.uri))?
+            .error_for_status()
+            .with_context(|| format!("Segment download failed: {}", segment.uri))?
+            .read_to_end(&mut data)
+            .context("Reading segment failed")?;
+
+        let mut found = Captions::default();
+        for pes in Demuxer::all(&data) {
+            match pes.stream_type {
+                STREAM_TYPE_H264 => found.scan(&pes.data, false),
+                STREAM_TYPE_HEVC => found.scan(&pes.data, true),
+                _ => {}
+            }
+        }
+        println!(
+            "  Segment {}{}: {}",
+            segment.sequence,
+            if segment.discontinuity {
+                " (discontinuity)"
+            } else {
+                ""
+            },
+            found.describe()
+        );
+        total.channels.extend(&found.channels);
+        total.services.extend(&found.services);
+        total.pairs += found.pairs;
+    }
+
+    if total.pairs == 0 {
+        println!("No caption data in the video");
+        if !variant.captions.is_empty() {
+            println!("The captions the playlist declares may only be sent while someone speaks");
+        }
+    } else {
+        println!(
+            "Captions are carried in the video and kept in recordings: {}",
+            total.describe()
+        );
+    }
+    Ok(())
+}
+
+impl Captions {
+    /// Looks for caption data in the SEI NAL units of an Annex B access unit.
+    fn scan(&mut self, data: &[u8], hevc: bool) {
+        for nal in nal_units(data) {
+            let (is_sei, header) = if hevc {
+                // Prefix SEI, after a two byte header.
+                (nal.first().is_some_and(|b| (b >> 1) & 0x3f == 39), 2)
+            } else {
+                (nal.first().is_some_and(|b| b & 0x1f == 6), 1)
+            };
+            if is_sei && let Some(payload) = nal.get(header..) {
+                self.sei(&unescape(payload));
+            }
+        }
+    }
+
+    fn sei(&mut self, mut data: &[u8]) {
+        // Stops at the rbsp trailing bits.
+        while data.len() > 1 {
+            let Some((kind, rest)) = sei_value(data) else {
+                return;
+            };
+            let Some((size, rest)) = sei_value(rest) else {
+                return;
+            };
+            let Some(payload) = rest.get(..size as usize) else {
+                return;
+            };
+            if kind == USER_DATA_REGISTERED
+                && let Some(data) = payload.strip_prefix(&ATSC_CAPTIONS)
+                // user_data_type_code 3 is cc_data.
+                && let [3, flags, _, cc_data @ ..] = data
+                && flags & 0x40 != 0
+            {
+                let count = usize::from(flags & 0x1f);
+                for cc in cc_data.chunks_exact(3).take(count) {
+                    if cc[0] & 0x04 != 0 {
+                        self.cc(cc[0] & 0x03, cc[1], cc[2]);
+                    }
+                }
+            }
+            data = &rest[size as usize..];
+        }
+    }
+
+    fn cc(&mut self, cc_type: u8, first: u8, second: u8) {
+        self.pairs += 1;
+        match cc_type {
+            // CEA-608 in field 1 (CC1/CC2) or field 2 (CC3/CC4). Control
+            // codes say which channel the text that follows is for.
+            0 | 1 => {
+                let field = usize::from(cc_type);
+                let first = first & 0x7f;
+                if (0x10..=0x1f).contains(&first) {
+                    let channels = [["CC1", "CC2"], ["CC3", "CC4"]][field];
+                    self.field_channel[field] = Some(channels[usize::from(first >= 0x18)]);
+                } else if first >= 0x20
+                    && let Some(channel) = self.field_channel[field]
+                {
+                    self.channels.insert(channel);
+                }
+            }
+            // CEA-708: a packet starts, then continues over further pairs.
+            3 => {
+                self.dtvcc_packet();
+                self.dtvcc = vec![first, second];
+            }
+            _ => self.dtvcc.extend([first, second]),
+        }
+        let size = match self.dtvcc.first().map(|header| header & 0x3f) {
+            Some(0) => 128,
+            Some(size) => usize::from(size) * 2,
+            None => return,
+        };
+        if self.dtvcc.len() >= size {
+            self.dtvcc_packet();
+        }
+    }
+
+    /// Notes the services of the service blocks in the packet so far.
+    fn dtvcc_packet(&mut self) {
+        let packet = std::mem::take(&mut self.dtvcc);
+        let mut blocks = packet.get(1..).unwrap_or_default();
+        while let [header, rest @ ..] = blocks {
+            let mut service = header >> 5;
+            let size = usize::from(header & 0x1f);
+            let mut rest = rest;
+            if service == 7 {
+                let [extended, more @ ..] = rest else {
+                    return;
+                };
+                service = extended & 0x3f;
+                rest = more;
+            }
+            // A null block header ends the packet.
+            if service == 0 || size == 0 {
+                return;
+            }
+            self.services.insert(service);
+            blocks = rest.get(size..).unwrap_or_default();
+        }
+    }
+
+    fn describe(&self) -> String {
+        let mut found = Vec::new();
+        if !self.channels.is_empty() {
+            let channels: Vec<_> = self.channels.iter().copied().collect();
+            found.push(format!("CEA-608 {}", channels.join(", ")));
+        }
+        if !self.services.is_empty() {
+            let services: Vec<_> = self
+                .services
+                .iter()
+                .map(|service| format!("SERVICE{service}"))
+                .collect();
+            found.push(format!("CEA-708 {}", services.join(", ")));
+        }
+        match (found.is_empty(), self.pairs) {
+            (false, _) => found.join(", "),
+            (true, 0) => "no caption data".to_string(),
+            (true, _) => "caption data, but no text".to_string(),
+        }
+    }
+}
+
+/// The NAL units of an Annex B byte stream.
+fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let ends: Vec<usize> = starts
+        .iter()
+        .skip(1)
+        .map(|&start| start - 3)
+        .chain([data.len()])
+        .collect();
+    starts
+        .into_iter()
+        .zip(ends)
+        .map(move |(start, end)| &data[start..end])
+}
+
+/// Removes the emulation prevention bytes (`00 00 03`) from a NAL unit.
+fn unescape(nal: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for &b in nal {
+        if zeros >= 2 && b == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+        out.push(b);
+    }
+    out
+}
+
+/// An SEI payload type or size, coded as a run of 0xff bytes and a last one.
+fn sei_value(data: &[u8]) -> Option<(u32, &[u8])> {
+    let mut value = 0u32;
+    for (i, &b) in data.iter().enumerate() {
+        value += u32::from(b);
+        if b != 0xff {
+            return Some((value, &data[i + 1..]));
+        }
+    }
+    None
+}
@@ -0,0 +1,213 @@
+//! `--split-by-chapter`: cuts a finished VOD recording into one file per
+//! chapter. Each cut is made at the keyframe nearest to where the chapter
+//! starts, so every file begins with a picture and nothing is re-encoded.
+
+use anyhow::{Context, Result, bail};
+use fors_core::provider::Chapter;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hls::SeekPoint;
+use crate::template;
+
+const TS_PACKET_SIZE: usize = 188;
+
+/// A place the recording can be cut at.
+#[derive(Debug, Clone, Copy)]
+struct Cut {
+    /// Byte offset of the TS packet that starts the keyframe.
+    offset: u64,
+    /// Seconds from the start of the recording.
+    time: f64,
+    /// The segment the cut is in.
+    segment: usize,
+}
+
+/// Splits the recording at `path`, `duration` seconds long, into a file per
+/// chapter and removes it once they are written. `seek_points` says where
+/// each segment starts.
+pub fn split(
+    path: &Path,
+    duration: f64,
+    chapters: &[Chapter],
+    seek_points: &[SeekPoint],
+) -> Result<Vec<PathBuf>> {
+    let length = fs::metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?
+        .len();
+    let Some(first) = seek_points.first() else {
+        bail!("Nothing was recorded to split");
+    };
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    // The chapter the recording starts in, then one cut per chapter after it.
+    let mut parts: Vec<(&Chapter, Cut)> = Vec::new();
+    for chapter in chapters {
+        if chapter.start >= duration {
+            break;
+        }
+        let cut = if chapter.start <= 0.0 || parts.is_empty() {
+            Cut {
+                offset: first.offset,
+                time: 0.0,
+                segment: 0,
+            }
+        } else {
+            nearest_keyframe(&mut file, length, seek_points, chapter.start)?
+        };
+        match parts.last_mut() {
+            // A chapter that ends up at the same keyframe as the one before
+            // takes its place.
+            Some((previous, last)) if cut.offset <= last.offset => *previous = chapter,
+            _ => parts.push((chapter, cut)),
+        }
+    }
+    if parts.len() < 2 {
+        bail!("The recording spans a single chapter");
+    }
+
+    let mut written = Vec::new();
+    for (index, (chapter, cut)) in parts.iter().enumerate() {
+        let until = parts.get(index + 1).map_or(length, |(_, next)| next.offset);
+        let output = part_path(path, index + 1, &chapter.title);
+        let mut out = File::create(&output)
+            .with_context(|| format!("Failed to create {}", output.display()))?;
+        let segment_start = seek_points[cut.segment].offset;
+        if cut.offset > segment_start {
+            // Cut inside a segment: the PAT and PMT at its start tell
+            // players what the streams are.
+            let tables = program_tables(&mut file, segment_start, cut.offset)?;
+            out.write_all(&tables)?;
+        }
+        file.seek(SeekFrom::Start(cut.offset))?;
+        io::copy(&mut (&mut file).take(until - cut.offset), &mut out)
+            .with_context(|| format!("Failed to write {}", output.display()))?;
+        written.push(output);
+    }
+    fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))?;
+    Ok(written)
+}
+
+/// `<stem> - 01 - <title>.<extension>` next to the recording.
+fn part_path(path: &Path, number: usize, title: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    let mut name = format!("{stem} - {number:02} - {}", template::sanitize(title));
+    if let Some(extension) = path.extension() {
+        name.push('.');
+        name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(name)
+}
+
+/// The keyframe closest to `time`: the start of the segment it falls in or
+/// of the next one, or a keyframe inside the segment.
+fn nearest_keyframe(
+    file: &mut File,
+    length: u64,
+    seek_points: &[SeekPoint],
+    time: f64,
+) -> Result<Cut> {
+    let segment = seek_points
+        .iter()
+        .rposition(|point| point.output_time <= time)
+        .unwrap_or(0);
+    let start = seek_points[segment];
+    let end = seek_points
+        .get(segment + 1)
+        .map_or(length, |point| point.offset);
+
+    let mut cuts = vec![Cut {
+        offset: start.offset,
+        time: start.output_time,
+        segment,
+    }];
+    if let Some(next) = seek_points.get(segment + 1) {
+        cuts.push(Cut {
+            offset: next.offset,
+            time: next.output_time,
+            segment: segment + 1,
+        });
+    }
+
+    let mut data = vec![0; (end - start.offset) as usize];
+    file.seek(SeekFrom::Start(start.offset))?;
+    file.read_exact(&mut data)
+        .context("Failed to read the recording")?;
+    let mut first_pts = None;
+    for (index, packet) in data.chunks_exact(TS_PACKET_SIZE).enumerate() {
+        let Some((pts, keyframe)) = video_pes_start(packet) else {
+            continue;
+        };
+        let first = *first_pts.get_or_insert(pts);
+        if keyframe && index > 0 {
+            cuts.push(Cut {
+                offset: start.offset + (index * TS_PACKET_SIZE) as u64,
+                time: start.output_time + pts.saturating_sub(first) as f64 / 90_000.0,
+                segment,
+            });
+        }
+    }
+    Ok(cuts
+        .into_iter()
+        .min_by(|a, b| (a.time - time).abs().total_cmp(&(b.time - time).abs()))
+        .expect("there is always the segment start"))
+}
+
+/// The PTS of the video PES packet that starts in `packet`, and whether the
+/// packet is marked as a random access point.
+fn video_pes_start(packet: &[u8]) -> Option<(u64, bool)> {
+    if packet[0] != 0x47 || packet[1] & 0x40 == 0 {
+        return None;
+    }
+    let (payload, random_access) = match (packet[3] >> 4) & 0x3 {
+        0x1 => (4, false),
+        0x3 => (
+            5 + packet[4] as usize,
+            packet[4] > 0 && packet[5] & 0x40 != 0,
+        ),
+        _ => return None,
+    };
+    let pes = packet.get(payload..).filter(|pes| pes.len() >= 14)?;
+    if pes[..3] != [0, 0, 1] || !(0xe0..=0xef).contains(&pes[3]) || pes[7] & 0x80 == 0 {
+        return None;
+    }
+    let pts = (u64::from(pes[9] >> 1) & 0x7) << 30
+        | u64::from(pes[10]) << 22
+        | u64::from(pes[11] >> 1) << 15
+        | u64::from(pes[12]) << 7
+        | u64::from(pes[13] >> 1);
+    Some((pts, random_access))
+}
+
+/// The PAT and PMT packets between `start` and `end`.
+fn program_tables(file: &mut File, start: u64, end: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0; (end - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut data)
+        .context("Failed to read the recording")?;
+    let mut pmt_pid = None;
+    let mut tables = Vec::new();
+    for packet in data.chunks_exact(TS_PACKET_SIZE) {
+        let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+        if pid == 0 && pmt_pid.is_none() {
+            // The first program in the PAT, after the pointer field and the
+            // 8 byte section header. Program 0 points at the network table.
+            let pointer = 5 + packet[4] as usize;
+            pmt_pid = packet
+                .get(pointer + 8..)
+                .unwrap_or_default()
+                .chunks_exact(4)
+                .find(|program| program[..2] != [0, 0])
+                .map(|program| (u16::from(program[2] & 0x1f) << 8) | u16::from(program[3]));
+            tables.extend_from_slice(packet);
+        } else if pmt_pid == Some(pid) {
+            tables.extend_from_slice(packet);
+            break;
+        }
+    }
+    Ok(tables)
+}
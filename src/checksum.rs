@@ -0,0 +1,165 @@
+//! SHA-256 manifests for finished recordings (`--checksum`) and `fors verify`,
+//! for checking archived VODs years later.
+//!
+//! `<output>.sha256` starts with the line `sha256sum -c` understands. With
+//! `--checksum-chunk`, comment lines after it hash each chunk of the file,
+//! so a damaged archive shows where it is damaged:
+//!
+//! ```text
+//! 3a7bd3e2360a3d29eea436fcfb7e44c735d117c42d1c1835420b6b9942dd4f1b  vod.ts
+//! # chunk 1073741824
+//! # 0 5f70bf18a086007016e948b04aed3b82103a36bea41755b6cddfaf10ace3c6ef
+//! # 1073741824 b5bb9d8014a0f9b1d61e21e796d78dccdf1352f23cd32812f4850b878ae4944c
+//! ```
+
+use anyhow::{Context, Result, bail};
+use hmac_sha256::Hash;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::units::format_byte_size;
+
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Where the manifest for `recording` goes.
+pub fn manifest_path(recording: &Path) -> PathBuf {
+    let mut name = recording.as_os_str().to_owned();
+    name.push(".sha256");
+    PathBuf::from(name)
+}
+
+/// The hash of a whole file, and of each `chunk` bytes of it when `chunk`
+/// is set.
+struct Digest {
+    file: String,
+    chunks: Vec<(u64, String)>,
+}
+
+fn digest(path: &Path, chunk: Option<u64>) -> Result<Digest> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut whole = Hash::new();
+    let mut part = Hash::new();
+    let mut part_start = 0;
+    let mut offset = 0;
+    let mut chunks = Vec::new();
+    let mut buf = vec![0; READ_SIZE];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        whole.update(&buf[..read]);
+        let Some(chunk) = chunk else {
+            continue;
+        };
+        let mut data = &buf[..read];
+        while !data.is_empty() {
+            let take = data.len().min((part_start + chunk - offset) as usize);
+            part.update(&data[..take]);
+            data = &data[take..];
+            offset += take as u64;
+            if offset == part_start + chunk {
+                chunks.push((part_start, hex(std::mem::take(&mut part).finalize())));
+                part_start = offset;
+            }
+        }
+    }
+    if chunk.is_some() && offset > part_start {
+        chunks.push((part_start, hex(part.finalize())));
+    }
+    Ok(Digest {
+        file: hex(whole.finalize()),
+        chunks,
+    })
+}
+
+/// Hashes `recording` and writes its manifest next to it.
+pub fn write(recording: &Path, chunk: Option<u64>) -> Result<PathBuf> {
+    let chunk = chunk.filter(|&size| size > 0);
+    let digest = digest(recording, chunk)?;
+    let name = recording
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let mut manifest = format!("{}  {name}\n", digest.file);
+    if let Some(chunk) = chunk {
+        manifest.push_str(&format!("# chunk {chunk}\n"));
+        for (offset, hash) in &digest.chunks {
+            manifest.push_str(&format!("# {offset} {hash}\n"));
+        }
+    }
+    let path = manifest_path(recording);
+    fs::write(&path, manifest)
+        .with_context(|| format!("Failed to write checksums to {}", path.display()))?;
+    Ok(path)
+}
+
+/// Checks `path`, a recording or its manifest, against the manifest.
+pub fn verify(path: &Path) -> Result<()> {
+    let (recording, manifest_file) = match path.extension() {
+        Some(extension) if extension == "sha256" => (path.with_extension(""), path.to_path_buf()),
+        _ => (path.to_path_buf(), manifest_path(path)),
+    };
+    let manifest = fs::read_to_string(&manifest_file)
+        .with_context(|| format!("Failed to read {}", manifest_file.display()))?;
+    let mut lines = manifest.lines();
+    let expected = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next())
+        .with_context(|| format!("{} lists no checksum", manifest_file.display()))?;
+    let mut chunk = None;
+    let mut chunks = Vec::new();
+    for line in lines.filter_map(|line| line.strip_prefix("# ")) {
+        let invalid = || format!("Invalid line '# {line}' in {}", manifest_file.display());
+        match line.split_once(' ').with_context(invalid)? {
+            ("chunk", size) => chunk = Some(size.parse::<u64>().with_context(invalid)?),
+            (offset, hash) => chunks.push((offset.parse::<u64>().with_context(invalid)?, hash)),
+        }
+    }
+    if chunk.is_none_or(|size| size == 0) && !chunks.is_empty() {
+        bail!(
+            "{} lists chunks without their size",
+            manifest_file.display()
+        );
+    }
+
+    let actual = digest(&recording, chunk.filter(|_| !chunks.is_empty()))?;
+    if actual.file == expected {
+        println!("{}: OK", recording.display());
+        return Ok(());
+    }
+    let size = chunk.unwrap_or_default();
+    let damaged: Vec<_> = chunks
+        .iter()
+        .filter(|(offset, hash)| {
+            actual
+                .chunks
+                .iter()
+                .find(|(actual, _)| actual == offset)
+                .is_none_or(|(_, actual)| actual != hash)
+        })
+        .map(|(offset, _)| {
+            format!(
+                "{}-{}",
+                format_byte_size(*offset),
+                format_byte_size(offset + size)
+            )
+        })
+        .collect();
+    if !damaged.is_empty() {
+        bail!(
+            "{} does not match its checksum, damaged in {}",
+            recording.display(),
+            damaged.join(", ")
+        );
+    }
+    bail!("{} does not match its checksum", recording.display());
+}
+
+fn hex(bytes: [u8; 32]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
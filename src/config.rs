@@ -0,0 +1,195 @@
+use anyhow::{Context, Result, bail};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::notify::{self, NotifyConfig};
+use crate::paths;
+
+#[cfg(test)]
+mod tests;
+
+/// Options read from `config.toml`. Keys are long option names (`-` or `_`
+/// separated), e.g. `twitch_low_latency = true` or `http-header = ["A=b"]`.
+///
+/// Tables named after a provider (`[twitch]`, `[youtube]`) hold defaults that
+/// only apply to that provider and override the global ones. Inside them the
+/// provider prefix may be dropped, so `[twitch] low_latency = true` works.
+/// The `[retention]` table works the same way for the `retention-*` options,
+/// whatever the provider.
+pub struct Config {
+    path: PathBuf,
+    table: Table,
+}
+
+impl Config {
+    /// Loads `explicit`, or the default location when it exists.
+    pub fn load(explicit: Option<&Path>) -> Result<Option<Self>> {
+        let path = match explicit {
+            Some(path) => path.to_path_buf(),
+            None => match default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(None),
+            },
+        };
+
+        let text = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let table: Table = text
+            .parse()
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+        Ok(Some(Config { path, table }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn has_section(&self, provider: &str) -> bool {
+        self.table.get(provider).is_some_and(Value::is_table)
+    }
+
+    /// The default quality, which is positional on the command line and
+    /// therefore cannot be expressed as an option.
+    pub fn quality(&self, provider: Option<&str>) -> Option<String> {
+        let global = self.table.get("quality");
+        provider
+            .and_then(|name| self.section(name))
+            .and_then(|section| section.get("quality"))
+            .or(global)
+            .and_then(Value::as_str)
+            .map(String::from)
+    }
+
+    /// Converts the global entries, followed by those of the `provider`
+    /// section, into command line arguments for `cmd`.
+    ///
+    /// Options `given` on the command line or by their environment variable
+    /// are skipped, and so are options that conflict with one of those, so
+    /// that both take precedence over the config file.
+    pub fn to_args(
+        &self,
+        cmd: &Command,
+        given: &ArgMatches,
+        provider: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let mut args = self.table_args(cmd, given, &self.table, None)?;
+        if let Some(section) = self.section("retention") {
+            args.extend(self.table_args(cmd, given, section, Some("retention"))?);
+        }
+        if let Some(name) = provider
+            && let Some(section) = self.section(name)
+        {
+            args.extend(self.table_args(cmd, given, section, Some(name))?);
+        }
+        Ok(args)
+    }
+
+    /// The `[notify]` table, see [`crate::notify`].
+    pub fn notify(&self) -> Result<Option<NotifyConfig>> {
+        self.table
+            .get("notify")
+            .map(|value| notify::parse_config(value.clone()))
+            .transpose()
+            .with_context(|| format!("Invalid [notify] table in {}", self.path.display()))
+    }
+
+    fn section(&self, provider: &str) -> Option<&Table> {
+        self.table.get(provider).and_then(Value::as_table)
+    }
+
+    fn table_args(
+        &self,
+        cmd: &Command,
+        given: &ArgMatches,
+        table: &Table,
+        prefix: Option<&str>,
+    ) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+
+        for (key, value) in table {
+            if key == "quality" || value.is_table() {
+                continue;
+            }
+
+            let long = key.replace('_', "-");
+            let prefixed = prefix.map(|p| format!("{p}-{long}"));
+            let Some(arg) = cmd.get_arguments().find(|a| {
+                a.get_long()
+                    .is_some_and(|l| l == long || Some(l) == prefixed.as_deref())
+            }) else {
+                bail!("Unknown option '{key}' in {}", self.path.display());
+            };
+            let long = arg.get_long().unwrap_or_default();
+            if overridden(cmd, given, arg) {
+                continue;
+            }
+
+            let flag = format!("--{long}");
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, Value::Boolean(enabled)) => {
+                    if *enabled {
+                        args.push(flag);
+                    }
+                }
+                (ArgAction::Count, Value::Integer(count)) => {
+                    for _ in 0..*count {
+                        args.push(flag.clone());
+                    }
+                }
+                (ArgAction::SetTrue | ArgAction::Count, _) => {
+                    bail!(
+                        "Option '{key}' in {} expects {}",
+                        self.path.display(),
+                        if matches!(arg.get_action(), ArgAction::Count) {
+                            "a number"
+                        } else {
+                            "true or false"
+                        }
+                    );
+                }
+                (_, Value::Array(items)) => {
+                    for item in items {
+                        args.push(format!("{flag}={}", scalar(item)));
+                    }
+                }
+                (_, value) => args.push(format!("{flag}={}", scalar(value))),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Whether `arg`, or an option it conflicts with either way, is `given`
+/// outside the config file.
+fn overridden(cmd: &Command, given: &ArgMatches, arg: &Arg) -> bool {
+    let is_given = |arg: &Arg| {
+        matches!(
+            given.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    is_given(arg)
+        || cmd.get_arg_conflicts_with(arg).into_iter().any(is_given)
+        || cmd.get_arguments().any(|other| {
+            is_given(other)
+                && cmd
+                    .get_arg_conflicts_with(other)
+                    .iter()
+                    .any(|conflict| conflict.get_id() == arg.get_id())
+        })
+}
+
+pub fn default_path() -> Option<PathBuf> {
+    paths::config_file("config.toml")
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,56 @@
+use super::Config;
+use crate::cli_command;
+
+fn config(text: &str) -> Config {
+    Config {
+        path: "config.toml".into(),
+        table: text.parse().unwrap(),
+    }
+}
+
+/// The config arguments for `line`, and whether the merged command line
+/// parses.
+fn merge(config: &Config, line: &[&str]) -> (Vec<String>, bool) {
+    let cmd = cli_command();
+    let line: Vec<String> = ["fors"].iter().chain(line).map(|s| s.to_string()).collect();
+    let given = cmd.clone().get_matches_from(&line);
+    let args = config.to_args(&cmd, &given, None).unwrap();
+    let merged = line[..1].iter().chain(&args).chain(&line[1..]);
+    let parses = cmd.try_get_matches_from(merged).is_ok();
+    (args, parses)
+}
+
+#[test]
+fn config_options_fill_in_what_the_command_line_leaves_out() {
+    let config = config("output = \"config.ts\"\nparallel = 2\n");
+
+    let (args, parses) = merge(&config, &["https://twitch.tv/a"]);
+
+    assert_eq!(args, ["--output=config.ts", "--parallel=2"]);
+    assert!(parses);
+}
+
+#[test]
+fn command_line_options_override_the_config() {
+    let config = config("output = \"config.ts\"\nparallel = 2\n");
+
+    let (args, parses) = merge(&config, &["--parallel", "3", "https://twitch.tv/a"]);
+
+    assert_eq!(args, ["--output=config.ts"]);
+    assert!(parses);
+}
+
+#[test]
+fn command_line_options_override_conflicting_config_options() {
+    // `--player` declares the conflict with `--output`; either way round,
+    // the command line wins.
+    let output = config("output = \"config.ts\"\n");
+    let (args, parses) = merge(&output, &["--player", "mpv", "https://twitch.tv/a"]);
+    assert!(args.is_empty());
+    assert!(parses);
+
+    let player = config("player = \"mpv\"\n");
+    let (args, parses) = merge(&player, &["--output", "cli.ts", "https://twitch.tv/a"]);
+    assert!(args.is_empty());
+    assert!(parses);
+}
@@ -0,0 +1,352 @@
+//! The recording queue behind `--url-file` and daemon mode (`--api`). Jobs
+//! run by priority, `--parallel` at a time, and failed ones are retried with
+//! backoff. In daemon mode the HTTP API in [`api`] can add to, inspect and
+//! stop them while fors keeps running.
+
+use serde::Serialize;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::events::{EventSink, SegmentEvent};
+use crate::hls::{StopHandle, StreamVariant};
+use crate::http::Retry;
+
+pub mod api;
+
+/// Finished recordings a daemon keeps around for `GET /recordings`.
+const MAX_FINISHED: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Recording,
+    Finished,
+    Failed,
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub url: String,
+    /// Higher runs first; equal priorities run in the order they were added.
+    pub priority: i32,
+    pub state: JobState,
+    /// Failed attempts so far.
+    pub attempts: u32,
+    /// When a failed job may run again.
+    pub retry_at: Option<u64>,
+    pub added_at: u64,
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub bytes_written: u64,
+    pub segments: u64,
+    /// Of the last segment, in kbit/s.
+    pub bitrate: Option<u64>,
+    /// Seconds the recording trails the live edge.
+    pub behind_live: Option<f64>,
+    pub in_ad_break: bool,
+    /// Seconds left of the advertised ad break.
+    pub ad_remaining: Option<f64>,
+    /// The quality the recording started at, if a reconnect lowered it.
+    pub downgraded_from: Option<String>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    stop: StopHandle,
+}
+
+impl Job {
+    fn stop(&mut self) {
+        self.stop.stop();
+        if self.state == JobState::Queued {
+            self.state = JobState::Stopped;
+            self.finished_at = Some(now_secs());
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub queued: usize,
+    pub recording: usize,
+    pub finished: usize,
+    pub failed: usize,
+    pub bytes_written: u64,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    jobs: Vec<Job>,
+}
+
+/// The recording jobs, shared by the workers and the API.
+pub struct Jobs {
+    state: Mutex<State>,
+    wake: Condvar,
+    /// Stops every job, e.g. on SIGTERM.
+    stop: StopHandle,
+    /// Wait for new jobs once the queue is empty, rather than ending.
+    keep_alive: bool,
+    retry: Retry,
+}
+
+impl Jobs {
+    pub fn new(stop: &StopHandle, keep_alive: bool, retry: Retry) -> Arc<Self> {
+        Arc::new(Jobs {
+            state: Mutex::default(),
+            wake: Condvar::new(),
+            stop: stop.clone(),
+            keep_alive,
+            retry,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn add(&self, url: &str, priority: i32) -> u64 {
+        let mut state = self.lock();
+        state.next_id += 1;
+        let id = state.next_id;
+        state.jobs.push(Job {
+            id,
+            url: url.to_string(),
+            priority,
+            state: JobState::Queued,
+            attempts: 0,
+            retry_at: None,
+            added_at: now_secs(),
+            started_at: None,
+            finished_at: None,
+            bytes_written: 0,
+            segments: 0,
+            bitrate: None,
+            behind_live: None,
+            in_ad_break: false,
+            ad_remaining: None,
+            downgraded_from: None,
+            error: None,
+            stop: self.stop.child(),
+        });
+        self.wake.notify_one();
+        id
+    }
+
+    /// Waits for the next job that is due. Returns `None` once fors is
+    /// stopping, or without `keep_alive` once no job is left to run.
+    pub fn next(&self) -> Option<(u64, String, StopHandle)> {
+        let mut state = self.lock();
+        loop {
+            if self.stop.is_stopped() {
+                return None;
+            }
+            let now = now_secs();
+            let due = state
+                .jobs
+                .iter_mut()
+                .filter(|job| job.state == JobState::Queued)
+                .filter(|job| job.retry_at.is_none_or(|at| at <= now))
+                .max_by_key(|job| (job.priority, std::cmp::Reverse(job.id)));
+            if let Some(job) = due {
+                job.state = JobState::Recording;
+                job.started_at = Some(now);
+                return Some((job.id, job.url.clone(), job.stop.clone()));
+            }
+            if self.done(&state) {
+                return None;
+            }
+            state = self
+                .wake
+                .wait_timeout(state, Duration::from_millis(250))
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Whether fors is stopping, or every job has run without `keep_alive`.
+    #[cfg(feature = "tui")]
+    pub fn is_done(&self) -> bool {
+        self.stop.is_stopped() || self.done(&self.lock())
+    }
+
+    fn done(&self, state: &State) -> bool {
+        // A running job may still fail and come back for a retry.
+        let pending = state
+            .jobs
+            .iter()
+            .any(|job| matches!(job.state, JobState::Queued | JobState::Recording));
+        !self.keep_alive && !pending
+    }
+
+    pub fn finish(&self, id: u64, result: &anyhow::Result<()>) {
+        let mut state = self.lock();
+        self.wake.notify_all();
+        if let Some(job) = state.jobs.iter_mut().find(|job| job.id == id) {
+            job.finished_at = Some(now_secs());
+            job.in_ad_break = false;
+            job.ad_remaining = None;
+            job.state = match result {
+                Ok(()) if job.stop.is_stopped() => JobState::Stopped,
+                Ok(()) => JobState::Finished,
+                Err(_) if job.stop.is_stopped() => JobState::Stopped,
+                Err(err) if job.attempts < self.retry.retries => {
+                    let delay = self.retry.delay(job.attempts);
+                    job.attempts += 1;
+                    warn!(
+                        "Retrying {} in {}s (attempt {} of {})",
+                        job.url,
+                        delay.as_secs(),
+                        job.attempts + 1,
+                        self.retry.retries + 1
+                    );
+                    job.error = Some(format!("{err:#}"));
+                    job.retry_at = Some(now_secs() + delay.as_secs());
+                    job.finished_at = None;
+                    JobState::Queued
+                }
+                Err(err) => {
+                    job.attempts += 1;
+                    job.error = Some(format!("{err:#}"));
+                    JobState::Failed
+                }
+            };
+        }
+        if !self.keep_alive {
+            return;
+        }
+        let done = state
+            .jobs
+            .iter()
+            .filter(|job| job.finished_at.is_some())
+            .count();
+        let mut excess = done.saturating_sub(MAX_FINISHED);
+        state.jobs.retain(|job| {
+            let drop = excess > 0 && job.finished_at.is_some();
+            excess -= usize::from(drop);
+            !drop
+        });
+    }
+
+    /// Stops a recording or takes it out of the queue. Returns false for
+    /// unknown jobs.
+    pub fn stop(&self, id: u64) -> bool {
+        let mut state = self.lock();
+        let Some(job) = state.jobs.iter_mut().find(|job| job.id == id) else {
+            return false;
+        };
+        job.stop();
+        true
+    }
+
+    /// Stops the recordings of `url` that have not finished, and returns
+    /// their ids.
+    pub fn stop_url(&self, url: &str) -> Vec<u64> {
+        let mut state = self.lock();
+        state
+            .jobs
+            .iter_mut()
+            .filter(|job| job.url == url && job.finished_at.is_none())
+            .map(|job| {
+                job.stop();
+                job.id
+            })
+            .collect()
+    }
+
+    /// Stops every job and ends the queue.
+    #[cfg(feature = "tui")]
+    pub fn stop_all(&self) {
+        self.stop.stop();
+        self.wake.notify_all();
+    }
+
+    pub fn list(&self) -> Vec<Job> {
+        self.lock().jobs.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let state = self.lock();
+        let mut stats = Stats::default();
+        for job in &state.jobs {
+            stats.bytes_written += job.bytes_written;
+            match job.state {
+                JobState::Queued => stats.queued += 1,
+                JobState::Recording => stats.recording += 1,
+                JobState::Finished | JobState::Stopped => stats.finished += 1,
+                JobState::Failed => stats.failed += 1,
+            }
+        }
+        stats
+    }
+
+    /// Collects a job's progress from its stream events.
+    pub fn progress(self: &Arc<Self>, id: u64) -> JobProgress {
+        JobProgress {
+            jobs: self.clone(),
+            id,
+        }
+    }
+}
+
+pub struct JobProgress {
+    jobs: Arc<Jobs>,
+    id: u64,
+}
+
+impl JobProgress {
+    fn update(&self, f: impl FnOnce(&mut Job)) {
+        let mut state = self.jobs.lock();
+        if let Some(job) = state.jobs.iter_mut().find(|job| job.id == self.id) {
+            f(job);
+        }
+    }
+}
+
+impl EventSink for JobProgress {
+    fn on_quality_downgrade(&mut self, from: &StreamVariant, _to: &StreamVariant) {
+        self.update(|job| {
+            job.downgraded_from
+                .get_or_insert_with(|| from.label.clone());
+        });
+    }
+
+    fn on_segment(&mut self, segment: &SegmentEvent) {
+        self.update(|job| {
+            job.bytes_written = segment.total_bytes;
+            job.segments += 1;
+            if segment.duration > 0.0 {
+                job.bitrate = Some((segment.bytes as f64 * 8.0 / segment.duration / 1000.0) as u64);
+            }
+            job.behind_live = segment.behind_live;
+        });
+    }
+
+    fn on_ad_break_start(&mut self, duration: Option<f64>) {
+        self.update(|job| {
+            job.in_ad_break = true;
+            job.ad_remaining = duration;
+        });
+    }
+
+    fn on_ad_progress(&mut self, _skipped: f64, remaining: Option<f64>) {
+        self.update(|job| job.ad_remaining = remaining);
+    }
+
+    fn on_ad_break_end(&mut self) {
+        self.update(|job| {
+            job.in_ad_break = false;
+            job.ad_remaining = None;
+        });
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
@@ -0,0 +1,260 @@
+//! A small JSON API over plain HTTP/1.1 for controlling a daemon:
+//!
+//! - `GET /recordings` lists queued, running and recently finished jobs
+//! - `POST /recordings` with `{"url": "...", "priority": 0}` queues a
+//!   recording; higher priorities run first
+//! - `DELETE /recordings/{id}` stops a recording or unqueues it, and
+//!   `DELETE /recordings?url=...` does so for every unfinished job of a URL
+//! - `GET /stats` sums them up
+//!
+//! Every request needs `Authorization: Bearer <--api-token>`.
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+use super::Jobs;
+use crate::providers;
+
+#[cfg(test)]
+mod tests;
+
+const MAX_BODY: usize = 64 * 1024;
+/// Longest request line plus headers, read before the token is checked.
+const MAX_HEADER_BYTES: u64 = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+/// Connections served at once; further ones wait to be accepted.
+const MAX_CONNECTIONS: usize = 16;
+
+struct Request {
+    method: String,
+    path: String,
+    token: Option<String>,
+    body: Vec<u8>,
+}
+
+/// Counts the connections being served, up to [`MAX_CONNECTIONS`].
+#[derive(Default)]
+struct Slots {
+    used: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    /// Waits for a free slot and takes it until the guard is dropped.
+    fn acquire(self: &Arc<Self>) -> Slot {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        while *used >= MAX_CONNECTIONS {
+            used = self.freed.wait(used).unwrap_or_else(|e| e.into_inner());
+        }
+        *used += 1;
+        Slot(self.clone())
+    }
+}
+
+struct Slot(Arc<Slots>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        *self.0.used.lock().unwrap_or_else(|e| e.into_inner()) -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Serves the API on a background thread, each connection on a thread of
+/// its own so a slow client can't hold up the others.
+pub fn serve(addr: SocketAddr, token: String, jobs: Arc<Jobs>) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Failed to listen on {addr}"))?;
+    info!("API listening on http://{}", listener.local_addr()?);
+    let token: Arc<str> = token.into();
+    let slots = Arc::new(Slots::default());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    debug!("API connection failed: {err:#}");
+                    continue;
+                }
+            };
+            let slot = slots.acquire();
+            let (token, jobs) = (token.clone(), jobs.clone());
+            std::thread::spawn(move || {
+                let _slot = slot;
+                if let Err(err) = handle(stream, &token, &jobs) {
+                    debug!("API connection failed: {err:#}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn handle(mut stream: TcpStream, token: &str, jobs: &Jobs) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let (status, body) = match read_request(&stream) {
+        Ok(request)
+            if !request
+                .token
+                .as_deref()
+                .is_some_and(|given| same_token(given, token)) =>
+        {
+            warn!("Rejected unauthenticated API request {}", request.path);
+            (401, json!({ "error": "missing or wrong API token" }))
+        }
+        Ok(request) => route(&request, jobs),
+        Err((status, error)) => (status, json!({ "error": error })),
+    };
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status} {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        reason(status),
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Compares in a time that only depends on the lengths, so the token can't be
+/// guessed a byte at a time.
+fn same_token(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn route(request: &Request, jobs: &Jobs) -> (u16, Value) {
+    let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+    let path = path.trim_end_matches('/');
+    match (request.method.as_str(), path) {
+        ("GET", "/recordings") => (200, json!(jobs.list())),
+        ("GET", "/stats") => (200, json!(jobs.stats())),
+        ("POST", "/recordings") => {
+            let body = serde_json::from_slice::<Value>(&request.body).unwrap_or_default();
+            let priority = body["priority"].as_i64().unwrap_or(0) as i32;
+            match body["url"].as_str() {
+                Some(url) if providers::provider_name_for(url).is_some() => {
+                    let id = jobs.add(url, priority);
+                    info!("Queued {url} (job {id}) from the API");
+                    (201, json!({ "id": id }))
+                }
+                Some(url) => (400, json!({ "error": format!("Unsupported URL {url}") })),
+                None => (400, json!({ "error": "expected {\"url\": \"...\"}" })),
+            }
+        }
+        ("DELETE", "/recordings") => {
+            let url = url::form_urlencoded::parse(query.as_bytes())
+                .find_map(|(key, value)| (key == "url").then_some(value));
+            let Some(url) = url else {
+                return (400, json!({ "error": "expected ?url=..." }));
+            };
+            let ids = jobs.stop_url(&url);
+            if ids.is_empty() {
+                return (404, json!({ "error": "no such recording" }));
+            }
+            info!("Stopping {url} (jobs {ids:?}) from the API");
+            (202, json!({ "ids": ids }))
+        }
+        ("DELETE", _) => match path
+            .strip_prefix("/recordings/")
+            .and_then(|id| id.parse().ok())
+        {
+            Some(id) if jobs.stop(id) => {
+                info!("Stopping job {id} from the API");
+                (202, json!({ "id": id }))
+            }
+            _ => (404, json!({ "error": "no such recording" })),
+        },
+        _ => (404, json!({ "error": "not found" })),
+    }
+}
+
+/// Reads a request, or the status and error to answer with instead.
+fn read_request(stream: &TcpStream) -> Result<Request, (u16, String)> {
+    // The head can only use up `MAX_HEADER_BYTES`; the body gets its own
+    // limit once its length is known.
+    let mut reader = BufReader::new(stream.take(MAX_HEADER_BYTES));
+    let too_large = || (431, "Request headers too large".to_string());
+    let mut read_line = |line: &mut String| {
+        line.clear();
+        let read = reader
+            .read_line(line)
+            .map_err(|err| (400, format!("Failed to read request: {err}")))?;
+        if !line.ends_with('\n') && reader.get_ref().limit() == 0 {
+            return Err(too_large());
+        }
+        Ok(read)
+    };
+
+    let mut line = String::new();
+    read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err((400, "Malformed request line".to_string()));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let mut token = None;
+    let mut length = 0;
+    let mut headers = 0;
+    loop {
+        if read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(too_large());
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            token = value.strip_prefix("Bearer ").map(str::to_string);
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = value
+                .parse()
+                .map_err(|_| (400, "Invalid Content-Length".to_string()))?;
+        }
+    }
+    if length > MAX_BODY {
+        return Err((413, "Request body too large".to_string()));
+    }
+    reader.get_mut().set_limit(length as u64);
+    let mut body = vec![0; length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|err| (400, format!("Failed to read request body: {err}")))?;
+    Ok(Request {
+        method,
+        path,
+        token,
+        body,
+    })
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Not Found",
+    }
+}
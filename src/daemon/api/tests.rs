@@ -0,0 +1,93 @@
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::{MAX_CONNECTIONS, MAX_HEADERS, Slots, read_request};
+
+/// What `read_request` makes of `request` sent over a real connection.
+fn read(request: &[u8]) -> Result<(String, String, Option<String>, Vec<u8>), u16> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    // Large requests fill the socket buffers before they are read.
+    let request = request.to_vec();
+    let writer = std::thread::spawn(move || {
+        client.write_all(&request).ok();
+        client
+    });
+    let result = read_request(&server)
+        .map(|request| (request.method, request.path, request.token, request.body))
+        .map_err(|(status, _)| status);
+    drop(server);
+    writer.join().unwrap();
+    result
+}
+
+#[test]
+fn requests_are_read_with_their_token_and_body() {
+    let request = b"POST /recordings HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Authorization: Bearer secret\r\n\
+        Content-Length: 13\r\n\
+        \r\n\
+        {\"url\": \"x\"}\n";
+
+    assert_eq!(
+        read(request),
+        Ok((
+            "POST".into(),
+            "/recordings".into(),
+            Some("secret".into()),
+            b"{\"url\": \"x\"}\n".to_vec()
+        ))
+    );
+}
+
+#[test]
+fn oversized_heads_are_refused() {
+    let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(20_000));
+    assert_eq!(read(long_line.as_bytes()), Err(431));
+
+    let long_header = format!("GET / HTTP/1.1\r\nX-Pad: {}\r\n\r\n", "a".repeat(20_000));
+    assert_eq!(read(long_header.as_bytes()), Err(431));
+
+    let many_headers = format!(
+        "GET / HTTP/1.1\r\n{}\r\n",
+        "X-Pad: a\r\n".repeat(MAX_HEADERS + 1)
+    );
+    assert_eq!(read(many_headers.as_bytes()), Err(431));
+}
+
+#[test]
+fn oversized_and_malformed_requests_are_refused() {
+    assert_eq!(
+        read(b"POST / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n"),
+        Err(413)
+    );
+    assert_eq!(
+        read(b"POST / HTTP/1.1\r\nContent-Length: lots\r\n\r\n"),
+        Err(400)
+    );
+    assert_eq!(read(b"\r\n"), Err(400));
+}
+
+#[test]
+fn connections_wait_for_a_free_slot() {
+    let slots = Arc::new(Slots::default());
+    let mut taken: Vec<_> = (0..MAX_CONNECTIONS).map(|_| slots.acquire()).collect();
+
+    let waiting = {
+        let slots = slots.clone();
+        std::thread::spawn(move || drop(slots.acquire()))
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!waiting.is_finished());
+
+    taken.pop();
+    waiting.join().unwrap();
+    assert_eq!(*slots.used.lock().unwrap(), MAX_CONNECTIONS - 1);
+}
@@ -0,0 +1,250 @@
+//! `--detect-dead-air`: runs ffmpeg's silencedetect and blackdetect filters
+//! over the stream while it is recorded and lists long silent or black
+//! stretches in `<output>.deadair.json`, so technical difficulties can be
+//! found in a long VOD without watching it.
+
+use anyhow::{Context, Result, anyhow};
+use serde_json::json;
+use std::fs;
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::output::Sink;
+use crate::units::format_duration;
+
+/// Below this the audio counts as silent.
+const SILENCE_THRESHOLD: &str = "-50dB";
+/// Share of dark pixels that makes a frame black.
+const BLACK_PIXELS: &str = "0.98";
+/// Writes held for a slow ffmpeg before they are dropped.
+const BACKLOG: usize = 64;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Silence,
+    Black,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Silence => "silence",
+            Kind::Black => "black",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Section {
+    kind: Kind,
+    start: f64,
+    end: f64,
+}
+
+/// Where the sidecar for `output` goes.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".deadair.json");
+    PathBuf::from(name)
+}
+
+/// Passes everything on to `inner` and a copy to ffmpeg, whose findings end
+/// up next to `output`. ffmpeg is fed from another thread and misses parts
+/// of the stream rather than holding up the recording when it falls behind.
+pub struct DeadAirDetector {
+    inner: Box<dyn Sink>,
+    child: Child,
+    sender: Option<SyncSender<Vec<u8>>>,
+    feeder: Option<JoinHandle<()>>,
+    reader: Option<JoinHandle<Vec<Section>>>,
+    /// Writes dropped since ffmpeg last kept up.
+    dropped: usize,
+}
+
+impl DeadAirDetector {
+    pub fn spawn(
+        inner: Box<dyn Sink>,
+        ffmpeg: &str,
+        output: &Path,
+        min_duration: Duration,
+    ) -> Result<Self> {
+        let min = min_duration.as_secs_f64();
+        let mut child = Command::new(ffmpeg)
+            .args(["-hide_banner", "-nostats", "-loglevel", "info"])
+            .args(["-f", "mpegts", "-i", "pipe:0"])
+            .args(["-map", "0:v:0?", "-map", "0:a:0?"])
+            .arg("-af")
+            .arg(format!("silencedetect=n={SILENCE_THRESHOLD}:d={min}"))
+            .arg("-vf")
+            .arg(format!("blackdetect=d={min}:pic_th={BLACK_PIXELS}"))
+            .args(["-f", "null", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| {
+                format!("Failed to start '{ffmpeg}' (needed for --detect-dead-air)")
+            })?;
+        let mut stdin = child.stdin.take().context("ffmpeg has no stdin")?;
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(BACKLOG);
+        let feeder = std::thread::spawn(move || {
+            for chunk in receiver {
+                // The recording goes on without the analysis if ffmpeg gives up.
+                if let Err(err) = stdin.write_all(&chunk) {
+                    if err.kind() != ErrorKind::BrokenPipe {
+                        warn!("Dead air detection stopped: {err}");
+                    }
+                    return;
+                }
+            }
+        });
+        let stderr = child.stderr.take().context("ffmpeg has no stderr")?;
+        let sidecar = sidecar_path(output);
+        info!(
+            "Listing silent or black stretches of {} or more in {}",
+            format_duration(min_duration),
+            sidecar.display()
+        );
+        let reader = std::thread::spawn(move || {
+            let mut sections = Vec::new();
+            let mut silence_start = None;
+            for line in BufReader::new(stderr).lines() {
+                let Ok(line) = line else { break };
+                let Some(section) = parse_line(&line, &mut silence_start) else {
+                    continue;
+                };
+                info!(
+                    "{} from {} to {}",
+                    match section.kind {
+                        Kind::Silence => "Silence",
+                        Kind::Black => "Black picture",
+                    },
+                    format_duration(Duration::from_secs_f64(section.start)),
+                    format_duration(Duration::from_secs_f64(section.end))
+                );
+                sections.push(section);
+                // Rewritten as they come in so a crash keeps what was found.
+                if let Err(err) = write(&sidecar, &sections) {
+                    warn!("{err:#}");
+                }
+            }
+            if sections.is_empty()
+                && let Err(err) = write(&sidecar, &sections)
+            {
+                warn!("{err:#}");
+            }
+            sections
+        });
+        Ok(DeadAirDetector {
+            inner,
+            child,
+            sender: Some(sender),
+            feeder: Some(feeder),
+            reader: Some(reader),
+            dropped: 0,
+        })
+    }
+}
+
+impl Write for DeadAirDetector {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write_all(buf)?;
+        if let Some(sender) = &self.sender {
+            match sender.try_send(buf.to_vec()) {
+                Ok(()) => {
+                    if self.dropped > 0 {
+                        warn!(
+                            "Dead air detection fell behind, {} writes were left out of it",
+                            self.dropped
+                        );
+                        self.dropped = 0;
+                    }
+                }
+                Err(TrySendError::Full(_)) => self.dropped += 1,
+                Err(TrySendError::Disconnected(_)) => self.sender = None,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for DeadAirDetector {
+    fn finish(&mut self) -> Result<()> {
+        let finished = self.inner.finish();
+        // ffmpeg reports the stretches still open once its input ends.
+        self.sender.take();
+        if let Some(feeder) = self.feeder.take() {
+            feeder.join().ok();
+        }
+        if let Some(reader) = self.reader.take() {
+            let sections = reader
+                .join()
+                .map_err(|_| anyhow!("ffmpeg reader thread panicked"))?;
+            info!("Found {} silent or black stretches", sections.len());
+        }
+        match self.child.wait() {
+            Ok(status) if !status.success() => {
+                warn!("Dead air detection: ffmpeg exited with {status}")
+            }
+            Ok(_) => {}
+            Err(err) => warn!("Failed to wait for ffmpeg: {err}"),
+        }
+        finished
+    }
+}
+
+/// A finished section from a line of ffmpeg's log. silencedetect reports
+/// the start and end on separate lines, blackdetect on one.
+fn parse_line(line: &str, silence_start: &mut Option<f64>) -> Option<Section> {
+    if let Some(start) = field(line, "silence_start:") {
+        *silence_start = Some(start);
+        return None;
+    }
+    if let Some(end) = field(line, "silence_end:") {
+        let start = silence_start.take()?;
+        return Some(Section {
+            kind: Kind::Silence,
+            start,
+            end,
+        });
+    }
+    Some(Section {
+        kind: Kind::Black,
+        start: field(line, "black_start:")?,
+        end: field(line, "black_end:")?,
+    })
+}
+
+fn field(line: &str, name: &str) -> Option<f64> {
+    let value = &line[line.find(name)? + name.len()..];
+    value.split_whitespace().next()?.parse().ok()
+}
+
+fn write(path: &Path, sections: &[Section]) -> Result<()> {
+    let sections: Vec<_> = sections
+        .iter()
+        .map(|section| {
+            json!({
+                "kind": section.kind.name(),
+                "start": section.start,
+                "end": section.end,
+                "duration": section.end - section.start,
+            })
+        })
+        .collect();
+    let contents = serde_json::to_string_pretty(&json!({ "dead_air": sections }))?;
+    fs::write(path, contents)
+        .with_context(|| format!("Failed to write dead air sections to {}", path.display()))
+}
@@ -0,0 +1,60 @@
+use super::{Kind, Section, parse_line};
+
+#[test]
+fn silence_spans_two_lines() {
+    let mut silence_start = None;
+
+    let start = parse_line(
+        "[silencedetect @ 0x5581] silence_start: 12.5",
+        &mut silence_start,
+    );
+    let end = parse_line(
+        "[silencedetect @ 0x5581] silence_end: 42.25 | silence_duration: 29.75",
+        &mut silence_start,
+    );
+
+    assert_eq!(start, None);
+    assert_eq!(
+        end,
+        Some(Section {
+            kind: Kind::Silence,
+            start: 12.5,
+            end: 42.25,
+        })
+    );
+    assert_eq!(silence_start, None);
+}
+
+#[test]
+fn black_picture_is_one_line() {
+    let section = parse_line(
+        "[blackdetect @ 0x55e0] black_start:3 black_end:13.4 black_duration:10.4",
+        &mut None,
+    );
+
+    assert_eq!(
+        section,
+        Some(Section {
+            kind: Kind::Black,
+            start: 3.0,
+            end: 13.4,
+        })
+    );
+}
+
+#[test]
+fn other_lines_and_unmatched_ends_are_ignored() {
+    let mut silence_start = None;
+
+    assert_eq!(
+        parse_line("Input #0, mpegts, from 'pipe:0':", &mut silence_start),
+        None
+    );
+    assert_eq!(
+        parse_line(
+            "[silencedetect @ 0x5581] silence_end: 5",
+            &mut silence_start
+        ),
+        None
+    );
+}
@@ -0,0 +1,60 @@
+use anyhow::{Context, Result, bail};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::units::format_byte_size;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Stops a recording before the output filesystem runs out of space.
+#[derive(Clone)]
+pub struct DiskGuard {
+    dir: PathBuf,
+    min_free: u64,
+    last_check: Option<Instant>,
+}
+
+impl DiskGuard {
+    pub fn for_output(output: &Path, min_free: u64) -> Self {
+        let dir = match output.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        DiskGuard {
+            dir,
+            min_free,
+            last_check: None,
+        }
+    }
+
+    /// Checks free space, at most once per [`CHECK_INTERVAL`].
+    pub fn check(&mut self) -> Result<()> {
+        if let Some(last) = self.last_check
+            && last.elapsed() < CHECK_INTERVAL
+        {
+            return Ok(());
+        }
+        self.check_now()
+    }
+
+    pub fn check_now(&mut self) -> Result<()> {
+        self.last_check = Some(Instant::now());
+        let available = fs4::available_space(&self.dir)
+            .with_context(|| format!("Failed to query free space on {}", self.dir.display()))?;
+        debug!(
+            "Free space on {}: {}",
+            self.dir.display(),
+            format_byte_size(available)
+        );
+        if available < self.min_free {
+            bail!(
+                "Free space on {} is {}, below the --min-free-space limit of {}",
+                self.dir.display(),
+                format_byte_size(available),
+                format_byte_size(self.min_free)
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+//! `fors doctor`: checks the setup without recording anything, so a report
+//! of fors not working comes with something to act on.
+
+use anyhow::{Result, bail};
+use fors_core::twitch::{self, CLIENT_ID, GQL_ENDPOINT};
+use reqwest::blocking::{Client, RequestBuilder};
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+use crate::http::{self, HttpOptions};
+use crate::paths;
+use crate::units::format_byte_size;
+
+/// Longest wait for each connectivity check.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CACHE_FILES: [&str; 3] = ["twitch_cache.json", "history.json", "resume.json"];
+
+pub struct DoctorOptions<'a> {
+    pub http: HttpOptions,
+    /// The config file that was loaded.
+    pub config: Option<&'a Path>,
+    pub cookie_jar: Option<&'a Path>,
+    pub ffmpeg: &'a str,
+    /// Environment variables that set an option.
+    pub known_env: Vec<String>,
+    /// Skip the connectivity checks.
+    pub offline: bool,
+}
+
+#[derive(Default)]
+struct Report {
+    warnings: usize,
+    failures: usize,
+}
+
+impl Report {
+    fn ok(&mut self, what: &str) {
+        println!("  ok    {what}");
+    }
+
+    fn warn(&mut self, what: &str) {
+        self.warnings += 1;
+        println!("  warn  {what}");
+    }
+
+    fn fail(&mut self, what: &str) {
+        self.failures += 1;
+        println!("  FAIL  {what}");
+    }
+}
+
+pub fn run(options: &DoctorOptions) -> Result<()> {
+    let mut report = Report::default();
+
+    println!("Configuration");
+    print_configuration(options);
+    check_environment(options, &mut report);
+    if options.http.no_ssl_verify {
+        report.warn("--http-no-ssl-verify accepts any certificate, only use it for debugging");
+    }
+    check_ffmpeg(options.ffmpeg, &mut report);
+
+    println!("Cache");
+    for name in CACHE_FILES {
+        check_json_file(&paths::cache_file(name), &mut report);
+    }
+    if let Some(jar) = options.cookie_jar {
+        check_json_file(jar, &mut report);
+    }
+
+    if options.offline {
+        println!("Connectivity (skipped)");
+    } else {
+        println!("Connectivity");
+        match http::client_builder(&options.http, None)
+            .and_then(|builder| Ok(builder.timeout(CHECK_TIMEOUT).build()?))
+        {
+            Ok(client) => check_connectivity(&client, &mut report),
+            Err(err) => report.fail(&format!("Failed to build HTTP client: {err:#}")),
+        }
+    }
+
+    println!("\n{} failed, {} warnings", report.failures, report.warnings);
+    if report.failures > 0 {
+        bail!("{} checks failed", report.failures);
+    }
+    Ok(())
+}
+
+fn print_configuration(options: &DoctorOptions) {
+    let http = &options.http;
+    let config = match options.config {
+        Some(path) => path.display().to_string(),
+        None => match crate::config::default_path() {
+            Some(path) => format!("none ({} does not exist)", path.display()),
+            None => "none".to_string(),
+        },
+    };
+    let proxy = http.proxy.clone().or_else(|| {
+        [
+            "HTTPS_PROXY",
+            "https_proxy",
+            "HTTP_PROXY",
+            "http_proxy",
+            "ALL_PROXY",
+        ]
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .map(|proxy| format!("{proxy} (from the environment)"))
+    });
+    let tls = match (http.no_ssl_verify, http.ca_certs.len()) {
+        (true, _) => "certificates are not verified".to_string(),
+        (false, 0) => "system root certificates".to_string(),
+        (false, extra) => format!("system root certificates and {extra} more"),
+    };
+    let cache = paths::cache_file("");
+    for (name, value) in [
+        ("config file", config),
+        ("cache", cache.display().to_string()),
+        ("proxy", proxy.unwrap_or_else(|| "none".to_string())),
+        ("TLS", tls),
+        (
+            "user agent",
+            http.user_agent
+                .clone()
+                .unwrap_or_else(|| "fors/0.1".to_string()),
+        ),
+        (
+            "DNS over HTTPS",
+            http.doh.clone().unwrap_or_else(|| "off".to_string()),
+        ),
+        (
+            "HTTP/2",
+            if http.http1_only { "off" } else { "on" }.to_string(),
+        ),
+    ] {
+        println!("  {name:<15} {value}");
+    }
+}
+
+/// `FORS_*` variables that match no option are ignored, usually because of
+/// a typo.
+fn check_environment(options: &DoctorOptions, report: &mut Report) {
+    let mut unknown: Vec<String> = std::env::vars_os()
+        .filter_map(|(name, _)| name.into_string().ok())
+        .filter(|name| name.starts_with("FORS_") && !options.known_env.contains(name))
+        .collect();
+    unknown.sort();
+    for name in unknown {
+        report.warn(&format!("{name} does not match any option and is ignored"));
+    }
+}
+
+fn check_ffmpeg(ffmpeg: &str, report: &mut Report) {
+    let found = Command::new(ffmpeg)
+        .arg("-version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if found {
+        report.ok(&format!("{ffmpeg} runs"));
+    } else {
+        report.warn(&format!(
+            "{ffmpeg} was not found, --audio-lang and YouTube formats need it"
+        ));
+    }
+}
+
+/// Cache files that do not parse are silently started over, so point them out.
+fn check_json_file(path: &Path, report: &mut Report) {
+    let name = path.display();
+    match fs::read(path) {
+        Ok(bytes) => match serde_json::from_slice::<serde_json::Value>(&bytes) {
+            Ok(_) => report.ok(&format!(
+                "{name} ({})",
+                format_byte_size(bytes.len() as u64)
+            )),
+            Err(err) => report.fail(&format!("{name} is not valid JSON ({err}), delete it")),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            println!("        {name} does not exist yet");
+        }
+        Err(err) => report.fail(&format!("Failed to read {name}: {err}")),
+    }
+}
+
+fn check_connectivity(client: &Client, report: &mut Report) {
+    let gql = client
+        .post(GQL_ENDPOINT)
+        .header("Client-ID", CLIENT_ID)
+        .json(&twitch::stream_status_request("twitch"));
+    check_reachable(report, "Twitch GQL", gql, |status| status.is_success());
+    // Without an access token usher refuses the request, which still shows
+    // that it can be reached.
+    let usher = client.get("https://usher.ttvnw.net/api/channel/hls/twitch.m3u8");
+    check_reachable(report, "Twitch usher", usher, |status| {
+        !status.is_server_error()
+    });
+    let youtube = client.get("https://www.youtube.com/");
+    check_reachable(report, "YouTube", youtube, |status| status.is_success());
+}
+
+fn check_reachable(
+    report: &mut Report,
+    name: &str,
+    request: RequestBuilder,
+    healthy: impl Fn(reqwest::StatusCode) -> bool,
+) {
+    let started = Instant::now();
+    match request.send() {
+        Ok(response) if healthy(response.status()) => report.ok(&format!(
+            "{name} answered in {} ms",
+            started.elapsed().as_millis()
+        )),
+        Ok(response) => report.fail(&format!(
+            "{name} answered {} ({})",
+            response.status(),
+            response.url()
+        )),
+        Err(err) => report.fail(&format!(
+            "{name} cannot be reached: {:#}",
+            anyhow::Error::from(err)
+        )),
+    }
+}
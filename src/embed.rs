@@ -0,0 +1,168 @@
+//! `--embed-metadata`: remuxes a finished recording into the MP4 or MKV
+//! container its file name asks for, with the title, channel and thumbnail
+//! embedded so media libraries such as Jellyfin and Plex index it properly.
+
+use anyhow::{Context, Result, bail};
+use fors_core::provider::StreamMetadata;
+use reqwest::blocking::Client;
+use reqwest::header::CONTENT_TYPE;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{info, warn};
+
+use crate::http::Retry;
+
+const CONTAINERS: [&str; 3] = ["mp4", "m4v", "mkv"];
+
+pub fn run(
+    client: &Client,
+    ffmpeg: &str,
+    path: &Path,
+    url: &str,
+    metadata: &StreamMetadata,
+    has_video: bool,
+) -> Result<()> {
+    let Some(container) = container(path) else {
+        warn!("--embed-metadata only applies to .mp4, .m4v and .mkv outputs");
+        return Ok(());
+    };
+
+    let cover = metadata.thumbnail.as_deref().and_then(|thumbnail| {
+        fetch_cover(client, thumbnail, path)
+            .inspect_err(|err| warn!("Embedding metadata without the thumbnail: {err:#}"))
+            .ok()
+    });
+    let tags = [
+        ("title", metadata.title.as_deref()),
+        ("artist", metadata.author.as_deref()),
+        ("date", metadata.started_at.as_deref()),
+        ("genre", metadata.category.as_deref()),
+        ("comment", Some(url)),
+    ];
+    let remuxed = path.with_extension(format!("embedding.{container}"));
+    let result = remux(
+        ffmpeg,
+        path,
+        &remuxed,
+        &container,
+        &tags,
+        cover.as_ref(),
+        has_video,
+    );
+    if let Some((cover, _)) = &cover {
+        fs::remove_file(cover).ok();
+    }
+    if let Err(err) = result {
+        fs::remove_file(&remuxed).ok();
+        return Err(err);
+    }
+    fs::rename(&remuxed, path)
+        .with_context(|| format!("Failed to replace {} with the remuxed file", path.display()))?;
+    info!(
+        "Embedded metadata{} in {}",
+        if cover.is_some() {
+            " and thumbnail"
+        } else {
+            ""
+        },
+        path.display()
+    );
+    Ok(())
+}
+
+/// Whether `path` names a container the recording can be remuxed into.
+pub fn supports(path: &Path) -> bool {
+    container(path).is_some()
+}
+
+fn container(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .filter(|extension| CONTAINERS.contains(&extension.as_str()))
+}
+
+/// Downloads the thumbnail next to the recording, returning its path and
+/// MIME type.
+fn fetch_cover(client: &Client, thumbnail: &str, path: &Path) -> Result<(PathBuf, String)> {
+    let response = Retry::API
+        .send(client.get(thumbnail))
+        .context("Failed to request the thumbnail")?
+        .error_for_status()
+        .context("Thumbnail request failed")?;
+    let mime = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|mime| mime.to_str().ok())
+        .filter(|mime| mime.starts_with("image/"))
+        .unwrap_or("image/jpeg")
+        .to_string();
+    let bytes = response.bytes().context("Failed to read the thumbnail")?;
+    let cover = path.with_extension(match mime.as_str() {
+        "image/png" => "cover.png",
+        "image/webp" => "cover.webp",
+        _ => "cover.jpg",
+    });
+    fs::write(&cover, bytes).with_context(|| format!("Failed to write {}", cover.display()))?;
+    Ok((cover, mime))
+}
+
+fn remux(
+    ffmpeg: &str,
+    input: &Path,
+    output: &Path,
+    container: &str,
+    tags: &[(&str, Option<&str>)],
+    cover: Option<&(PathBuf, String)>,
+    has_video: bool,
+) -> Result<()> {
+    let mut cmd = Command::new(ffmpeg);
+    cmd.args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(input);
+    match cover {
+        // Matroska keeps cover art as an attachment named cover.*.
+        Some((cover, mime)) if container == "mkv" => {
+            let name = cover.extension().and_then(|extension| extension.to_str());
+            cmd.args(["-map", "0:v?", "-map", "0:a?"])
+                .arg("-attach")
+                .arg(cover)
+                .arg("-metadata:s:t")
+                .arg(format!("mimetype={mime}"))
+                .arg("-metadata:s:t")
+                .arg(format!("filename={}", name.unwrap_or("cover.jpg")));
+        }
+        // MP4 has it as a video stream marked as the attached picture.
+        Some((cover, _)) => {
+            cmd.arg("-i")
+                .arg(cover)
+                .args(["-map", "0:v?", "-map", "0:a?", "-map", "1"])
+                .arg(format!("-disposition:v:{}", usize::from(has_video)))
+                .arg("attached_pic");
+        }
+        None => {
+            cmd.args(["-map", "0:v?", "-map", "0:a?"]);
+        }
+    }
+    cmd.args(["-c", "copy"]);
+    for &(key, value) in tags {
+        if let Some(value) = value {
+            cmd.arg("-metadata").arg(format!("{key}={value}"));
+        }
+    }
+    if container != "mkv" {
+        cmd.args(["-movflags", "+faststart"]);
+    }
+    let status = cmd
+        .arg(output)
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to start '{ffmpeg}' (needed for --embed-metadata)"))?;
+    if !status.success() {
+        bail!(
+            "ffmpeg exited with {status} while remuxing {}",
+            input.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,162 @@
+use serde_json::{Value, json};
+use std::io::Write;
+
+use crate::hls::{StreamVariant, TimedMetadata};
+
+/// A media segment that was written to the output.
+pub struct SegmentEvent {
+    pub sequence: u64,
+    pub duration: f64,
+    pub bytes: u64,
+    /// Bytes written since streaming started, including this segment.
+    pub total_bytes: u64,
+    /// Seconds of live media already in the playlist after this segment.
+    pub behind_live: Option<f64>,
+}
+
+/// Receives progress while a stream is recorded, so frontends do not have to
+/// scrape log output. All methods default to doing nothing.
+pub trait EventSink {
+    fn on_variant_selected(&mut self, _variant: &StreamVariant) {}
+    /// A reconnect continued the recording at a lower quality.
+    fn on_quality_downgrade(&mut self, _from: &StreamVariant, _to: &StreamVariant) {}
+    fn on_segment(&mut self, _segment: &SegmentEvent) {}
+    /// `duration` is the advertised length of the break in seconds, if known.
+    fn on_ad_break_start(&mut self, _duration: Option<f64>) {}
+    /// An ad segment was skipped. `skipped` is the ad time skipped so far in
+    /// this break, `remaining` what is left of its advertised length.
+    fn on_ad_progress(&mut self, _skipped: f64, _remaining: Option<f64>) {}
+    fn on_ad_break_end(&mut self) {}
+    /// An ID3 tag was found in a written segment (`--timed-metadata`).
+    fn on_timed_metadata(&mut self, _tag: &TimedMetadata) {}
+    fn on_error(&mut self, _error: &anyhow::Error) {}
+    fn on_end(&mut self, _reason: &str) {}
+}
+
+/// Passes every event on to each sink in turn.
+impl EventSink for Vec<Box<dyn EventSink>> {
+    fn on_variant_selected(&mut self, variant: &StreamVariant) {
+        for sink in self {
+            sink.on_variant_selected(variant);
+        }
+    }
+
+    fn on_quality_downgrade(&mut self, from: &StreamVariant, to: &StreamVariant) {
+        for sink in self {
+            sink.on_quality_downgrade(from, to);
+        }
+    }
+
+    fn on_segment(&mut self, segment: &SegmentEvent) {
+        for sink in self {
+            sink.on_segment(segment);
+        }
+    }
+
+    fn on_ad_break_start(&mut self, duration: Option<f64>) {
+        for sink in self {
+            sink.on_ad_break_start(duration);
+        }
+    }
+
+    fn on_ad_progress(&mut self, skipped: f64, remaining: Option<f64>) {
+        for sink in self {
+            sink.on_ad_progress(skipped, remaining);
+        }
+    }
+
+    fn on_ad_break_end(&mut self) {
+        for sink in self {
+            sink.on_ad_break_end();
+        }
+    }
+
+    fn on_timed_metadata(&mut self, tag: &TimedMetadata) {
+        for sink in self {
+            sink.on_timed_metadata(tag);
+        }
+    }
+
+    fn on_error(&mut self, error: &anyhow::Error) {
+        for sink in self {
+            sink.on_error(error);
+        }
+    }
+
+    fn on_end(&mut self, reason: &str) {
+        for sink in self {
+            sink.on_end(reason);
+        }
+    }
+}
+
+/// Writes one JSON object per event and line to stderr (`--progress-json`).
+pub struct JsonEvents;
+
+impl JsonEvents {
+    fn emit(&self, event: &str, mut fields: Value) {
+        fields["event"] = event.into();
+        let mut stderr = std::io::stderr().lock();
+        writeln!(stderr, "{fields}").ok();
+    }
+}
+
+impl EventSink for JsonEvents {
+    fn on_variant_selected(&mut self, variant: &StreamVariant) {
+        self.emit(
+            "variant_selected",
+            json!({
+                "label": variant.label,
+                "bandwidth": variant.bandwidth,
+                "url": variant.uri.as_str(),
+            }),
+        );
+    }
+
+    fn on_quality_downgrade(&mut self, from: &StreamVariant, to: &StreamVariant) {
+        self.emit(
+            "quality_downgrade",
+            json!({ "from": from.label, "to": to.label }),
+        );
+    }
+
+    fn on_segment(&mut self, segment: &SegmentEvent) {
+        self.emit(
+            "segment",
+            json!({
+                "sequence": segment.sequence,
+                "duration": segment.duration,
+                "bytes": segment.bytes,
+                "total_bytes": segment.total_bytes,
+                "behind_live": segment.behind_live,
+            }),
+        );
+    }
+
+    fn on_ad_break_start(&mut self, duration: Option<f64>) {
+        self.emit("ad_break_start", json!({ "duration": duration }));
+    }
+
+    fn on_ad_progress(&mut self, skipped: f64, remaining: Option<f64>) {
+        self.emit(
+            "ad_progress",
+            json!({ "skipped": skipped, "remaining": remaining }),
+        );
+    }
+
+    fn on_ad_break_end(&mut self) {
+        self.emit("ad_break_end", json!({}));
+    }
+
+    fn on_timed_metadata(&mut self, tag: &TimedMetadata) {
+        self.emit("timed_metadata", tag.to_json());
+    }
+
+    fn on_error(&mut self, error: &anyhow::Error) {
+        self.emit("error", json!({ "message": format!("{error:#}") }));
+    }
+
+    fn on_end(&mut self, reason: &str) {
+        self.emit("end", json!({ "reason": reason }));
+    }
+}
@@ -0,0 +1,175 @@
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::hls::StreamSummary;
+use crate::{paths, persist, units};
+
+#[cfg(test)]
+mod tests;
+
+/// Downloads of VODs and videos to local files, kept next to the cache so
+/// re-downloading something already archived can be flagged.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct HistoryFile {
+    downloads: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Entry {
+    pub provider: String,
+    pub id: String,
+    pub quality: String,
+    pub path: PathBuf,
+    #[serde(alias = "complete", deserialize_with = "status_or_complete")]
+    pub status: Status,
+    pub bytes: u64,
+    pub updated_at: u64,
+}
+
+/// How far a download got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    /// Still running, or the process died before it ended.
+    Started,
+    /// Ended before the end of the VOD.
+    Partial,
+    Complete,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Started => "started",
+            Status::Partial => "partial",
+            Status::Complete => "complete",
+        }
+    }
+}
+
+/// Reads a [`Status`], or the `complete` flag older histories have instead.
+fn status_or_complete<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Status, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Status(Status),
+        Complete(bool),
+    }
+    Ok(match Stored::deserialize(deserializer)? {
+        Stored::Status(status) => status,
+        Stored::Complete(true) => Status::Complete,
+        Stored::Complete(false) => Status::Partial,
+    })
+}
+
+pub struct History {
+    data: HistoryFile,
+}
+
+impl History {
+    pub fn load() -> Self {
+        let data = fs::read(path())
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+
+        History { data }
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.data.downloads
+    }
+
+    pub fn find(&self, provider: &str, id: &str) -> Option<&Entry> {
+        self.data
+            .downloads
+            .iter()
+            .find(|entry| entry.provider == provider && entry.id == id)
+    }
+}
+
+/// A download to a local file, kept in the history from when it starts to
+/// how it ends.
+pub struct Download {
+    provider: String,
+    id: String,
+    quality: String,
+    path: PathBuf,
+}
+
+impl Download {
+    /// Warns if the same VOD or video was downloaded before, and records the
+    /// new download as started.
+    pub fn start(provider: &str, id: &str, quality: &str, path: &Path) -> Self {
+        if let Some(entry) = History::load().find(provider, id) {
+            let state = match entry.status {
+                Status::Complete => "already downloaded",
+                Status::Started | Status::Partial => "partially downloaded",
+            };
+            warn!(
+                "{provider} {id} was {state} to {} ({})",
+                entry.path.display(),
+                units::format_byte_size(entry.bytes)
+            );
+        }
+        let download = Download {
+            provider: provider.to_string(),
+            id: id.to_string(),
+            quality: quality.to_string(),
+            path: std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf()),
+        };
+        download.record(Status::Started, 0);
+        download
+    }
+
+    /// Records how the download ended.
+    pub fn finish(&self, summary: &StreamSummary) {
+        let status = if summary.is_complete() {
+            Status::Complete
+        } else {
+            Status::Partial
+        };
+        self.record(status, summary.bytes_written);
+    }
+
+    fn record(&self, status: Status, bytes: u64) {
+        let entry = Entry {
+            provider: self.provider.clone(),
+            id: self.id.clone(),
+            quality: self.quality.clone(),
+            path: self.path.clone(),
+            status,
+            bytes,
+            updated_at: now_secs(),
+        };
+        let path = path();
+        if let Err(err) = record(&path, entry) {
+            warn!("Failed to write history to {}: {err:#}", path.display());
+        }
+    }
+}
+
+/// Replaces any earlier entry for the same download in the history at
+/// `path`, keeping what other jobs recorded since it was loaded.
+fn record(path: &Path, entry: Entry) -> Result<()> {
+    persist::update_json(path, |data: &mut HistoryFile| {
+        data.downloads
+            .retain(|other| !(other.provider == entry.provider && other.id == entry.id));
+        data.downloads.push(entry);
+    })
+}
+
+fn path() -> PathBuf {
+    paths::cache_file("history.json")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+
+use super::{Entry, HistoryFile, Status, record};
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fors-history-{}-{name}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn entry(id: &str, status: Status) -> Entry {
+    Entry {
+        provider: "twitch".into(),
+        id: id.into(),
+        quality: "best".into(),
+        path: format!("/videos/{id}.ts").into(),
+        status,
+        bytes: 0,
+        updated_at: 0,
+    }
+}
+
+#[test]
+fn older_histories_with_a_complete_flag_still_load() {
+    let data: HistoryFile = serde_json::from_str(
+        r#"{"downloads": [
+            {"provider": "twitch", "id": "1", "quality": "best", "path": "/a.ts", "complete": true, "bytes": 1, "updated_at": 0},
+            {"provider": "twitch", "id": "2", "quality": "best", "path": "/b.ts", "complete": false, "bytes": 1, "updated_at": 0},
+            {"provider": "twitch", "id": "3", "quality": "best", "path": "/c.ts", "status": "started", "bytes": 0, "updated_at": 0}
+        ]}"#,
+    )
+    .unwrap();
+
+    let statuses: Vec<Status> = data.downloads.iter().map(|entry| entry.status).collect();
+    assert_eq!(
+        statuses,
+        [Status::Complete, Status::Partial, Status::Started]
+    );
+}
+
+#[test]
+fn records_replace_their_download_and_keep_the_others() {
+    let dir = scratch("record");
+    let path = dir.join("history.json");
+
+    record(&path, entry("1", Status::Started)).unwrap();
+    record(&path, entry("2", Status::Started)).unwrap();
+    record(&path, entry("1", Status::Complete)).unwrap();
+
+    let data: HistoryFile = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    let downloads: Vec<(&str, Status)> = data
+        .downloads
+        .iter()
+        .map(|entry| (entry.id.as_str(), entry.status))
+        .collect();
+    assert_eq!(downloads, [("2", Status::Started), ("1", Status::Complete)]);
+    std::fs::remove_dir_all(dir).ok();
+}
@@ -1,589 +1,525 @@
-use anyhow::{Context, Result, bail};
-use log::{debug, info};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
 use reqwest::blocking::Client;
+use serde_json::{Value, json};
 use std::io::Write;
-use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info};
 use url::Url;
 
+mod filler;
+mod id3;
+mod keys;
+mod pipeline;
 #[cfg(test)]
-mod tests;
-pub mod twitch_policy;
-use crate::hls::twitch_policy::TwitchHlsPolicy;
-
-#[derive(Debug, Clone)]
-pub struct StreamVariant {
-    pub label: String,
-    pub aliases: Vec<String>,
-    pub bandwidth: u64,
-    pub resolution: Option<(u64, u64)>,
-    pub frame_rate: Option<f64>,
-    pub uri: Url,
-    pub is_audio_only: bool,
-}
-
-#[derive(Debug)]
-pub struct MediaPlaylist {
-    pub target_duration: f64,
-    pub end_list: bool,
-    pub segments: Vec<MediaSegment>,
-    pub ads_active: bool,
-    pub ad_daterange: Option<(Option<String>, Option<f64>)>,
+pub(crate) mod tests;
+use crate::disk::DiskGuard;
+use crate::events::EventSink;
+use crate::http::Retry;
+use crate::units;
+pub use filler::AdFiller;
+pub use fors_core::playlist::{
+    AudioRendition, IFramePlaylist, MediaPlaylist, MediaSegment, QueryPassthrough, SegmentKey,
+    StreamVariant, parse_audio_renditions, parse_iframe_playlists, parse_master_playlist,
+    parse_media_playlist,
+};
+pub use id3::write as write_timed_metadata;
+use keys::KeyCache;
+use pipeline::{
+    DuplicateFilter, Pacer, Pipeline, PlaylistPoller, Scheduler, SegmentFetcher, TsFixer,
+};
+
+/// Asks the provider whether the broadcast is still live.
+pub type LiveCheck<'a> = Box<dyn FnMut() -> Result<bool> + 'a>;
+
+pub struct StreamOptions<'a> {
+    /// Copies of the media playlist on redundant servers.
+    pub backups: Vec<Url>,
+    pub is_live: bool,
+    pub low_latency: bool,
+    pub debug_ads: bool,
+    pub start_offset: Option<StartOffset>,
+    pub disk_guard: Option<DiskGuard>,
+    /// Written in place of skipped ads.
+    pub ad_filler: Option<AdFiller>,
+    pub pace: Pace,
+    pub ad_resync: AdResync,
+    /// Drop ad segments; VODs keep them unless asked.
+    pub skip_ads: bool,
+    /// Read size for segment downloads.
+    pub buffer_size: usize,
+    /// The first media playlist, if it was requested ahead of time.
+    pub prefetch: Option<PlaylistPrefetch>,
+    /// Fetched instead of the key URIs of encrypted playlists.
+    pub key_uri_override: Option<Url>,
+    /// Drop segments whose content repeats a recent one.
+    pub dedup_content: bool,
+    /// Warn about segments whose size does not match what was announced.
+    pub check_sizes: bool,
+    /// How segment and key URLs treat the media playlist's query.
+    pub segment_query: QueryPassthrough,
+    /// Look for ID3 tags in the segments.
+    pub timed_metadata: bool,
+    /// Confirms the stream really ended before playlist errors end it.
+    pub live_check: Option<LiveCheck<'a>>,
+    pub stop: StopConditions,
 }
 
-#[derive(Debug)]
-pub struct MediaSegment {
-    pub uri: Url,
-    pub init: Option<Url>,
-    pub sequence: u64,
-    pub duration: f64,
-    pub prefetch: bool,
-    pub ad: bool,
-    pub discontinuity: bool,
+/// A media playlist request started in the background, so it overlaps with
+/// opening the output instead of delaying the first segment.
+pub struct PlaylistPrefetch {
+    url: Url,
+    handle: JoinHandle<Result<(Url, String)>>,
 }
 
-pub fn parse_master_playlist(base_url: &Url, body: &str) -> Result<Vec<StreamVariant>> {
-    let mut variants = Vec::new();
-    let mut pending_attrs: Option<Vec<(String, String)>> = None;
-
-    for line in body.lines().map(str::trim) {
-        if line.starts_with("#EXT-X-STREAM-INF:") {
-            let attrs = parse_attribute_line(line.trim_start_matches("#EXT-X-STREAM-INF:"));
-            pending_attrs = Some(attrs);
-            continue;
+impl PlaylistPrefetch {
+    pub fn start(client: &Client, url: &Url) -> Self {
+        let client = client.clone();
+        let request = url.clone();
+        let handle = std::thread::spawn(move || {
+            let response = client.get(request).send()?.error_for_status()?;
+            let url = response.url().clone();
+            Ok((url, response.text()?))
+        });
+        PlaylistPrefetch {
+            url: url.clone(),
+            handle,
         }
+    }
 
-        if let Some(attrs) = pending_attrs.take() {
-            if line.is_empty() || line.starts_with('#') {
-                continue;
-            }
-
-            let uri = resolve_url(base_url, line)
-                .with_context(|| format!("Resolving stream URI from master playlist: {line}"))?;
-
-            let mut bandwidth = 0;
-            let mut resolution = None;
-            let mut frame_rate = None;
-            let mut name = None;
-            let mut audio_only = false;
-
-            for (key, value) in attrs {
-                match key.as_str() {
-                    "BANDWIDTH" => bandwidth = value.parse().unwrap_or(0),
-                    "AVERAGE-BANDWIDTH" if bandwidth == 0 => bandwidth = value.parse().unwrap_or(0),
-                    "RESOLUTION" => resolution = parse_resolution(&value),
-                    "FRAME-RATE" => frame_rate = value.parse().ok(),
-                    "NAME" => name = Some(value),
-                    "VIDEO" if name.is_none() => name = Some(value),
-                    "AUDIO" if value.contains("audio") => audio_only = true,
-                    _ => {}
-                }
+    /// The final URL and body, if the request for `url` succeeded.
+    fn finish(self, url: &Url) -> Option<(Url, String)> {
+        if &self.url != url {
+            return None;
+        }
+        match self.handle.join() {
+            Ok(Ok(response)) => Some(response),
+            Ok(Err(err)) => {
+                debug!("Prefetching the media playlist failed: {err:#}");
+                None
             }
+            Err(_) => None,
+        }
+    }
+}
 
-            if resolution.is_none() && name.as_deref() == Some("audio_only") {
-                audio_only = true;
-            }
+/// How fast segments are written to the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Pace {
+    /// Each segment as soon as it is downloaded
+    #[default]
+    Burst,
+    /// Spread over the segment's duration, for players, restreams and UDP
+    Realtime,
+}
 
-            let (label, mut aliases) =
-                build_labels(name.as_deref(), resolution, frame_rate, audio_only);
-            if bandwidth == 0 && !audio_only {
-                // fall back to rough estimate based on height
-                if let Some((_, h)) = resolution {
-                    bandwidth = h * 1000;
-                }
-            }
+/// Where to pick up a live stream after an ad break.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum AdResync {
+    /// At the first segment after the ads, so no content is lost
+    #[default]
+    Resume,
+    /// Near the live edge, to keep the delay low
+    LiveEdge,
+}
 
-            aliases.sort();
-            aliases.dedup();
-
-            variants.push(StreamVariant {
-                label,
-                aliases,
-                bandwidth,
-                resolution,
-                frame_rate,
-                uri,
-                is_audio_only: audio_only,
-            });
-        }
-    }
+/// Where in the playlist to begin writing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartOffset {
+    /// This far into a VOD.
+    FromStart(Duration),
+    /// This far behind the live edge, as far as the playlist reaches back.
+    BeforeLive(Duration),
+    /// Right after this segment of a live stream, if the playlist still has
+    /// it; otherwise at the live edge.
+    AfterSegment(u64),
+}
 
-    if variants.is_empty() {
-        bail!("No playable variants found in playlist");
+/// Parses `--start-offset`: `-30m` or `-1:30:00` is relative to live, `1h2m`
+/// or `90` counts from the start.
+pub fn parse_start_offset(input: &str) -> Result<StartOffset, String> {
+    let value = input.trim();
+    match value.strip_prefix('-') {
+        Some(back) => units::parse_duration(back).map(StartOffset::BeforeLive),
+        None => units::parse_duration(value).map(StartOffset::FromStart),
     }
-
-    Ok(variants)
 }
 
-pub fn stream_to_writer(
-    client: &Client,
-    media_url: &Url,
-    writer: &mut dyn Write,
-    is_live: bool,
-    low_latency: bool,
-    debug_ads: bool,
-) -> Result<()> {
-    let mut last_sequence: Option<u64> = None;
-    let mut current_url = media_url.clone();
-    let mut consecutive_errors = 0u32;
-    let mut last_init: Option<Url> = None;
-    let mut initial = true;
-    let mut in_ads = false;
-    let mut had_content = false;
-
-    loop {
-        let response = match client.get(current_url.clone()).send() {
-            Ok(resp) => resp,
-            Err(err) => {
-                consecutive_errors += 1;
-                if consecutive_errors >= 3 && had_content {
-                    info!("Stream ended (failed to reload playlist after errors)");
-                    break;
-                }
-                debug!("Failed to fetch media playlist: {err}");
-                std::thread::sleep(Duration::from_millis(750));
-                continue;
-            }
-        };
+/// Limits for unattended runs, checked between segments.
+#[derive(Debug, Default)]
+pub struct StopConditions {
+    pub max_bytes: Option<u64>,
+    pub deadline: Option<SystemTime>,
+    /// When `--quality-schedule` switches to another variant.
+    pub quality_change: Option<SystemTime>,
+    pub handle: Option<StopHandle>,
+}
 
-        if !response.status().is_success() {
-            consecutive_errors += 1;
-            if response.status().as_u16() == 404 && had_content {
-                info!("Stream ended (playlist not found)");
-                break;
-            }
-            if consecutive_errors >= 3 && had_content {
-                info!("Stream ended (playlist unavailable)");
-                break;
-            }
-            debug!(
-                "Media playlist returned status {} - retrying",
-                response.status()
-            );
-            std::thread::sleep(Duration::from_millis(750));
-            continue;
+impl StopConditions {
+    /// Why the stream should stop now, if it should.
+    pub fn reason(&self, bytes_written: u64) -> Option<EndReason> {
+        if self.handle.as_ref().is_some_and(StopHandle::is_stopped) {
+            return Some(EndReason::StopRequested);
         }
-
-        consecutive_errors = 0;
-
-        let playlist_url = response.url().clone();
-        let body = response.text().context("Reading media playlist failed")?;
-        let playlist = match parse_media_playlist(&playlist_url, &body, low_latency, debug_ads) {
-            Ok(pl) => pl,
-            Err(err) => {
-                consecutive_errors += 1;
-                if consecutive_errors >= 3 && had_content {
-                    info!("Stream ended (unreadable playlist)");
-                    break;
-                }
-                debug!("Failed to parse media playlist: {err}");
-                std::thread::sleep(Duration::from_millis(500));
-                continue;
-            }
-        };
-
-        if !in_ads && playlist.ads_active {
-            in_ads = true;
-            if let Some((_, Some(duration))) = &playlist.ad_daterange {
-                info!("Entering ad break ({}s)", duration.ceil() as u64);
-            } else {
-                info!("Entering ad break");
-            }
+        if self.max_bytes.is_some_and(|max| bytes_written >= max) {
+            return Some(EndReason::ByteLimit);
         }
-
-        if in_ads && !playlist.ads_active {
-            in_ads = false;
-            info!("Exiting ad break");
-            if had_content {
-                if let Some(max_seq) = playlist.segments.iter().map(|s| s.sequence).max() {
-                    let live_edge = if low_latency { 2 } else { 3 };
-                    last_sequence = Some(max_seq.saturating_sub(live_edge));
-                } else {
-                    last_sequence = None;
-                }
-                last_init = None;
-            } else {
-                last_sequence = None;
-                last_init = None;
-            }
+        if self.deadline.is_some_and(|at| SystemTime::now() >= at) {
+            return Some(EndReason::StopTime);
         }
-
-        let mut wrote_segment = false;
-
-        // Fast-start: on first load of a live playlist, jump to the latest edge rather than older segments
-        if initial && is_live {
-            if let Some(max_seq) = playlist.segments.iter().map(|s| s.sequence).max() {
-                let live_edge = if low_latency { 2 } else { 3 };
-                last_sequence = Some(max_seq.saturating_sub(live_edge));
-                debug!(
-                    "Starting near live edge at sequence {} (max {})",
-                    last_sequence.unwrap_or(0),
-                    max_seq
-                );
-            }
-            initial = false;
+        if self
+            .quality_change
+            .is_some_and(|at| SystemTime::now() >= at)
+        {
+            return Some(EndReason::QualityChange);
         }
+        None
+    }
+}
 
-        let mut warned_discontinuity = false;
-        for segment in &playlist.segments {
-            if segment.discontinuity && !in_ads {
-                last_sequence = None;
-                last_init = None;
-            }
-
-            if let Some(last) = last_sequence
-                && segment.sequence <= last
-            {
-                continue;
-            }
-
-            if segment.ad {
-                if debug_ads {
-                    info!(
-                        "[ads] skipping ad segment seq={}{} uri={}",
-                        segment.sequence,
-                        if segment.prefetch { " (prefetch)" } else { "" },
-                        segment.uri
-                    );
-                }
-                if !in_ads && segment.discontinuity && !warned_discontinuity {
-                    log::warn!("Encountered a stream discontinuity while filtering ads");
-                    warned_discontinuity = true;
-                }
-                wrote_segment = true;
-                last_sequence = Some(segment.sequence);
-                continue;
-            }
-
-            if let Some(init_url) = &segment.init {
-                let needs_init = last_init
-                    .as_ref()
-                    .map(|url| url != init_url)
-                    .unwrap_or(true);
-                if needs_init {
-                    debug!("Downloading initialization segment {}", init_url);
-                    let mut init_response = client
-                        .get(init_url.clone())
-                        .send()
-                        .with_context(|| format!("Requesting initialization segment {}", init_url))?
-                        .error_for_status()
-                        .with_context(|| {
-                            format!("Initialization segment download failed: {}", init_url)
-                        })?;
-                    std::io::copy(&mut init_response, writer)
-                        .context("Writing initialization segment failed")?;
-                    writer.flush().ok();
-                    last_init = Some(init_url.clone());
-                    had_content = true;
-                    wrote_segment = true;
-                }
-            }
+/// Why `stream_to_writer` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    /// A VOD was written to its end.
+    EndOfVod,
+    /// A live playlist was closed, or a VOD playlist brought nothing new.
+    EndOfPlaylist,
+    /// The media playlist kept failing to load.
+    PlaylistErrors,
+    /// The media playlist is gone (404).
+    PlaylistNotFound,
+    /// The media playlist kept answering with an error status.
+    PlaylistUnavailable,
+    /// The media playlist kept failing to parse.
+    UnreadablePlaylist,
+    StopRequested,
+    ByteLimit,
+    StopTime,
+    /// `--quality-schedule` switches to another variant.
+    QualityChange,
+    /// Free space fell below `--min-free-space`.
+    DiskFull,
+}
 
-            debug!(
-                "Downloading segment {}{}{} ({}s) {}",
-                segment.sequence,
-                if segment.prefetch { " (prefetch)" } else { "" },
-                if segment.discontinuity {
-                    " (discontinuity)"
-                } else {
-                    ""
-                },
-                segment.duration,
-                segment.uri
-            );
-            let mut segment_response = client
-                .get(segment.uri.clone())
-                .send()
-                .with_context(|| format!("Requesting segment {}", segment.uri))?
-                .error_for_status()
-                .with_context(|| format!("Segment download failed: {}", segment.uri))?;
-
-            std::io::copy(&mut segment_response, writer)
-                .context("Writing segment to output failed")?;
-            writer.flush().ok();
-            if debug_ads {
-                info!(
-                    "[ads] advanced to sequence {}{}",
-                    segment.sequence,
-                    if segment.prefetch { " (prefetch)" } else { "" }
-                );
-            }
-            last_sequence = Some(segment.sequence);
-            if !had_content {
-                had_content = true;
-            }
-            if !wrote_segment {
-                wrote_segment = true;
-            }
+impl EndReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EndReason::EndOfVod => "end of VOD",
+            EndReason::EndOfPlaylist => "end of playlist",
+            EndReason::PlaylistErrors => "playlist errors",
+            EndReason::PlaylistNotFound => "playlist not found",
+            EndReason::PlaylistUnavailable => "playlist unavailable",
+            EndReason::UnreadablePlaylist => "unreadable playlist",
+            EndReason::StopRequested => "stop requested",
+            EndReason::ByteLimit => "byte limit reached",
+            EndReason::StopTime => "stop time reached",
+            EndReason::QualityChange => "scheduled quality change",
+            EndReason::DiskFull => "low disk space",
         }
+    }
+}
 
-        if playlist.end_list && !is_live {
-            info!("End of VOD reached");
-            break;
-        }
+impl std::fmt::Display for EndReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
 
-        if !is_live && !wrote_segment {
-            break;
-        }
+/// Lets other threads end a running `stream_to_writer` cleanly. The stream
+/// stops before the next playlist reload or after the current segment.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    flag: Arc<AtomicBool>,
+    /// Stopping the handle this was made from stops this one too.
+    parent: Option<Arc<AtomicBool>>,
+}
 
-        current_url = playlist_url;
-        let last_real_duration = playlist
-            .segments
-            .iter()
-            .rev()
-            .find(|s| s.duration > 0.0)
-            .map(|s| s.duration);
-        let reload = if in_ads {
-            0.5
-        } else if low_latency {
-            last_real_duration.unwrap_or(playlist.target_duration)
-        } else {
-            playlist.target_duration * 0.75
-        };
-        if debug_ads {
-            info!("[ads] polling every {:.3}s (ads_active={})", reload, in_ads);
+impl StopHandle {
+    /// A handle that can be stopped on its own or along with this one.
+    pub fn child(&self) -> StopHandle {
+        StopHandle {
+            flag: Arc::default(),
+            parent: Some(self.flag.clone()),
         }
-        let sleep_ms = (reload * 1000.0) as u64;
-        std::thread::sleep(Duration::from_millis(sleep_ms));
     }
 
-    Ok(())
-}
+    pub fn stop(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
 
-fn parse_media_playlist(
-    base_url: &Url,
-    body: &str,
-    low_latency: bool,
-    debug_ads: bool,
-) -> Result<MediaPlaylist> {
-    let mut target_duration = 4.0;
-    let mut media_sequence: u64 = 0;
-    let mut end_list = false;
-    let mut segments = Vec::new();
-    let mut pending_duration: Option<f64> = None;
-    let mut pending_title: Option<String> = None;
-    let mut last_duration: Option<f64> = None;
-    let mut discontinuity_next = false;
-    let mut current_init: Option<Url> = None;
-    let mut policy = TwitchHlsPolicy::new();
-
-    for line in body.lines().map(str::trim) {
-        if line.starts_with("#EXT-X-TARGETDURATION:") {
-            if let Some(value) = line.split_once(':').map(|(_, v)| v)
-                && let Ok(parsed) = value.parse::<f64>()
-            {
-                target_duration = parsed;
-            }
-        } else if line.starts_with("#EXT-X-MEDIA-SEQUENCE:") {
-            if let Some(value) = line.split_once(':').map(|(_, v)| v)
-                && let Ok(parsed) = value.parse::<u64>()
-            {
-                media_sequence = parsed;
-            }
-        } else if line.starts_with("#EXTINF:") {
-            let value = line.trim_start_matches("#EXTINF:");
-            let mut parts = value.splitn(2, ',');
-            if let Some(duration_part) = parts.next() {
-                pending_duration = duration_part.parse::<f64>().ok();
-            }
-            pending_title = parts
-                .next()
-                .map(|s| s.trim().to_string())
-                .filter(|s| !s.is_empty());
-            last_duration = pending_duration;
-        } else if line.starts_with("#EXT-X-DISCONTINUITY") {
-            discontinuity_next = true;
-        } else if line.starts_with("#EXT-X-TWITCH-PREFETCH:") {
-            if !low_latency {
-                continue;
-            }
-            let uri = resolve_url(base_url, line.trim_start_matches("#EXT-X-TWITCH-PREFETCH:"))
-                .with_context(|| format!("Resolving prefetch segment URL: {line}"))?;
-            let sequence = media_sequence + segments.len() as u64;
-            let duration = last_duration.unwrap_or(target_duration);
-            let ad_flag = policy.classify_segment(&uri, None, true);
-            if debug_ads {
-                info!(
-                    "[ads] segment={} classified={} prefetch=true",
-                    sequence,
-                    if ad_flag { "AD" } else { "CONTENT" }
-                );
-            }
-            segments.push(MediaSegment {
-                uri,
-                init: current_init.clone(),
-                sequence,
-                duration: if ad_flag { 0.0 } else { duration },
-                prefetch: true,
-                ad: ad_flag,
-                discontinuity: discontinuity_next,
-            });
-            if discontinuity_next {
-                discontinuity_next = false;
-            }
-            continue;
-        } else if line.starts_with("#EXT-X-DATERANGE:") {
-            let attrs = parse_attribute_line(line.trim_start_matches("#EXT-X-DATERANGE:"));
-            policy.on_daterange(&attrs);
-            if debug_ads && let Some((id, duration)) = policy.last_daterange.clone() {
-                match duration {
-                    Some(d) => info!(
-                        "[ads] playlist contains stitched ad daterange id={} duration={:.0}",
-                        id.unwrap_or_else(|| "unknown".into()),
-                        d
-                    ),
-                    None => info!(
-                        "[ads] playlist contains stitched ad daterange id={} duration=unknown",
-                        id.unwrap_or_else(|| "unknown".into()),
-                    ),
-                }
-            }
-        } else if line.starts_with("#EXT-X-ENDLIST") {
-            end_list = true;
-        } else if line.starts_with("#EXT-X-MAP:") {
-            let attrs = parse_attribute_line(line.trim_start_matches("#EXT-X-MAP:"));
-            if let Some((_, uri_value)) = attrs.iter().find(|(k, _)| k == "URI") {
-                let map_url = resolve_url(base_url, uri_value)
-                    .with_context(|| format!("Resolving init segment URL: {uri_value}"))?;
-                current_init = Some(map_url);
-            }
-        } else if line.starts_with('#') {
-            continue;
-        } else if let Some(duration) = pending_duration.take() {
-            let uri = resolve_url(base_url, line)
-                .with_context(|| format!("Resolving segment URL: {line}"))?;
-            let sequence = media_sequence + segments.len() as u64;
-            let title = pending_title.take();
-            let ad_flag = policy.classify_segment(&uri, title.as_deref(), false);
-            if debug_ads {
-                info!(
-                    "[ads] segment={} classified={} prefetch=false",
-                    sequence,
-                    if ad_flag { "AD" } else { "CONTENT" }
-                );
-            }
-            segments.push(MediaSegment {
-                uri,
-                init: current_init.clone(),
-                sequence,
-                duration: if ad_flag { 0.0 } else { duration },
-                prefetch: false,
-                ad: ad_flag,
-                discontinuity: discontinuity_next,
-            });
-            if discontinuity_next {
-                discontinuity_next = false;
+    pub fn is_stopped(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.load(Ordering::Relaxed))
+    }
+
+    /// Sleeps for `duration` unless a stop is requested first. Returns
+    /// whether the full time passed.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let until = Instant::now() + duration;
+        while !self.is_stopped() {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
             }
+            std::thread::sleep(left.min(Duration::from_millis(250)));
         }
+        false
     }
 
-    if segments.is_empty() {
-        bail!("No segments found in media playlist");
+    /// Requests a stop on the first SIGINT/SIGTERM. A second one terminates
+    /// the process immediately.
+    #[cfg(unix)]
+    pub fn stop_on_signal(&self) -> Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals =
+            Signals::new([SIGINT, SIGTERM]).context("Failed to install signal handler")?;
+        let handle = self.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if handle.is_stopped() {
+                    std::process::exit(128 + signal);
+                }
+                info!("Stopping after the current segment (interrupt again to quit)");
+                handle.stop();
+            }
+        });
+        Ok(())
     }
 
-    let ads_active = segments.iter().any(|s| s.ad);
+    #[cfg(not(unix))]
+    pub fn stop_on_signal(&self) -> Result<()> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+        use signal_hook::flag;
 
-    Ok(MediaPlaylist {
-        target_duration,
-        end_list,
-        segments,
-        ads_active,
-        ad_daterange: policy.last_daterange,
-    })
+        for signal in [SIGINT, SIGTERM] {
+            flag::register_conditional_shutdown(signal, 1, self.flag.clone())
+                .context("Failed to install signal handler")?;
+            flag::register(signal, self.flag.clone())
+                .context("Failed to install signal handler")?;
+        }
+        Ok(())
+    }
 }
 
-fn resolve_url(base: &Url, input: &str) -> Result<Url> {
-    if let Ok(url) = Url::parse(input) {
-        return Ok(url);
-    }
+/// What a finished `stream_to_writer` call did.
+#[derive(Debug)]
+pub struct StreamSummary {
+    pub bytes_written: u64,
+    pub elapsed: Duration,
+    pub segments: u64,
+    /// Seconds of media written, including ad filler.
+    pub output_time: f64,
+    /// Seconds of ad segments that were skipped.
+    pub ad_time: f64,
+    /// Where ads were cut out of the output, in order.
+    pub ad_gaps: Vec<AdGap>,
+    /// Where each media segment starts in the output, in order.
+    pub seek_points: Vec<SeekPoint>,
+    /// ID3 tags found in the segments, with `--timed-metadata`.
+    pub timed_metadata: Vec<TimedMetadata>,
+    pub end_reason: EndReason,
+}
 
-    base.join(input).context("Failed to resolve relative URL")
+/// A run of skipped ad segments.
+#[derive(Debug, Clone)]
+pub struct AdGap {
+    /// Seconds of media written before the gap.
+    pub output_time: f64,
+    /// Seconds of media skipped, including earlier gaps, before this one.
+    pub skipped_before: f64,
+    /// Seconds of ads skipped in this gap.
+    pub duration: f64,
+    pub started_at: SystemTime,
+    pub ended_at: SystemTime,
 }
 
-fn parse_attribute_line(value: &str) -> Vec<(String, String)> {
-    let mut pairs = Vec::new();
-    let mut current = String::new();
-    let mut in_quotes = false;
-
-    for ch in value.chars() {
-        match ch {
-            ',' if !in_quotes => {
-                if !current.is_empty() {
-                    pairs.push(current.trim().to_string());
-                    current.clear();
-                }
-            }
-            '"' => {
-                in_quotes = !in_quotes;
-                current.push(ch);
-            }
-            _ => current.push(ch),
-        }
-    }
+/// Where a media segment starts in the output.
+#[derive(Debug, Clone, Copy)]
+pub struct SeekPoint {
+    /// Seconds of media written before the segment.
+    pub output_time: f64,
+    /// Bytes written before the segment.
+    pub offset: u64,
+    pub sequence: u64,
+}
 
-    if !current.is_empty() {
-        pairs.push(current.trim().to_string());
-    }
+/// An ID3 tag embedded in the stream.
+#[derive(Debug, Clone)]
+pub struct TimedMetadata {
+    /// Seconds of media written before the tag.
+    pub output_time: f64,
+    /// The segment the tag is in.
+    pub sequence: u64,
+    pub frames: Vec<id3::Frame>,
+}
 
-    pairs
-        .into_iter()
-        .filter_map(|pair| {
-            pair.split_once('=').map(|(k, v)| {
-                let val = v.trim().trim_matches('"').to_string();
-                (k.trim().to_string(), val)
+impl TimedMetadata {
+    pub fn to_json(&self) -> Value {
+        let frames: Vec<_> = self
+            .frames
+            .iter()
+            .map(|frame| {
+                json!({
+                    "id": frame.id,
+                    "description": frame.description,
+                    "value": frame.value,
+                })
             })
+            .collect();
+        json!({
+            "output_time": self.output_time,
+            "sequence": self.sequence,
+            "frames": frames,
         })
-        .collect()
+    }
 }
 
-fn parse_resolution(value: &str) -> Option<(u64, u64)> {
-    let (w, h) = value.split_once('x')?;
-    let width = w.parse().ok()?;
-    let height = h.parse().ok()?;
-    Some((width, height))
-}
+impl StreamSummary {
+    /// Whether the whole VOD was written, rather than being cut short.
+    pub fn is_complete(&self) -> bool {
+        self.end_reason == EndReason::EndOfVod
+    }
 
-fn build_labels(
-    name: Option<&str>,
-    resolution: Option<(u64, u64)>,
-    frame_rate: Option<f64>,
-    audio_only: bool,
-) -> (String, Vec<String>) {
-    let mut aliases = Vec::new();
+    /// Whether the media playlist went away, as it does when a live stream
+    /// ends or drops.
+    pub fn stream_dropped(&self) -> bool {
+        matches!(
+            self.end_reason,
+            EndReason::PlaylistErrors
+                | EndReason::PlaylistNotFound
+                | EndReason::PlaylistUnavailable
+                | EndReason::UnreadablePlaylist
+        )
+    }
 
-    if let Some(name) = name {
-        aliases.push(name.to_lowercase());
+    /// Whether the stream stopped to switch to the quality `--quality-schedule`
+    /// asks for.
+    pub fn quality_change_due(&self) -> bool {
+        self.end_reason == EndReason::QualityChange
     }
 
-    let resolution_label = resolution.map(|(_, height)| {
-        let suffix = if frame_rate.map(|fr| fr >= 59.5).unwrap_or(false) {
-            "60"
-        } else {
-            ""
-        };
-        let label = format!("{height}p{suffix}");
-        aliases.push(label.to_lowercase());
-        label
-    });
-
-    if audio_only {
-        aliases.push("audio_only".into());
-        aliases.push("audio".into());
+    /// Adds a later stream written to the same output.
+    pub fn extend(&mut self, next: StreamSummary) {
+        self.elapsed += next.elapsed;
+        self.segments += next.segments;
+        self.ad_gaps
+            .extend(next.ad_gaps.into_iter().map(|gap| AdGap {
+                output_time: gap.output_time + self.output_time,
+                skipped_before: gap.skipped_before + self.ad_time,
+                ..gap
+            }));
+        self.seek_points
+            .extend(next.seek_points.into_iter().map(|point| SeekPoint {
+                output_time: point.output_time + self.output_time,
+                offset: point.offset + self.bytes_written,
+                ..point
+            }));
+        self.timed_metadata
+            .extend(next.timed_metadata.into_iter().map(|tag| TimedMetadata {
+                output_time: tag.output_time + self.output_time,
+                ..tag
+            }));
+        self.bytes_written += next.bytes_written;
+        self.output_time += next.output_time;
+        self.ad_time += next.ad_time;
+        self.end_reason = next.end_reason;
     }
+}
 
-    let primary = name
-        .map(|n| n.to_string())
-        .or(resolution_label)
-        .unwrap_or_else(|| {
-            if audio_only {
-                "audio_only".into()
-            } else {
-                "unknown".into()
-            }
-        });
+/// Streams the media playlist at `media_url` into `writer` until the stream
+/// ends or a stop condition is met, reporting progress to `events`.
+pub fn stream_to_writer(
+    client: &Client,
+    media_url: &Url,
+    writer: &mut dyn Write,
+    options: StreamOptions<'_>,
+    events: &mut dyn EventSink,
+) -> Result<StreamSummary> {
+    match stream(client, media_url, writer, options, events) {
+        Ok(summary) => {
+            events.on_end(summary.end_reason.as_str());
+            Ok(summary)
+        }
+        Err(err) => {
+            events.on_error(&err);
+            Err(err)
+        }
+    }
+}
 
-    aliases.push(primary.to_lowercase());
-    aliases.sort();
-    aliases.dedup();
+fn stream(
+    client: &Client,
+    media_url: &Url,
+    writer: &mut dyn Write,
+    options: StreamOptions<'_>,
+    events: &mut dyn EventSink,
+) -> Result<StreamSummary> {
+    let StreamOptions {
+        backups,
+        is_live,
+        low_latency,
+        debug_ads,
+        start_offset,
+        disk_guard,
+        ad_filler,
+        pace,
+        ad_resync,
+        skip_ads,
+        buffer_size,
+        prefetch,
+        key_uri_override,
+        dedup_content,
+        check_sizes,
+        segment_query,
+        timed_metadata,
+        live_check,
+        stop,
+    } = options;
+
+    Pipeline {
+        poller: PlaylistPoller::new(client, media_url.clone(), low_latency, debug_ads)
+            .with_prefetch(prefetch)
+            .with_segment_query(segment_query)
+            .with_backups(backups)
+            .with_live_check(live_check)
+            .with_stop(stop.handle.clone()),
+        scheduler: Scheduler::new(is_live, low_latency, debug_ads, start_offset)
+            .with_ad_resync(ad_resync)
+            .with_skip_ads(skip_ads),
+        fetcher: SegmentFetcher::new(client, buffer_size, KeyCache::new(key_uri_override))
+            .with_size_checks(check_sizes),
+        filters: if dedup_content {
+            vec![Box::new(TsFixer), Box::new(DuplicateFilter::default())]
+        } else {
+            vec![Box::new(TsFixer)]
+        },
+        sink: writer,
+        is_live,
+        disk_guard,
+        ad_filler,
+        pacer: (pace == Pace::Realtime).then(Pacer::default),
+        timed_metadata,
+        stop,
+    }
+    .run(events)
+}
 
-    (primary, aliases)
+/// Fetches and parses a media playlist once, outside of the streaming loop.
+pub fn fetch_media_playlist(client: &Client, url: &Url) -> Result<MediaPlaylist> {
+    let response = Retry::PLAYLIST
+        .send(client.get(url.clone()))
+        .context("Failed to request media playlist")?
+        .error_for_status()
+        .context("Media playlist request failed")?;
+    let playlist_url = response.url().clone();
+    let body = response.text().context("Reading media playlist failed")?;
+    parse_media_playlist(&playlist_url, &body, QueryPassthrough::Off, false, false)
 }
@@ -0,0 +1,88 @@
+use anyhow::{Context, Result, bail};
+use std::fs;
+use std::path::Path;
+
+use super::pipeline::TS_PACKET_SIZE;
+
+/// A transport stream clip written in place of skipped ad segments
+/// (`--ad-filler`), so downstream encoders and restreams keep getting video.
+///
+/// The clip is repeated as is, so it should use the same codecs and
+/// resolution as the stream it fills in for.
+#[derive(Clone)]
+pub struct AdFiller {
+    data: Vec<u8>,
+    duration: f64,
+    /// Seconds of ads not yet covered by the clip. Goes negative when the
+    /// last repetition overran, which the next gap makes up for.
+    owed: f64,
+}
+
+impl AdFiller {
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path)
+            .with_context(|| format!("Failed to read ad filler {}", path.display()))?;
+        let Some(duration) = ts_duration(&data) else {
+            bail!(
+                "Ad filler {} is not an MPEG-TS clip with timestamps",
+                path.display()
+            );
+        };
+        Ok(AdFiller {
+            data,
+            duration,
+            owed: 0.0,
+        })
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.duration
+    }
+
+    pub fn clip(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// How many times to repeat the clip to stand in for `ad_duration`
+    /// seconds of ads.
+    pub fn repeats_for(&mut self, ad_duration: f64) -> usize {
+        self.owed += ad_duration;
+        let mut repeats = 0;
+        while self.owed > 0.0 {
+            self.owed -= self.duration;
+            repeats += 1;
+        }
+        repeats
+    }
+}
+
+/// Duration of an MPEG-TS clip from the span of its PES timestamps.
+fn ts_duration(data: &[u8]) -> Option<f64> {
+    let mut first: Option<u64> = None;
+    let mut last: Option<u64> = None;
+    for packet in data.chunks_exact(TS_PACKET_SIZE) {
+        if packet[0] != 0x47 || packet[1] & 0x40 == 0 {
+            continue;
+        }
+        let payload = match (packet[3] >> 4) & 0x3 {
+            0x1 => 4,
+            0x3 => 5 + packet[4] as usize,
+            _ => continue,
+        };
+        let Some(pes) = packet.get(payload..).filter(|pes| pes.len() >= 14) else {
+            continue;
+        };
+        if pes[..3] != [0, 0, 1] || pes[7] & 0x80 == 0 {
+            continue;
+        }
+        let pts = ((pes[9] as u64 >> 1) & 0x7) << 30
+            | (pes[10] as u64) << 22
+            | (pes[11] as u64 >> 1) << 15
+            | (pes[12] as u64) << 7
+            | pes[13] as u64 >> 1;
+        first = Some(first.map_or(pts, |first| first.min(pts)));
+        last = Some(last.map_or(pts, |last| last.max(pts)));
+    }
+    let span = last? - first?;
+    (span > 0).then(|| span as f64 / 90_000.0)
+}
@@ -0,0 +1,254 @@
+//! ID3 timed metadata (`--timed-metadata`): now-playing titles, markers and
+//! the like that streams embed in their segments, either as a metadata
+//! stream in MPEG-TS or as a tag in front of packed audio.
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::TimedMetadata;
+use crate::output::ts::Demuxer;
+
+/// MPEG-TS stream type of metadata carried in PES packets.
+const STREAM_TYPE_METADATA: u8 = 0x15;
+/// The PRIV frame that maps packed audio to MPEG-TS timestamps.
+const TIMESTAMP_OWNER: &str = "com.apple.streaming.transportStreamTimestamp";
+
+/// One frame of an ID3 tag, e.g. `TIT2` with the title.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub id: String,
+    /// The description of `TXXX`/`WXXX`/`COMM` frames, the owner of `PRIV`.
+    pub description: Option<String>,
+    pub value: String,
+}
+
+/// The tags in one media segment, each with its offset in seconds from the
+/// start of the segment.
+pub fn segment_tags(data: &[u8]) -> Vec<(f64, Vec<Frame>)> {
+    // Packed audio starts with the tag that gives its timestamp.
+    if data.starts_with(b"ID3") {
+        return parse_tag(data)
+            .map(|(frames, _)| frames)
+            .filter(|frames| !frames.is_empty())
+            .map(|frames| vec![(0.0, frames)])
+            .unwrap_or_default();
+    }
+
+    // Every segment starts with its own PAT and PMT.
+    let mut first_pts = None;
+    let mut tags = Vec::new();
+    for pes in Demuxer::all(data) {
+        if pes.stream_type != STREAM_TYPE_METADATA {
+            if let Some(pts) = pes.pts {
+                first_pts = Some(first_pts.map_or(pts, |first: u64| first.min(pts)));
+            }
+            continue;
+        }
+        let mut rest = pes.data.as_slice();
+        while let Some((frames, length)) = parse_tag(rest) {
+            if !frames.is_empty() {
+                tags.push((pes.pts, frames));
+            }
+            rest = &rest[length..];
+        }
+    }
+    tags.into_iter()
+        .map(|(pts, frames)| {
+            let offset = match (pts, first_pts) {
+                (Some(pts), Some(first)) => pts.saturating_sub(first) as f64 / 90_000.0,
+                _ => 0.0,
+            };
+            (offset, frames)
+        })
+        .collect()
+}
+
+/// The frames of the ID3v2.3/2.4 tag at the start of `data` and the tag's
+/// length.
+fn parse_tag(data: &[u8]) -> Option<(Vec<Frame>, usize)> {
+    let header = data.get(..10)?;
+    if &header[..3] != b"ID3" || !matches!(header[3], 3 | 4) {
+        return None;
+    }
+    let version = header[3];
+    let flags = header[5];
+    let length = 10 + syncsafe(&header[6..10]);
+    let footer = if version == 4 && flags & 0x10 != 0 {
+        10
+    } else {
+        0
+    };
+    // A tag cut off by the end of the segment is left out.
+    if length + footer > data.len() {
+        return None;
+    }
+    let body = &data[10..length];
+
+    let mut pos = 0;
+    if flags & 0x40 != 0 {
+        // Skip the extended header, whose size in 2.3 leaves itself out.
+        let size = body.get(..4)?;
+        pos = if version == 4 {
+            syncsafe(size)
+        } else {
+            4 + u32::from_be_bytes(size.try_into().ok()?) as usize
+        };
+    }
+
+    let mut frames = Vec::new();
+    while let Some(header) = body.get(pos..pos + 10) {
+        // Padding.
+        if header[0] == 0 {
+            break;
+        }
+        let id = String::from_utf8_lossy(&header[..4]).into_owned();
+        let size = if version == 4 {
+            syncsafe(&header[4..8])
+        } else {
+            u32::from_be_bytes(header[4..8].try_into().ok()?) as usize
+        };
+        let Some(content) = body.get(pos + 10..pos + 10 + size) else {
+            break;
+        };
+        if let Some(frame) = parse_frame(id, content) {
+            frames.push(frame);
+        }
+        pos += 10 + size;
+    }
+    Some((frames, length + footer))
+}
+
+fn parse_frame(id: String, content: &[u8]) -> Option<Frame> {
+    let (description, value) = match id.as_str() {
+        "PRIV" => {
+            let (owner, data) = split_terminated(content, 0);
+            let owner = latin1(owner);
+            // Only there to line up packed audio, which fors leaves as is.
+            if owner == TIMESTAMP_OWNER {
+                return None;
+            }
+            (Some(owner), binary(data))
+        }
+        "TXXX" | "WXXX" => {
+            let (&encoding, rest) = content.split_first()?;
+            let (description, value) = split_terminated(rest, encoding);
+            let value = if id == "WXXX" {
+                latin1(value)
+            } else {
+                text(value, encoding)
+            };
+            (Some(text(description, encoding)), value)
+        }
+        "COMM" | "USLT" => {
+            let (&encoding, rest) = content.split_first()?;
+            let (description, value) = split_terminated(rest.get(3..)?, encoding);
+            (Some(text(description, encoding)), text(value, encoding))
+        }
+        _ if id.starts_with('T') => {
+            let (&encoding, rest) = content.split_first()?;
+            (None, text(rest, encoding))
+        }
+        _ if id.starts_with('W') => (None, latin1(content)),
+        _ => (None, binary(content)),
+    };
+    Some(Frame {
+        id,
+        description: description.filter(|description| !description.is_empty()),
+        value,
+    })
+}
+
+/// Splits at the first string terminator of the text encoding, which is two
+/// bytes wide in UTF-16.
+fn split_terminated(data: &[u8], encoding: u8) -> (&[u8], &[u8]) {
+    if matches!(encoding, 1 | 2) {
+        let end = data
+            .chunks_exact(2)
+            .position(|pair| pair == [0, 0])
+            .map(|pair| pair * 2);
+        match end {
+            Some(end) => (&data[..end], &data[end + 2..]),
+            None => (data, &[]),
+        }
+    } else {
+        match data.iter().position(|&b| b == 0) {
+            Some(end) => (&data[..end], &data[end + 1..]),
+            None => (data, &[]),
+        }
+    }
+}
+
+/// Decodes a text field. ID3v2.4 separates multiple values with a
+/// terminator.
+fn text(data: &[u8], encoding: u8) -> String {
+    let mut values = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (value, next) = split_terminated(rest, encoding);
+        values.push(match encoding {
+            0 => latin1(value),
+            1 | 2 => utf16(value, encoding == 2),
+            _ => String::from_utf8_lossy(value).into_owned(),
+        });
+        rest = next;
+    }
+    values.retain(|value| !value.is_empty());
+    values.join(" / ")
+}
+
+fn latin1(data: &[u8]) -> String {
+    data.iter().map(|&b| char::from(b)).collect()
+}
+
+/// UTF-16 with a byte order mark, or big endian without one.
+fn utf16(data: &[u8], big_endian: bool) -> String {
+    let (big_endian, data) = match data {
+        [0xff, 0xfe, rest @ ..] => (false, rest),
+        [0xfe, 0xff, rest @ ..] => (true, rest),
+        _ => (big_endian, data),
+    };
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|pair| {
+            if big_endian {
+                u16::from_be_bytes([pair[0], pair[1]])
+            } else {
+                u16::from_le_bytes([pair[0], pair[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Binary frame data as text when it is text, else just its size.
+fn binary(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.chars().any(char::is_control) => text.to_string(),
+        _ => format!("<{} bytes>", data.len()),
+    }
+}
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |size, &b| (size << 7) | usize::from(b & 0x7f))
+}
+
+/// Where the sidecar for `output` goes.
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".id3.json");
+    PathBuf::from(name)
+}
+
+/// Lists the tags of a recording next to it.
+pub fn write(output: &Path, tags: &[TimedMetadata]) -> Result<PathBuf> {
+    let path = sidecar_path(output);
+    let tags: Vec<_> = tags.iter().map(TimedMetadata::to_json).collect();
+    let contents = serde_json::to_string_pretty(&json!({ "timed_metadata": tags }))?;
+    fs::write(&path, contents)
+        .with_context(|| format!("Failed to write timed metadata to {}", path.display()))?;
+    Ok(path)
+}
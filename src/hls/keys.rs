@@ -0,0 +1,78 @@
+//! AES-128 segment decryption. Keys usually stay the same for many segments
+//! and rotate now and then, so they are fetched once per URI.
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockDecryptMut, KeyIvInit};
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::blocking::Client;
+use std::collections::VecDeque;
+use tracing::debug;
+use url::Url;
+
+use super::SegmentKey;
+use crate::http::Retry;
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// Keys kept around, enough for a playlist that rotates keys often.
+const CACHED_KEYS: usize = 16;
+
+pub struct KeyCache {
+    /// Fetched instead of every key URI in the playlist (`--hls-key-uri-override`).
+    uri_override: Option<Url>,
+    keys: VecDeque<(Url, [u8; 16])>,
+}
+
+impl KeyCache {
+    pub fn new(uri_override: Option<Url>) -> Self {
+        KeyCache {
+            uri_override,
+            keys: VecDeque::new(),
+        }
+    }
+
+    /// Decrypts a segment of sequence number `sequence` in place.
+    pub fn decrypt(
+        &mut self,
+        client: &Client,
+        key: &SegmentKey,
+        sequence: u64,
+        data: &mut Vec<u8>,
+    ) -> Result<()> {
+        let secret = self.get(client, &key.uri)?;
+        let length = Aes128CbcDec::new(&secret.into(), &key.iv_for(sequence).into())
+            .decrypt_padded_mut::<Pkcs7>(data)
+            .map_err(|_| anyhow!("Failed to decrypt segment {sequence}: wrong key or padding"))?
+            .len();
+        data.truncate(length);
+        Ok(())
+    }
+
+    fn get(&mut self, client: &Client, uri: &Url) -> Result<[u8; 16]> {
+        if let Some((_, secret)) = self.keys.iter().find(|(known, _)| known == uri) {
+            return Ok(*secret);
+        }
+
+        let url = self.uri_override.as_ref().unwrap_or(uri);
+        debug!("Fetching decryption key {url}");
+        let body = Retry::SEGMENT
+            .send(client.get(url.clone()))
+            .with_context(|| format!("Requesting decryption key {url}"))?
+            .error_for_status()
+            .with_context(|| format!("Download of decryption key failed: {url}"))?
+            .bytes()
+            .with_context(|| format!("Reading decryption key failed: {url}"))?;
+        let Ok(secret) = <[u8; 16]>::try_from(body.as_ref()) else {
+            bail!(
+                "Decryption key {url} is {} bytes instead of 16; try --hls-key-uri-override",
+                body.len()
+            );
+        };
+
+        if self.keys.len() == CACHED_KEYS {
+            self.keys.pop_front();
+        }
+        self.keys.push_back((uri.clone(), secret));
+        Ok(secret)
+    }
+}
@@ -0,0 +1,1169 @@
+//! The stages `stream_to_writer` is assembled from:
+//!
+//! playlist poller → segment scheduler (ad filter) → segment fetcher →
+//! filters → sink
+//!
+//! Each stage only knows about its own state. New processing such as
+//! remuxing or timestamp repair is added as a [`Filter`].
+
+use anyhow::{Context, Result, anyhow};
+use reqwest::StatusCode;
+use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT_ENCODING, RANGE};
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{Span, debug, field, info, trace_span, warn};
+use url::Url;
+
+use super::keys::KeyCache;
+use super::{
+    AdFiller, AdGap, AdResync, EndReason, LiveCheck, MediaPlaylist, MediaSegment, PlaylistPrefetch,
+    QueryPassthrough, SeekPoint, StartOffset, StopConditions, StopHandle, StreamSummary,
+    TimedMetadata, id3, parse_media_playlist,
+};
+use crate::disk::DiskGuard;
+use crate::events::{EventSink, SegmentEvent};
+use crate::http::{Backoff, Retry};
+use crate::systemd;
+
+pub(super) const TS_PACKET_SIZE: usize = 188;
+/// How often `--pace realtime` writes a piece of the current segment.
+const PACE_SLICES_PER_SECOND: f64 = 10.0;
+/// Seconds the paced output may lag before the clock is reset.
+const MAX_PACE_LAG: f64 = 5.0;
+/// Times in a row the provider may vouch for a broken playlist before the
+/// stream is ended anyway, e.g. because its playlist URL expired.
+const MAX_CONFIRMED_OUTAGES: u32 = 3;
+/// Recently written segments remembered to catch repeats.
+const RECENT_SEGMENTS: usize = 64;
+/// Leading TS packets hashed to recognize a segment's content.
+const FINGERPRINT_PACKETS: usize = 16;
+
+/// Result of one media playlist reload.
+pub enum Poll {
+    Playlist(MediaPlaylist),
+    /// The reload failed but may succeed after waiting.
+    Retry(Duration),
+    End(EndReason),
+}
+
+/// Reloads the media playlist, following redirects and tolerating a few
+/// consecutive failures once content has been received.
+pub struct PlaylistPoller<'a> {
+    client: &'a Client,
+    url: Url,
+    low_latency: bool,
+    debug_ads: bool,
+    segment_query: QueryPassthrough,
+    errors: Backoff,
+    /// `_HLS_msn`/`_HLS_part` for the next blocking reload.
+    blocking_reload: Option<(u64, Option<u64>)>,
+    prefetch: Option<PlaylistPrefetch>,
+    live_check: Option<LiveCheck<'a>>,
+    confirmed_outages: u32,
+    /// Redundant copies of the playlist, the next one to try first.
+    backups: VecDeque<Url>,
+    /// Backups switched to since a playlist last loaded.
+    failovers: usize,
+    /// Cuts short waits for a rate limited server.
+    stop: Option<StopHandle>,
+}
+
+impl<'a> PlaylistPoller<'a> {
+    pub fn new(client: &'a Client, url: Url, low_latency: bool, debug_ads: bool) -> Self {
+        PlaylistPoller {
+            client,
+            url,
+            low_latency,
+            debug_ads,
+            segment_query: QueryPassthrough::Off,
+            errors: Backoff::new(Retry::PLAYLIST),
+            blocking_reload: None,
+            prefetch: None,
+            live_check: None,
+            confirmed_outages: 0,
+            backups: VecDeque::new(),
+            failovers: 0,
+            stop: None,
+        }
+    }
+
+    pub fn with_stop(mut self, stop: Option<StopHandle>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Switches to these copies of the playlist on other servers when the
+    /// current one fails.
+    pub fn with_backups(mut self, backups: Vec<Url>) -> Self {
+        self.backups = backups.into();
+        self
+    }
+
+    /// Moves on to the next backup, unless every server failed since the
+    /// playlist last loaded.
+    fn fail_over(&mut self, reason: &str) -> bool {
+        if self.failovers >= self.backups.len() {
+            return false;
+        }
+        let Some(next) = self.backups.pop_front() else {
+            return false;
+        };
+        self.failovers += 1;
+        let failed = std::mem::replace(&mut self.url, next);
+        warn!(
+            "Media playlist failed ({reason}), switching to the backup on {}",
+            self.url.host_str().unwrap_or_default()
+        );
+        self.backups.push_back(failed);
+        // The backup may not be at the same point of a blocking reload.
+        self.blocking_reload = None;
+        true
+    }
+
+    /// Asks `live_check` before treating playlist errors as the end of the
+    /// stream, so CDN hiccups do not end a recording.
+    pub fn with_live_check(mut self, live_check: Option<LiveCheck<'a>>) -> Self {
+        self.live_check = live_check;
+        self
+    }
+
+    /// Ends the stream for `reason`, unless the provider says the broadcast
+    /// is still going, in which case the playlist is retried after `delay`.
+    fn end_or_retry(&mut self, reason: EndReason, delay: Duration) -> Poll {
+        let Some(check) = self.live_check.as_mut() else {
+            info!("Stream ended ({reason})");
+            return Poll::End(reason);
+        };
+        match check() {
+            Ok(true) if self.confirmed_outages < MAX_CONFIRMED_OUTAGES => {
+                self.confirmed_outages += 1;
+                warn!("Media playlist failed ({reason}) but the stream is still live, retrying");
+                self.errors.reset();
+                Poll::Retry(delay)
+            }
+            Ok(true) => {
+                warn!("Stream is live but its media playlist keeps failing ({reason})");
+                Poll::End(reason)
+            }
+            Ok(false) => {
+                info!("Stream ended ({reason})");
+                Poll::End(reason)
+            }
+            Err(err) => {
+                debug!("Failed to check whether the stream is still live: {err:#}");
+                info!("Stream ended ({reason})");
+                Poll::End(reason)
+            }
+        }
+    }
+
+    pub fn with_segment_query(mut self, query: QueryPassthrough) -> Self {
+        self.segment_query = query;
+        self
+    }
+
+    /// Uses `prefetch` as the first reload if it is for this playlist.
+    pub fn with_prefetch(mut self, prefetch: Option<PlaylistPrefetch>) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    pub fn poll(&mut self, had_content: bool) -> Result<Poll> {
+        let span = trace_span!(
+            "playlist_reload",
+            url = %self.url,
+            status = field::Empty,
+            segments = field::Empty,
+        );
+        let _entered = span.enter();
+
+        let prefetched = self
+            .prefetch
+            .take()
+            .and_then(|prefetch| prefetch.finish(&self.url));
+        let (mut playlist_url, body) = match prefetched {
+            Some(prefetched) => {
+                debug!("Using the prefetched media playlist");
+                prefetched
+            }
+            None => match self.fetch(had_content, &span)? {
+                Ok(response) => response,
+                Err(poll) => return Ok(poll),
+            },
+        };
+
+        if self.blocking_reload.is_some() {
+            let query: Vec<(String, String)> = playlist_url
+                .query_pairs()
+                .filter(|(key, _)| !key.starts_with("_HLS_"))
+                .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                .collect();
+            if query.is_empty() {
+                playlist_url.set_query(None);
+            } else {
+                playlist_url.query_pairs_mut().clear().extend_pairs(query);
+            }
+        }
+        match parse_media_playlist(
+            &playlist_url,
+            &body,
+            self.segment_query,
+            self.low_latency,
+            self.debug_ads,
+        ) {
+            Ok(playlist) => {
+                span.record("segments", playlist.segments.len());
+                self.errors.reset();
+                self.confirmed_outages = 0;
+                self.failovers = 0;
+                self.blocking_reload = playlist.blocking_reload;
+                self.url = playlist_url;
+                Ok(Poll::Playlist(playlist))
+            }
+            Err(err) => {
+                if self.fail_over(&format!("{err}")) {
+                    return Ok(Poll::Retry(Duration::ZERO));
+                }
+                let delay = self.errors.failed();
+                if self.errors.exhausted() && had_content {
+                    return Ok(self.end_or_retry(EndReason::UnreadablePlaylist, delay));
+                }
+                debug!("Failed to parse media playlist: {err}");
+                Ok(Poll::Retry(delay))
+            }
+        }
+    }
+
+    /// Requests the playlist, or says how the poll should end if that fails.
+    fn fetch(&mut self, had_content: bool, span: &Span) -> Result<Result<(Url, String), Poll>> {
+        let mut url = self.url.clone();
+        if let Some((msn, part)) = self.blocking_reload {
+            let mut query = url.query_pairs_mut();
+            query.append_pair("_HLS_msn", &msn.to_string());
+            if let Some(part) = part {
+                query.append_pair("_HLS_part", &part.to_string());
+            }
+        }
+
+        // Once, as failed reloads are retried by the poll loop, but within
+        // the server's rate limit.
+        let response = match Retry::ONCE.send_stoppable(self.client.get(url), self.stop.as_ref()) {
+            Ok(resp) => resp,
+            Err(err) => {
+                let err = anyhow::Error::from(err);
+                if self.fail_over(&format!("{err:#}")) {
+                    return Ok(Err(Poll::Retry(Duration::ZERO)));
+                }
+                let delay = self.errors.failed();
+                if self.errors.exhausted() && had_content {
+                    return Ok(Err(self.end_or_retry(EndReason::PlaylistErrors, delay)));
+                }
+                debug!("Failed to fetch media playlist: {err}");
+                return Ok(Err(Poll::Retry(delay)));
+            }
+        };
+
+        span.record("status", response.status().as_u16());
+        if !response.status().is_success() {
+            if self.fail_over(&format!("status {}", response.status())) {
+                return Ok(Err(Poll::Retry(Duration::ZERO)));
+            }
+            let delay = self.errors.failed();
+            if response.status().as_u16() == 404 && had_content {
+                return Ok(Err(self.end_or_retry(EndReason::PlaylistNotFound, delay)));
+            }
+            if self.errors.exhausted() && had_content {
+                return Ok(Err(self.end_or_retry(EndReason::PlaylistUnavailable, delay)));
+            }
+            debug!(
+                "Media playlist returned status {} - retrying",
+                response.status()
+            );
+            return Ok(Err(Poll::Retry(delay)));
+        }
+
+        let url = response.url().clone();
+        let body = response.text().context("Reading media playlist failed")?;
+        Ok(Ok((url, body)))
+    }
+}
+
+/// What to do with the next piece of a playlist.
+pub enum Step<'p> {
+    Init(Arc<Url>),
+    Segment(&'p MediaSegment),
+    SkipAd(&'p MediaSegment),
+}
+
+/// Sequence number, URI and byte range of a planned segment.
+type Planned = (u64, Url, Option<(u64, u64)>);
+
+/// Decides which segments of each reload are new, skips ads and tracks ad
+/// break transitions.
+pub struct Scheduler {
+    is_live: bool,
+    low_latency: bool,
+    debug_ads: bool,
+    start_offset: Option<StartOffset>,
+    last_sequence: Option<u64>,
+    last_init: Option<Arc<Url>>,
+    initial: bool,
+    in_ads: bool,
+    skip_ads: bool,
+    ad_resync: AdResync,
+    /// Newest segment of the last playlist reload, to tell whether the next
+    /// one brought anything new.
+    newest_seen: Option<u64>,
+    /// Recently planned segments.
+    recent: VecDeque<Planned>,
+}
+
+impl Scheduler {
+    pub fn new(
+        is_live: bool,
+        low_latency: bool,
+        debug_ads: bool,
+        start_offset: Option<StartOffset>,
+    ) -> Self {
+        Scheduler {
+            is_live,
+            low_latency,
+            debug_ads,
+            start_offset,
+            last_sequence: None,
+            last_init: None,
+            initial: true,
+            in_ads: false,
+            skip_ads: true,
+            ad_resync: AdResync::default(),
+            newest_seen: None,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub fn with_ad_resync(mut self, ad_resync: AdResync) -> Self {
+        self.ad_resync = ad_resync;
+        self
+    }
+
+    /// Whether ad segments are dropped, or kept like any other.
+    pub fn with_skip_ads(mut self, skip_ads: bool) -> Self {
+        self.skip_ads = skip_ads;
+        self
+    }
+
+    fn live_edge(&self) -> u64 {
+        if self.low_latency { 2 } else { 3 }
+    }
+
+    pub fn plan<'p>(
+        &mut self,
+        playlist: &'p MediaPlaylist,
+        had_content: bool,
+        events: &mut dyn EventSink,
+    ) -> Vec<Step<'p>> {
+        let max_sequence = playlist.segments.iter().map(|s| s.sequence).max();
+        let ads_active = playlist.ads_active && self.skip_ads;
+
+        if !self.in_ads && ads_active {
+            self.in_ads = true;
+            let duration = playlist.ad_break_duration();
+            if let Some(duration) = duration {
+                info!("Entering ad break ({}s)", duration.ceil() as u64);
+            } else {
+                info!("Entering ad break");
+            }
+            events.on_ad_break_start(duration);
+        }
+
+        if self.in_ads && !ads_active {
+            self.in_ads = false;
+            info!("Exiting ad break");
+            events.on_ad_break_end();
+            self.last_sequence = match self.ad_resync {
+                _ if !had_content => None,
+                // The skipped ads are already behind `last_sequence`.
+                AdResync::Resume => self.last_sequence,
+                AdResync::LiveEdge => {
+                    max_sequence.map(|max_seq| max_seq.saturating_sub(self.live_edge()))
+                }
+            };
+            self.last_init = None;
+        }
+
+        match self.start_offset {
+            // DVR: walk back from the newest segment until enough time is covered.
+            Some(StartOffset::BeforeLive(back)) if self.initial => {
+                let mut covered = 0.0;
+                for segment in playlist.segments.iter().rev() {
+                    if covered >= back.as_secs_f64() {
+                        break;
+                    }
+                    covered += segment.duration;
+                    self.last_sequence = segment.sequence.checked_sub(1);
+                }
+                if covered < back.as_secs_f64() {
+                    warn!(
+                        "Only {covered:.0}s of the stream are available, starting at the oldest segment"
+                    );
+                } else {
+                    info!("Starting {covered:.0}s behind live");
+                }
+                self.initial = false;
+            }
+            Some(StartOffset::AfterSegment(sequence)) if self.initial => {
+                let oldest = playlist.segments.first().map(|s| s.sequence);
+                if oldest.is_some_and(|oldest| oldest <= sequence + 1)
+                    && max_sequence.is_some_and(|max| max >= sequence)
+                {
+                    info!("Resuming after segment {sequence}");
+                    self.last_sequence = Some(sequence);
+                    self.initial = false;
+                } else {
+                    warn!(
+                        "Segment {sequence} is no longer in the playlist, joining at the live edge"
+                    );
+                }
+            }
+            // VODs linked with a start time begin at the segment containing it.
+            Some(StartOffset::FromStart(start)) if self.initial && !self.is_live => {
+                let mut skipped = 0.0;
+                for segment in &playlist.segments {
+                    if skipped + segment.duration > start.as_secs_f64() {
+                        break;
+                    }
+                    skipped += segment.duration;
+                    self.last_sequence = Some(segment.sequence);
+                }
+                info!("Starting {skipped:.0}s into the VOD");
+                self.initial = false;
+            }
+            _ => {}
+        }
+
+        // Fast-start: on first load of a live playlist, jump to the latest edge rather than older segments
+        if self.initial && self.is_live {
+            if let Some(max_seq) = max_sequence {
+                self.last_sequence = Some(max_seq.saturating_sub(self.live_edge()));
+                debug!(
+                    "Starting near live edge at sequence {} (max {})",
+                    self.last_sequence.unwrap_or(0),
+                    max_seq
+                );
+            }
+            self.initial = false;
+        }
+
+        let mut steps = Vec::new();
+        let mut warned_discontinuity = false;
+        for segment in &playlist.segments {
+            if segment.discontinuity && !self.in_ads {
+                self.last_sequence = None;
+                self.last_init = None;
+            }
+
+            if let Some(last) = self.last_sequence
+                && segment.sequence <= last
+            {
+                continue;
+            }
+            self.last_sequence = Some(segment.sequence);
+
+            if segment.ad && self.skip_ads {
+                if self.debug_ads {
+                    info!(
+                        "[ads] skipping ad segment seq={}{} uri={}",
+                        segment.sequence,
+                        if segment.prefetch { " (prefetch)" } else { "" },
+                        segment.uri
+                    );
+                }
+                if !self.in_ads && segment.discontinuity && !warned_discontinuity {
+                    warn!("Encountered a stream discontinuity while filtering ads");
+                    warned_discontinuity = true;
+                }
+                steps.push(Step::SkipAd(segment));
+                continue;
+            }
+
+            // The sequence check above starts over at every discontinuity
+            // still in the playlist, which must not write a segment twice.
+            // The URI keeps a restarted media sequence, whose numbers repeat,
+            // from counting as written.
+            let key = (segment.sequence, segment.uri.clone(), segment.byte_range);
+            if self.recent.contains(&key) {
+                debug!(
+                    "Skipping segment {} which was already written",
+                    segment.sequence
+                );
+                continue;
+            }
+            if self.recent.len() == RECENT_SEGMENTS {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(key);
+
+            if let Some(init_url) = &segment.init
+                && self.last_init.as_ref() != Some(init_url)
+            {
+                self.last_init = Some(init_url.clone());
+                steps.push(Step::Init(init_url.clone()));
+            }
+            steps.push(Step::Segment(segment));
+        }
+        steps
+    }
+
+    /// How long to wait before reloading `playlist`, which was fetched at
+    /// `fetched_at`.
+    ///
+    /// Reloads are timed from when the newest segment showed up rather than
+    /// from after it was written, so they keep pace with the server instead
+    /// of drifting against it. An unchanged playlist is retried after half
+    /// the target duration (RFC 8216, 6.3.4).
+    pub fn reload_delay(&mut self, playlist: &MediaPlaylist, fetched_at: Instant) -> Duration {
+        let newest = playlist.segments.last().map(|s| s.sequence);
+        let changed = newest > self.newest_seen;
+        if changed {
+            self.newest_seen = newest;
+        }
+        let last_real_duration = playlist
+            .segments
+            .iter()
+            .rev()
+            .map(MediaSegment::effective_duration)
+            .find(|&duration| duration > 0.0);
+        let reload = if self.in_ads {
+            0.5
+        } else if playlist.blocking_reload.is_some() {
+            // The server holds the next reload until there is new media.
+            0.0
+        } else if self.low_latency {
+            last_real_duration.unwrap_or(playlist.target_duration)
+        } else if changed {
+            let due = last_real_duration.unwrap_or(playlist.target_duration);
+            (due - fetched_at.elapsed().as_secs_f64()).max(0.0)
+        } else {
+            playlist.target_duration * 0.5
+        };
+        if self.debug_ads {
+            info!(
+                "[ads] polling every {:.3}s (ads_active={})",
+                reload, self.in_ads
+            );
+        }
+        Duration::from_millis((reload * 1000.0) as u64)
+    }
+}
+
+/// Downloads segments into memory so filters see whole segments.
+///
+/// The buffer of the last written segment is reused for the next one, so a
+/// long recording does not allocate a fresh multi-megabyte buffer per
+/// segment.
+pub struct SegmentFetcher<'a> {
+    client: &'a Client,
+    /// Bytes read from the response at a time.
+    read_size: usize,
+    spare: Vec<u8>,
+    keys: KeyCache,
+    check_sizes: bool,
+}
+
+impl<'a> SegmentFetcher<'a> {
+    pub fn new(client: &'a Client, read_size: usize, keys: KeyCache) -> Self {
+        SegmentFetcher {
+            client,
+            read_size: read_size.max(1),
+            spare: Vec::new(),
+            keys,
+            check_sizes: false,
+        }
+    }
+
+    /// Warns about segments that are shorter than announced or far off
+    /// their `EXT-X-BITRATE`.
+    pub fn with_size_checks(mut self, check_sizes: bool) -> Self {
+        self.check_sizes = check_sizes;
+        self
+    }
+
+    fn check_size(
+        &self,
+        url: &Url,
+        segment: Option<&MediaSegment>,
+        data: &[u8],
+        announced: Option<u64>,
+    ) {
+        if !self.check_sizes {
+            return;
+        }
+        let what = segment.map_or_else(
+            || "Initialization segment".to_string(),
+            |s| format!("Segment {}", s.sequence),
+        );
+        let size = data.len() as u64;
+        if let Some(announced) = announced
+            && size != announced
+        {
+            warn!("{what} is {size} bytes but {announced} were announced: {url}");
+        }
+        if let Some(segment) = segment
+            && let Some(kbps) = segment.bitrate
+            && segment.duration > 0.0
+        {
+            let expected = kbps as f64 * 125.0 * segment.duration;
+            let ratio = size as f64 / expected;
+            if !(0.5..=2.0).contains(&ratio) {
+                warn!(
+                    "{what} is {size} bytes, {:.0}% of the {:.0} expected at {kbps} kbit/s: {url}",
+                    ratio * 100.0,
+                    expected
+                );
+            }
+        }
+    }
+
+    fn decrypt(&mut self, segment: &MediaSegment, data: &mut Vec<u8>) -> Result<()> {
+        match &segment.key {
+            Some(key) => self.keys.decrypt(self.client, key, segment.sequence, data),
+            None => Ok(()),
+        }
+    }
+
+    /// Hands back a segment's buffer once it has been written.
+    pub fn recycle(&mut self, data: Vec<u8>) {
+        if data.capacity() > self.spare.capacity() {
+            self.spare = data;
+        }
+    }
+
+    pub fn fetch(
+        &mut self,
+        url: &Url,
+        kind: ChunkKind,
+        segment: Option<&MediaSegment>,
+    ) -> Result<Vec<u8>> {
+        let data = std::mem::take(&mut self.spare);
+        let (sequence, range) = segment.map_or((0, None), |s| (s.sequence, s.byte_range));
+        let (mut data, announced) = download(
+            self.client,
+            self.read_size,
+            url,
+            kind,
+            sequence,
+            range,
+            data,
+        )?;
+        self.check_size(url, segment, &data, announced);
+        if let Some(segment) = segment {
+            self.decrypt(segment, &mut data)?;
+        }
+        Ok(data)
+    }
+
+    /// Downloads an initialization segment and the media segment after it at
+    /// the same time, which saves a round trip when an fMP4 stream starts.
+    pub fn fetch_with_init(
+        &mut self,
+        init: &Url,
+        segment: &MediaSegment,
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (client, read_size) = (self.client, self.read_size);
+        let data = std::mem::take(&mut self.spare);
+        let ((init_data, init_announced), (mut media, announced)) = std::thread::scope(|scope| {
+            let media = scope.spawn(|| {
+                download(
+                    client,
+                    read_size,
+                    &segment.uri,
+                    ChunkKind::Media,
+                    segment.sequence,
+                    segment.byte_range,
+                    data,
+                )
+            });
+            let init = download(
+                client,
+                read_size,
+                init,
+                ChunkKind::Init,
+                0,
+                None,
+                Vec::new(),
+            );
+            let media = media
+                .join()
+                .map_err(|_| anyhow!("Segment download thread panicked"))?;
+            anyhow::Ok((init?, media?))
+        })?;
+        self.check_size(init, None, &init_data, init_announced);
+        self.check_size(&segment.uri, Some(segment), &media, announced);
+        self.decrypt(segment, &mut media)?;
+        Ok((init_data, media))
+    }
+}
+
+fn download(
+    client: &Client,
+    read_size: usize,
+    url: &Url,
+    kind: ChunkKind,
+    sequence: u64,
+    range: Option<(u64, u64)>,
+    mut data: Vec<u8>,
+) -> Result<(Vec<u8>, Option<u64>)> {
+    let what = match kind {
+        ChunkKind::Init => "initialization segment",
+        ChunkKind::Media => "segment",
+    };
+    let span = trace_span!(
+        "segment",
+        sequence,
+        kind = what,
+        bytes = field::Empty,
+        first_byte_ms = field::Empty,
+        elapsed_ms = field::Empty,
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+
+    let mut request = client.get(url.clone());
+    if let Some((offset, length)) = range {
+        let last = offset + length.saturating_sub(1);
+        // Ranges of a compressed response would not line up with the file.
+        request = request
+            .header(RANGE, format!("bytes={offset}-{last}"))
+            .header(ACCEPT_ENCODING, "identity");
+    }
+    let mut response = Retry::SEGMENT
+        .send(request)
+        .with_context(|| format!("Requesting {what} {url}"))?
+        .error_for_status()
+        .with_context(|| format!("Download of {what} failed: {url}"))?;
+    span.record("first_byte_ms", started.elapsed().as_millis() as u64);
+    // Servers without range support send the whole resource.
+    let whole = range.filter(|_| response.status() != StatusCode::PARTIAL_CONTENT);
+    // Unknown when the body is decompressed on the fly.
+    let announced = range
+        .map(|(_, length)| length)
+        .or(response.content_length());
+    data.clear();
+    data.reserve(response.content_length().unwrap_or(0) as usize);
+    loop {
+        let read = (&mut response)
+            .take(read_size as u64)
+            .read_to_end(&mut data)
+            .with_context(|| format!("Reading {what} failed: {url}"))?;
+        if read == 0 {
+            break;
+        }
+    }
+    if let Some((offset, length)) = whole {
+        let start = (offset as usize).min(data.len());
+        let end = (offset + length).min(data.len() as u64) as usize;
+        data.truncate(end);
+        data.drain(..start);
+    }
+    span.record("bytes", data.len());
+    span.record("elapsed_ms", started.elapsed().as_millis() as u64);
+    Ok((data, announced))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkKind {
+    Init,
+    Media,
+}
+
+/// One downloaded segment on its way to the sink.
+pub struct Chunk {
+    pub kind: ChunkKind,
+    pub sequence: u64,
+    pub data: Vec<u8>,
+}
+
+/// A processing step between the fetcher and the sink. Filters may rewrite
+/// `chunk.data` in place, or empty it to drop the chunk.
+pub trait Filter {
+    fn process(&mut self, chunk: &mut Chunk) -> Result<()>;
+}
+
+/// Drops media segments whose leading packets match a recently written
+/// segment, as happens when a CDN serves the same segment again under a new
+/// URL and sequence number after a failover (`--dedup-content`).
+#[derive(Default)]
+pub struct DuplicateFilter {
+    recent: VecDeque<u64>,
+}
+
+impl Filter for DuplicateFilter {
+    fn process(&mut self, chunk: &mut Chunk) -> Result<()> {
+        if chunk.kind != ChunkKind::Media || chunk.data.is_empty() {
+            return Ok(());
+        }
+        let head = &chunk.data[..chunk.data.len().min(FINGERPRINT_PACKETS * TS_PACKET_SIZE)];
+        let mut hasher = DefaultHasher::new();
+        chunk.data.len().hash(&mut hasher);
+        head.hash(&mut hasher);
+        let fingerprint = hasher.finish();
+        if self.recent.contains(&fingerprint) {
+            warn!(
+                "Segment {} repeats an earlier segment, dropping it",
+                chunk.sequence
+            );
+            chunk.data.clear();
+            return Ok(());
+        }
+        if self.recent.len() == RECENT_SEGMENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(fingerprint);
+        Ok(())
+    }
+}
+
+/// Trims torn packets from MPEG-TS segments so a bad CDN response cannot
+/// desync the demuxer for the rest of the recording. Other containers pass
+/// through untouched.
+pub struct TsFixer;
+
+impl Filter for TsFixer {
+    fn process(&mut self, chunk: &mut Chunk) -> Result<()> {
+        let data = &chunk.data;
+        if chunk.kind != ChunkKind::Media || data.len() < TS_PACKET_SIZE {
+            return Ok(());
+        }
+        let is_synced = |start: usize| {
+            (0..3)
+                .map(|n| start + n * TS_PACKET_SIZE)
+                .take_while(|&i| i < data.len())
+                .all(|i| data[i] == 0x47)
+        };
+        let Some(start) = (0..TS_PACKET_SIZE).find(|&start| is_synced(start)) else {
+            // Not MPEG-TS (e.g. fMP4 or raw AAC).
+            return Ok(());
+        };
+        let end = start + (data.len() - start) / TS_PACKET_SIZE * TS_PACKET_SIZE;
+        if start != 0 || end != data.len() {
+            warn!(
+                "Segment {} has {} stray bytes, trimming to whole TS packets",
+                chunk.sequence,
+                data.len() - (end - start)
+            );
+            chunk.data.truncate(end);
+            chunk.data.drain(..start);
+        }
+        Ok(())
+    }
+}
+
+/// Writes media at the rate it plays (`--pace realtime`) rather than as fast
+/// as it downloads, for sinks that cannot buffer a whole segment.
+#[derive(Default)]
+pub struct Pacer {
+    /// When media time zero should have been written.
+    clock: Option<Instant>,
+    media_time: f64,
+}
+
+impl Pacer {
+    fn write(&mut self, sink: &mut dyn Write, data: &[u8], duration: f64) -> std::io::Result<()> {
+        let now = Instant::now();
+        let clock = *self
+            .clock
+            .get_or_insert_with(|| now - Duration::from_secs_f64(self.media_time));
+        let due = clock + Duration::from_secs_f64(self.media_time);
+        // After a stall, carry on from now instead of bursting to catch up.
+        let clock = if now.saturating_duration_since(due).as_secs_f64() > MAX_PACE_LAG {
+            debug!("Output fell behind real time, resetting pacing clock");
+            let clock = now - Duration::from_secs_f64(self.media_time);
+            self.clock = Some(clock);
+            clock
+        } else {
+            clock
+        };
+
+        let slices = ((duration * PACE_SLICES_PER_SECOND).ceil() as usize).max(1);
+        let slice_size = data.len().div_ceil(slices).max(1);
+        for (i, slice) in data.chunks(slice_size).enumerate() {
+            let offset = self.media_time + duration * i as f64 / slices as f64;
+            let deadline = clock + Duration::from_secs_f64(offset);
+            let wait = deadline.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                std::thread::sleep(wait);
+            }
+            sink.write_all(slice)?;
+            sink.flush().ok();
+        }
+        self.media_time += duration;
+        Ok(())
+    }
+}
+
+/// Runs the stages until the stream ends or a stop condition is met.
+pub struct Pipeline<'a> {
+    pub poller: PlaylistPoller<'a>,
+    pub scheduler: Scheduler,
+    pub fetcher: SegmentFetcher<'a>,
+    pub filters: Vec<Box<dyn Filter>>,
+    pub sink: &'a mut dyn Write,
+    pub is_live: bool,
+    pub disk_guard: Option<DiskGuard>,
+    pub ad_filler: Option<AdFiller>,
+    pub pacer: Option<Pacer>,
+    /// Look for ID3 tags in the media segments.
+    pub timed_metadata: bool,
+    pub stop: StopConditions,
+}
+
+impl Pipeline<'_> {
+    /// Writes `duration` seconds of media, spread out over that time when
+    /// pacing.
+    fn write(&mut self, data: &[u8], duration: f64) -> std::io::Result<()> {
+        match self.pacer.as_mut() {
+            Some(pacer) => pacer.write(self.sink, data, duration),
+            None => self.sink.write_all(data),
+        }
+    }
+
+    pub fn run(mut self, events: &mut dyn EventSink) -> Result<StreamSummary> {
+        let started = Instant::now();
+        let mut had_content = false;
+        let mut bytes_written = 0u64;
+        let mut segments_written = 0u64;
+        let mut ad_time = 0.0;
+        let mut output_time = 0.0;
+        let mut ad_gaps: Vec<AdGap> = Vec::new();
+        let mut seek_points: Vec<SeekPoint> = Vec::new();
+        let mut timed_metadata: Vec<TimedMetadata> = Vec::new();
+        let mut open_gap: Option<AdGap> = None;
+
+        let end = 'stream: loop {
+            systemd::heartbeat();
+            if let Some(reason) = self.stop.reason(bytes_written) {
+                info!("Stopping ({reason})");
+                break reason;
+            }
+
+            let playlist = match self.poller.poll(had_content)? {
+                Poll::Playlist(playlist) => playlist,
+                Poll::Retry(delay) => {
+                    systemd::idle_for(delay);
+                    match &self.stop.handle {
+                        Some(handle) => {
+                            handle.sleep(delay);
+                        }
+                        None => std::thread::sleep(delay),
+                    }
+                    continue;
+                }
+                Poll::End(reason) => break reason,
+            };
+            let fetched_at = Instant::now();
+
+            if let Some(guard) = self.disk_guard.as_mut()
+                && let Err(err) = guard.check()
+            {
+                warn!("Stopping ({}): {err:#}", EndReason::DiskFull);
+                break EndReason::DiskFull;
+            }
+
+            let steps = self.scheduler.plan(&playlist, had_content, events);
+            let progressed = !steps.is_empty();
+            let mut steps = steps.into_iter().peekable();
+            // A media segment downloaded together with its init segment.
+            let mut fetched: Option<(u64, Vec<u8>)> = None;
+            while let Some(step) = steps.next() {
+                let (kind, url, segment) = match step {
+                    Step::SkipAd(segment) => {
+                        let gap = open_gap.get_or_insert_with(|| AdGap {
+                            output_time,
+                            skipped_before: ad_time,
+                            duration: 0.0,
+                            started_at: SystemTime::now(),
+                            ended_at: SystemTime::now(),
+                        });
+                        gap.duration += segment.duration;
+                        ad_time += segment.duration;
+                        let remaining = playlist
+                            .ad_break_duration()
+                            .map(|duration| (duration - gap.duration).max(0.0));
+                        match remaining {
+                            Some(remaining) => debug!(
+                                "Skipped ad segment {} ({:.0}s of the break left)",
+                                segment.sequence, remaining
+                            ),
+                            None => debug!("Skipped ad segment {}", segment.sequence),
+                        }
+                        events.on_ad_progress(gap.duration, remaining);
+                        if let Some(mut filler) = self.ad_filler.take() {
+                            for _ in 0..filler.repeats_for(segment.duration) {
+                                self.write(filler.clip(), filler.duration())
+                                    .context("Writing ad filler to output failed")?;
+                                bytes_written += filler.clip().len() as u64;
+                                output_time += filler.duration();
+                                had_content = true;
+                            }
+                            self.ad_filler = Some(filler);
+                        }
+                        continue;
+                    }
+                    Step::Init(url) => {
+                        debug!("Downloading initialization segment {}", url);
+                        (ChunkKind::Init, Url::clone(&url), None)
+                    }
+                    Step::Segment(segment) => {
+                        debug!(
+                            "Downloading segment {}{}{} ({}s) {}",
+                            segment.sequence,
+                            if segment.prefetch { " (prefetch)" } else { "" },
+                            if segment.discontinuity {
+                                " (discontinuity)"
+                            } else {
+                                ""
+                            },
+                            segment.duration,
+                            segment.uri
+                        );
+                        (ChunkKind::Media, segment.uri.clone(), Some(segment))
+                    }
+                };
+
+                let sequence = segment.map_or(0, |s| s.sequence);
+                let data = match (kind, steps.peek(), fetched.take()) {
+                    (ChunkKind::Init, Some(Step::Segment(next)), _) => {
+                        let (init, media) = self.fetcher.fetch_with_init(&url, next)?;
+                        fetched = Some((next.sequence, media));
+                        init
+                    }
+                    (ChunkKind::Media, _, Some((fetched_sequence, data)))
+                        if fetched_sequence == sequence =>
+                    {
+                        data
+                    }
+                    _ => self.fetcher.fetch(&url, kind, segment)?,
+                };
+                let mut chunk = Chunk {
+                    kind,
+                    sequence,
+                    data,
+                };
+                for filter in &mut self.filters {
+                    filter.process(&mut chunk)?;
+                }
+                if chunk.data.is_empty() && kind == ChunkKind::Media {
+                    self.fetcher.recycle(chunk.data);
+                    continue;
+                }
+                self.write(&chunk.data, segment.map_or(0.0, |s| s.duration))
+                    .context("Writing segment to output failed")?;
+                self.sink.flush().ok();
+                let bytes = chunk.data.len() as u64;
+                bytes_written += bytes;
+                had_content = true;
+                systemd::heartbeat();
+
+                let Some(segment) = segment else {
+                    self.fetcher.recycle(chunk.data);
+                    continue;
+                };
+                if self.timed_metadata {
+                    for (offset, frames) in id3::segment_tags(&chunk.data) {
+                        let tag = TimedMetadata {
+                            output_time: output_time + offset,
+                            sequence: segment.sequence,
+                            frames,
+                        };
+                        events.on_timed_metadata(&tag);
+                        timed_metadata.push(tag);
+                    }
+                }
+                self.fetcher.recycle(chunk.data);
+                segments_written += 1;
+                seek_points.push(SeekPoint {
+                    output_time,
+                    offset: bytes_written - bytes,
+                    sequence: segment.sequence,
+                });
+                output_time += segment.duration;
+                if let Some(mut gap) = open_gap.take() {
+                    gap.ended_at = SystemTime::now();
+                    ad_gaps.push(gap);
+                }
+                if self.scheduler.debug_ads {
+                    info!(
+                        "[ads] advanced to sequence {}{}",
+                        segment.sequence,
+                        if segment.prefetch { " (prefetch)" } else { "" }
+                    );
+                }
+                events.on_segment(&SegmentEvent {
+                    sequence: segment.sequence,
+                    duration: segment.duration,
+                    bytes,
+                    total_bytes: bytes_written,
+                    behind_live: self.is_live.then(|| {
+                        playlist
+                            .segments
+                            .iter()
+                            .filter(|s| s.sequence > segment.sequence)
+                            .fold(0.0, |behind, s| behind + s.duration)
+                    }),
+                });
+
+                if let Some(reason) = self.stop.reason(bytes_written) {
+                    info!("Stopping ({reason})");
+                    break 'stream reason;
+                }
+            }
+
+            // A closed playlist never changes again, so it is not reloaded;
+            // for a live stream it means the broadcast is over.
+            if playlist.end_list && self.is_live {
+                info!("Stream ended (end of playlist)");
+                break EndReason::EndOfPlaylist;
+            }
+            if playlist.end_list {
+                info!("End of VOD reached");
+                break EndReason::EndOfVod;
+            }
+
+            if !self.is_live && !progressed {
+                break EndReason::EndOfPlaylist;
+            }
+
+            let delay = self.scheduler.reload_delay(&playlist, fetched_at);
+            match &self.stop.handle {
+                Some(handle) => {
+                    if !handle.sleep(delay) {
+                        info!("Stopping ({})", EndReason::StopRequested);
+                        break EndReason::StopRequested;
+                    }
+                }
+                None => std::thread::sleep(delay),
+            }
+        };
+
+        self.sink.flush().context("Flushing output failed")?;
+        if let Some(mut gap) = open_gap {
+            gap.ended_at = SystemTime::now();
+            ad_gaps.push(gap);
+        }
+        Ok(StreamSummary {
+            bytes_written,
+            elapsed: started.elapsed(),
+            segments: segments_written,
+            output_time,
+            ad_time,
+            ad_gaps,
+            seek_points,
+            timed_metadata,
+            end_reason: end,
+        })
+    }
+}
@@ -0,0 +1,108 @@
+use crate::hls::id3::segment_tags;
+
+/// An ID3v2.4 tag with a title.
+fn title_tag(title: &str) -> Vec<u8> {
+    let mut frame = b"TIT2".to_vec();
+    frame.extend((title.len() as u32 + 1).to_be_bytes());
+    frame.extend([0, 0, 3]);
+    frame.extend(title.as_bytes());
+    let mut tag = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+    tag.push(frame.len() as u8);
+    tag.extend(frame);
+    tag
+}
+
+/// One MPEG-TS packet of `pid`, padded with stuffing bytes.
+fn ts_packet(pid: u16, unit_start: bool, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![
+        0x47,
+        u8::from(unit_start) << 6 | (pid >> 8) as u8,
+        pid as u8,
+        0x10,
+    ];
+    packet.extend(payload);
+    packet.resize(188, 0xff);
+    packet
+}
+
+/// A PES packet with a PTS. Video leaves its length open.
+fn pes(stream_id: u8, pts: u64, data: &[u8]) -> Vec<u8> {
+    let length = if stream_id == 0xe0 { 0 } else { 8 + data.len() };
+    let mut pes = vec![0, 0, 1, stream_id, (length >> 8) as u8, length as u8];
+    pes.extend([0x80, 0x80, 5]);
+    pes.extend([
+        0x21 | ((pts >> 29) & 0x0e) as u8,
+        (pts >> 22) as u8,
+        ((pts >> 14) & 0xfe) as u8 | 1,
+        (pts >> 7) as u8,
+        ((pts << 1) & 0xfe) as u8 | 1,
+    ]);
+    pes.extend(data);
+    pes
+}
+
+/// A segment with H.264 on PID 0x101 and ID3 metadata on PID 0x102.
+fn ts_segment(video_pts: &[u64], metadata: (u64, &[u8])) -> Vec<u8> {
+    let pat = [
+        0, 0x00, 0xb0, 13, 0, 1, 0xc1, 0, 0, 0, 1, 0xe1, 0x00, 0, 0, 0, 0,
+    ];
+    let pmt = [
+        0, 0x02, 0xb0, 23, 0, 1, 0xc1, 0, 0, 0xe1, 0x01, 0xf0, 0, 0x1b, 0xe1, 0x01, 0xf0, 0, 0x15,
+        0xe1, 0x02, 0xf0, 0, 0, 0, 0, 0,
+    ];
+    let mut segment = ts_packet(0, true, &pat);
+    segment.extend(ts_packet(0x100, true, &pmt));
+    for &pts in video_pts {
+        segment.extend(ts_packet(0x101, true, &pes(0xe0, pts, &[0, 0, 0, 1, 0x09])));
+    }
+    let (pts, tag) = metadata;
+    segment.extend(ts_packet(0x102, true, &pes(0xbd, pts, tag)));
+    segment
+}
+
+#[test]
+fn id3_tags_lead_packed_audio_segments() {
+    // ID3v2.4 with the timestamp PRIV frame and a title, then ADTS audio.
+    let mut frames = Vec::new();
+    frames.extend(b"PRIV\x00\x00\x00\x35\x00\x00");
+    frames.extend(b"com.apple.streaming.transportStreamTimestamp\x00");
+    frames.extend([0, 0, 0, 0, 0, 0x01, 0x5f, 0x90]);
+    frames.extend(b"TIT2\x00\x00\x00\x0c\x00\x00\x03Now playing");
+    let mut segment = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+    segment.push(frames.len() as u8);
+    segment.extend(frames);
+    segment.extend([0xff, 0xf1, 0x50, 0x80]);
+
+    let tags = segment_tags(&segment);
+
+    assert_eq!(tags.len(), 1);
+    let (offset, frames) = &tags[0];
+    assert_eq!(*offset, 0.0);
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].id, "TIT2");
+    assert_eq!(frames[0].value, "Now playing");
+}
+
+#[test]
+fn truncated_tags_are_left_out() {
+    let mut tag = title_tag("Now playing");
+    tag.truncate(tag.len() - 4);
+
+    assert!(segment_tags(&tag).is_empty());
+}
+
+#[test]
+fn tags_in_the_metadata_stream_are_timed_from_the_first_frame() {
+    let mut tags = title_tag("Song");
+    // The segment ends halfway through a second tag.
+    tags.extend(&title_tag("Next song")[..12]);
+    let segment = ts_segment(&[900_000, 903_000], (990_000, &tags));
+
+    let tags = segment_tags(&segment);
+
+    assert_eq!(tags.len(), 1);
+    let (offset, frames) = &tags[0];
+    assert_eq!(*offset, 1.0);
+    assert_eq!(frames[0].id, "TIT2");
+    assert_eq!(frames[0].value, "Song");
+}
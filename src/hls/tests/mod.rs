@@ -1 +1,3 @@
-mod twitch_ads;
+mod id3;
+mod pipeline;
+pub(crate) mod server;
@@ -0,0 +1,295 @@
+use reqwest::blocking::Client;
+use std::time::{Duration, Instant};
+use url::Url;
+
+use super::server::{TestServer, response};
+use crate::disk::DiskGuard;
+use crate::events::EventSink;
+use crate::hls::keys::KeyCache;
+use crate::hls::pipeline::{
+    Chunk, ChunkKind, Filter, Pipeline, PlaylistPoller, Poll, Scheduler, SegmentFetcher, Step,
+    TsFixer,
+};
+use crate::hls::{
+    AdResync, EndReason, QueryPassthrough, SeekPoint, StartOffset, StopConditions, StopHandle,
+    StreamSummary, parse_media_playlist,
+};
+
+#[test]
+fn ts_fixer_trims_torn_packets() {
+    let mut data = vec![0xAA; 5];
+    for _ in 0..3 {
+        data.push(0x47);
+        data.extend([0u8; 187]);
+    }
+    data.extend([0x47, 1, 2]);
+    let mut chunk = Chunk {
+        kind: ChunkKind::Media,
+        sequence: 1,
+        data,
+    };
+
+    TsFixer.process(&mut chunk).unwrap();
+
+    assert_eq!(chunk.data.len(), 3 * 188);
+    assert_eq!(chunk.data[0], 0x47);
+}
+
+#[test]
+fn scheduler_starts_behind_live_edge() {
+    let mut body = String::from("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:100\n");
+    for i in 0..10 {
+        body.push_str(&format!("#EXTINF:2.000,live\nseg{i}.ts\n"));
+    }
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let playlist = parse_media_playlist(&base, &body, QueryPassthrough::Off, false, false).unwrap();
+
+    let offset = StartOffset::BeforeLive(Duration::from_secs(7));
+    let mut scheduler = Scheduler::new(true, false, false, Some(offset));
+    let steps = scheduler.plan(&playlist, false, &mut Vec::<Box<dyn EventSink>>::new());
+
+    let sequences: Vec<u64> = steps
+        .iter()
+        .filter_map(|step| match step {
+            Step::Segment(segment) => Some(segment.sequence),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(sequences, [106, 107, 108, 109]);
+}
+
+#[test]
+fn an_offset_from_the_start_only_applies_to_vods() {
+    let mut body = String::from("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:100\n");
+    for i in 0..10 {
+        body.push_str(&format!("#EXTINF:2.000,\nseg{i}.ts\n"));
+    }
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let playlist = parse_media_playlist(&base, &body, QueryPassthrough::Off, false, false).unwrap();
+    let first = |is_live, offset| {
+        let mut scheduler = Scheduler::new(is_live, false, false, offset);
+        let steps = scheduler.plan(&playlist, false, &mut Vec::<Box<dyn EventSink>>::new());
+        steps.iter().find_map(|step| match step {
+            Step::Segment(segment) => Some(segment.sequence),
+            _ => None,
+        })
+    };
+    let offset = Some(StartOffset::FromStart(Duration::from_secs(10)));
+
+    assert_eq!(first(false, offset), Some(105));
+    // A live stream joins near its edge as it does without an offset.
+    assert_eq!(first(true, offset), first(true, None));
+    assert!(first(true, None) > Some(105));
+}
+
+#[test]
+fn a_restarted_media_sequence_is_not_taken_for_written_segments() {
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let load = |body: &str| {
+        parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap()
+    };
+    let planned = |scheduler: &mut Scheduler, body: &str| -> Vec<String> {
+        scheduler
+            .plan(&load(body), false, &mut Vec::<Box<dyn EventSink>>::new())
+            .iter()
+            .filter_map(|step| match step {
+                Step::Segment(segment) => Some(segment.uri.path().to_string()),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut scheduler = Scheduler::new(false, false, false, None);
+
+    let before = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n\
+        #EXTINF:2,\na0.ts\n#EXTINF:2,\na1.ts\n";
+    assert_eq!(planned(&mut scheduler, before), ["/a0.ts", "/a1.ts"]);
+
+    // The encoder restarted and numbers its segments from 0 again.
+    let restarted = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:0\n\
+        #EXT-X-DISCONTINUITY\n#EXTINF:2,\nb0.ts\n#EXTINF:2,\nb1.ts\n";
+    assert_eq!(planned(&mut scheduler, restarted), ["/b0.ts", "/b1.ts"]);
+    // Reloads keep the discontinuity, but its segments are written once.
+    assert!(planned(&mut scheduler, restarted).is_empty());
+}
+
+#[test]
+fn ad_resync_resumes_or_jumps_to_live() {
+    let playlist = |first: u64, ads: std::ops::Range<u64>, last: u64| {
+        let mut body = format!("#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:{first}\n");
+        for i in first..=last {
+            let title = if ads.contains(&i) { "Amazon" } else { "live" };
+            body.push_str(&format!("#EXTINF:2.000,{title}\nseg{i}.ts\n"));
+        }
+        let base = Url::parse("https://example.com/live.m3u8").unwrap();
+        parse_media_playlist(&base, &body, QueryPassthrough::Off, false, false).unwrap()
+    };
+    let in_ads = playlist(100, 106..110, 109);
+    let after_ads = playlist(110, 0..0, 125);
+
+    for (resync, first) in [(AdResync::Resume, 110), (AdResync::LiveEdge, 123)] {
+        let mut events = Vec::<Box<dyn EventSink>>::new();
+        let mut scheduler = Scheduler::new(true, false, false, None).with_ad_resync(resync);
+        scheduler.plan(&in_ads, true, &mut events);
+        let steps = scheduler.plan(&after_ads, true, &mut events);
+
+        let next = steps.iter().find_map(|step| match step {
+            Step::Segment(segment) => Some(segment.sequence),
+            _ => None,
+        });
+        assert_eq!(next, Some(first), "{resync:?}");
+    }
+}
+
+#[test]
+fn reloads_follow_segment_arrival() {
+    let body =
+        "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXT-X-MEDIA-SEQUENCE:7\n#EXTINF:2.000,live\nseg7.ts\n";
+    let base = Url::parse("https://example.com/live.m3u8").unwrap();
+    let playlist = parse_media_playlist(&base, body, QueryPassthrough::Off, false, false).unwrap();
+    let mut scheduler = Scheduler::new(true, false, false, None);
+
+    let fetched_at = Instant::now() - Duration::from_millis(500);
+    let delay = scheduler.reload_delay(&playlist, fetched_at);
+    assert!(delay <= Duration::from_millis(1500) && delay > Duration::from_millis(1000));
+
+    // Nothing new: back off by half the target duration.
+    let delay = scheduler.reload_delay(&playlist, Instant::now());
+    assert_eq!(delay, Duration::from_secs(2));
+}
+
+#[test]
+fn extended_summary_shifts_seek_points() {
+    let summary = |sequence| StreamSummary {
+        bytes_written: 1000,
+        elapsed: Duration::ZERO,
+        segments: 2,
+        output_time: 4.0,
+        ad_time: 0.0,
+        ad_gaps: Vec::new(),
+        seek_points: vec![
+            SeekPoint {
+                output_time: 0.0,
+                offset: 0,
+                sequence,
+            },
+            SeekPoint {
+                output_time: 2.0,
+                offset: 500,
+                sequence: sequence + 1,
+            },
+        ],
+        timed_metadata: Vec::new(),
+        end_reason: EndReason::EndOfPlaylist,
+    };
+
+    let mut first = summary(10);
+    first.extend(summary(1));
+
+    let points: Vec<_> = first
+        .seek_points
+        .iter()
+        .map(|point| (point.output_time, point.offset, point.sequence))
+        .collect();
+    assert_eq!(
+        points,
+        [(0.0, 0, 10), (2.0, 500, 11), (4.0, 1000, 1), (6.0, 1500, 2)]
+    );
+}
+
+const LIVE_PLAYLIST: &str =
+    "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:2.000,live\nseg1.ts\n";
+
+#[test]
+fn playlist_reloads_wait_out_a_429_unless_stopped() {
+    let server = TestServer::start(vec![
+        response(429, &[("Retry-After", "1")], ""),
+        response(200, &[], LIVE_PLAYLIST),
+    ]);
+    // Its own host name, so the pause does not hold up other tests.
+    let url = server.url("localhost", "/live.m3u8");
+    let client = Client::new();
+    let throttled = Instant::now();
+    let mut poller = PlaylistPoller::new(&client, url.clone(), false, false);
+    assert!(matches!(poller.poll(true).unwrap(), Poll::Retry(_)));
+
+    let stop = StopHandle::default();
+    stop.stop();
+    let mut stopped = PlaylistPoller::new(&client, url, false, false).with_stop(Some(stop));
+    assert!(matches!(stopped.poll(true).unwrap(), Poll::Playlist(_)));
+    assert!(throttled.elapsed() < Duration::from_millis(500));
+
+    assert!(matches!(poller.poll(true).unwrap(), Poll::Playlist(_)));
+    assert!(throttled.elapsed() >= Duration::from_millis(900));
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn a_stop_cuts_the_playlist_reload_wait_short() {
+    let playlist = "#EXTM3U\n#EXT-X-TARGETDURATION:30\n#EXT-X-MEDIA-SEQUENCE:1\n\
+                    #EXTINF:30.000,live\nseg1.ts\n";
+    let server = TestServer::start(vec![response(200, &[], playlist)]);
+    let client = Client::new();
+    let stop = StopHandle::default();
+    let mut sink = Vec::new();
+    let pipeline = Pipeline {
+        poller: PlaylistPoller::new(&client, server.url("127.0.0.1", "/live.m3u8"), false, false)
+            .with_stop(Some(stop.clone())),
+        scheduler: Scheduler::new(true, false, false, None),
+        fetcher: SegmentFetcher::new(&client, 64 * 1024, KeyCache::new(None)),
+        filters: Vec::new(),
+        sink: &mut sink,
+        is_live: true,
+        disk_guard: None,
+        ad_filler: None,
+        pacer: None,
+        timed_metadata: false,
+        stop: StopConditions {
+            max_bytes: None,
+            deadline: None,
+            quality_change: None,
+            handle: Some(stop.clone()),
+        },
+    };
+
+    let started = Instant::now();
+    let stopper = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(300));
+        stop.stop();
+    });
+    let summary = pipeline.run(&mut Vec::<Box<dyn EventSink>>::new()).unwrap();
+    stopper.join().unwrap();
+
+    assert_eq!(summary.end_reason, EndReason::StopRequested);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn low_disk_space_ends_the_stream_instead_of_failing_it() {
+    let server = TestServer::start(vec![response(200, &[], LIVE_PLAYLIST)]);
+    let client = Client::new();
+    let mut sink = Vec::new();
+    let output = std::env::temp_dir().join("fors-disk-full.ts");
+    let pipeline = Pipeline {
+        poller: PlaylistPoller::new(&client, server.url("127.0.0.1", "/live.m3u8"), false, false),
+        scheduler: Scheduler::new(true, false, false, None),
+        fetcher: SegmentFetcher::new(&client, 64 * 1024, KeyCache::new(None)),
+        filters: Vec::new(),
+        sink: &mut sink,
+        is_live: true,
+        disk_guard: Some(DiskGuard::for_output(&output, u64::MAX)),
+        ad_filler: None,
+        pacer: None,
+        timed_metadata: false,
+        stop: StopConditions {
+            max_bytes: None,
+            deadline: None,
+            quality_change: None,
+            handle: None,
+        },
+    };
+
+    let summary = pipeline.run(&mut Vec::<Box<dyn EventSink>>::new()).unwrap();
+
+    assert_eq!(summary.end_reason, EndReason::DiskFull);
+    assert_eq!(summary.bytes_written, 0);
+}
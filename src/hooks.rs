@@ -0,0 +1,30 @@
+use std::process::Command;
+use tracing::{debug, warn};
+
+/// Runs a user supplied hook command through the platform shell.
+///
+/// The event name and any extra values are exported as `FORS_*` environment
+/// variables. Failures are logged but never abort the caller.
+pub fn run(command: &str, event: &str, vars: &[(&str, &str)]) {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+
+    cmd.env("FORS_EVENT", event);
+    for (key, value) in vars {
+        cmd.env(format!("FORS_{}", key.to_ascii_uppercase()), value);
+    }
+
+    debug!("Running {event} hook: {command}");
+    match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("The {event} hook exited with {status}"),
+        Err(err) => warn!("Failed to run the {event} hook: {err}"),
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use reqwest::Certificate;
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+mod cookies;
+mod ratelimit;
+mod resolve;
+mod retry;
+
+pub use cookies::CookieJar;
+pub use ratelimit::set_rate_limit;
+pub use resolve::AddressFamily;
+pub use retry::{Backoff, Retry};
+
+/// A browser to pose as in provider API requests (`--user-agent-profile`).
+/// Segment downloads keep the client's neutral user agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UserAgentProfile {
+    /// Chrome on Windows
+    Chrome,
+    /// Chrome on Android
+    Android,
+    /// Safari on iOS
+    Ios,
+}
+
+impl UserAgentProfile {
+    pub fn user_agent(self) -> &'static str {
+        match self {
+            UserAgentProfile::Chrome => {
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Safari/537.36"
+            }
+            UserAgentProfile::Android => {
+                "Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/131.0.0.0 Mobile Safari/537.36"
+            }
+            UserAgentProfile::Ios => {
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 18_1 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/18.1 Mobile/15E148 Safari/604.1"
+            }
+        }
+    }
+
+    /// The other headers this browser sends with a page load, or with a
+    /// script's request to the same site when `navigation` is false.
+    /// Chromium adds its client hints, Safari has none.
+    pub fn browser_headers(self, navigation: bool) -> Vec<(&'static str, &'static str)> {
+        let mut headers = vec![("accept-language", "en-US,en;q=0.9")];
+        let platform = match self {
+            UserAgentProfile::Chrome => Some(("?0", "\"Windows\"")),
+            UserAgentProfile::Android => Some(("?1", "\"Android\"")),
+            UserAgentProfile::Ios => None,
+        };
+        if let Some((mobile, platform)) = platform {
+            headers.extend([
+                (
+                    "sec-ch-ua",
+                    "\"Google Chrome\";v=\"131\", \"Chromium\";v=\"131\", \"Not_A Brand\";v=\"24\"",
+                ),
+                ("sec-ch-ua-mobile", mobile),
+                ("sec-ch-ua-platform", platform),
+            ]);
+        }
+        if navigation {
+            headers.extend([
+                (
+                    "accept",
+                    "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8",
+                ),
+                ("sec-fetch-dest", "document"),
+                ("sec-fetch-mode", "navigate"),
+                ("sec-fetch-site", "none"),
+                ("sec-fetch-user", "?1"),
+                ("upgrade-insecure-requests", "1"),
+            ]);
+        } else {
+            headers.extend([
+                ("accept", "*/*"),
+                ("sec-fetch-dest", "empty"),
+                ("sec-fetch-mode", "cors"),
+                ("sec-fetch-site", "same-origin"),
+            ]);
+        }
+        headers
+    }
+}
+
+/// Settings shared by every HTTP client fors builds.
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    pub user_agent: Option<String>,
+    pub proxy: Option<String>,
+    /// Accept any TLS certificate. Only meant for debugging and MITM proxies.
+    pub no_ssl_verify: bool,
+    /// Extra PEM files with root certificates to trust.
+    pub ca_certs: Vec<PathBuf>,
+    /// Only connect over this address family.
+    pub family: Option<AddressFamily>,
+    /// `HOST:IP` pairs that bypass DNS.
+    pub resolve: Vec<String>,
+    /// DNS-over-HTTPS server used instead of the system resolver.
+    pub doh: Option<String>,
+    /// Disable HTTP/2, which is otherwise negotiated through ALPN.
+    pub http1_only: bool,
+    /// Do not ask for gzip, brotli or deflate responses.
+    pub no_compression: bool,
+}
+
+pub fn client_builder(
+    options: &HttpOptions,
+    jar: Option<&Arc<CookieJar>>,
+) -> Result<ClientBuilder> {
+    let mut headers = HeaderMap::new();
+    let agent = options.user_agent.as_deref().unwrap_or("fors/0.1");
+    headers.insert(
+        USER_AGENT,
+        HeaderValue::from_str(agent).context("Invalid user agent value")?,
+    );
+
+    let mut builder = Client::builder()
+        .default_headers(headers)
+        .redirect(reqwest::redirect::Policy::limited(10))
+        // Segments are fetched back to back from the same few hosts, so keep
+        // warm connections around and send small requests immediately.
+        .pool_max_idle_per_host(4)
+        .pool_idle_timeout(Duration::from_secs(90))
+        .tcp_nodelay(true)
+        .tcp_keepalive(Duration::from_secs(60));
+    if options.http1_only {
+        builder = builder.http1_only();
+    }
+    // Large VOD playlists shrink a lot when compressed; responses are
+    // decompressed before anything reads them.
+    if options.no_compression {
+        builder = builder.no_gzip().no_brotli().no_deflate();
+    }
+    if let Some(proxy) = &options.proxy {
+        builder = builder.proxy(
+            reqwest::Proxy::all(proxy).with_context(|| format!("Invalid proxy URL '{proxy}'"))?,
+        );
+    }
+    for path in &options.ca_certs {
+        let pem = std::fs::read(path)
+            .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+        for cert in Certificate::from_pem_bundle(&pem)
+            .with_context(|| format!("Invalid PEM certificate in {}", path.display()))?
+        {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if options.no_ssl_verify {
+        warn!("TLS certificate verification is disabled");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if options.family.is_some() || options.doh.is_some() {
+        let resolver = resolve::Resolver::new(options.family, options.doh.as_deref())?;
+        builder = builder.dns_resolver(Arc::new(resolver));
+    }
+    let mut overrides: Vec<(String, Vec<SocketAddr>)> = Vec::new();
+    for value in &options.resolve {
+        let (host, addr) = resolve::parse_override(value, options.family)?;
+        match overrides
+            .iter_mut()
+            .find(|(h, _)| h.eq_ignore_ascii_case(&host))
+        {
+            Some((_, addrs)) => addrs.push(addr),
+            None => overrides.push((host, vec![addr])),
+        }
+    }
+    for (host, addrs) in &overrides {
+        builder = builder.resolve_to_addrs(host, addrs);
+    }
+    if let Some(jar) = jar {
+        builder = builder.cookie_provider(jar.clone());
+    }
+    Ok(builder)
+}
+
+/// The `--http-header` values, which only go with upload requests so that
+/// credentials meant for the upload target never reach other hosts.
+pub fn upload_headers(headers: &[String]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (name, value) = header
+            .split_once('=')
+            .with_context(|| format!("Invalid --http-header '{header}', expected KEY=VALUE"))?;
+        map.insert(
+            HeaderName::from_bytes(name.trim().as_bytes())
+                .with_context(|| format!("Invalid header name '{name}'"))?,
+            HeaderValue::from_str(value.trim())
+                .with_context(|| format!("Invalid value for header '{name}'"))?,
+        );
+    }
+    Ok(map)
+}
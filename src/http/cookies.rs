@@ -0,0 +1,183 @@
+use anyhow::{Context, Result, anyhow};
+use cookie_store::{Cookie, CookieStore, RawCookie};
+use reqwest::header::HeaderValue;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::persist;
+
+#[cfg(test)]
+mod tests;
+
+/// Cookie store that can be loaded from and saved to a JSON file, plus
+/// cookies from `--http-cookie`. Cookies are only sent to and taken from
+/// the provider domains, never from CDNs or upload targets.
+pub struct CookieJar {
+    store: Mutex<CookieStore>,
+    /// The store as loaded, to tell which cookies this run changed.
+    loaded: CookieStore,
+    fixed: Vec<(String, String)>,
+    path: Option<PathBuf>,
+    domains: &'static [&'static str],
+}
+
+impl CookieJar {
+    pub fn new(
+        fixed: &[String],
+        path: Option<&Path>,
+        domains: &'static [&'static str],
+    ) -> Result<Self> {
+        let fixed = fixed
+            .iter()
+            .map(|cookie| {
+                cookie
+                    .split_once('=')
+                    .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                    .ok_or_else(|| anyhow!("Invalid --http-cookie '{cookie}', expected NAME=VALUE"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let store = match path {
+            Some(path) if path.exists() => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open cookie jar {}", path.display()))?;
+                cookie_store::serde::json::load(BufReader::new(file))
+                    .map_err(|err| anyhow!("Failed to read cookie jar {}: {err}", path.display()))?
+            }
+            _ => CookieStore::default(),
+        };
+
+        Ok(CookieJar {
+            loaded: store.clone(),
+            store: Mutex::new(store),
+            fixed,
+            path: path.map(Path::to_path_buf),
+            domains,
+        })
+    }
+
+    /// Writes persistent, unexpired cookies back to the jar file. Cookies
+    /// this run did not change keep what other jobs saved meanwhile.
+    pub fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        persist::update_private(path, |current| {
+            let saved = match current {
+                Some(bytes) => cookie_store::serde::json::load(&bytes[..]).map_err(|err| {
+                    anyhow!("Failed to read cookie jar {}: {err}", path.display())
+                })?,
+                None => CookieStore::default(),
+            };
+            let merged = merge(&saved, &self.loaded, &store);
+            let mut data = Vec::new();
+            cookie_store::serde::json::save(&merged, &mut data)
+                .map_err(|err| anyhow!("Failed to save cookie jar: {err}"))?;
+            Ok(data)
+        })?;
+        debug!("Saved cookie jar to {}", path.display());
+        Ok(())
+    }
+
+    fn allows(&self, url: &Url) -> bool {
+        url.host_str().is_some_and(|host| {
+            self.domains.iter().any(|domain| {
+                host == *domain
+                    || host
+                        .strip_suffix(domain)
+                        .is_some_and(|sub| sub.ends_with('.'))
+            })
+        })
+    }
+}
+
+/// `saved` with the cookies that changed between `loaded` and `current`
+/// applied to it.
+fn merge(saved: &CookieStore, loaded: &CookieStore, current: &CookieStore) -> CookieStore {
+    let changed: Vec<&Cookie<'static>> = current
+        .iter_any()
+        .filter(|cookie| find(loaded, cookie) != Some(*cookie))
+        .collect();
+    let removed: Vec<&Cookie<'static>> = loaded
+        .iter_any()
+        .filter(|cookie| find(current, cookie).is_none())
+        .collect();
+    let kept: Vec<&Cookie<'static>> = saved
+        .iter_any()
+        .filter(|cookie| {
+            !changed
+                .iter()
+                .chain(&removed)
+                .any(|other| key(other) == key(cookie))
+        })
+        .collect();
+    CookieStore::from_cookies(
+        kept.into_iter()
+            .chain(changed)
+            .cloned()
+            .map(Ok::<_, std::convert::Infallible>),
+        false,
+    )
+    .unwrap_or_default()
+}
+
+fn find<'a>(store: &'a CookieStore, cookie: &Cookie<'_>) -> Option<&'a Cookie<'static>> {
+    let (domain, path, name) = key(cookie);
+    store.get_any(&domain, &path, name)
+}
+
+fn key<'a>(cookie: &'a Cookie<'_>) -> (String, String, &'a str) {
+    (
+        String::from(&cookie.domain),
+        String::from(&cookie.path),
+        cookie.name(),
+    )
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        if !self.allows(url) {
+            return;
+        }
+        let cookies = cookie_headers.filter_map(|value| {
+            let text = value.to_str().ok()?;
+            match RawCookie::parse(text.to_string()) {
+                Ok(cookie) => Some(cookie.into_owned()),
+                Err(err) => {
+                    warn!(
+                        "Ignoring malformed cookie from {}: {err}",
+                        url.host_str().unwrap_or_default()
+                    );
+                    None
+                }
+            }
+        });
+        let mut store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        store.store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        if !self.allows(url) {
+            return None;
+        }
+        let store = self.store.lock().unwrap_or_else(|e| e.into_inner());
+        let header = store
+            .get_request_values(url)
+            .filter(|(name, _)| !self.fixed.iter().any(|(fixed, _)| fixed == name))
+            .chain(self.fixed.iter().map(|(n, v)| (n.as_str(), v.as_str())))
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        if header.is_empty() {
+            None
+        } else {
+            HeaderValue::from_str(&header).ok()
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use reqwest::cookie::CookieStore as _;
+use reqwest::header::HeaderValue;
+use std::fs;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use super::CookieJar;
+
+const DOMAINS: &[&str] = &["twitch.tv"];
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fors-cookies-{}-{name}", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn url() -> Url {
+    Url::parse("https://www.twitch.tv/").unwrap()
+}
+
+/// A jar loaded from `path` that was sent `cookies` by twitch.tv.
+fn jar(path: &Path, cookies: &[&str]) -> CookieJar {
+    let jar = CookieJar::new(&[], Some(path), DOMAINS).unwrap();
+    let headers: Vec<HeaderValue> = cookies
+        .iter()
+        .map(|cookie| HeaderValue::from_str(&format!("{cookie}; Max-Age=3600")).unwrap())
+        .collect();
+    jar.set_cookies(&mut headers.iter(), &url());
+    jar
+}
+
+fn sent(path: &Path) -> String {
+    let jar = CookieJar::new(&[], Some(path), DOMAINS).unwrap();
+    let header = jar.cookies(&url()).unwrap();
+    let mut cookies: Vec<&str> = header.to_str().unwrap().split("; ").collect();
+    cookies.sort();
+    cookies.join("; ")
+}
+
+#[test]
+fn jars_saved_at_once_keep_each_others_changes() {
+    let dir = scratch("merge");
+    let path = dir.join("cookies.json");
+    jar(&path, &["shared=old", "kept=yes"]).save().unwrap();
+
+    let first = jar(&path, &["shared=first", "one=1"]);
+    let second = jar(&path, &["two=2"]);
+    std::thread::scope(|scope| {
+        scope.spawn(|| first.save().unwrap());
+        scope.spawn(|| second.save().unwrap());
+    });
+
+    assert_eq!(sent(&path), "kept=yes; one=1; shared=first; two=2");
+    fs::remove_dir_all(dir).ok();
+}
+
+#[cfg(unix)]
+#[test]
+fn the_jar_file_is_only_readable_by_the_user() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = scratch("mode");
+    let path = dir.join("cookies.json");
+    jar(&path, &["session=abc"]).save().unwrap();
+
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    fs::remove_dir_all(dir).ok();
+}
@@ -0,0 +1,99 @@
+//! Request budgets for provider APIs (`--twitch-rate-limit`,
+//! `--youtube-rate-limit`), shared by every job of the process. Requests
+//! over budget wait their turn instead of going out and coming back as 429s,
+//! so mass VOD downloads and channel polling stay under anti-abuse limits.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// Waits at least this long are logged, as a sign the budget is too tight
+/// for the number of jobs.
+const NOTABLE_WAIT: Duration = Duration::from_secs(5);
+
+static BUDGETS: Mutex<Vec<Budget>> = Mutex::new(Vec::new());
+
+/// A token bucket that holds ten seconds' worth of requests, so short
+/// bursts go out at once and longer ones are spread over the minute.
+struct Budget {
+    provider: &'static str,
+    hosts: &'static [&'static str],
+    per_minute: u32,
+    /// Below zero when requests are queued.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Budget {
+    fn burst(&self) -> f64 {
+        (f64::from(self.per_minute) / 6.0).max(1.0)
+    }
+
+    /// Takes a request from the budget and returns how long it has to wait
+    /// for its turn.
+    fn take(&mut self, now: Instant) -> Duration {
+        let rate = f64::from(self.per_minute) / 60.0;
+        let refill = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(self.burst());
+        self.updated = now;
+        self.tokens -= 1.0;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+/// Limits requests to `hosts` to `per_minute`. Changing the limit keeps the
+/// requests already queued.
+pub fn set_rate_limit(provider: &'static str, hosts: &'static [&'static str], per_minute: u32) {
+    let mut budgets = BUDGETS.lock().unwrap_or_else(|e| e.into_inner());
+    match budgets
+        .iter_mut()
+        .find(|budget| budget.provider == provider)
+    {
+        Some(budget) => budget.per_minute = per_minute.max(1),
+        None => {
+            let mut budget = Budget {
+                provider,
+                hosts,
+                per_minute: per_minute.max(1),
+                tokens: 0.0,
+                updated: Instant::now(),
+            };
+            budget.tokens = budget.burst();
+            budgets.push(budget);
+        }
+    }
+}
+
+/// Sleeps until a request to `host` fits its provider's budget.
+pub fn wait_turn(host: &str) {
+    let (provider, per_minute, wait) = {
+        let mut budgets = BUDGETS.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(budget) = budgets
+            .iter_mut()
+            .find(|budget| budget.hosts.contains(&host))
+        else {
+            return;
+        };
+        (
+            budget.provider,
+            budget.per_minute,
+            budget.take(Instant::now()),
+        )
+    };
+    if wait >= NOTABLE_WAIT {
+        info!(
+            "Waiting {:.0}s for a turn within the {provider} budget of {per_minute} requests a minute",
+            wait.as_secs_f64()
+        );
+    } else if !wait.is_zero() {
+        debug!(
+            "Waiting {:.1}s for a {provider} request",
+            wait.as_secs_f64()
+        );
+    }
+    std::thread::sleep(wait);
+}
@@ -0,0 +1,209 @@
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::Deserialize;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::task::{Poll, Waker};
+use url::Url;
+
+#[cfg(test)]
+mod tests;
+
+type LookupError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Restricts connections to one IP address family.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    fn flag(self) -> &'static str {
+        match self {
+            AddressFamily::V4 => "-4",
+            AddressFamily::V6 => "-6",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AddressFamily::V4 => "IPv4",
+            AddressFamily::V6 => "IPv6",
+        }
+    }
+
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+/// Resolves through the system resolver or a DNS-over-HTTPS server, and drops
+/// addresses outside the requested family.
+pub struct Resolver {
+    family: Option<AddressFamily>,
+    doh: Option<DohServer>,
+}
+
+#[derive(Clone)]
+struct DohServer {
+    client: Client,
+    url: Url,
+}
+
+impl Resolver {
+    pub fn new(family: Option<AddressFamily>, doh: Option<&str>) -> Result<Self> {
+        let doh = doh
+            .map(|url| -> Result<DohServer> {
+                let url = Url::parse(url).with_context(|| format!("Invalid DoH URL '{url}'"))?;
+                if url.scheme() != "https" {
+                    bail!("The DoH server URL must use https");
+                }
+                // The DoH server itself is looked up with the system resolver.
+                let client = Client::builder()
+                    .build()
+                    .context("Failed to build DoH client")?;
+                Ok(DohServer { client, url })
+            })
+            .transpose()?;
+        Ok(Resolver { family, doh })
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let family = self.family;
+        let doh = self.doh.clone();
+        let host = name.as_str().to_string();
+        let lookup = on_thread(move || {
+            let addrs = match doh {
+                Some(server) => server.lookup(&host, family)?,
+                None => (host.as_str(), 0).to_socket_addrs()?.collect(),
+            };
+            let filtered: Vec<SocketAddr> = addrs
+                .into_iter()
+                .filter(|a| family.is_none_or(|f| f.matches(a)))
+                .collect();
+            if filtered.is_empty() {
+                let kind = family.map(AddressFamily::name).unwrap_or("IP");
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    format!("{host} has no {kind} address"),
+                )
+                .into());
+            }
+            Ok(filtered)
+        });
+        Box::pin(async move { Ok(Box::new(lookup.await?.into_iter()) as Addrs) })
+    }
+}
+
+/// Runs `lookup` on a thread of its own, since both the system resolver and
+/// the blocking DoH client block, and resolves to its result.
+fn on_thread(
+    lookup: impl FnOnce() -> Result<Vec<SocketAddr>, LookupError> + Send + 'static,
+) -> impl Future<Output = Result<Vec<SocketAddr>, LookupError>> {
+    type Slot = (Option<Result<Vec<SocketAddr>, LookupError>>, Option<Waker>);
+    let slot: Arc<Mutex<Slot>> = Arc::default();
+    let filled = slot.clone();
+    std::thread::spawn(move || {
+        let result = std::panic::catch_unwind(AssertUnwindSafe(lookup))
+            .unwrap_or_else(|_| Err("DNS lookup panicked".into()));
+        let mut slot = filled.lock().unwrap_or_else(|e| e.into_inner());
+        slot.0 = Some(result);
+        if let Some(waker) = slot.1.take() {
+            waker.wake();
+        }
+    });
+    std::future::poll_fn(move |cx| {
+        let mut slot = slot.lock().unwrap_or_else(|e| e.into_inner());
+        match slot.0.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    kind: u16,
+    data: String,
+}
+
+impl DohServer {
+    /// Queries A and AAAA records using the JSON API understood by
+    /// Cloudflare, Google and most public DoH servers.
+    fn lookup(
+        &self,
+        host: &str,
+        family: Option<AddressFamily>,
+    ) -> Result<Vec<SocketAddr>, LookupError> {
+        let mut addrs = Vec::new();
+        for (record, kind) in [("A", AddressFamily::V4), ("AAAA", AddressFamily::V6)] {
+            if family.is_some_and(|f| f != kind) {
+                continue;
+            }
+            let response: DohResponse = self
+                .client
+                .get(self.url.clone())
+                .query(&[("name", host), ("type", record)])
+                .header("accept", "application/dns-json")
+                .send()?
+                .error_for_status()?
+                .json()?;
+            // Answers also contain CNAME records, which do not parse as IPs.
+            addrs.extend(
+                response
+                    .answer
+                    .iter()
+                    .filter(|a| a.kind == 1 || a.kind == 28)
+                    .filter_map(|a| a.data.parse::<IpAddr>().ok())
+                    .map(|ip| SocketAddr::new(ip, 0)),
+            );
+        }
+        Ok(addrs)
+    }
+}
+
+/// Parses a `--resolve HOST:IP` override. IPv6 addresses may be bracketed,
+/// and must not contradict `-4` or `-6`.
+pub fn parse_override(value: &str, family: Option<AddressFamily>) -> Result<(String, SocketAddr)> {
+    let (host, ip) = value
+        .split_once(':')
+        .with_context(|| format!("Invalid --resolve '{value}', expected HOST:IP"))?;
+    let ip: IpAddr = ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .with_context(|| format!("Invalid IP address in --resolve '{value}'"))?;
+    if host.is_empty() {
+        bail!("Invalid --resolve '{value}', expected HOST:IP");
+    }
+    let addr = SocketAddr::new(ip, 0);
+    if let Some(family) = family
+        && !family.matches(&addr)
+    {
+        bail!(
+            "--resolve '{value}' is not an {} address, which {} asks for",
+            family.name(),
+            family.flag()
+        );
+    }
+    Ok((host.to_string(), addr))
+}
@@ -0,0 +1,70 @@
+use reqwest::blocking::Client;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::{AddressFamily, DohServer, Resolver, parse_override};
+use crate::hls::tests::server::{TestServer, response};
+
+#[test]
+fn overrides_parse_both_families() {
+    let (host, addr) = parse_override("example.com:192.0.2.1", None).unwrap();
+    assert_eq!(host, "example.com");
+    assert_eq!(addr, SocketAddr::from(([192, 0, 2, 1], 0)));
+
+    let (_, addr) = parse_override("example.com:[2001:db8::1]", Some(AddressFamily::V6)).unwrap();
+    assert_eq!(addr, "[2001:db8::1]:0".parse().unwrap());
+
+    assert!(parse_override("example.com", None).is_err());
+    assert!(parse_override(":192.0.2.1", None).is_err());
+    assert!(parse_override("example.com:not-an-ip", None).is_err());
+}
+
+#[test]
+fn overrides_must_match_the_requested_family() {
+    let error = parse_override("example.com:2001:db8::1", Some(AddressFamily::V4)).unwrap_err();
+    assert!(error.to_string().contains("-4"), "{error}");
+    assert!(parse_override("example.com:192.0.2.1", Some(AddressFamily::V6)).is_err());
+}
+
+#[test]
+fn the_system_resolver_is_filtered_by_family() {
+    let server = TestServer::start(vec![response(200, &[], "ok")]);
+    let client = |family| {
+        Client::builder()
+            .dns_resolver(Arc::new(Resolver::new(Some(family), None).unwrap()))
+            .build()
+            .unwrap()
+    };
+    let url = server.url("localhost", "/");
+
+    let body = client(AddressFamily::V4).get(url).send().unwrap().text();
+    assert_eq!(body.unwrap(), "ok");
+    // Only the IPv4 server listens, whatever localhost resolves to.
+    assert!(
+        client(AddressFamily::V6)
+            .get(server.url("localhost", "/"))
+            .send()
+            .is_err()
+    );
+}
+
+#[test]
+fn doh_answers_keep_only_addresses() {
+    let answer = r#"{"Status": 0, "Answer": [
+        {"name": "example.com", "type": 5, "data": "cdn.example.net."},
+        {"name": "cdn.example.net", "type": 1, "data": "192.0.2.7"}
+    ]}"#;
+    let server = TestServer::start(vec![response(200, &[], answer)]);
+    let doh = DohServer {
+        client: Client::new(),
+        url: server.url("127.0.0.1", "/dns-query"),
+    };
+
+    let addrs = doh.lookup("example.com", Some(AddressFamily::V4)).unwrap();
+
+    assert_eq!(addrs, [SocketAddr::from(([192, 0, 2, 7], 0))]);
+    assert_eq!(
+        server.requests(),
+        ["GET /dns-query?name=example.com&type=A HTTP/1.1"]
+    );
+}
@@ -0,0 +1,240 @@
+use reqwest::StatusCode;
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::header::RETRY_AFTER;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+use crate::hls::StopHandle;
+
+#[cfg(test)]
+mod tests;
+
+/// Longest `Retry-After` that is honoured, so a bogus value cannot stall a
+/// recording for hours.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+
+/// Hosts that answered 429 and until when every request to them waits, so
+/// the playlist poller, segment downloads and API calls all back off
+/// together instead of making the rate limit worse.
+static THROTTLED: Mutex<Vec<(String, Instant)>> = Mutex::new(Vec::new());
+
+/// Retry budget and exponential backoff for one kind of request.
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// Attempts after the first one.
+    pub retries: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Retry {
+    /// Token, GQL and page requests made once before streaming starts.
+    pub const API: Retry = Retry {
+        retries: 3,
+        base: Duration::from_millis(500),
+        max: Duration::from_secs(4),
+    };
+    /// Media playlist reloads. Live playlists are polled again anyway, so
+    /// this only bounds how long a broken playlist is tolerated.
+    pub const PLAYLIST: Retry = Retry {
+        retries: 2,
+        base: Duration::from_millis(500),
+        max: Duration::from_secs(2),
+    };
+    /// Status checks that are repeated anyway, sent once but still within
+    /// rate limits.
+    pub const ONCE: Retry = Retry {
+        retries: 0,
+        base: Duration::from_millis(500),
+        max: Duration::from_secs(2),
+    };
+    /// Whole `--url-file` and API jobs; `retries` comes from `--job-retries`.
+    pub const JOB: Retry = Retry {
+        retries: 0,
+        base: Duration::from_secs(30),
+        max: Duration::from_secs(600),
+    };
+    /// Segment downloads, which must finish before the next one is due.
+    pub const SEGMENT: Retry = Retry {
+        retries: 2,
+        base: Duration::from_millis(250),
+        max: Duration::from_secs(1),
+    };
+
+    /// Delay before retry number `attempt` (starting at 0): exponential,
+    /// capped, with the upper half randomized so parallel clients spread out.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.base.saturating_mul(1 << attempt.min(16)).min(self.max);
+        let half = exp / 2;
+        let jitter = RandomState::new().build_hasher().finish() % (half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+
+    /// Sends `request`, retrying transport errors and transient statuses.
+    /// Other error statuses are returned as-is for the caller to handle.
+    ///
+    /// A 429 pauses every request to that host for its `Retry-After`, and
+    /// requests to provider APIs wait for their turn within `--*-rate-limit`.
+    pub fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        self.send_stoppable(request, None)
+    }
+
+    /// [`Retry::send`] that stops waiting and retrying once `stop` is
+    /// stopped, sending the request as it is.
+    pub fn send_stoppable(
+        &self,
+        request: RequestBuilder,
+        stop: Option<&StopHandle>,
+    ) -> reqwest::Result<Response> {
+        let host = request
+            .try_clone()
+            .and_then(|request| request.build().ok())
+            .and_then(|request| request.url().host_str().map(String::from));
+        let mut attempt = 0;
+        loop {
+            let stopped = stop.is_some_and(StopHandle::is_stopped);
+            let next = (attempt < self.retries && !stopped)
+                .then(|| request.try_clone())
+                .flatten();
+            if let Some(host) = &host {
+                wait_for(host, stop);
+                super::ratelimit::wait_turn(host);
+            }
+            let Some(next) = next else {
+                let response = request.send()?;
+                if let Some(host) = &host {
+                    note_rate_limit(host, &response, self.delay(attempt));
+                }
+                return Ok(response);
+            };
+            match next.send() {
+                Ok(response) if !is_transient(response.status()) => return Ok(response),
+                Ok(response) => {
+                    debug!(
+                        "{} returned {}, retrying",
+                        response.url(),
+                        response.status()
+                    );
+                    if let Some(host) = &host
+                        && note_rate_limit(host, &response, self.delay(attempt))
+                    {
+                        // `wait_for` sleeps until the host may be asked again.
+                        attempt += 1;
+                        continue;
+                    }
+                }
+                Err(err) if err.is_builder() || err.is_redirect() => return Err(err),
+                Err(err) => debug!("Request failed, retrying: {err}"),
+            }
+            pause(self.delay(attempt), stop);
+            attempt += 1;
+        }
+    }
+}
+
+/// Sleeps for `duration`, or until `stop` is stopped.
+fn pause(duration: Duration, stop: Option<&StopHandle>) {
+    match stop {
+        Some(stop) => {
+            stop.sleep(duration);
+        }
+        None => std::thread::sleep(duration),
+    }
+}
+
+/// Sleeps while `host` is rate limiting.
+fn wait_for(host: &str, stop: Option<&StopHandle>) {
+    let until = {
+        let mut throttled = THROTTLED.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        throttled.retain(|(_, until)| *until > now);
+        throttled
+            .iter()
+            .find(|(known, _)| known == host)
+            .map(|(_, until)| *until)
+    };
+    if let Some(until) = until {
+        pause(until.saturating_duration_since(Instant::now()), stop);
+    }
+}
+
+/// Pauses requests to `host` if `response` is a 429, for its `Retry-After`
+/// or else `fallback`. Returns whether it was one.
+fn note_rate_limit(host: &str, response: &Response, fallback: Duration) -> bool {
+    if response.status() != StatusCode::TOO_MANY_REQUESTS {
+        return false;
+    }
+    let delay = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_retry_after)
+        .unwrap_or(fallback)
+        .min(MAX_RETRY_AFTER);
+    let until = Instant::now() + delay;
+
+    let mut throttled = THROTTLED.lock().unwrap_or_else(|e| e.into_inner());
+    match throttled.iter_mut().find(|(known, _)| known == host) {
+        Some((_, known)) => *known = (*known).max(until),
+        None => {
+            warn!(
+                "{host} is rate limiting requests, pausing them for {:.1}s",
+                delay.as_secs_f64()
+            );
+            throttled.push((host.to_string(), until));
+        }
+    }
+    true
+}
+
+/// `Retry-After` is either seconds or an HTTP date.
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = chrono::DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        SystemTime::from(date)
+            .duration_since(SystemTime::now())
+            .unwrap_or_default(),
+    )
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+}
+
+/// Counts consecutive failures of a recurring request against its budget.
+pub struct Backoff {
+    policy: Retry,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: Retry) -> Self {
+        Backoff {
+            policy,
+            failures: 0,
+        }
+    }
+
+    /// Records a failure and returns how long to wait before trying again.
+    pub fn failed(&mut self) -> Duration {
+        let delay = self.policy.delay(self.failures);
+        self.failures += 1;
+        delay
+    }
+
+    pub fn exhausted(&self) -> bool {
+        self.failures > self.policy.retries
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
@@ -0,0 +1,132 @@
+use reqwest::blocking::Client;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::{Backoff, MAX_RETRY_AFTER, Retry, THROTTLED, parse_retry_after};
+use crate::hls::tests::server::{TestServer, response};
+
+const POLICY: Retry = Retry {
+    retries: 2,
+    base: Duration::from_millis(100),
+    max: Duration::from_secs(1),
+};
+
+/// A client that reaches `server` under `host`, so each test throttles a
+/// host of its own.
+fn client(server: &TestServer, host: &str) -> Client {
+    let port = server.url("127.0.0.1", "/").port().unwrap();
+    Client::builder()
+        .resolve(host, SocketAddr::from(([127, 0, 0, 1], port)))
+        .build()
+        .unwrap()
+}
+
+fn millis(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn delays_double_with_jitter_in_the_upper_half() {
+    let bounds = [(50, 100), (100, 200), (200, 400), (400, 800)];
+
+    for (attempt, (low, high)) in bounds.into_iter().enumerate() {
+        for _ in 0..20 {
+            let delay = POLICY.delay(attempt as u32);
+            assert!(
+                (millis(low)..=millis(high)).contains(&delay),
+                "attempt {attempt}: {delay:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn delays_stop_growing_at_the_cap() {
+    for attempt in [4, 10, 16, 17, 64, u32::MAX] {
+        let delay = POLICY.delay(attempt);
+        assert!(
+            (millis(500)..=millis(1000)).contains(&delay),
+            "attempt {attempt}: {delay:?}"
+        );
+    }
+}
+
+#[test]
+fn backoff_runs_out_after_the_retries_and_starts_over_on_reset() {
+    let mut backoff = Backoff::new(POLICY);
+
+    assert!(backoff.failed() <= millis(100));
+    assert!(backoff.failed() <= millis(200));
+    assert!(!backoff.exhausted());
+    assert!(backoff.failed() >= millis(200));
+    assert!(backoff.exhausted());
+
+    backoff.reset();
+    assert!(!backoff.exhausted());
+    assert!(backoff.failed() <= millis(100));
+}
+
+#[test]
+fn retry_after_is_seconds_or_a_date() {
+    assert_eq!(parse_retry_after("5"), Some(Duration::from_secs(5)));
+    assert_eq!(parse_retry_after(" 7 "), Some(Duration::from_secs(7)));
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+        Some(Duration::ZERO)
+    );
+    let in_a_minute =
+        chrono::DateTime::<chrono::Utc>::from(SystemTime::now() + Duration::from_secs(60))
+            .to_rfc2822();
+    let delay = parse_retry_after(&in_a_minute).unwrap();
+    assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+    assert_eq!(parse_retry_after("soon"), None);
+    assert_eq!(parse_retry_after("-1"), None);
+}
+
+#[test]
+fn a_429_is_retried_after_its_retry_after() {
+    let server = TestServer::start(vec![
+        response(429, &[("Retry-After", "1")], ""),
+        response(200, &[], "ok"),
+    ]);
+    let client = client(&server, "retry-after.test");
+    let url = server.url("retry-after.test", "/api");
+
+    let started = Instant::now();
+    let response = POLICY.send(client.get(url.clone())).unwrap();
+
+    assert_eq!(response.status(), 200);
+    // Much longer than the 100ms backoff the policy would have used.
+    assert!(started.elapsed() >= Duration::from_millis(900));
+    assert_eq!(server.requests().len(), 2);
+
+    // The host may be asked again right away now.
+    let started = Instant::now();
+    Retry::ONCE.send(client.get(url)).unwrap();
+    assert!(started.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn a_long_retry_after_is_capped() {
+    let server = TestServer::start(vec![response(429, &[("Retry-After", "86400")], "")]);
+    let client = client(&server, "capped.test");
+
+    let response = Retry::ONCE
+        .send(client.get(server.url("capped.test", "/api")))
+        .unwrap();
+
+    assert_eq!(response.status(), 429);
+    let until = THROTTLED
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(host, _)| host == "capped.test")
+        .map(|(_, until)| *until)
+        .unwrap();
+    assert!(until <= Instant::now() + MAX_RETRY_AFTER);
+    assert!(until > Instant::now() + MAX_RETRY_AFTER - Duration::from_secs(10));
+    THROTTLED
+        .lock()
+        .unwrap()
+        .retain(|(host, _)| host != "capped.test");
+}
@@ -0,0 +1,105 @@
+//! `--library-layout`: names recordings the way media servers such as
+//! Jellyfin and Plex expect and writes an NFO file with the stream's details
+//! next to each one.
+
+use anyhow::{Context, Result};
+use chrono::Local;
+use fors_core::provider::StreamMetadata;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Layouts that can be given by name instead of as a template.
+const PRESETS: [(&str, &str); 2] = [
+    // A show per channel with date based episodes, for TV libraries.
+    (
+        "series",
+        "{channel}/Season {year}/{channel} - {date} - {title}.mkv",
+    ),
+    // A folder per channel, for home video libraries.
+    ("channel", "Channel/{channel}/{date} - {title}.mkv"),
+];
+
+/// The output template for `layout`, a preset name or a template, inside
+/// the library directory `root`.
+pub fn template(layout: &str, root: Option<&str>) -> String {
+    let layout = PRESETS
+        .iter()
+        .find(|(name, _)| *name == layout)
+        .map_or(layout, |(_, template)| template);
+    match root {
+        Some(root) => Path::new(root).join(layout).to_string_lossy().into_owned(),
+        None => layout.to_string(),
+    }
+}
+
+/// Where the NFO file for `output` goes.
+pub fn nfo_path(output: &Path) -> PathBuf {
+    output.with_extension("nfo")
+}
+
+pub fn write_nfo(
+    output: &Path,
+    metadata: &StreamMetadata,
+    provider: Option<&str>,
+    runtime: f64,
+) -> Result<PathBuf> {
+    let path = nfo_path(output);
+    fs::write(&path, to_nfo(metadata, provider, runtime))
+        .with_context(|| format!("Failed to write NFO file to {}", path.display()))?;
+    Ok(path)
+}
+
+/// An `episodedetails` document, which Jellyfin, Plex (with the XBMCnfo
+/// agent) and Kodi read.
+fn to_nfo(metadata: &StreamMetadata, provider: Option<&str>, runtime: f64) -> String {
+    let aired = metadata
+        .started_at
+        .as_deref()
+        .and_then(|started| started.get(..10))
+        .map_or_else(
+            || Local::now().format("%Y-%m-%d").to_string(),
+            str::to_string,
+        );
+    let studio = provider.map(|provider| match provider {
+        "twitch" => "Twitch",
+        "youtube" => "YouTube",
+        other => other,
+    });
+    let minutes = ((runtime / 60.0).round() as u64).to_string();
+
+    let mut nfo = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+    nfo.push_str("<episodedetails>\n");
+    for (tag, value) in [
+        ("title", metadata.title.as_deref()),
+        ("showtitle", metadata.author.as_deref()),
+        ("aired", Some(aired.as_str())),
+        ("genre", metadata.category.as_deref()),
+        ("studio", studio),
+        ("runtime", Some(minutes.as_str())),
+        ("thumb", metadata.thumbnail.as_deref()),
+    ] {
+        if let Some(value) = value {
+            writeln!(nfo, "  <{tag}>{}</{tag}>", escape(value)).ok();
+        }
+    }
+    nfo.push_str("</episodedetails>\n");
+    nfo
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Not allowed in XML 1.0 at all.
+            c if c.is_control() && !matches!(c, '\t' | '\n' | '\r') => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,205 @@
+use anyhow::{Context, Result};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::level_filters::LevelFilter;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, Targets};
+use tracing_subscriber::fmt::format::{FmtSpan, Format, FormatEvent, FormatFields, Writer};
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+thread_local! {
+    /// The stream this thread is recording in multi-stream mode.
+    static STREAM: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Tags log lines of the current thread with `[name]` until dropped.
+pub struct StreamScope(());
+
+pub fn stream_scope(name: &str) -> StreamScope {
+    STREAM.with(|stream| *stream.borrow_mut() = Some(name.into()));
+    StreamScope(())
+}
+
+impl Drop for StreamScope {
+    fn drop(&mut self) {
+        STREAM.with(|stream| stream.borrow_mut().take());
+    }
+}
+
+fn current_stream() -> Option<Arc<str>> {
+    STREAM.with(|stream| stream.borrow().clone())
+}
+
+/// Sets up logging from the `-v`/`-q` counts. `RUST_LOG` still applies when
+/// neither flag is given, for fine grained per-module filters.
+///
+/// With `trace_json`, every event and the timing of the playlist reload and
+/// segment spans are also written there as JSON lines, regardless of the
+/// console level. With `log_split`, each stream of a multi-stream run also
+/// gets its own log file in that directory. With `tui`, nothing is logged to
+/// the console.
+pub fn init(
+    verbose: u8,
+    quiet: u8,
+    logfile: Option<&Path>,
+    trace_json: Option<&Path>,
+    log_split: Option<&Path>,
+    tui: bool,
+) -> Result<()> {
+    let level = match i16::from(verbose) - i16::from(quiet) {
+        ..=-2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    };
+
+    let filter = || match EnvFilter::try_from_default_env() {
+        Ok(filter) if verbose == 0 && quiet == 0 => filter,
+        _ => EnvFilter::default().add_directive(level.into()),
+    };
+
+    let console = match logfile {
+        Some(path) => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(Mutex::new(open_append(path)?))
+                .event_format(Prefixed(Format::default()))
+                .boxed(),
+        ),
+        // The status screen owns the terminal.
+        None if tui => None,
+        None => Some(
+            tracing_subscriber::fmt::layer()
+                .with_ansi(std::io::stderr().is_terminal())
+                .with_writer(std::io::stderr)
+                .event_format(Prefixed(Format::default().without_time()))
+                .boxed(),
+        ),
+    };
+
+    let split = log_split
+        .map(|dir| -> Result<_> {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create log directory {}", dir.display()))?;
+            Ok(tracing_subscriber::fmt::layer()
+                .with_ansi(false)
+                .with_writer(SplitLogs {
+                    dir: dir.to_path_buf(),
+                    files: Mutex::default(),
+                })
+                .with_filter(filter()))
+        })
+        .transpose()?;
+
+    let trace = trace_json
+        .map(|path| -> Result<_> {
+            Ok(tracing_subscriber::fmt::layer()
+                .json()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(Mutex::new(open_append(path)?))
+                .with_filter(
+                    Targets::new()
+                        .with_target("fors", LevelFilter::TRACE)
+                        .with_default(LevelFilter::INFO),
+                ))
+        })
+        .transpose()?;
+
+    tracing_subscriber::registry()
+        .with(console.map(|console| console.with_filter(filter())))
+        .with(split)
+        .with(trace)
+        .try_init()
+        .context("Failed to initialize logging")
+}
+
+/// Puts the `[name]` of the current stream in front of each line.
+struct Prefixed<F>(F);
+
+impl<S, N, F> FormatEvent<S, N> for Prefixed<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> std::fmt::Result {
+        if let Some(stream) = current_stream() {
+            write!(writer, "[{stream}] ")?;
+        }
+        self.0.format_event(ctx, writer, event)
+    }
+}
+
+/// Writes events logged while recording a stream to `<dir>/<stream>.log`.
+/// Everything else is dropped.
+struct SplitLogs {
+    dir: PathBuf,
+    files: Mutex<HashMap<Arc<str>, Arc<Mutex<File>>>>,
+}
+
+impl SplitLogs {
+    fn file(&self, stream: Arc<str>) -> Option<Arc<Mutex<File>>> {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(file) = files.get(&stream) {
+            return Some(file.clone());
+        }
+        let name: String = stream
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || "-_.".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let file = Arc::new(Mutex::new(
+            open_append(&self.dir.join(format!("{name}.log"))).ok()?,
+        ));
+        files.insert(stream, file.clone());
+        Some(file)
+    }
+}
+
+impl<'a> MakeWriter<'a> for SplitLogs {
+    type Writer = StreamLog;
+
+    fn make_writer(&'a self) -> StreamLog {
+        StreamLog(current_stream().and_then(|stream| self.file(stream)))
+    }
+}
+
+struct StreamLog(Option<Arc<Mutex<File>>>);
+
+impl Write for StreamLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &self.0 {
+            Some(file) => file.lock().unwrap_or_else(|e| e.into_inner()).write(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
@@ -0,0 +1,152 @@
+//! Merging a video variant with a separately delivered audio track, or
+//! remuxing raw YouTube formats. The inputs are independent streams, so the
+//! interleaving is left to ffmpeg.
+
+use anyhow::{Context, Result, bail};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::Instant;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::disk::DiskGuard;
+use crate::hls::{EndReason, StopConditions, StreamSummary};
+use crate::http::HttpOptions;
+use crate::systemd;
+
+#[cfg(test)]
+mod tests;
+
+/// What ffmpeg writes the inputs as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    MpegTs,
+    /// For the VP9, AV1 and Opus formats of YouTube, which MPEG-TS cannot
+    /// carry.
+    Matroska,
+}
+
+impl Container {
+    /// MPEG-TS if it can carry every one of `codecs` (RFC 6381 names such as
+    /// `avc1.640028`), Matroska otherwise.
+    pub fn for_codecs<'a>(codecs: impl IntoIterator<Item = &'a str>) -> Self {
+        const TS_CODECS: &[&str] = &["avc1", "avc3", "hev1", "hvc1", "mp4a", "ac-3", "ec-3"];
+        let fits_ts = codecs.into_iter().all(|codec| {
+            let family = codec.trim().split('.').next().unwrap_or_default();
+            TS_CODECS.contains(&family)
+        });
+        if fits_ts {
+            Container::MpegTs
+        } else {
+            Container::Matroska
+        }
+    }
+
+    fn format(self) -> &'static str {
+        match self {
+            Container::MpegTs => "mpegts",
+            Container::Matroska => "matroska",
+        }
+    }
+}
+
+/// What a mux writes, and watches for while it runs.
+pub struct MuxOptions {
+    pub container: Container,
+    pub is_live: bool,
+    pub disk_guard: Option<DiskGuard>,
+    pub stop: StopConditions,
+}
+
+/// Streams `inputs` into `writer` as `options.container` until they end or a stop
+/// condition is met. With two inputs, the video comes from the first and the
+/// audio from the second.
+pub fn mux_to_writer(
+    ffmpeg: &str,
+    inputs: &[&Url],
+    http: &HttpOptions,
+    writer: &mut dyn Write,
+    mut options: MuxOptions,
+) -> Result<StreamSummary> {
+    let mut input_options = Vec::new();
+    if let Some(agent) = &http.user_agent {
+        input_options.extend(["-user_agent".to_string(), agent.clone()]);
+    }
+    if let Some(proxy) = &http.proxy {
+        input_options.extend(["-http_proxy".to_string(), proxy.clone()]);
+    }
+
+    let mut cmd = Command::new(ffmpeg);
+    cmd.args(["-hide_banner", "-loglevel", "error"]);
+    for input in inputs {
+        cmd.args(&input_options).arg("-i").arg(input.as_str());
+    }
+    if inputs.len() == 2 {
+        cmd.args(["-map", "0:v:0", "-map", "1:a:0"]);
+    }
+    cmd.args(["-c", "copy", "-f", options.container.format(), "pipe:1"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+
+    info!("Muxing {} inputs with {ffmpeg}", inputs.len());
+    let mut child = cmd.spawn().with_context(|| {
+        format!("Failed to start '{ffmpeg}' (needed for --audio-lang and --format)")
+    })?;
+    let mut stdout = child.stdout.take().context("ffmpeg has no stdout")?;
+
+    let started = Instant::now();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut written = 0u64;
+    let result = loop {
+        if let Some(reason) = options.stop.reason(written) {
+            break Ok(Some(reason));
+        }
+        if let Some(guard) = options.disk_guard.as_mut()
+            && let Err(err) = guard.check()
+        {
+            warn!("Stopping ({}): {err:#}", EndReason::DiskFull);
+            break Ok(Some(EndReason::DiskFull));
+        }
+        match stdout.read(&mut buf) {
+            Ok(0) => break Ok(None),
+            Ok(n) => {
+                if let Err(err) = writer.write_all(&buf[..n]) {
+                    break Err(err).context("Failed to write muxed stream");
+                }
+                written += n as u64;
+                systemd::heartbeat();
+            }
+            Err(err) => break Err(err).context("Failed to read from ffmpeg"),
+        }
+    };
+
+    let stopped = !matches!(result, Ok(None));
+    if stopped {
+        let _ = child.kill();
+    }
+    if result.is_err() {
+        writer.flush().ok();
+    }
+    let status = child.wait().context("Failed to wait for ffmpeg")?;
+    let stop_reason = result?;
+    if !status.success() && !stopped {
+        bail!("ffmpeg exited with {status}");
+    }
+    let end_reason = stop_reason.unwrap_or(if options.is_live {
+        EndReason::EndOfPlaylist
+    } else {
+        EndReason::EndOfVod
+    });
+    Ok(StreamSummary {
+        bytes_written: written,
+        elapsed: started.elapsed(),
+        // ffmpeg does not say how much media it wrote.
+        segments: 0,
+        output_time: 0.0,
+        ad_time: 0.0,
+        ad_gaps: Vec::new(),
+        seek_points: Vec::new(),
+        timed_metadata: Vec::new(),
+        end_reason,
+    })
+}
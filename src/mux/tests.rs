@@ -0,0 +1,22 @@
+use super::Container;
+
+#[test]
+fn h264_and_aac_stay_in_mpeg_ts() {
+    assert_eq!(
+        Container::for_codecs(["avc1.640028", "mp4a.40.2"]),
+        Container::MpegTs
+    );
+}
+
+#[test]
+fn vp9_av1_and_opus_go_to_matroska() {
+    assert_eq!(Container::for_codecs(["vp9"]), Container::Matroska);
+    assert_eq!(
+        Container::for_codecs(["av01.0.08M.08", "mp4a.40.2"]),
+        Container::Matroska
+    );
+    assert_eq!(
+        Container::for_codecs(["avc1.4d401f", "opus"]),
+        Container::Matroska
+    );
+}
@@ -0,0 +1,217 @@
+//! Built-in notifications for recordings, configured in the `[notify]` table
+//! of `config.toml`:
+//!
+//! ```toml
+//! [notify]
+//! discord = "https://discord.com/api/webhooks/ID/TOKEN"
+//! webhook = "https://example.com/fors"   # gets the event as JSON
+//! telegram = { bot_token = "123:ABC", chat_id = "-100123" }
+//! events = ["live", "finished", "failed"]
+//!
+//! [notify.messages]
+//! finished = "{stream} done: {size} in {duration}"
+//! ```
+//!
+//! Messages may use `{url}`, `{stream}`, `{quality}`, `{size}`, `{duration}`
+//! and `{error}`.
+
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::template;
+use crate::units::{format_byte_size, format_duration};
+
+static NOTIFIER: OnceLock<Notifier> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A live stream was found and its recording starts.
+    Live,
+    Finished,
+    Failed,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Live => "live",
+            Kind::Finished => "finished",
+            Kind::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotifyConfig {
+    discord: Option<String>,
+    telegram: Option<Telegram>,
+    webhook: Option<String>,
+    events: Option<Vec<Kind>>,
+    #[serde(default)]
+    messages: Messages,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Telegram {
+    bot_token: String,
+    chat_id: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Messages {
+    live: Option<String>,
+    finished: Option<String>,
+    failed: Option<String>,
+}
+
+pub struct Notification {
+    pub kind: Kind,
+    pub url: String,
+    pub stream: String,
+    pub quality: Option<String>,
+    pub bytes: Option<u64>,
+    pub elapsed: Option<Duration>,
+    pub error: Option<String>,
+}
+
+struct Notifier {
+    config: NotifyConfig,
+    client: Client,
+}
+
+/// Sets up the notifiers for this run. Without a `[notify]` table, sending
+/// does nothing.
+pub fn init(config: NotifyConfig, client: Client) {
+    NOTIFIER.get_or_init(|| Notifier { config, client });
+}
+
+/// Sends `notification` to every configured notifier. Failures are logged
+/// but never abort the recording.
+pub fn send(notification: &Notification) {
+    let Some(notifier) = NOTIFIER.get() else {
+        return;
+    };
+    let config = &notifier.config;
+    if config
+        .events
+        .as_ref()
+        .is_some_and(|events| !events.contains(&notification.kind))
+    {
+        return;
+    }
+
+    let message = notification.message(config);
+    let kind = notification.kind.name();
+    let mut targets: Vec<(&str, String, Value)> = Vec::new();
+    if let Some(url) = &config.discord {
+        targets.push(("Discord", url.clone(), json!({ "content": message })));
+    }
+    if let Some(telegram) = &config.telegram {
+        targets.push((
+            "Telegram",
+            format!(
+                "https://api.telegram.org/bot{}/sendMessage",
+                telegram.bot_token
+            ),
+            json!({ "chat_id": telegram.chat_id, "text": message }),
+        ));
+    }
+    if let Some(url) = &config.webhook {
+        targets.push((
+            "webhook",
+            url.clone(),
+            json!({
+                "event": kind,
+                "message": message,
+                "url": notification.url,
+                "stream": notification.stream,
+                "quality": notification.quality,
+                "bytes": notification.bytes,
+                "duration": notification.elapsed.map(|elapsed| elapsed.as_secs()),
+                "error": notification.error,
+            }),
+        ));
+    }
+
+    for (name, url, body) in targets {
+        let result = notifier
+            .client
+            .post(&url)
+            .timeout(Duration::from_secs(10))
+            .json(&body)
+            .send()
+            .and_then(|response| response.error_for_status())
+            // The Telegram URL holds the bot token.
+            .map_err(reqwest::Error::without_url)
+            .with_context(|| format!("Failed to send the {kind} notification to {name}"));
+        match result {
+            Ok(_) => debug!("Sent the {kind} notification to {name}"),
+            Err(err) => warn!("{err:#}"),
+        }
+    }
+}
+
+/// Sends `notification` without holding up the caller.
+pub fn send_in_background(notification: Notification) {
+    if NOTIFIER.get().is_some() {
+        std::thread::spawn(move || send(&notification));
+    }
+}
+
+impl Notification {
+    pub fn new(kind: Kind, url: &str, stream: String) -> Self {
+        Notification {
+            kind,
+            url: url.to_string(),
+            stream,
+            quality: None,
+            bytes: None,
+            elapsed: None,
+            error: None,
+        }
+    }
+
+    fn message(&self, config: &NotifyConfig) -> String {
+        let custom = match self.kind {
+            Kind::Live => &config.messages.live,
+            Kind::Finished => &config.messages.finished,
+            Kind::Failed => &config.messages.failed,
+        };
+        let default = match self.kind {
+            Kind::Live => "{stream} is live, recording {quality}",
+            Kind::Finished => "Finished recording {stream}: {size} in {duration}",
+            Kind::Failed => "Recording {stream} failed: {error}",
+        };
+        let size = self.bytes.map(format_byte_size).unwrap_or_default();
+        let duration = self.elapsed.map(format_duration).unwrap_or_default();
+        template::expand(
+            custom.as_deref().unwrap_or(default),
+            &[
+                ("url", &self.url),
+                ("stream", &self.stream),
+                ("quality", self.quality.as_deref().unwrap_or_default()),
+                ("size", &size),
+                ("duration", &duration),
+                ("error", self.error.as_deref().unwrap_or_default()),
+            ],
+        )
+    }
+}
+
+/// Checks the `[notify]` table of the config file.
+pub fn parse_config(value: toml::Value) -> Result<NotifyConfig> {
+    let config: NotifyConfig = value.try_into()?;
+    for url in [&config.discord, &config.webhook].into_iter().flatten() {
+        url::Url::parse(url).with_context(|| format!("Invalid notification URL {url}"))?;
+    }
+    Ok(config)
+}
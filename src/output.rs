@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use reqwest::blocking::Client;
+use reqwest::header::HeaderMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use url::Url;
+
+use crate::paths;
+use crate::timeshift::RingBuffer;
+
+mod audio;
+mod flv;
+mod http;
+mod icecast;
+mod player;
+mod rtmp;
+#[cfg(feature = "s3")]
+mod s3;
+mod tap;
+pub mod ts;
+mod udp;
+
+#[cfg(test)]
+mod tests;
+
+pub use audio::{AudioFormat, extract_audio};
+pub use player::PlayerOutput;
+pub use tap::AudioTap;
+
+pub use http::UploadMethod;
+
+/// Where the stream data ends up, as parsed from `--output`.
+#[derive(Debug, Clone)]
+pub enum OutputTarget {
+    Stdout,
+    File(PathBuf),
+    Http(Url),
+    Udp(Url),
+    S3(Url),
+    Icecast(Url),
+    Rtmp(Url),
+}
+
+impl OutputTarget {
+    pub fn parse(spec: Option<&str>) -> Self {
+        match spec {
+            None | Some("-") => OutputTarget::Stdout,
+            Some(value) => match Url::parse(value) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => OutputTarget::Http(url),
+                Ok(url) if matches!(url.scheme(), "udp" | "rtp") => OutputTarget::Udp(url),
+                Ok(url) if url.scheme() == "s3" => OutputTarget::S3(url),
+                Ok(url) if url.scheme() == "icecast" => OutputTarget::Icecast(url),
+                Ok(url) if url.scheme() == "rtmp" => OutputTarget::Rtmp(url),
+                _ => OutputTarget::File(PathBuf::from(value)),
+            },
+        }
+    }
+
+    pub fn local_path(&self) -> Option<&Path> {
+        match self {
+            OutputTarget::File(path) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+/// A stream destination that may need to do work once all data is written.
+pub trait Sink: Write {
+    fn finish(&mut self) -> Result<()> {
+        self.flush().context("Flushing output failed")
+    }
+}
+
+impl Sink for BufWriter<File> {}
+impl Sink for io::Stdout {}
+impl Sink for RingBuffer {}
+
+/// Opens the sink for `target`. `upload` is only called for upload targets.
+/// Files are written through a buffer of `buffer_size` bytes.
+pub fn open(
+    target: &OutputTarget,
+    buffer_size: usize,
+    upload: impl FnOnce() -> Result<UploadOptions>,
+) -> Result<Box<dyn Sink>> {
+    Ok(match target {
+        OutputTarget::Stdout => stdout(),
+        OutputTarget::File(path) => {
+            let file = File::create(paths::long_path(path))
+                .with_context(|| format!("Failed to create output file {}", path.display()))?;
+            Box::new(BufWriter::with_capacity(buffer_size, file))
+        }
+        OutputTarget::Http(url) => {
+            let upload = upload()?;
+            Box::new(http::HttpUpload::start(
+                upload.client,
+                url.clone(),
+                upload.method,
+                upload.headers,
+            ))
+        }
+        OutputTarget::Udp(url) => Box::new(udp::UdpOutput::connect(url)?),
+        OutputTarget::Icecast(url) => Box::new(icecast::IcecastOutput::connect(url)?),
+        OutputTarget::Rtmp(url) => Box::new(rtmp::RtmpOutput::connect(url)?),
+        #[cfg(feature = "s3")]
+        OutputTarget::S3(url) => Box::new(s3::S3Upload::start(upload()?.client, url)?),
+        #[cfg(not(feature = "s3"))]
+        OutputTarget::S3(url) => {
+            anyhow::bail!("Cannot upload to {url}: fors was built without the \"s3\" feature")
+        }
+    })
+}
+
+#[cfg(not(windows))]
+fn stdout() -> Box<dyn Sink> {
+    Box::new(io::stdout())
+}
+
+/// Writes straight to the stdout handle: `io::Stdout` insists on UTF-8 when
+/// it is a console, and nothing may translate the bytes of a recording.
+#[cfg(windows)]
+fn stdout() -> Box<dyn Sink> {
+    use std::mem::ManuallyDrop;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+
+    struct BinaryStdout(ManuallyDrop<File>);
+
+    impl Write for BinaryStdout {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Sink for BinaryStdout {}
+
+    // The handle stays open for the life of the process, so it must not be
+    // closed when the file is dropped.
+    let file = unsafe { File::from_raw_handle(io::stdout().as_raw_handle()) };
+    Box::new(BinaryStdout(ManuallyDrop::new(file)))
+}
+
+pub struct UploadOptions {
+    /// Client without a request timeout, used for long running uploads.
+    pub client: Client,
+    pub method: UploadMethod,
+    /// `--http-header` values for the upload request.
+    pub headers: HeaderMap,
+}
@@ -0,0 +1,177 @@
+use anyhow::{Context, Result, anyhow, bail};
+use clap::ValueEnum;
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread::JoinHandle;
+use tracing::info;
+
+use super::Sink;
+use super::ts::{Demuxer, Pes, STREAM_TYPE_ADTS};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AudioFormat {
+    Mp3,
+    Aac,
+    Opus,
+}
+
+/// Wraps `inner` so only the audio of the stream reaches it. AAC is taken
+/// out of the MPEG-TS as is, MP3 and Opus are encoded by `ffmpeg`.
+pub fn extract_audio(
+    inner: Box<dyn Sink>,
+    format: AudioFormat,
+    ffmpeg: &str,
+) -> Result<Box<dyn Sink>> {
+    Ok(match format {
+        AudioFormat::Aac => Box::new(AacExtractor {
+            inner,
+            demuxer: Demuxer::default(),
+            audio_pid: None,
+        }),
+        AudioFormat::Mp3 => Box::new(AudioTranscoder::spawn(
+            inner,
+            ffmpeg,
+            &["-c:a", "libmp3lame", "-q:a", "2", "-f", "mp3"],
+        )?),
+        AudioFormat::Opus => Box::new(AudioTranscoder::spawn(
+            inner,
+            ffmpeg,
+            &["-c:a", "libopus", "-b:a", "96k", "-f", "ogg"],
+        )?),
+    })
+}
+
+/// Passes on the ADTS frames of the first AAC stream, which players and
+/// podcast tools read as a plain `.aac` file.
+struct AacExtractor {
+    inner: Box<dyn Sink>,
+    demuxer: Demuxer,
+    audio_pid: Option<u16>,
+}
+
+impl AacExtractor {
+    fn extract(&mut self, packets: Vec<Pes>) -> io::Result<()> {
+        for pes in packets {
+            if pes.stream_type == STREAM_TYPE_ADTS
+                && *self.audio_pid.get_or_insert(pes.pid) == pes.pid
+            {
+                self.inner.write_all(&pes.data)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for AacExtractor {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let packets = self.demuxer.push(buf);
+        self.extract(packets)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for AacExtractor {
+    fn finish(&mut self) -> Result<()> {
+        let packets = self.demuxer.finish();
+        self.extract(packets)?;
+        self.inner.finish()
+    }
+}
+
+/// Pipes the stream through ffmpeg. Its output is read on another thread,
+/// so ffmpeg never blocks on a full pipe while fors is writing to it, and
+/// handed to `inner` on the next write.
+struct AudioTranscoder {
+    inner: Box<dyn Sink>,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    encoded: Receiver<Vec<u8>>,
+    reader: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl AudioTranscoder {
+    fn spawn(inner: Box<dyn Sink>, ffmpeg: &str, encoder: &[&str]) -> Result<Self> {
+        let mut child = Command::new(ffmpeg)
+            .args(["-hide_banner", "-loglevel", "error", "-f", "mpegts"])
+            .args(["-i", "pipe:0", "-map", "0:a:0", "-vn"])
+            .args(encoder)
+            .arg("pipe:1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start '{ffmpeg}' (needed for --extract-audio)"))?;
+        info!("Encoding the audio with {ffmpeg}");
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().context("ffmpeg has no stdout")?;
+        let (sender, encoded) = mpsc::channel();
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                match stdout.read(&mut buf)? {
+                    0 => return Ok(()),
+                    read => {
+                        if sender.send(buf[..read].to_vec()).is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        });
+        Ok(AudioTranscoder {
+            inner,
+            child,
+            stdin,
+            encoded,
+            reader: Some(reader),
+        })
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        while let Ok(chunk) = self.encoded.try_recv() {
+            self.inner.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for AudioTranscoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.drain()?;
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "ffmpeg closed"))?
+            .write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.inner.flush()
+    }
+}
+
+impl Sink for AudioTranscoder {
+    fn finish(&mut self) -> Result<()> {
+        // ffmpeg writes the rest once its input ends.
+        self.stdin.take();
+        while let Ok(chunk) = self.encoded.recv() {
+            self.inner.write_all(&chunk)?;
+        }
+        if let Some(reader) = self.reader.take() {
+            reader
+                .join()
+                .map_err(|_| anyhow!("ffmpeg reader thread panicked"))?
+                .context("Failed to read from ffmpeg")?;
+        }
+        let status = self.child.wait().context("Failed to wait for ffmpeg")?;
+        if !status.success() {
+            bail!("ffmpeg exited with {status}");
+        }
+        self.inner.finish()
+    }
+}
@@ -0,0 +1,204 @@
+//! Turns H.264 and AAC from MPEG-TS into the FLV tag bodies RTMP carries.
+
+use tracing::{debug, warn};
+
+use super::ts::{Pes, STREAM_TYPE_ADTS, STREAM_TYPE_H264};
+
+pub const TAG_AUDIO: u8 = 8;
+pub const TAG_VIDEO: u8 = 9;
+
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// An FLV audio or video tag body with its timestamp in milliseconds.
+pub struct Tag {
+    pub kind: u8,
+    pub timestamp: u32,
+    pub body: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct FlvMuxer {
+    /// First timestamp of the stream, which becomes 0.
+    base: Option<u64>,
+    /// Last unwrapped timestamp, to follow the 33-bit wrap around.
+    last: u64,
+    video_config: Option<(Vec<u8>, Vec<u8>)>,
+    audio_config: Option<[u8; 2]>,
+    /// Video is held back until a keyframe follows the decoder config.
+    keyframe_seen: bool,
+}
+
+impl FlvMuxer {
+    pub fn tags(&mut self, pes: Pes) -> Vec<Tag> {
+        let Some(dts) = pes.dts else {
+            return Vec::new();
+        };
+        match pes.stream_type {
+            STREAM_TYPE_H264 => self.video(&pes.data, dts, pes.pts.unwrap_or(dts)),
+            STREAM_TYPE_ADTS => self.audio(&pes.data, dts),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Milliseconds since the start of the stream.
+    fn millis(&mut self, ts: u64) -> u32 {
+        const WRAP: u64 = 1 << 33;
+        let mut ts = ts + self.last / WRAP * WRAP;
+        if ts + WRAP / 2 < self.last {
+            ts += WRAP;
+        } else if ts > self.last + WRAP / 2 && ts >= WRAP {
+            ts -= WRAP;
+        }
+        self.last = self.last.max(ts);
+        let base = *self.base.get_or_insert(ts);
+        (ts.saturating_sub(base) / 90) as u32
+    }
+
+    fn video(&mut self, data: &[u8], dts: u64, pts: u64) -> Vec<Tag> {
+        let mut sps = None;
+        let mut pps = None;
+        let mut keyframe = false;
+        let mut body = Vec::with_capacity(data.len() + 16);
+        for nal in nal_units(data) {
+            match nal[0] & 0x1f {
+                NAL_SPS => sps = Some(nal.to_vec()),
+                NAL_PPS => pps = Some(nal.to_vec()),
+                NAL_AUD => {}
+                kind => {
+                    keyframe |= kind == NAL_IDR;
+                    body.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    body.extend_from_slice(nal);
+                }
+            }
+        }
+
+        let timestamp = self.millis(dts);
+        let mut tags = Vec::new();
+        if let (Some(sps), Some(pps)) = (sps, pps)
+            && self.video_config.as_ref() != Some(&(sps.clone(), pps.clone()))
+        {
+            if sps.len() < 4 {
+                warn!("Ignoring a truncated H.264 SPS");
+            } else {
+                debug!("Sending H.264 decoder configuration");
+                tags.push(Tag {
+                    kind: TAG_VIDEO,
+                    timestamp,
+                    body: avc_sequence_header(&sps, &pps),
+                });
+                self.video_config = Some((sps, pps));
+            }
+        }
+        if self.video_config.is_none() || body.is_empty() {
+            return tags;
+        }
+        self.keyframe_seen |= keyframe;
+        if !self.keyframe_seen {
+            return tags;
+        }
+
+        let composition = (pts as i64 - dts as i64) / 90;
+        let mut header = vec![if keyframe { 0x17 } else { 0x27 }, 1];
+        header.extend_from_slice(&(composition as i32).to_be_bytes()[1..]);
+        header.extend_from_slice(&body);
+        tags.push(Tag {
+            kind: TAG_VIDEO,
+            timestamp,
+            body: header,
+        });
+        tags
+    }
+
+    fn audio(&mut self, mut data: &[u8], pts: u64) -> Vec<Tag> {
+        let mut tags = Vec::new();
+        let mut frame_pts = pts;
+        while data.len() >= 7 && data[0] == 0xff && data[1] & 0xf0 == 0xf0 {
+            let header_length = if data[1] & 0x01 == 0 { 9 } else { 7 };
+            let frame_length = (usize::from(data[3] & 0x03) << 11)
+                | (usize::from(data[4]) << 3)
+                | usize::from(data[5] >> 5);
+            let Some(frame) = data.get(header_length..frame_length) else {
+                break;
+            };
+            let profile = (data[2] >> 6) + 1;
+            let rate_index = (data[2] >> 2) & 0x0f;
+            let channels = ((data[2] & 0x01) << 2) | (data[3] >> 6);
+
+            let config = [
+                (profile << 3) | (rate_index >> 1),
+                ((rate_index & 0x01) << 7) | (channels << 3),
+            ];
+            let timestamp = self.millis(frame_pts);
+            if self.audio_config != Some(config) {
+                debug!("Sending AAC decoder configuration");
+                tags.push(Tag {
+                    kind: TAG_AUDIO,
+                    timestamp,
+                    body: vec![0xaf, 0x00, config[0], config[1]],
+                });
+                self.audio_config = Some(config);
+            }
+            let mut body = vec![0xaf, 0x01];
+            body.extend_from_slice(frame);
+            tags.push(Tag {
+                kind: TAG_AUDIO,
+                timestamp,
+                body,
+            });
+
+            let rate = AAC_SAMPLE_RATES
+                .get(rate_index as usize)
+                .copied()
+                .unwrap_or(48000);
+            frame_pts += 1024 * 90_000 / u64::from(rate);
+            data = &data[frame_length..];
+        }
+        tags
+    }
+}
+
+/// Splits Annex B data on its start codes.
+fn nal_units(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+        .iter()
+        .enumerate()
+        .filter_map(|(n, &start)| {
+            let end = starts.get(n + 1).map_or(data.len(), |&next| next - 3);
+            // Four byte start codes leave a zero behind the previous unit.
+            let nal = &data[start..end];
+            let nal = match nal.iter().rposition(|&b| b != 0) {
+                Some(last) => &nal[..=last],
+                None => return None,
+            };
+            Some(nal)
+        })
+        .collect()
+}
+
+fn avc_sequence_header(sps: &[u8], pps: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x17, 0x00, 0, 0, 0];
+    body.extend_from_slice(&[1, sps[1], sps[2], sps[3], 0xff, 0xe1]);
+    body.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+    body.extend_from_slice(sps);
+    body.push(1);
+    body.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+    body.extend_from_slice(pps);
+    body
+}
@@ -0,0 +1,128 @@
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use reqwest::blocking::{Body, Client};
+use reqwest::header::HeaderMap;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::thread::JoinHandle;
+use tracing::{debug, info};
+use url::Url;
+
+use super::Sink;
+
+/// Number of written chunks that may queue up before writes block.
+const QUEUE_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum UploadMethod {
+    Put,
+    Post,
+}
+
+/// Streams everything written to it as a chunked HTTP request body.
+pub struct HttpUpload {
+    sender: Option<SyncSender<Vec<u8>>>,
+    handle: Option<JoinHandle<Result<()>>>,
+}
+
+impl HttpUpload {
+    pub fn start(client: Client, url: Url, method: UploadMethod, headers: HeaderMap) -> Self {
+        let (sender, receiver) = sync_channel(QUEUE_DEPTH);
+        let body = Body::new(ChannelReader {
+            receiver,
+            current: Vec::new(),
+            offset: 0,
+        });
+
+        info!("Uploading output to {url}");
+        let handle = std::thread::spawn(move || {
+            let request = match method {
+                UploadMethod::Put => client.put(url.clone()),
+                UploadMethod::Post => client.post(url.clone()),
+            };
+            let response = request
+                .headers(headers)
+                .header(reqwest::header::CONTENT_TYPE, "video/mp2t")
+                .body(body)
+                .send()
+                .with_context(|| format!("Upload to {url} failed"))?;
+            let status = response.status();
+            if !status.is_success() {
+                let text = response.text().unwrap_or_default();
+                return Err(anyhow!("Upload to {url} was rejected ({status}): {text}"));
+            }
+            debug!("Upload finished with status {status}");
+            Ok(())
+        });
+
+        HttpUpload {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    fn join(&mut self) -> Result<()> {
+        self.sender.take();
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| anyhow!("Upload thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Write for HttpUpload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sent = self
+            .sender
+            .as_ref()
+            .map(|sender| sender.send(buf.to_vec()).is_ok())
+            .unwrap_or(false);
+        if sent {
+            return Ok(buf.len());
+        }
+
+        // The upload thread is gone, surface its error instead of a bare broken pipe.
+        let reason = match self.join() {
+            Err(err) => format!("{err:#}"),
+            Ok(()) => "upload closed early".to_string(),
+        };
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, reason))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for HttpUpload {
+    fn finish(&mut self) -> Result<()> {
+        self.join()
+    }
+}
+
+struct ChannelReader {
+    receiver: Receiver<Vec<u8>>,
+    current: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.offset >= self.current.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.current = chunk;
+                    self.offset = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.current.len() - self.offset);
+        buf[..n].copy_from_slice(&self.current[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
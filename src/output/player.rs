@@ -0,0 +1,248 @@
+use anyhow::{Context, Result, anyhow};
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info};
+
+use super::Sink;
+
+/// Upper bound for data held back while the player is paused.
+const MAX_PAUSE_BUFFER: usize = 256 * 1024 * 1024;
+
+/// Pipes the stream into a player's stdin. For mpv, also talks to its JSON IPC
+/// socket to set the title and hold data back while playback is paused.
+pub struct PlayerOutput {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    paused: Arc<AtomicBool>,
+    held: VecDeque<Vec<u8>>,
+    held_bytes: usize,
+    ipc: Option<ipc::MpvIpc>,
+}
+
+impl PlayerOutput {
+    pub fn spawn(command: &str, title: &str) -> Result<Self> {
+        let mut parts = command.split_whitespace();
+        let program = parts
+            .next()
+            .ok_or_else(|| anyhow!("--player must not be empty"))?;
+        let is_mpv = Path::new(program)
+            .file_stem()
+            .is_some_and(|stem| stem.eq_ignore_ascii_case("mpv"));
+
+        let mut cmd = Command::new(program);
+        cmd.args(parts).stdin(Stdio::piped());
+
+        let paused = Arc::new(AtomicBool::new(false));
+        let socket = (cfg!(unix) && is_mpv).then(ipc::socket_path);
+        if let Some(path) = &socket {
+            cmd.arg(format!("--input-ipc-server={}", path.display()));
+            cmd.arg(format!("--force-media-title={title}"));
+        }
+        cmd.arg("-");
+
+        info!("Starting player: {command}");
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to start player '{program}'"))?;
+        let stdin = child.stdin.take();
+        let ipc = socket.map(|path| ipc::MpvIpc::start(path, title.to_string(), paused.clone()));
+
+        Ok(PlayerOutput {
+            child,
+            stdin,
+            paused,
+            held: VecDeque::new(),
+            held_bytes: 0,
+            ipc,
+        })
+    }
+
+    fn pipe(&mut self) -> io::Result<&mut ChildStdin> {
+        self.stdin
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "player closed"))
+    }
+
+    fn release_held(&mut self) -> io::Result<()> {
+        while let Some(chunk) = self.held.pop_front() {
+            self.held_bytes -= chunk.len();
+            self.pipe()?.write_all(&chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for PlayerOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Ok(Some(status)) = self.child.try_wait() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("player exited ({status})"),
+            ));
+        }
+
+        // While mpv is paused a blocking write would stall playlist polling and
+        // let the live window slip away, so keep the data in memory instead.
+        if self.paused.load(Ordering::Relaxed) && self.held_bytes + buf.len() <= MAX_PAUSE_BUFFER {
+            if self.held.is_empty() {
+                debug!("Player paused, holding back stream data");
+            }
+            self.held_bytes += buf.len();
+            self.held.push_back(buf.to_vec());
+            return Ok(buf.len());
+        }
+
+        self.release_held()?;
+        self.pipe()?.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.paused.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        self.release_held()?;
+        self.pipe()?.flush()
+    }
+}
+
+impl Sink for PlayerOutput {
+    fn finish(&mut self) -> Result<()> {
+        self.release_held().ok();
+        self.stdin.take();
+        let status = self.child.wait().context("Waiting for player failed")?;
+        debug!("Player exited with {status}");
+        self.ipc.take();
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+mod ipc {
+    use serde_json::{Value, json};
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::time::Duration;
+    use tracing::{debug, info};
+
+    const PAUSE_ID: u64 = 1;
+    const CACHE_ID: u64 = 2;
+
+    /// Numbers the players of this process, which `--parallel` runs
+    /// several of.
+    static PLAYERS: AtomicU64 = AtomicU64::new(0);
+
+    pub fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "fors-mpv-{}-{}.sock",
+            std::process::id(),
+            PLAYERS.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    pub struct MpvIpc {
+        path: PathBuf,
+    }
+
+    impl MpvIpc {
+        pub fn start(path: PathBuf, title: String, paused: Arc<AtomicBool>) -> Self {
+            let socket = path.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = run(&socket, &title, &paused) {
+                    debug!("mpv IPC connection ended: {err}");
+                }
+                paused.store(false, Ordering::Relaxed);
+            });
+            MpvIpc { path }
+        }
+    }
+
+    impl Drop for MpvIpc {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    fn run(path: &PathBuf, title: &str, paused: &AtomicBool) -> std::io::Result<()> {
+        // mpv creates the socket shortly after starting up.
+        let mut stream = None;
+        for _ in 0..50 {
+            match UnixStream::connect(path) {
+                Ok(s) => {
+                    stream = Some(s);
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100)),
+            }
+        }
+        let mut stream = stream.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "mpv IPC socket never appeared",
+            )
+        })?;
+        debug!("Connected to mpv IPC at {}", path.display());
+
+        for command in [
+            json!({ "command": ["set_property", "force-media-title", title] }),
+            json!({ "command": ["observe_property", PAUSE_ID, "pause"] }),
+            json!({ "command": ["observe_property", CACHE_ID, "demuxer-cache-duration"] }),
+        ] {
+            writeln!(stream, "{command}")?;
+        }
+
+        let mut last_reported = 0.0f64;
+        for line in BufReader::new(stream).lines() {
+            let Ok(event) = serde_json::from_str::<Value>(&line?) else {
+                continue;
+            };
+            if event["event"] != "property-change" {
+                continue;
+            }
+            match event["id"].as_u64() {
+                Some(PAUSE_ID) => {
+                    let is_paused = event["data"].as_bool().unwrap_or(false);
+                    if paused.swap(is_paused, Ordering::Relaxed) != is_paused {
+                        info!("Player {}", if is_paused { "paused" } else { "resumed" });
+                    }
+                }
+                Some(CACHE_ID) => {
+                    if let Some(seconds) = event["data"].as_f64()
+                        && (seconds - last_reported).abs() >= 1.0
+                    {
+                        debug!("Player buffer: {seconds:.1}s");
+                        last_reported = seconds;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod ipc {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+
+    pub fn socket_path() -> PathBuf {
+        unreachable!("mpv IPC is only used on unix")
+    }
+
+    pub struct MpvIpc;
+
+    impl MpvIpc {
+        pub fn start(_path: PathBuf, _title: String, _paused: Arc<AtomicBool>) -> Self {
+            MpvIpc
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use anyhow::{Context, Result, anyhow, bail};
+use std::collections::HashMap;
+use std::io::{self, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+use url::Url;
+
+use super::Sink;
+use super::flv::{FlvMuxer, TAG_VIDEO};
+use super::ts::{Demuxer, Pes};
+
+const DEFAULT_PORT: u16 = 1935;
+const HANDSHAKE_SIZE: usize = 1536;
+const CHUNK_SIZE: usize = 4096;
+
+const MSG_SET_CHUNK_SIZE: u8 = 1;
+const MSG_DATA: u8 = 18;
+const MSG_COMMAND: u8 = 20;
+
+const CSID_COMMAND: u8 = 3;
+const CSID_AUDIO: u8 = 4;
+const CSID_VIDEO: u8 = 6;
+
+/// Publishes the stream to an RTMP ingest (`rtmp://host/app/key`), remuxing
+/// the H.264 and AAC in the MPEG-TS segments to FLV on the way.
+pub struct RtmpOutput {
+    connection: Connection,
+    stream_id: u32,
+    demuxer: Demuxer,
+    muxer: FlvMuxer,
+}
+
+impl RtmpOutput {
+    pub fn connect(url: &Url) -> Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Missing host in {url}"))?;
+        let port = url.port().unwrap_or(DEFAULT_PORT);
+        let (app, key) = url
+            .path()
+            .trim_start_matches('/')
+            .split_once('/')
+            .filter(|(app, key)| !app.is_empty() && !key.is_empty())
+            .ok_or_else(|| anyhow!("Expected rtmp://host/app/stream-key, got {url}"))?;
+        let key = match url.query() {
+            Some(query) => format!("{key}?{query}"),
+            None => key.to_string(),
+        };
+
+        let socket = TcpStream::connect((host, port))
+            .with_context(|| format!("Failed to connect to RTMP server {host}:{port}"))?;
+        socket.set_read_timeout(Some(Duration::from_secs(10))).ok();
+        socket.set_nodelay(true).ok();
+        let mut connection = Connection::handshake(socket)
+            .with_context(|| format!("RTMP handshake with {host}:{port} failed"))?;
+
+        connection.send(
+            CSID_COMMAND,
+            MSG_SET_CHUNK_SIZE,
+            0,
+            0,
+            &(CHUNK_SIZE as u32).to_be_bytes(),
+        )?;
+        connection.command(
+            0,
+            &[
+                Amf::String("connect".into()),
+                Amf::Number(1.0),
+                Amf::Object(vec![
+                    ("app".into(), Amf::String(app.into())),
+                    ("type".into(), Amf::String("nonprivate".into())),
+                    (
+                        "flashVer".into(),
+                        Amf::String("FMLE/3.0 (compatible; fors)".into()),
+                    ),
+                    (
+                        "tcUrl".into(),
+                        Amf::String(format!("rtmp://{host}:{port}/{app}")),
+                    ),
+                ]),
+            ],
+        )?;
+        connection.wait_for_result(1.0)?;
+
+        for (txn, name) in [(2.0, "releaseStream"), (3.0, "FCPublish")] {
+            connection.command(
+                0,
+                &[
+                    Amf::String(name.into()),
+                    Amf::Number(txn),
+                    Amf::Null,
+                    Amf::String(key.clone()),
+                ],
+            )?;
+        }
+        connection.command(
+            0,
+            &[
+                Amf::String("createStream".into()),
+                Amf::Number(4.0),
+                Amf::Null,
+            ],
+        )?;
+        let stream_id = match connection.wait_for_result(4.0)?.get(3) {
+            Some(Amf::Number(id)) => *id as u32,
+            _ => bail!("RTMP server did not create a stream"),
+        };
+
+        connection.command(
+            stream_id,
+            &[
+                Amf::String("publish".into()),
+                Amf::Number(5.0),
+                Amf::Null,
+                Amf::String(key),
+                Amf::String("live".into()),
+            ],
+        )?;
+        connection.wait_for_publish()?;
+        // Nothing the server sends from here on needs an answer; keep reading
+        // so its messages cannot fill the socket buffer.
+        let mut reader = connection.reader.try_clone()?;
+        reader.set_read_timeout(None).ok();
+        std::thread::spawn(move || io::copy(&mut reader, &mut io::sink()));
+
+        let mut metadata = Vec::new();
+        for value in [
+            Amf::String("@setDataFrame".into()),
+            Amf::String("onMetaData".into()),
+            Amf::EcmaArray(vec![
+                ("videocodecid".into(), Amf::Number(7.0)),
+                ("audiocodecid".into(), Amf::Number(10.0)),
+                ("encoder".into(), Amf::String("fors".into())),
+            ]),
+        ] {
+            value.encode(&mut metadata);
+        }
+        connection.send(CSID_COMMAND, MSG_DATA, 0, stream_id, &metadata)?;
+
+        info!("Publishing to rtmp://{host}:{port}/{app}");
+        Ok(RtmpOutput {
+            connection,
+            stream_id,
+            demuxer: Demuxer::default(),
+            muxer: FlvMuxer::default(),
+        })
+    }
+
+    fn publish(&mut self, packets: Vec<Pes>) -> io::Result<()> {
+        for pes in packets {
+            for tag in self.muxer.tags(pes) {
+                let csid = match tag.kind {
+                    TAG_VIDEO => CSID_VIDEO,
+                    _ => CSID_AUDIO,
+                };
+                self.connection
+                    .send(csid, tag.kind, tag.timestamp, self.stream_id, &tag.body)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for RtmpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let packets = self.demuxer.push(buf);
+        self.publish(packets)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.writer.flush()
+    }
+}
+
+impl Sink for RtmpOutput {
+    fn finish(&mut self) -> Result<()> {
+        let packets = self.demuxer.finish();
+        self.publish(packets)?;
+        self.connection.command(
+            0,
+            &[
+                Amf::String("deleteStream".into()),
+                Amf::Number(6.0),
+                Amf::Null,
+                Amf::Number(f64::from(self.stream_id)),
+            ],
+        )?;
+        self.connection
+            .writer
+            .flush()
+            .context("Failed to flush RTMP connection")
+    }
+}
+
+/// Incoming chunk stream state, kept per chunk stream id.
+#[derive(Default, Clone)]
+struct ChunkHeader {
+    length: usize,
+    kind: u8,
+    payload: Vec<u8>,
+}
+
+struct Connection {
+    reader: TcpStream,
+    writer: BufWriter<TcpStream>,
+    in_chunk_size: usize,
+    in_headers: HashMap<u32, ChunkHeader>,
+}
+
+impl Connection {
+    fn handshake(mut socket: TcpStream) -> Result<Self> {
+        let mut c1 = vec![0u8; HANDSHAKE_SIZE];
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        for (i, byte) in c1.iter_mut().enumerate().skip(8) {
+            *byte = (seed.wrapping_mul(i as u32 + 1) >> 8) as u8;
+        }
+        socket.write_all(&[3])?;
+        socket.write_all(&c1)?;
+
+        let mut s0s1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+        socket.read_exact(&mut s0s1)?;
+        if s0s1[0] != 3 {
+            bail!("Unsupported RTMP version {}", s0s1[0]);
+        }
+        socket.write_all(&s0s1[1..])?;
+        let mut s2 = vec![0u8; HANDSHAKE_SIZE];
+        socket.read_exact(&mut s2)?;
+        // S2 echoes the random bytes of C1.
+        if s2[8..] != c1[8..] {
+            bail!("RTMP server did not echo the handshake");
+        }
+
+        Ok(Connection {
+            reader: socket.try_clone()?,
+            writer: BufWriter::new(socket),
+            in_chunk_size: 128,
+            in_headers: HashMap::new(),
+        })
+    }
+
+    /// Writes one message, always with full (type 0) chunk headers.
+    fn send(
+        &mut self,
+        csid: u8,
+        kind: u8,
+        timestamp: u32,
+        stream_id: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let extended = timestamp >= 0xff_ffff;
+        let mut header = vec![csid];
+        header.extend_from_slice(&timestamp.min(0xff_ffff).to_be_bytes()[1..]);
+        header.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+        header.push(kind);
+        header.extend_from_slice(&stream_id.to_le_bytes());
+        if extended {
+            header.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        self.writer.write_all(&header)?;
+
+        for (i, chunk) in payload.chunks(CHUNK_SIZE).enumerate() {
+            if i > 0 {
+                self.writer.write_all(&[0xc0 | csid])?;
+                if extended {
+                    self.writer.write_all(&timestamp.to_be_bytes())?;
+                }
+            }
+            self.writer.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, stream_id: u32, values: &[Amf]) -> Result<()> {
+        let mut payload = Vec::new();
+        for value in values {
+            value.encode(&mut payload);
+        }
+        self.send(CSID_COMMAND, MSG_COMMAND, 0, stream_id, &payload)?;
+        self.writer.flush().context("Failed to send RTMP command")
+    }
+
+    /// Reads messages until the `_result` of transaction `txn`.
+    fn wait_for_result(&mut self, txn: f64) -> Result<Vec<Amf>> {
+        loop {
+            let values = self.read_command()?;
+            match (values.first(), values.get(1)) {
+                (Some(Amf::String(name)), Some(Amf::Number(id))) if *id == txn => {
+                    if name == "_result" {
+                        return Ok(values);
+                    }
+                    bail!("RTMP server rejected the request: {values:?}");
+                }
+                _ => debug!("Ignoring RTMP command {values:?}"),
+            }
+        }
+    }
+
+    fn wait_for_publish(&mut self) -> Result<()> {
+        loop {
+            let values = self.read_command()?;
+            if !matches!(values.first(), Some(Amf::String(name)) if name == "onStatus") {
+                continue;
+            }
+            let code = values.iter().find_map(|value| match value {
+                Amf::Object(fields) => fields.iter().find_map(|(key, value)| match value {
+                    Amf::String(code) if key == "code" => Some(code.clone()),
+                    _ => None,
+                }),
+                _ => None,
+            });
+            match code.as_deref() {
+                Some("NetStream.Publish.Start") => return Ok(()),
+                Some(code) => bail!("RTMP server refused to publish ({code})"),
+                None => {}
+            }
+        }
+    }
+
+    /// Reads messages until an AMF0 command arrives, handling chunk size
+    /// changes on the way.
+    fn read_command(&mut self) -> Result<Vec<Amf>> {
+        loop {
+            let (kind, payload) = self.read_message()?;
+            match kind {
+                MSG_SET_CHUNK_SIZE if payload.len() >= 4 => {
+                    let size = u32::from_be_bytes(payload[..4].try_into()?) & 0x7fff_ffff;
+                    if size == 0 {
+                        bail!("RTMP server set a chunk size of 0");
+                    }
+                    self.in_chunk_size = size as usize;
+                }
+                MSG_COMMAND => return Ok(Amf::decode_all(&payload)),
+                _ => {}
+            }
+        }
+    }
+
+    fn read_message(&mut self) -> Result<(u8, Vec<u8>)> {
+        let reader = &mut self.reader;
+        loop {
+            let mut first = [0u8; 1];
+            reader
+                .read_exact(&mut first)
+                .context("RTMP server closed the connection")?;
+            let format = first[0] >> 6;
+            let csid = match first[0] & 0x3f {
+                0 => {
+                    let mut b = [0u8; 1];
+                    reader.read_exact(&mut b)?;
+                    64 + u32::from(b[0])
+                }
+                1 => {
+                    let mut b = [0u8; 2];
+                    reader.read_exact(&mut b)?;
+                    64 + u32::from(b[0]) + (u32::from(b[1]) << 8)
+                }
+                id => u32::from(id),
+            };
+
+            let header_length = [11, 7, 3, 0][format as usize];
+            let mut header = vec![0u8; header_length];
+            reader.read_exact(&mut header)?;
+            let state = self.in_headers.entry(csid).or_default();
+            if header_length >= 7 {
+                state.length = (usize::from(header[3]) << 16)
+                    | (usize::from(header[4]) << 8)
+                    | usize::from(header[5]);
+                state.kind = header[6];
+            }
+            if header_length >= 3 && header[..3] == [0xff, 0xff, 0xff] {
+                let mut extended = [0u8; 4];
+                reader.read_exact(&mut extended)?;
+            }
+
+            let left = state.length.saturating_sub(state.payload.len());
+            let mut chunk = vec![0u8; left.min(self.in_chunk_size)];
+            reader.read_exact(&mut chunk)?;
+            state.payload.extend_from_slice(&chunk);
+            if state.payload.len() >= state.length {
+                let payload = std::mem::take(&mut state.payload);
+                return Ok((state.kind, payload));
+            }
+        }
+    }
+}
+
+/// AMF0 values, as far as RTMP commands need them.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Amf {
+    Number(f64),
+    Bool(bool),
+    String(String),
+    Object(Vec<(String, Amf)>),
+    EcmaArray(Vec<(String, Amf)>),
+    Null,
+}
+
+impl Amf {
+    pub(super) fn encode(&self, out: &mut Vec<u8>) {
+        let key = |out: &mut Vec<u8>, key: &str| {
+            out.extend_from_slice(&(key.len() as u16).to_be_bytes());
+            out.extend_from_slice(key.as_bytes());
+        };
+        match self {
+            Amf::Number(n) => {
+                out.push(0x00);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+            Amf::Bool(b) => out.extend_from_slice(&[0x01, u8::from(*b)]),
+            Amf::String(s) => {
+                out.push(0x02);
+                key(out, s);
+            }
+            Amf::Object(fields) | Amf::EcmaArray(fields) => {
+                if let Amf::EcmaArray(_) = self {
+                    out.push(0x08);
+                    out.extend_from_slice(&(fields.len() as u32).to_be_bytes());
+                } else {
+                    out.push(0x03);
+                }
+                for (name, value) in fields {
+                    key(out, name);
+                    value.encode(out);
+                }
+                out.extend_from_slice(&[0, 0, 0x09]);
+            }
+            Amf::Null => out.push(0x05),
+        }
+    }
+
+    /// Decodes values until the data ends or an unsupported type shows up.
+    pub(super) fn decode_all(mut data: &[u8]) -> Vec<Amf> {
+        let mut values = Vec::new();
+        while let Some((value, rest)) = Amf::decode(data) {
+            values.push(value);
+            data = rest;
+        }
+        values
+    }
+
+    fn decode(data: &[u8]) -> Option<(Amf, &[u8])> {
+        let (&marker, rest) = data.split_first()?;
+        match marker {
+            0x00 => {
+                let number = f64::from_be_bytes(rest.get(..8)?.try_into().ok()?);
+                Some((Amf::Number(number), &rest[8..]))
+            }
+            0x01 => Some((Amf::Bool(*rest.first()? != 0), &rest[1..])),
+            0x02 => {
+                let (string, rest) = decode_string(rest)?;
+                Some((Amf::String(string), rest))
+            }
+            0x03 | 0x08 => {
+                let mut rest = if marker == 0x08 { rest.get(4..)? } else { rest };
+                let mut fields = Vec::new();
+                loop {
+                    if rest.get(..3)? == [0, 0, 0x09] {
+                        rest = &rest[3..];
+                        break;
+                    }
+                    let (name, after) = decode_string(rest)?;
+                    let (value, after) = Amf::decode(after)?;
+                    fields.push((name, value));
+                    rest = after;
+                }
+                Some((Amf::Object(fields), rest))
+            }
+            0x05 | 0x06 => Some((Amf::Null, rest)),
+            _ => None,
+        }
+    }
+}
+
+fn decode_string(data: &[u8]) -> Option<(String, &[u8])> {
+    let length = usize::from(u16::from_be_bytes(data.get(..2)?.try_into().ok()?));
+    let string = String::from_utf8_lossy(data.get(2..2 + length)?).into_owned();
+    Some((string, &data[2 + length..]))
+}
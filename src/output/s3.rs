@@ -0,0 +1,398 @@
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use hmac_sha256::{HMAC, Hash};
+use reqwest::Method;
+use reqwest::blocking::{Client, Response};
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{SyncSender, sync_channel};
+use std::thread::JoinHandle;
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::Sink;
+
+#[cfg(test)]
+mod tests;
+
+/// Size of each uploaded part. S3 needs at least 5 MiB for all but the last.
+const PART_SIZE: usize = 16 * 1024 * 1024;
+/// Parts that may wait for upload before writes block.
+const QUEUE_DEPTH: usize = 2;
+
+/// Uploads the output to `s3://bucket/key` as a multipart upload, so nothing
+/// but the part being filled is kept locally.
+///
+/// Credentials and region come from the usual `AWS_*` environment variables.
+/// `AWS_ENDPOINT_URL` points it at MinIO and other S3 compatible stores.
+pub struct S3Upload {
+    object: Arc<S3Object>,
+    client: Client,
+    /// Taken once the upload is completed or aborted.
+    upload_id: Option<String>,
+    buffer: Vec<u8>,
+    sender: Option<SyncSender<Vec<u8>>>,
+    handle: Option<JoinHandle<Result<Vec<String>>>>,
+}
+
+impl S3Upload {
+    pub fn start(client: Client, url: &Url) -> Result<Self> {
+        let object = Arc::new(S3Object::from_url(url)?);
+        let response = object.send(&client, Method::POST, &[("uploads", "")], Vec::new())?;
+        let body = response.text().context("Failed to read S3 response")?;
+        let upload_id = xml_value(&body, "UploadId")
+            .with_context(|| format!("S3 did not return an upload id: {body}"))?
+            .to_string();
+        info!("Uploading output to {url}");
+
+        let (sender, receiver) = sync_channel::<Vec<u8>>(QUEUE_DEPTH);
+        let handle = {
+            let object = object.clone();
+            let client = client.clone();
+            let upload_id = upload_id.clone();
+            std::thread::spawn(move || {
+                let mut etags = Vec::new();
+                for part in receiver {
+                    let number = (etags.len() + 1).to_string();
+                    let response = object.send(
+                        &client,
+                        Method::PUT,
+                        &[("partNumber", &number), ("uploadId", &upload_id)],
+                        part,
+                    )?;
+                    let etag = response
+                        .headers()
+                        .get(reqwest::header::ETAG)
+                        .and_then(|value| value.to_str().ok())
+                        .with_context(|| format!("S3 returned no ETag for part {number}"))?;
+                    debug!("Uploaded part {number}");
+                    etags.push(etag.to_string());
+                }
+                Ok(etags)
+            })
+        };
+
+        Ok(S3Upload {
+            object,
+            client,
+            upload_id: Some(upload_id),
+            buffer: Vec::with_capacity(PART_SIZE),
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    fn join(&mut self) -> Result<Vec<String>> {
+        self.sender.take();
+        match self.handle.take() {
+            Some(handle) => handle
+                .join()
+                .map_err(|_| anyhow!("Upload thread panicked"))?,
+            None => bail!("Upload already finished"),
+        }
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        let last = std::mem::take(&mut self.buffer);
+        if let Some(sender) = &self.sender {
+            // A failed send means the thread has stopped; join reports why.
+            let _ = sender.send(last);
+        }
+        let etags = self.join()?;
+
+        let parts: String = etags
+            .iter()
+            .enumerate()
+            .map(|(i, etag)| {
+                format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{etag}</ETag></Part>",
+                    i + 1
+                )
+            })
+            .collect();
+        let body = format!("<CompleteMultipartUpload>{parts}</CompleteMultipartUpload>");
+        let upload_id = self
+            .upload_id
+            .as_deref()
+            .context("Upload already aborted")?;
+        let response = self.object.send(
+            &self.client,
+            Method::POST,
+            &[("uploadId", upload_id)],
+            body.into_bytes(),
+        )?;
+        // Completion can fail after the 200 status has been sent.
+        let text = response.text().context("Failed to read S3 response")?;
+        if text.contains("<Error>") {
+            bail!("S3 rejected the upload: {text}");
+        }
+        self.upload_id = None;
+        info!("Upload finished ({} parts)", etags.len());
+        Ok(())
+    }
+
+    /// Deletes the parts uploaded so far. Only the first call does anything.
+    fn abort(&mut self) {
+        let Some(upload_id) = self.upload_id.take() else {
+            return;
+        };
+        if let Err(err) = self.object.send(
+            &self.client,
+            Method::DELETE,
+            &[("uploadId", &upload_id)],
+            Vec::new(),
+        ) {
+            warn!("Failed to abort the S3 upload: {err:#}");
+        }
+    }
+}
+
+impl Write for S3Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() < PART_SIZE {
+            return Ok(buf.len());
+        }
+
+        let part = std::mem::replace(&mut self.buffer, Vec::with_capacity(PART_SIZE));
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.send(part).is_ok());
+        if sent {
+            return Ok(buf.len());
+        }
+
+        // The upload thread is gone, surface its error instead of a bare broken pipe.
+        let reason = match self.join() {
+            Err(err) => format!("{err:#}"),
+            Ok(_) => "upload closed early".to_string(),
+        };
+        self.abort();
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, reason))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for S3Upload {
+    fn finish(&mut self) -> Result<()> {
+        let result = self.complete();
+        if result.is_err() {
+            self.abort();
+        }
+        result
+    }
+}
+
+impl Drop for S3Upload {
+    /// An upload that was never finished would keep its parts, and their
+    /// storage costs, until a lifecycle rule cleans them up.
+    fn drop(&mut self) {
+        if self.upload_id.is_some() {
+            self.sender.take();
+            self.abort();
+        }
+    }
+}
+
+struct S3Object {
+    /// Object URL without a query.
+    url: Url,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl S3Object {
+    fn from_url(url: &Url) -> Result<Self> {
+        let bucket = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Missing bucket in {url}"))?;
+        // Keep the URL's escapes and slashes, and escape what SigV4 wants
+        // escaped but URLs allow in paths.
+        let key: String = url
+            .path()
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                segment
+                    .split('%')
+                    .map(uri_encode)
+                    .collect::<Vec<_>>()
+                    .join("%")
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        if key.is_empty() {
+            bail!("Missing object key in {url}");
+        }
+        let env = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let region = env("AWS_REGION")
+            .or_else(|| env("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_string());
+
+        // Custom endpoints get path-style URLs, which every S3 clone supports.
+        let object_url = match env("AWS_ENDPOINT_URL") {
+            Some(endpoint) => format!("{}/{bucket}/{key}", endpoint.trim_end_matches('/')),
+            None => format!("https://{bucket}.s3.{region}.amazonaws.com/{key}"),
+        };
+        Ok(S3Object {
+            url: Url::parse(&object_url)
+                .with_context(|| format!("Invalid S3 object URL {object_url}"))?,
+            region,
+            access_key: env("AWS_ACCESS_KEY_ID").context("AWS_ACCESS_KEY_ID is not set")?,
+            secret_key: env("AWS_SECRET_ACCESS_KEY").context("AWS_SECRET_ACCESS_KEY is not set")?,
+            session_token: env("AWS_SESSION_TOKEN"),
+        })
+    }
+
+    /// Sends a request signed with AWS Signature Version 4 and fails on
+    /// error statuses.
+    fn send(
+        &self,
+        client: &Client,
+        method: Method,
+        query: &[(&str, &str)],
+        body: Vec<u8>,
+    ) -> Result<Response> {
+        let mut query: Vec<String> = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", uri_encode(key), uri_encode(value)))
+            .collect();
+        query.sort();
+        let query = query.join("&");
+        let mut url = self.url.clone();
+        url.set_query(Some(&query));
+
+        let now = Utc::now();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(&Hash::hash(&body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap_or_default()),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let mut headers = vec![
+            ("host", host),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed = self.sign(
+            &method,
+            url.path(),
+            &query,
+            &headers,
+            &payload_hash,
+            &timestamp,
+        );
+
+        let mut request = client.request(method.clone(), url).header(
+            reqwest::header::AUTHORIZATION,
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, signed.scope, signed.signed_headers, signed.signature
+            ),
+        );
+        for (name, value) in headers.into_iter().skip(1) {
+            request = request.header(name, value);
+        }
+        let response = request
+            .body(body)
+            .send()
+            .with_context(|| format!("S3 {method} request to {} failed", self.url))?;
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().unwrap_or_default();
+            // S3 echoes what it signed when the signatures differ.
+            debug!(
+                "Signed canonical request:\n{}\nString to sign:\n{}",
+                signed.canonical_request, signed.string_to_sign
+            );
+            bail!("S3 {method} request was rejected ({status}): {text}");
+        }
+        Ok(response)
+    }
+
+    /// Signs a request with AWS Signature Version 4. `headers` are the
+    /// signed ones, lowercase and sorted by name.
+    fn sign(
+        &self,
+        method: &Method,
+        path: &str,
+        query: &str,
+        headers: &[(&str, String)],
+        payload_hash: &str,
+        timestamp: &str,
+    ) -> Signed {
+        let date = &timestamp[..8];
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+            .collect();
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\n{canonical_headers}\n{signed_headers}\n{payload_hash}"
+        );
+
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Hash::hash(canonical_request.as_bytes()))
+        );
+        let mut key = HMAC::mac(date, format!("AWS4{}", self.secret_key));
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key = HMAC::mac(part, key);
+        }
+        let signature = hex(&HMAC::mac(&string_to_sign, key));
+        Signed {
+            canonical_request,
+            string_to_sign,
+            scope,
+            signed_headers,
+            signature,
+        }
+    }
+}
+
+/// A SigV4 signature, and what went into it.
+struct Signed {
+    canonical_request: String,
+    string_to_sign: String,
+    scope: String,
+    signed_headers: String,
+    signature: String,
+}
+
+/// Percent-encodes everything but unreserved characters, as SigV4 expects.
+fn uri_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn xml_value<'a>(body: &'a str, tag: &str) -> Option<&'a str> {
+    let start = body.find(&format!("<{tag}>"))? + tag.len() + 2;
+    let end = start + body[start..].find(&format!("</{tag}>"))?;
+    Some(&body[start..end])
+}
@@ -0,0 +1,204 @@
+use anyhow::{Context, Result, bail};
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use tracing::{info, warn};
+
+use super::Sink;
+use super::ts::{Demuxer, Pes, STREAM_TYPE_ADTS};
+
+/// Audio chunks held for a slow tap before they are dropped.
+const TAP_BACKLOG: usize = 512;
+
+/// Passes the stream on to `inner` untouched and the ADTS frames of its audio
+/// to a transcription or captioning tool (`--audio-tap`). The tool is fed
+/// from another thread and loses audio rather than holding up the recording
+/// when it falls behind.
+pub struct AudioTap {
+    inner: Box<dyn Sink>,
+    demuxer: Demuxer,
+    audio_pid: Option<u16>,
+    sender: Option<SyncSender<Vec<u8>>>,
+    feeder: Option<JoinHandle<()>>,
+    child: Option<Child>,
+    /// Chunks dropped since the tap last kept up.
+    dropped: usize,
+    #[cfg(unix)]
+    socket: Option<std::path::PathBuf>,
+}
+
+impl AudioTap {
+    /// `target` is a command run through the shell, which gets the audio on
+    /// its stdin, or `unix:PATH` for a socket that tools can connect to.
+    pub fn spawn(inner: Box<dyn Sink>, target: &str) -> Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(TAP_BACKLOG);
+        let mut tap = AudioTap {
+            inner,
+            demuxer: Demuxer::default(),
+            audio_pid: None,
+            sender: Some(sender),
+            feeder: None,
+            child: None,
+            dropped: 0,
+            #[cfg(unix)]
+            socket: None,
+        };
+
+        if let Some(path) = target.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                let path = std::path::PathBuf::from(path);
+                tap.feeder = Some(socket::listen(&path, receiver)?);
+                info!("Serving the audio on {}", path.display());
+                tap.socket = Some(path);
+                return Ok(tap);
+            }
+            #[cfg(not(unix))]
+            bail!("--audio-tap unix:{path} needs Unix domain sockets");
+        }
+
+        if target.trim().is_empty() {
+            bail!("--audio-tap must not be empty");
+        }
+        let mut cmd = if cfg!(windows) {
+            let mut cmd = Command::new("cmd");
+            cmd.arg("/C").arg(target);
+            cmd
+        } else {
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(target);
+            cmd
+        };
+        info!("Starting audio tap: {target}");
+        let mut child = cmd
+            .env("FORS_AUDIO_FORMAT", "adts")
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to start audio tap '{target}'"))?;
+        let mut stdin = child.stdin.take().context("The audio tap has no stdin")?;
+        tap.feeder = Some(std::thread::spawn(move || {
+            for chunk in receiver {
+                if let Err(err) = stdin.write_all(&chunk) {
+                    warn!("The audio tap stopped taking audio: {err}");
+                    return;
+                }
+            }
+        }));
+        tap.child = Some(child);
+        Ok(tap)
+    }
+
+    fn tap_audio(&mut self, packets: Vec<Pes>) {
+        for pes in packets {
+            if pes.stream_type == STREAM_TYPE_ADTS
+                && *self.audio_pid.get_or_insert(pes.pid) == pes.pid
+            {
+                self.tap(pes.data);
+            }
+        }
+    }
+
+    fn tap(&mut self, frames: Vec<u8>) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        match sender.try_send(frames) {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    warn!(
+                        "The audio tap fell behind, {} chunks of audio were left out",
+                        self.dropped
+                    );
+                    self.dropped = 0;
+                }
+            }
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            // The tap is gone; the recording goes on without it.
+            Err(TrySendError::Disconnected(_)) => self.sender = None,
+        }
+    }
+}
+
+impl Write for AudioTap {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write_all(buf)?;
+        let packets = self.demuxer.push(buf);
+        self.tap_audio(packets);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Sink for AudioTap {
+    fn finish(&mut self) -> Result<()> {
+        let packets = self.demuxer.finish();
+        self.tap_audio(packets);
+        let finished = self.inner.finish();
+        // Lets the tap see the end of the audio.
+        self.sender.take();
+        if let Some(feeder) = self.feeder.take() {
+            feeder.join().ok();
+        }
+        if let Some(mut child) = self.child.take() {
+            info!("Waiting for the audio tap to finish");
+            match child.wait() {
+                Ok(status) if !status.success() => warn!("The audio tap exited with {status}"),
+                Ok(_) => {}
+                Err(err) => warn!("Failed to wait for the audio tap: {err}"),
+            }
+        }
+        #[cfg(unix)]
+        if let Some(path) = self.socket.take() {
+            std::fs::remove_file(path).ok();
+        }
+        finished
+    }
+}
+
+#[cfg(unix)]
+mod socket {
+    use anyhow::{Context, Result};
+    use std::fs;
+    use std::io::Write;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::thread::JoinHandle;
+    use tracing::debug;
+
+    /// Accepts clients on `path` and sends every chunk to all of them. Clients
+    /// that connect later start at the current audio.
+    pub fn listen(path: &Path, receiver: Receiver<Vec<u8>>) -> Result<JoinHandle<()>> {
+        // Left behind by an earlier run that did not finish.
+        if fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path).ok();
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Failed to listen on {}", path.display()))?;
+        let clients: Arc<Mutex<Vec<UnixStream>>> = Arc::default();
+
+        let accepted = clients.clone();
+        std::thread::spawn(move || {
+            for client in listener.incoming().flatten() {
+                debug!("Audio tap client connected");
+                accepted.lock().unwrap().push(client);
+            }
+        });
+        Ok(std::thread::spawn(move || {
+            for chunk in receiver {
+                clients
+                    .lock()
+                    .unwrap()
+                    .retain_mut(|client| client.write_all(&chunk).is_ok());
+            }
+            // Ends the audio for the clients.
+            clients.lock().unwrap().clear();
+        }))
+    }
+}
@@ -0,0 +1,155 @@
+use crate::output::flv::{FlvMuxer, TAG_AUDIO, TAG_VIDEO, Tag};
+use crate::output::ts::{Pes, STREAM_TYPE_ADTS, STREAM_TYPE_H264};
+
+const SPS: [u8; 6] = [0x67, 0x64, 0x00, 0x1f, 0xac, 0xd9];
+const PPS: [u8; 4] = [0x68, 0xeb, 0xe3, 0xcb];
+const IDR: [u8; 5] = [0x65, 0x88, 0x84, 0x00, 0x33];
+const SLICE: [u8; 4] = [0x41, 0x9a, 0x02, 0x04];
+
+fn annex_b(units: &[&[u8]]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for unit in units {
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(unit);
+    }
+    data
+}
+
+fn video(pts: u64, dts: u64, data: Vec<u8>) -> Pes {
+    Pes {
+        pid: 0x100,
+        stream_type: STREAM_TYPE_H264,
+        pts: Some(pts),
+        dts: Some(dts),
+        data,
+    }
+}
+
+/// ADTS frames of AAC LC, 48 kHz stereo, each with `payload` behind a
+/// 7 byte header.
+fn adts(frames: usize, payload: &[u8]) -> Vec<u8> {
+    let length = 7 + payload.len();
+    let mut data = Vec::new();
+    for _ in 0..frames {
+        data.extend_from_slice(&[
+            0xff,
+            0xf1,
+            0x4c,
+            0x80 | (length >> 11) as u8,
+            (length >> 3) as u8,
+            ((length & 7) << 5) as u8 | 0x1f,
+            0xfc,
+        ]);
+        data.extend_from_slice(payload);
+    }
+    data
+}
+
+fn audio(pts: u64, data: Vec<u8>) -> Pes {
+    Pes {
+        pid: 0x101,
+        stream_type: STREAM_TYPE_ADTS,
+        pts: Some(pts),
+        dts: Some(pts),
+        data,
+    }
+}
+
+fn summary(tags: &[Tag]) -> Vec<(u8, u32, u8, u8)> {
+    tags.iter()
+        .map(|tag| (tag.kind, tag.timestamp, tag.body[0], tag.body[1]))
+        .collect()
+}
+
+#[test]
+fn keyframes_follow_the_decoder_configuration() {
+    let mut muxer = FlvMuxer::default();
+
+    let tags = muxer.tags(video(
+        90_000 + 9000,
+        90_000,
+        annex_b(&[&[0x09, 0xf0], &SPS, &PPS, &IDR]),
+    ));
+
+    assert_eq!(
+        summary(&tags),
+        [(TAG_VIDEO, 0, 0x17, 0), (TAG_VIDEO, 0, 0x17, 1)]
+    );
+    let config = &tags[0].body;
+    assert_eq!(config[5..9], [1, 0x64, 0x00, 0x1f]);
+    assert_eq!(config[11..13], [0, SPS.len() as u8]);
+    assert_eq!(config[13..19], SPS);
+    assert_eq!(config[19..22], [1, 0, PPS.len() as u8]);
+    assert_eq!(config[22..], PPS);
+    // A 100ms composition offset, then the length prefixed units without
+    // the access unit delimiter.
+    let frame = &tags[1].body;
+    assert_eq!(frame[2..5], [0, 0, 100]);
+    assert_eq!(frame[5..9], (IDR.len() as u32).to_be_bytes());
+    assert_eq!(frame[9..], IDR);
+}
+
+#[test]
+fn video_waits_for_a_keyframe() {
+    let mut muxer = FlvMuxer::default();
+
+    let before = muxer.tags(video(0, 0, annex_b(&[&SPS, &PPS, &SLICE])));
+    let keyframe = muxer.tags(video(3000, 3000, annex_b(&[&IDR])));
+    let after = muxer.tags(video(6000, 6000, annex_b(&[&SLICE])));
+
+    assert_eq!(summary(&before), [(TAG_VIDEO, 0, 0x17, 0)]);
+    assert_eq!(summary(&keyframe), [(TAG_VIDEO, 33, 0x17, 1)]);
+    assert_eq!(summary(&after), [(TAG_VIDEO, 66, 0x27, 1)]);
+}
+
+#[test]
+fn adts_frames_become_raw_aac_tags() {
+    let mut muxer = FlvMuxer::default();
+
+    let tags = muxer.tags(audio(0, adts(2, &[0x21, 0x10])));
+
+    // 1024 samples at 48 kHz apart.
+    assert_eq!(
+        summary(&tags),
+        [
+            (TAG_AUDIO, 0, 0xaf, 0),
+            (TAG_AUDIO, 0, 0xaf, 1),
+            (TAG_AUDIO, 21, 0xaf, 1),
+        ]
+    );
+    assert_eq!(tags[0].body[2..], [0x11, 0x90]);
+    assert_eq!(tags[1].body[2..], [0x21, 0x10]);
+}
+
+#[test]
+fn truncated_adts_frames_are_dropped() {
+    let mut muxer = FlvMuxer::default();
+    let mut data = adts(1, &[1, 2, 3]);
+    data.truncate(8);
+
+    assert!(muxer.tags(audio(0, data)).is_empty());
+}
+
+#[test]
+fn timestamps_continue_across_the_33_bit_wrap() {
+    const WRAP: u64 = 1 << 33;
+    let mut muxer = FlvMuxer::default();
+
+    let before = muxer.tags(audio(WRAP - 90_000, adts(1, &[0])));
+    let after = muxer.tags(audio(90_000, adts(1, &[0])));
+    // A late packet from before the wrap does not go back to the start.
+    let late = muxer.tags(audio(WRAP - 45_000, adts(1, &[0])));
+
+    assert_eq!(before.last().unwrap().timestamp, 0);
+    assert_eq!(after.last().unwrap().timestamp, 2000);
+    assert_eq!(late.last().unwrap().timestamp, 500);
+}
+
+#[test]
+fn packets_without_timestamps_are_skipped() {
+    let mut muxer = FlvMuxer::default();
+    let mut pes = audio(0, adts(1, &[0]));
+    pes.dts = None;
+
+    assert!(muxer.tags(pes).is_empty());
+}
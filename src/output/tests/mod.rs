@@ -0,0 +1,3 @@
+mod flv;
+mod rtmp;
+mod ts;
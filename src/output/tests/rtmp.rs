@@ -0,0 +1,119 @@
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::thread::JoinHandle;
+use url::Url;
+
+use crate::output::rtmp::{Amf, RtmpOutput};
+
+const HANDSHAKE_SIZE: usize = 1536;
+
+fn encode(values: &[Amf]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        value.encode(&mut out);
+    }
+    out
+}
+
+#[test]
+fn amf_values_are_encoded_as_amf0() {
+    let encoded = encode(&[
+        Amf::String("connect".into()),
+        Amf::Number(1.0),
+        Amf::Bool(true),
+        Amf::Null,
+    ]);
+
+    let mut expected = vec![0x02, 0, 7];
+    expected.extend_from_slice(b"connect");
+    expected.extend_from_slice(&[0x00, 0x3f, 0xf0, 0, 0, 0, 0, 0, 0]);
+    expected.extend_from_slice(&[0x01, 1, 0x05]);
+    assert_eq!(encoded, expected);
+}
+
+#[test]
+fn amf_values_round_trip() {
+    let values = vec![
+        Amf::String("onStatus".into()),
+        Amf::Number(0.0),
+        Amf::Null,
+        Amf::Object(vec![
+            ("level".into(), Amf::String("status".into())),
+            ("code".into(), Amf::String("NetStream.Publish.Start".into())),
+            (
+                "nested".into(),
+                Amf::Object(vec![("ok".into(), Amf::Bool(false))]),
+            ),
+            ("count".into(), Amf::Number(-2.5)),
+        ]),
+    ];
+
+    assert_eq!(Amf::decode_all(&encode(&values)), values);
+}
+
+#[test]
+fn ecma_arrays_decode_as_objects() {
+    let fields = vec![("encoder".to_string(), Amf::String("fors".into()))];
+
+    let encoded = encode(&[Amf::EcmaArray(fields.clone())]);
+
+    assert_eq!(encoded[..5], [0x08, 0, 0, 0, 1]);
+    assert_eq!(Amf::decode_all(&encoded), [Amf::Object(fields)]);
+}
+
+#[test]
+fn amf_decoding_stops_at_unsupported_or_truncated_values() {
+    let mut data = encode(&[Amf::Number(3.0)]);
+    data.push(0x0a);
+    assert_eq!(Amf::decode_all(&data), [Amf::Number(3.0)]);
+
+    let mut data = encode(&[Amf::Null, Amf::String("truncated".into())]);
+    data.truncate(data.len() - 2);
+    assert_eq!(Amf::decode_all(&data), [Amf::Null]);
+}
+
+/// A server that answers the handshake with `s2` (or an echo of C1) and then
+/// sends `after`, and the URL to publish to it.
+fn server(s2: Option<Vec<u8>>, after: Vec<u8>) -> (Url, JoinHandle<io::Result<()>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("rtmp://{}/app/key", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept()?;
+        let mut c0c1 = vec![0; 1 + HANDSHAKE_SIZE];
+        socket.read_exact(&mut c0c1)?;
+        let mut reply = vec![3];
+        reply.extend(vec![0x5a; HANDSHAKE_SIZE]);
+        reply.extend(s2.unwrap_or_else(|| c0c1[1..].to_vec()));
+        socket.write_all(&reply)?;
+        socket.write_all(&after)?;
+        // Until the client gives up.
+        io::copy(&mut socket, &mut io::sink())?;
+        Ok(())
+    });
+    (Url::parse(&url).unwrap(), handle)
+}
+
+#[test]
+fn handshake_must_echo_c1() {
+    let (url, server) = server(Some(vec![0; HANDSHAKE_SIZE]), Vec::new());
+
+    let err = RtmpOutput::connect(&url).err().unwrap();
+
+    assert!(
+        format!("{err:#}").contains("did not echo the handshake"),
+        "{err:#}"
+    );
+    server.join().unwrap().ok();
+}
+
+#[test]
+fn a_chunk_size_of_zero_is_rejected() {
+    // Set Chunk Size on chunk stream 2 with a full header.
+    let message = [2, 0, 0, 0, 0, 0, 4, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    let (url, server) = server(None, message.to_vec());
+
+    let err = RtmpOutput::connect(&url).err().unwrap();
+
+    assert!(format!("{err:#}").contains("chunk size of 0"), "{err:#}");
+    server.join().unwrap().ok();
+}
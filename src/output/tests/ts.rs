@@ -0,0 +1,160 @@
+use crate::output::ts::{Demuxer, STREAM_TYPE_ADTS, STREAM_TYPE_H264, TS_PACKET_SIZE};
+
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+const PMT_PID: u16 = 0x1000;
+
+/// A TS packet of `pid`, filled up with adaptation field stuffing.
+fn packet(pid: u16, unit_start: bool, payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= TS_PACKET_SIZE - 4);
+    let mut packet = vec![
+        0x47,
+        (u8::from(unit_start) << 6) | (pid >> 8) as u8,
+        pid as u8,
+    ];
+    let stuffing = TS_PACKET_SIZE - 4 - payload.len();
+    if stuffing == 0 {
+        packet.push(0x10);
+    } else {
+        packet.push(0x30);
+        packet.push(stuffing as u8 - 1);
+        if stuffing > 1 {
+            packet.push(0);
+            packet.resize(TS_PACKET_SIZE - payload.len(), 0xff);
+        }
+    }
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// A PSI section with a dummy CRC, behind a zero pointer field.
+fn section(table_id: u8, body: &[u8]) -> Vec<u8> {
+    let length = body.len() + 4;
+    let mut payload = vec![0, table_id, 0xb0 | (length >> 8) as u8, length as u8];
+    payload.extend_from_slice(body);
+    payload.extend_from_slice(&[0; 4]);
+    payload
+}
+
+/// The PAT and PMT of a stream with H.264 video and ADTS audio.
+fn tables() -> Vec<u8> {
+    let mut pat = vec![0, 1, 0xc1, 0, 0];
+    pat.extend_from_slice(&[0, 1, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8]);
+    let mut pmt = vec![0, 1, 0xc1, 0, 0, 0xe1, 0x00, 0xf0, 0x00];
+    for (stream_type, pid) in [(STREAM_TYPE_H264, VIDEO_PID), (STREAM_TYPE_ADTS, AUDIO_PID)] {
+        pmt.extend_from_slice(&[stream_type, 0xe0 | (pid >> 8) as u8, pid as u8, 0xf0, 0]);
+    }
+    let mut data = packet(0, true, &section(0, &pat));
+    data.extend(packet(PMT_PID, true, &section(2, &pmt)));
+    data
+}
+
+fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        (prefix << 4) | ((ts >> 29) as u8 & 0x0e) | 1,
+        (ts >> 22) as u8,
+        ((ts >> 14) as u8 & 0xfe) | 1,
+        (ts >> 7) as u8,
+        ((ts << 1) as u8 & 0xfe) | 1,
+    ]
+}
+
+/// A PES packet. Video ones leave the length open, like real streams do.
+fn pes(video: bool, pts: u64, dts: Option<u64>, data: &[u8]) -> Vec<u8> {
+    let mut header = match dts {
+        Some(dts) => {
+            let mut header = vec![0x80, 0xc0, 10];
+            header.extend(timestamp(3, pts));
+            header.extend(timestamp(1, dts));
+            header
+        }
+        None => {
+            let mut header = vec![0x80, 0x80, 5];
+            header.extend(timestamp(2, pts));
+            header
+        }
+    };
+    let length = if video { 0 } else { header.len() + data.len() };
+    let mut pes = vec![0, 0, 1, if video { 0xe0 } else { 0xc0 }];
+    pes.extend_from_slice(&(length as u16).to_be_bytes());
+    pes.append(&mut header);
+    pes.extend_from_slice(data);
+    pes
+}
+
+/// Splits a PES packet over as many TS packets as it needs.
+fn packets(pid: u16, pes: &[u8]) -> Vec<u8> {
+    pes.chunks(TS_PACKET_SIZE - 4)
+        .enumerate()
+        .flat_map(|(i, chunk)| packet(pid, i == 0, chunk))
+        .collect()
+}
+
+#[test]
+fn audio_packets_complete_on_their_announced_length() {
+    let mut data = tables();
+    data.extend(packets(AUDIO_PID, &pes(false, 9000, None, &[7; 300])));
+
+    let done = Demuxer::default().push(&data);
+
+    assert_eq!(done.len(), 1);
+    assert_eq!(done[0].pid, AUDIO_PID);
+    assert_eq!(done[0].stream_type, STREAM_TYPE_ADTS);
+    assert_eq!((done[0].pts, done[0].dts), (Some(9000), Some(9000)));
+    assert_eq!(done[0].data, [7; 300]);
+}
+
+#[test]
+fn video_packets_end_at_the_next_one_or_the_end_of_the_stream() {
+    let mut data = tables();
+    data.extend(packets(VIDEO_PID, &pes(true, 3600, Some(0), &[1; 400])));
+    data.extend(packets(VIDEO_PID, &pes(true, 7200, Some(3600), &[2; 10])));
+    let mut demuxer = Demuxer::default();
+
+    let done = demuxer.push(&data);
+    assert_eq!(done.len(), 1);
+    assert_eq!((done[0].pts, done[0].dts), (Some(3600), Some(0)));
+    // Stuffing of the last packet is not part of the payload.
+    assert_eq!(done[0].data, [1; 400]);
+
+    let last = demuxer.finish();
+    assert_eq!(last.len(), 1);
+    assert_eq!((last[0].pts, last[0].dts), (Some(7200), Some(3600)));
+    assert_eq!(last[0].data, [2; 10]);
+    assert!(demuxer.finish().is_empty());
+}
+
+#[test]
+fn torn_input_is_resynced_and_reassembled() {
+    let mut data = vec![0xaa; 13];
+    data.extend(tables());
+    data.extend(packets(AUDIO_PID, &pes(false, 0, None, &[5; 500])));
+    let mut demuxer = Demuxer::default();
+
+    let mut done = Vec::new();
+    for chunk in data.chunks(100) {
+        done.extend(demuxer.push(chunk));
+    }
+
+    assert_eq!(done.len(), 1);
+    assert_eq!(done[0].data, [5; 500]);
+}
+
+#[test]
+fn timestamps_use_all_33_bits() {
+    let pts = (1 << 33) - 1;
+    let mut data = tables();
+    data.extend(packets(AUDIO_PID, &pes(false, pts, None, &[0; 4])));
+
+    let done = Demuxer::all(&data);
+
+    assert_eq!(done[0].pts, Some(pts));
+}
+
+#[test]
+fn streams_outside_the_pmt_are_ignored() {
+    let mut data = tables();
+    data.extend(packets(0x200, &pes(false, 0, None, &[0; 4])));
+
+    assert!(Demuxer::all(&data).is_empty());
+}
@@ -0,0 +1,189 @@
+//! Just enough of an MPEG-TS demuxer to pull the elementary streams out of
+//! HLS segments for outputs that speak other formats.
+
+use std::collections::HashMap;
+use tracing::debug;
+
+pub const TS_PACKET_SIZE: usize = 188;
+/// AAC audio in ADTS framing.
+pub const STREAM_TYPE_ADTS: u8 = 0x0f;
+pub const STREAM_TYPE_H264: u8 = 0x1b;
+pub const STREAM_TYPE_HEVC: u8 = 0x24;
+
+/// One complete PES packet. Timestamps are in 90 kHz units.
+pub struct Pes {
+    pub pid: u16,
+    pub stream_type: u8,
+    pub pts: Option<u64>,
+    pub dts: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+struct PartialPes {
+    pes: Pes,
+    /// Payload size from the PES header; 0 means unbounded (video).
+    expected: usize,
+}
+
+#[derive(Default)]
+pub struct Demuxer {
+    pending: Vec<u8>,
+    pmt_pid: Option<u16>,
+    /// Stream type by PID, from the PMT.
+    streams: HashMap<u16, u8>,
+    partial: HashMap<u16, PartialPes>,
+}
+
+impl Demuxer {
+    /// Demuxes a stream that is complete, such as one segment.
+    pub fn all(data: &[u8]) -> Vec<Pes> {
+        let mut demuxer = Demuxer::default();
+        let mut done = demuxer.push(data);
+        done.extend(demuxer.finish());
+        done
+    }
+
+    /// Feeds stream bytes and returns the PES packets they completed.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Pes> {
+        self.pending.extend_from_slice(data);
+        let start = self
+            .pending
+            .iter()
+            .position(|&b| b == 0x47)
+            .unwrap_or(self.pending.len());
+        if start > 0 {
+            debug!("Dropping {start} bytes to resync MPEG-TS stream");
+            self.pending.drain(..start);
+        }
+
+        let whole = self.pending.len() - self.pending.len() % TS_PACKET_SIZE;
+        let packets: Vec<u8> = self.pending.drain(..whole).collect();
+        let mut done = Vec::new();
+        for packet in packets.chunks_exact(TS_PACKET_SIZE) {
+            self.packet(packet, &mut done);
+        }
+        done
+    }
+
+    /// Returns the PES packets still being filled at the end of the stream,
+    /// such as the last video frame, which no next packet completes.
+    pub fn finish(&mut self) -> Vec<Pes> {
+        let mut done: Vec<Pes> = self
+            .partial
+            .drain()
+            .map(|(_, partial)| partial.pes)
+            .collect();
+        done.sort_by_key(|pes| (pes.dts, pes.pid));
+        done
+    }
+
+    fn packet(&mut self, packet: &[u8], done: &mut Vec<Pes>) {
+        let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+        let unit_start = packet[1] & 0x40 != 0;
+        let control = (packet[3] >> 4) & 0x3;
+        if control & 0x1 == 0 {
+            return;
+        }
+        let offset = if control & 0x2 != 0 {
+            5 + packet[4] as usize
+        } else {
+            4
+        };
+        let Some(payload) = packet.get(offset..) else {
+            return;
+        };
+
+        if pid == 0 && unit_start {
+            self.pmt_pid = section(payload).and_then(|pat| {
+                pat.get(8..)?
+                    .chunks_exact(4)
+                    .find(|program| program[..2] != [0, 0])
+                    .map(|program| (u16::from(program[2] & 0x1f) << 8) | u16::from(program[3]))
+            });
+        } else if Some(pid) == self.pmt_pid && unit_start {
+            if let Some(pmt) = section(payload) {
+                self.streams = elementary_streams(pmt);
+            }
+        } else if let Some(&stream_type) = self.streams.get(&pid) {
+            if unit_start {
+                if let Some(previous) = self.partial.remove(&pid) {
+                    done.push(previous.pes);
+                }
+                if let Some(partial) = pes_start(pid, stream_type, payload) {
+                    self.partial.insert(pid, partial);
+                }
+            } else if let Some(partial) = self.partial.get_mut(&pid) {
+                partial.pes.data.extend_from_slice(payload);
+            }
+
+            // Audio PES packets announce their size, so they need not wait
+            // for the next one.
+            if self.partial.get(&pid).is_some_and(|partial| {
+                partial.expected > 0 && partial.pes.data.len() >= partial.expected
+            }) && let Some(mut complete) = self.partial.remove(&pid)
+            {
+                complete.pes.data.truncate(complete.expected);
+                done.push(complete.pes);
+            }
+        }
+    }
+}
+
+fn pes_start(pid: u16, stream_type: u8, payload: &[u8]) -> Option<PartialPes> {
+    if payload.len() < 9 || payload[..3] != [0, 0, 1] {
+        return None;
+    }
+    let packet_length = (usize::from(payload[4]) << 8) | usize::from(payload[5]);
+    let header_length = payload[8] as usize;
+    let flags = payload[7] >> 6;
+    let pts = (flags & 0x2 != 0)
+        .then(|| payload.get(9..14).map(timestamp))
+        .flatten();
+    let dts = (flags == 0x3)
+        .then(|| payload.get(14..19).map(timestamp))
+        .flatten();
+    Some(PartialPes {
+        pes: Pes {
+            pid,
+            stream_type,
+            pts,
+            dts: dts.or(pts),
+            data: payload.get(9 + header_length..)?.to_vec(),
+        },
+        expected: packet_length.saturating_sub(3 + header_length),
+    })
+}
+
+fn timestamp(b: &[u8]) -> u64 {
+    (u64::from(b[0] >> 1) & 0x7) << 30
+        | u64::from(b[1]) << 22
+        | u64::from(b[2] >> 1) << 15
+        | u64::from(b[3]) << 7
+        | u64::from(b[4] >> 1)
+}
+
+/// The PSI section at the start of `payload`, without its CRC.
+fn section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    let length = (usize::from(section.get(1)? & 0x0f) << 8) | usize::from(*section.get(2)?);
+    section.get(..(3 + length).checked_sub(4)?)
+}
+
+fn elementary_streams(pmt: &[u8]) -> HashMap<u16, u8> {
+    let mut streams = HashMap::new();
+    let Some(info_length) = pmt
+        .get(10..12)
+        .map(|b| (usize::from(b[0] & 0x0f) << 8) | usize::from(b[1]))
+    else {
+        return streams;
+    };
+    let mut entries = pmt.get(12 + info_length..).unwrap_or_default();
+    while entries.len() >= 5 {
+        let pid = (u16::from(entries[1] & 0x1f) << 8) | u16::from(entries[2]);
+        streams.insert(pid, entries[0]);
+        let es_info_length = (usize::from(entries[3] & 0x0f) << 8) | usize::from(entries[4]);
+        entries = entries.get(5 + es_info_length..).unwrap_or_default();
+    }
+    streams
+}
@@ -0,0 +1,209 @@
+use anyhow::{Context, Result, anyhow};
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+use url::Url;
+
+use super::Sink;
+
+const TS_PACKET_SIZE: usize = 188;
+const PACKETS_PER_DATAGRAM: usize = 7;
+const PCR_HZ: f64 = 27_000_000.0;
+/// PCR jumps larger than this are treated as a discontinuity rather than waited out.
+const MAX_PCR_JUMP: f64 = 5.0;
+
+/// Sends MPEG-TS over UDP (optionally RTP framed), paced by the stream's PCR.
+pub struct UdpOutput {
+    socket: UdpSocket,
+    rtp: Option<RtpHeader>,
+    pending: Vec<u8>,
+    datagram: Vec<u8>,
+    pacer: PcrPacer,
+}
+
+impl UdpOutput {
+    pub fn connect(url: &Url) -> Result<Self> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow!("Missing host in {url}"))?
+            .trim_matches(|c| c == '[' || c == ']');
+        let port = url.port().ok_or_else(|| anyhow!("Missing port in {url}"))?;
+        let addr: SocketAddr = (host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed to resolve {host}"))?
+            .next()
+            .ok_or_else(|| anyhow!("No address found for {host}"))?;
+
+        let bind: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind).context("Failed to open UDP socket")?;
+        if let Some(ttl) = url
+            .query_pairs()
+            .find(|(k, _)| k == "ttl")
+            .and_then(|(_, v)| v.parse().ok())
+        {
+            socket.set_multicast_ttl_v4(ttl).ok();
+            socket.set_ttl(ttl).ok();
+        }
+        socket
+            .connect(addr)
+            .with_context(|| format!("Failed to connect UDP socket to {addr}"))?;
+
+        let rtp = (url.scheme() == "rtp").then(RtpHeader::new);
+        info!(
+            "Sending MPEG-TS over {} to {addr}",
+            if rtp.is_some() { "RTP" } else { "UDP" }
+        );
+
+        Ok(UdpOutput {
+            socket,
+            rtp,
+            pending: Vec::new(),
+            datagram: Vec::with_capacity(12 + TS_PACKET_SIZE * PACKETS_PER_DATAGRAM),
+            pacer: PcrPacer::default(),
+        })
+    }
+
+    fn send_packets(&mut self, packets: &[u8]) -> io::Result<()> {
+        for chunk in packets.chunks(TS_PACKET_SIZE * PACKETS_PER_DATAGRAM) {
+            let mut deadline = None;
+            for packet in chunk.chunks(TS_PACKET_SIZE) {
+                if let Some(at) = self.pacer.observe(packet) {
+                    deadline = Some(at);
+                }
+            }
+            if let Some(at) = deadline {
+                let now = Instant::now();
+                if at > now {
+                    std::thread::sleep(at - now);
+                }
+            }
+
+            self.datagram.clear();
+            if let Some(rtp) = self.rtp.as_mut() {
+                rtp.write_next(&mut self.datagram);
+            }
+            self.datagram.extend_from_slice(chunk);
+            self.socket.send(&self.datagram)?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for UdpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+
+        // Drop bytes until we are aligned on a sync byte.
+        let start = self
+            .pending
+            .iter()
+            .position(|&b| b == 0x47)
+            .unwrap_or(self.pending.len());
+        if start > 0 {
+            debug!("Dropping {start} bytes to resync MPEG-TS stream");
+            self.pending.drain(..start);
+        }
+
+        let whole = self.pending.len() - self.pending.len() % TS_PACKET_SIZE;
+        if whole > 0 {
+            let packets: Vec<u8> = self.pending.drain(..whole).collect();
+            self.send_packets(&packets)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for UdpOutput {}
+
+/// Maps PCR values from a single PID onto wall-clock deadlines.
+#[derive(Default)]
+struct PcrPacer {
+    pid: Option<u16>,
+    base: Option<(Instant, u64)>,
+}
+
+impl PcrPacer {
+    fn observe(&mut self, packet: &[u8]) -> Option<Instant> {
+        let (pid, pcr) = read_pcr(packet)?;
+        if *self.pid.get_or_insert(pid) != pid {
+            return None;
+        }
+
+        let now = Instant::now();
+        let (base_time, base_pcr) = *self.base.get_or_insert((now, pcr));
+        let offset = (pcr as f64 - base_pcr as f64) / PCR_HZ;
+        let target = base_time + Duration::from_secs_f64(offset.max(0.0));
+
+        let drift = if target > now {
+            (target - now).as_secs_f64()
+        } else {
+            -(now - target).as_secs_f64()
+        };
+        if offset < 0.0 || drift.abs() > MAX_PCR_JUMP {
+            debug!("PCR discontinuity, resetting pacing clock");
+            self.base = Some((now, pcr));
+            return Some(now);
+        }
+        Some(target)
+    }
+}
+
+fn read_pcr(packet: &[u8]) -> Option<(u16, u64)> {
+    if packet.len() < 12 || packet[0] != 0x47 {
+        return None;
+    }
+    let pid = (u16::from(packet[1] & 0x1f) << 8) | u16::from(packet[2]);
+    let has_adaptation = packet[3] & 0x20 != 0;
+    if !has_adaptation || packet[4] < 7 || packet[5] & 0x10 == 0 {
+        return None;
+    }
+
+    let b = &packet[6..12];
+    let base = (u64::from(b[0]) << 25)
+        | (u64::from(b[1]) << 17)
+        | (u64::from(b[2]) << 9)
+        | (u64::from(b[3]) << 1)
+        | (u64::from(b[4]) >> 7);
+    let ext = (u64::from(b[4] & 0x01) << 8) | u64::from(b[5]);
+    Some((pid, base * 300 + ext))
+}
+
+struct RtpHeader {
+    sequence: u16,
+    ssrc: u32,
+    start: Instant,
+}
+
+impl RtpHeader {
+    fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0);
+        RtpHeader {
+            sequence: 0,
+            ssrc: seed ^ std::process::id(),
+            start: Instant::now(),
+        }
+    }
+
+    fn write_next(&mut self, out: &mut Vec<u8>) {
+        // 90 kHz media clock, payload type 33 (MP2T) per RFC 2250.
+        let timestamp = (self.start.elapsed().as_secs_f64() * 90_000.0) as u64 as u32;
+        out.push(0x80);
+        out.push(33);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
@@ -0,0 +1,66 @@
+//! File locations and the platform quirks of paths: Windows refuses paths
+//! longer than 260 characters unless they carry the `\\?\` prefix.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Replaces the per-user directories when set (`--home`, `--portable`).
+static HOME: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the config and cache files in `dir`. Only the first call counts.
+pub fn set_home(dir: PathBuf) {
+    HOME.set(dir).ok();
+}
+
+/// `fors-data` next to the fors binary, for `--portable`.
+pub fn portable_home() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("Failed to locate the fors binary")?;
+    let dir = exe.parent().context("Failed to locate the fors binary")?;
+    Ok(dir.join("fors-data"))
+}
+
+/// Where fors keeps the cache file `name`.
+pub fn cache_file(name: &str) -> PathBuf {
+    let dir = match HOME.get() {
+        Some(home) => home.clone(),
+        None => dirs::cache_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("fors"),
+    };
+    long_path(&dir.join(name))
+}
+
+/// Where fors looks for the config file `name`.
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    let dir = match HOME.get() {
+        Some(home) => home.clone(),
+        None => dirs::config_dir()?.join("fors"),
+    };
+    Some(long_path(&dir.join(name)))
+}
+
+/// `path` in a form that may exceed `MAX_PATH` on Windows.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    const MAX_PATH: usize = 260;
+
+    let Some(absolute) = std::path::absolute(path)
+        .ok()
+        .and_then(|absolute| absolute.to_str().map(str::to_string))
+    else {
+        return path.to_path_buf();
+    };
+    if absolute.encode_utf16().count() < MAX_PATH || absolute.starts_with(r"\\?\") {
+        return path.to_path_buf();
+    }
+    match absolute.strip_prefix(r"\\") {
+        Some(share) => PathBuf::from(format!(r"\\?\UNC\{share}")),
+        None => PathBuf::from(format!(r"\\?\{absolute}")),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
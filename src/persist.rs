@@ -0,0 +1,112 @@
+//! Updates of the state files that several jobs, or several fors processes,
+//! share: the history, the resume points and the cookie jar. An update holds
+//! a lock on the file while it reads what is there now and writes the result
+//! through a temp file of its own, so concurrent updates neither lose each
+//! other's changes nor write over each other's temp files.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(test)]
+mod tests;
+
+/// Numbers the temp files of this process.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Replaces the contents of `path` with what `change` makes of its current
+/// contents, `None` if there is no file yet.
+pub fn update(path: &Path, change: impl FnOnce(Option<Vec<u8>>) -> Result<Vec<u8>>) -> Result<()> {
+    replace(path, false, change)
+}
+
+/// [`update`] for a file only the user may read, such as the cookie jar with
+/// its session cookies.
+pub fn update_private(
+    path: &Path,
+    change: impl FnOnce(Option<Vec<u8>>) -> Result<Vec<u8>>,
+) -> Result<()> {
+    replace(path, true, change)
+}
+
+fn replace(
+    path: &Path,
+    private: bool,
+    change: impl FnOnce(Option<Vec<u8>>) -> Result<Vec<u8>>,
+) -> Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    let lock_path = with_suffix(path, "lock");
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+    // Released when `lock` is closed.
+    lock.lock()
+        .with_context(|| format!("Failed to lock {}", lock_path.display()))?;
+
+    let current = match fs::read(path) {
+        Ok(bytes) => Some(bytes),
+        Err(err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let data = change(current)?;
+
+    let tmp = with_suffix(
+        path,
+        &format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ),
+    );
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    // Set on creation, so the data is never readable by others.
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let written = options
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(&data))
+        .and_then(|()| fs::rename(&tmp, path));
+    if written.is_err() {
+        fs::remove_file(&tmp).ok();
+    }
+    written.with_context(|| format!("Failed to write {}", path.display()))
+}
+
+/// [`update`] for a JSON file holding a `T`. A file that is missing or cannot
+/// be parsed counts as `T::default()`.
+pub fn update_json<T: Serialize + DeserializeOwned + Default>(
+    path: &Path,
+    change: impl FnOnce(&mut T),
+) -> Result<()> {
+    update(path, |current| {
+        let mut data = current
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        change(&mut data);
+        Ok(serde_json::to_vec_pretty(&data)?)
+    })
+}
+
+/// `path` with `.suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(OsStr::new("."));
+    name.push(suffix);
+    PathBuf::from(name)
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use super::update;
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fors-persist-{}-{name}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn concurrent_updates_keep_every_change() {
+    let dir = scratch("concurrent");
+    let path = dir.join("state.json");
+
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let path = &path;
+            scope.spawn(move || {
+                for i in 0..10 {
+                    update(path, |current| {
+                        let mut entries: Vec<u32> = current
+                            .map(|bytes| serde_json::from_slice(&bytes).unwrap())
+                            .unwrap_or_default();
+                        entries.push(thread * 100 + i);
+                        Ok(serde_json::to_vec(&entries)?)
+                    })
+                    .unwrap();
+                }
+            });
+        }
+    });
+
+    let mut entries: Vec<u32> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    entries.sort();
+    let expected: Vec<u32> = (0..8)
+        .flat_map(|thread| (0..10).map(move |i| thread * 100 + i))
+        .collect();
+    assert_eq!(entries, expected);
+    // Only the file and its lock are left, no temp files.
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    std::fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn failed_change_leaves_the_file_alone() {
+    let dir = scratch("failed");
+    let path = dir.join("state");
+    std::fs::write(&path, "before").unwrap();
+
+    let result = update(&path, |current| {
+        assert_eq!(current.as_deref(), Some(&b"before"[..]));
+        anyhow::bail!("no change")
+    });
+
+    assert!(result.is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "before");
+    std::fs::remove_dir_all(dir).ok();
+}
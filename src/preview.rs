@@ -0,0 +1,169 @@
+//! `--preview-frames`: a contact sheet of a stream from its I-frame playlist,
+//! so a long VOD can be judged from a few keyframes before downloading it.
+
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+use reqwest::header::{ACCEPT_ENCODING, RANGE};
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::hls::{IFramePlaylist, MediaSegment, StreamVariant, fetch_media_playlist};
+use crate::http::Retry;
+use crate::units::format_duration;
+
+/// Frames in the sheet when no interval is given.
+const DEFAULT_FRAMES: usize = 24;
+const COLUMNS: usize = 4;
+/// Width of a frame in the contact sheet.
+const TILE_WIDTH: u32 = 320;
+
+pub fn run(
+    client: &Client,
+    ffmpeg: &str,
+    playlists: &[IFramePlaylist],
+    variant: &StreamVariant,
+    dir: &Path,
+    interval: Option<Duration>,
+) -> Result<()> {
+    let playlist = playlists
+        .iter()
+        .find(|playlist| playlist.resolution.is_some() && playlist.resolution == variant.resolution)
+        .or_else(|| playlists.iter().max_by_key(|playlist| playlist.bandwidth))
+        .context("The stream has no I-frame playlist to preview")?;
+    info!("Using the I-frame playlist {}", playlist.uri);
+    let playlist = fetch_media_playlist(client, &playlist.uri)?;
+    let frames = pick_frames(&playlist.segments, interval);
+    if frames.is_empty() {
+        bail!("The I-frame playlist has no frames");
+    }
+
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    let mut extracted = 0usize;
+    for (time, frame) in frames {
+        let time = format_duration(Duration::from_secs_f64(time));
+        let path = dir.join(format!("frame-{:04}.jpg", extracted + 1));
+        match download(client, frame).and_then(|data| extract_frame(ffmpeg, &data, &path)) {
+            Ok(()) => {
+                extracted += 1;
+                println!("{time:>9}  {}", path.display());
+            }
+            Err(err) => warn!("Skipping the frame at {time}: {err:#}"),
+        }
+    }
+    if extracted == 0 {
+        bail!("None of the frames could be extracted");
+    }
+
+    let sheet = dir.join("contact-sheet.jpg");
+    let status = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+        .arg(dir.join("frame-%04d.jpg"))
+        .arg("-vf")
+        .arg(format!(
+            // Frames of an earlier run may follow in the directory.
+            "trim=end_frame={extracted},scale={TILE_WIDTH}:-1,tile={COLUMNS}x{}",
+            extracted.div_ceil(COLUMNS)
+        ))
+        .args(["-frames:v", "1"])
+        .arg(&sheet)
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to start '{ffmpeg}' (needed for --preview-frames)"))?;
+    if !status.success() {
+        bail!("ffmpeg exited with {status} while building the contact sheet");
+    }
+    println!("Contact sheet of {extracted} frames: {}", sheet.display());
+    Ok(())
+}
+
+/// The first frame at or after every `interval`, with the time it shows.
+fn pick_frames(segments: &[MediaSegment], interval: Option<Duration>) -> Vec<(f64, &MediaSegment)> {
+    let total: f64 = segments.iter().map(|segment| segment.duration).sum();
+    let interval = interval
+        .map_or(total / DEFAULT_FRAMES as f64, |interval| {
+            interval.as_secs_f64()
+        })
+        .max(f64::EPSILON);
+    let mut frames = Vec::new();
+    let mut time = 0.0;
+    let mut next = 0.0;
+    for segment in segments {
+        if time >= next && !segment.ad {
+            frames.push((time, segment));
+            next = (time / interval).floor() * interval + interval;
+        }
+        time += segment.duration;
+    }
+    frames
+}
+
+/// The frame's bytes, after the initialization section it needs.
+fn download(client: &Client, frame: &MediaSegment) -> Result<Vec<u8>> {
+    if frame.key.is_some() {
+        bail!("Encrypted I-frame playlists are not supported");
+    }
+    let mut data = match &frame.init {
+        Some(init) => get(client, init, frame.init_range)?,
+        None => Vec::new(),
+    };
+    data.extend(get(client, &frame.uri, frame.byte_range)?);
+    Ok(data)
+}
+
+fn get(client: &Client, url: &Url, range: Option<(u64, u64)>) -> Result<Vec<u8>> {
+    let mut request = client.get(url.clone());
+    if let Some((offset, length)) = range {
+        request = request
+            .header(RANGE, format!("bytes={offset}-{}", offset + length - 1))
+            .header(ACCEPT_ENCODING, "identity");
+    }
+    let mut response = Retry::SEGMENT
+        .send(request)
+        .with_context(|| format!("Requesting {url}"))?
+        .error_for_status()
+        .with_context(|| format!("Download failed: {url}"))?;
+    let mut data = Vec::new();
+    response
+        .read_to_end(&mut data)
+        .with_context(|| format!("Reading {url} failed"))?;
+    if let Some((offset, length)) = range
+        && response.status() != reqwest::StatusCode::PARTIAL_CONTENT
+    {
+        // The server ignored the range and sent the whole file.
+        let start = (offset as usize).min(data.len());
+        let end = (start + length as usize).min(data.len());
+        data = data[start..end].to_vec();
+    }
+    Ok(data)
+}
+
+/// Decodes the one frame in `data` into a JPEG at `path`.
+fn extract_frame(ffmpeg: &str, data: &[u8], path: &Path) -> Result<()> {
+    let mut child = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-y", "-i", "pipe:0"])
+        .args(["-frames:v", "1", "-q:v", "3"])
+        .arg(path)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start '{ffmpeg}' (needed for --preview-frames)"))?;
+    let mut stdin = child.stdin.take().context("ffmpeg has no stdin")?;
+    // ffmpeg may stop reading once it has the frame.
+    if let Err(err) = stdin.write_all(data)
+        && err.kind() != ErrorKind::BrokenPipe
+    {
+        child.kill().ok();
+        return Err(err).context("Failed to write to ffmpeg");
+    }
+    drop(stdin);
+    let status = child.wait().context("Failed to wait for ffmpeg")?;
+    if !status.success() {
+        bail!("ffmpeg exited with {status}");
+    }
+    Ok(())
+}
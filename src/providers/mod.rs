@@ -1,16 +1,62 @@
 use anyhow::{Result, bail};
+use fors_core::provider::{self, StreamMetadata, Target};
+use fors_core::twitch::TwitchTarget;
 use reqwest::blocking::Client;
-use url::Url;
+use std::time::Duration;
 
-use crate::hls::StreamVariant;
+use crate::hls::{AudioRendition, IFramePlaylist, QueryPassthrough, StopHandle, StreamVariant};
+use crate::http::UserAgentProfile;
 
 pub mod twitch;
 pub mod youtube;
 
+pub use fors_core::provider::provider_name_for;
+
+/// The domains the cookie jar sends cookies to and takes them from: the
+/// provider sites and the consent pages in front of YouTube, but no CDN.
+pub const COOKIE_DOMAINS: &[&str] = &["twitch.tv", "youtube.com", "google.com"];
+
+/// Provider specific settings taken from the command line or config.
+#[derive(Debug, Clone, Default)]
+pub struct ProviderOptions {
+    pub twitch_low_latency: bool,
+    pub cache: bool,
+    pub twitch_proxy_playlist: Option<String>,
+    /// JavaScript runtime that evaluates YouTube's player code.
+    pub js_runtime: String,
+    pub youtube_po_token: Option<String>,
+    pub youtube_visitor_data: Option<String>,
+    pub user_agent_profile: Option<UserAgentProfile>,
+    /// `--user-agent` replaced the default, so providers keep it.
+    pub custom_user_agent: bool,
+    /// Send the headers a browser would with YouTube page and API requests.
+    pub youtube_browser_headers: bool,
+    /// How URLs in the playlists treat the query of the playlist URL.
+    pub segment_query: QueryPassthrough,
+}
+
+impl ProviderOptions {
+    /// The user agent for API requests of a provider that prefers posing as
+    /// `preferred`; `None` keeps the client's.
+    pub fn api_user_agent(&self, preferred: Option<UserAgentProfile>) -> Option<&'static str> {
+        self.user_agent_profile
+            .or(preferred.filter(|_| !self.custom_user_agent))
+            .map(UserAgentProfile::user_agent)
+    }
+}
+
 pub struct StreamSet {
     pub variants: Vec<StreamVariant>,
+    /// Alternative audio tracks, e.g. dubs, for `--audio-lang`.
+    pub audio_tracks: Vec<AudioRendition>,
+    /// Keyframe-only playlists, for `--preview-frames`.
+    pub iframe_playlists: Vec<IFramePlaylist>,
     pub is_live: bool,
     pub low_latency: bool,
+    pub segment_query: QueryPassthrough,
+    /// Where in a VOD to start, e.g. from a `?t=` link.
+    pub start_offset: Option<Duration>,
+    pub metadata: StreamMetadata,
 }
 
 pub enum Provider {
@@ -18,19 +64,35 @@ pub enum Provider {
     YouTube(youtube::YouTubeSource),
 }
 
+/// Expands URLs that stand for several streams, such as Twitch collections,
+/// into one URL per stream.
+pub fn expand_url(client: &Client, url: &str) -> Result<Vec<String>> {
+    match provider::resolve(url) {
+        Ok(Target::TwitchCollection { id }) => twitch::collection_urls(client, &id),
+        _ => Ok(vec![url.to_string()]),
+    }
+}
+
 impl Provider {
-    pub fn from_url(input: &str, twitch_low_latency: bool, cache: bool) -> Result<Self> {
-        let url = Url::parse(input)?;
-
-        if twitch::is_twitch_url(&url) {
-            let source = twitch::TwitchSource::from_url(url, twitch_low_latency, cache)?;
-            Ok(Provider::Twitch(source))
-        } else if youtube::is_youtube_url(&url) {
-            let source = youtube::YouTubeSource::from_url(url)?;
-            Ok(Provider::YouTube(source))
-        } else {
-            bail!("Unsupported URL: {input}");
-        }
+    pub fn from_url(input: &str, options: &ProviderOptions) -> Result<Self> {
+        Ok(match provider::resolve(input)? {
+            Target::TwitchLive { channel } => Provider::Twitch(twitch::TwitchSource::new(
+                TwitchTarget::Live { channel },
+                None,
+                options,
+            )),
+            Target::TwitchVod { id, start } => Provider::Twitch(twitch::TwitchSource::new(
+                TwitchTarget::Vod { id },
+                start.map(Duration::from_secs),
+                options,
+            )),
+            Target::TwitchCollection { id } => {
+                bail!("Twitch collection {id} has to be expanded into its videos first")
+            }
+            Target::YouTube { video_id } => {
+                Provider::YouTube(youtube::YouTubeSource::new(video_id, options)?)
+            }
+        })
     }
 
     pub fn load_streams(&self, client: &Client) -> Result<StreamSet> {
@@ -40,6 +102,32 @@ impl Provider {
         }
     }
 
+    /// Asks the provider's API whether the live stream is still on, to tell
+    /// an ended stream from a broken playlist.
+    pub fn is_live(&self, client: &Client) -> Result<bool> {
+        match self {
+            Provider::Twitch(src) => src.is_live(client),
+            Provider::YouTube(src) => src.is_live(client),
+        }
+    }
+
+    /// Waits for an offline stream to come back, for `--wait`. Returns false
+    /// if `stop` was stopped first.
+    pub fn wait_until_live(&self, client: &Client, stop: &StopHandle, interval: Duration) -> bool {
+        match self {
+            Provider::Twitch(src) => src.wait_until_live(client, stop, interval),
+            Provider::YouTube(_) => stop.sleep(interval),
+        }
+    }
+
+    /// Channel name or video id, used for output templates and bookkeeping.
+    pub fn id(&self) -> String {
+        match self {
+            Provider::Twitch(src) => src.id().to_string(),
+            Provider::YouTube(src) => src.video_id().to_string(),
+        }
+    }
+
     pub fn name(&self) -> &'static str {
         match self {
             Provider::Twitch(_) => "twitch",
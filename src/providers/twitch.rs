@@ -1,117 +1,242 @@
-use anyhow::{Context, Result, anyhow, bail};
-use log::info;
-use reqwest::blocking::Client;
-use serde::Deserialize;
-use serde_json::json;
+use anyhow::{Context, Result, bail};
+use fors_core::provider::StreamMetadata;
+use fors_core::twitch::{self, AccessToken, CLIENT_ID, GQL_ENDPOINT, TwitchTarget};
+use reqwest::blocking::{Client, RequestBuilder};
+use reqwest::header::USER_AGENT;
+use std::time::Duration;
+use tracing::{debug, info, warn};
 use url::Url;
 
-use super::StreamSet;
+use super::{ProviderOptions, StreamSet};
 mod cache;
-use crate::hls::parse_master_playlist;
+mod monitor;
+use crate::hls::{
+    QueryPassthrough, StopHandle, StreamVariant, parse_iframe_playlists, parse_master_playlist,
+};
+use crate::http::Retry;
 use cache::Cache;
 
-const CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
-const GQL_ENDPOINT: &str = "https://gql.twitch.tv/gql";
-// Persisted query hash used by Twitch web player (2024-12)
-const PLAYBACK_HASH: &str = "ed230aa1e33e07eebb8928504583da78a5173989fadfb1ac94be06a04f3cdbe9";
-
-pub enum TwitchTarget {
-    Live { channel: String },
-    Vod { id: String },
-}
+/// The hosts of the Twitch API, which `--twitch-rate-limit` applies to.
+pub const API_HOSTS: &[&str] = &["gql.twitch.tv", "usher.ttvnw.net"];
 
 pub struct TwitchSource {
     target: TwitchTarget,
+    start: Option<Duration>,
     low_latency: bool,
     use_cache: bool,
-}
-
-pub fn is_twitch_url(url: &Url) -> bool {
-    url.host_str()
-        .map(|host| host == "twitch.tv" || host.ends_with(".twitch.tv"))
-        .unwrap_or(false)
+    proxy_playlist: Option<String>,
+    user_agent: Option<&'static str>,
+    segment_query: QueryPassthrough,
 }
 
 impl TwitchSource {
-    pub fn from_url(url: Url, low_latency: bool, use_cache: bool) -> Result<Self> {
-        let segments: Vec<String> = url
-            .path_segments()
-            .map(|segments| {
-                segments
-                    .filter(|p| !p.is_empty())
-                    .map(String::from)
-                    .collect()
-            })
-            .unwrap_or_default();
-
-        if segments.first().map(|s| s == "videos").unwrap_or(false) {
-            let id = segments
-                .get(1)
-                .cloned()
-                .ok_or_else(|| anyhow!("Missing VOD id in URL"))?;
-            Ok(TwitchSource {
-                target: TwitchTarget::Vod { id },
-                low_latency,
-                use_cache,
-            })
-        } else if let Some(channel) = segments.first() {
-            Ok(TwitchSource {
-                target: TwitchTarget::Live {
-                    channel: channel.clone(),
-                },
-                low_latency,
-                use_cache,
-            })
-        } else {
-            bail!("Invalid Twitch URL: {}", url);
+    pub fn new(target: TwitchTarget, start: Option<Duration>, options: &ProviderOptions) -> Self {
+        TwitchSource {
+            target,
+            start,
+            low_latency: options.twitch_low_latency,
+            use_cache: options.cache,
+            proxy_playlist: options.twitch_proxy_playlist.clone(),
+            user_agent: options.api_user_agent(None),
+            segment_query: options.segment_query,
+        }
+    }
+
+    /// Adds what every request to the Twitch API carries.
+    fn api(&self, request: RequestBuilder) -> RequestBuilder {
+        let request = request.header("Client-ID", CLIENT_ID);
+        match self.user_agent {
+            Some(agent) => request.header(USER_AGENT, agent),
+            None => request,
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match &self.target {
+            TwitchTarget::Live { channel } => channel,
+            TwitchTarget::Vod { id } => id,
         }
     }
 
     pub fn load_streams(&self, client: &Client) -> Result<StreamSet> {
+        if let (Some(template), TwitchTarget::Live { channel }) =
+            (&self.proxy_playlist, &self.target)
+        {
+            match self.load_proxy_playlist(client, template, channel) {
+                Ok(mut streams) => {
+                    streams.metadata = self.fetch_metadata(client);
+                    return Ok(streams);
+                }
+                Err(err) => warn!("Playlist proxy failed, falling back to Twitch: {err:#}"),
+            }
+        }
+
         let cache = Cache::new()?;
         let cached_manifest = if self.use_cache {
-            cache.load_manifest_url(&self.target)
+            cache
+                .load_manifest_url(&self.target)
+                .and_then(|url| Url::parse(&url).ok())
         } else {
             None
         };
 
-        let token = self.fetch_access_token(client, &cache)?;
-        let manifest_url = cached_manifest
-            .and_then(|url| Url::parse(&url).ok())
-            .unwrap_or_else(|| {
-                self.build_manifest_url(&token)
-                    .expect("Failed to build manifest URL")
-            });
+        // A cached manifest URL usually still works, so it is tried while the
+        // token that a fresh one needs is being fetched.
+        let (token, cached, metadata) = std::thread::scope(|scope| {
+            let cached = cached_manifest
+                .map(|url| scope.spawn(move || self.fetch_master_playlist(client, url)));
+            let metadata = scope.spawn(|| self.fetch_metadata(client));
+            let token = self.fetch_access_token(client, &cache);
+            (
+                token,
+                cached.map(|handle| handle.join()),
+                metadata.join().unwrap_or_default(),
+            )
+        });
+        let (playlist_url, variants) = match cached {
+            Some(Ok(Ok(master))) => master,
+            cached => {
+                if let Some(Ok(Err(err))) = cached {
+                    info!("Cached Twitch playlist failed, requesting a new one: {err:#}");
+                }
+                let manifest_url = twitch::manifest_url(&self.target, &token?, self.low_latency)
+                    .context("Failed to build manifest URL")?;
+                self.fetch_master_playlist(client, manifest_url)?
+            }
+        };
 
-        let response = client
-            .get(manifest_url.clone())
-            .header("Client-ID", CLIENT_ID)
-            .send()
-            .context("Failed to request Twitch master playlist")?
-            .error_for_status()
-            .context("Twitch returned an error for the playlist request")?;
+        if self.use_cache {
+            cache.store_manifest_url(&self.target, playlist_url.as_str());
+        }
+
+        let mut streams = self.stream_set(variants);
+        streams.metadata = metadata;
+        Ok(streams)
+    }
+
+    /// Title, viewers and category for listings. Not needed to record, so
+    /// failures only leave them empty.
+    fn fetch_metadata(&self, client: &Client) -> StreamMetadata {
+        let request = self
+            .api(client.post(GQL_ENDPOINT))
+            .json(&twitch::metadata_request(&self.target));
+        let result = Retry::ONCE
+            .send(request)
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json::<serde_json::Value>())
+            .context("Failed to request Twitch stream metadata")
+            .and_then(|value| twitch::parse_metadata(&self.target, &value));
+        result.unwrap_or_else(|err| {
+            debug!("{err:#}");
+            StreamMetadata::default()
+        })
+    }
 
+    fn fetch_master_playlist(
+        &self,
+        client: &Client,
+        manifest_url: Url,
+    ) -> Result<(Url, Vec<StreamVariant>)> {
+        let response = Retry::API
+            .send(self.api(client.get(manifest_url)))
+            .context("Failed to request Twitch master playlist")?;
+        let status = response.status();
         let playlist_url = response.url().clone();
         let body = response
             .text()
             .context("Failed to read Twitch playlist body")?;
-        let variants = parse_master_playlist(&playlist_url, &body)?;
+        if let Some(err) = twitch::usher_error(&self.target, status.as_u16(), &body) {
+            return Err(err);
+        }
+        if !status.is_success() {
+            bail!("Twitch returned {status} for the playlist request");
+        }
+        let variants = parse_master_playlist(&playlist_url, &body, self.segment_query)?;
+        Ok((playlist_url, variants))
+    }
 
-        if self.use_cache {
-            cache.store_manifest_url(&self.target, playlist_url.as_str());
+    /// Fetches the master playlist from a user supplied playlist proxy. The
+    /// template must contain a `{channel}` placeholder.
+    fn load_proxy_playlist(
+        &self,
+        client: &Client,
+        template: &str,
+        channel: &str,
+    ) -> Result<StreamSet> {
+        if !template.contains("{channel}") {
+            bail!("The playlist proxy URL must contain a {{channel}} placeholder");
         }
+        let url = Url::parse(&template.replace("{channel}", &channel.to_lowercase()))
+            .context("Invalid playlist proxy URL")?;
+
+        info!(
+            "Requesting Twitch playlist through proxy {}",
+            url.host_str().unwrap_or_default()
+        );
+        let response = Retry::API
+            .send(client.get(url))
+            .context("Failed to request proxied playlist")?
+            .error_for_status()
+            .context("Playlist proxy returned an error")?;
+        let playlist_url = response.url().clone();
+        let body = response.text().context("Failed to read proxied playlist")?;
+        let variants = parse_master_playlist(&playlist_url, &body, self.segment_query)?;
+
+        let mut streams = self.stream_set(variants);
+        streams.iframe_playlists =
+            parse_iframe_playlists(&playlist_url, &body, self.segment_query)?;
+        Ok(streams)
+    }
 
-        info!("Will skip Twitch ad segments");
+    fn stream_set(&self, variants: Vec<StreamVariant>) -> StreamSet {
+        let is_live = matches!(self.target, TwitchTarget::Live { .. });
+        if is_live {
+            info!("Will skip Twitch ad segments");
+        }
         if self.low_latency {
             info!("Low latency streaming (prefetch segments enabled)");
         }
 
-        let is_live = matches!(self.target, TwitchTarget::Live { .. });
-        Ok(StreamSet {
+        StreamSet {
             variants,
+            audio_tracks: Vec::new(),
+            iframe_playlists: Vec::new(),
             is_live,
             low_latency: self.low_latency,
-        })
+            segment_query: self.segment_query,
+            start_offset: self.start,
+            metadata: StreamMetadata::default(),
+        }
+    }
+
+    /// Whether the channel is broadcasting, according to the API rather than
+    /// its playlist.
+    pub fn is_live(&self, client: &Client) -> Result<bool> {
+        let TwitchTarget::Live { channel } = &self.target else {
+            return Ok(false);
+        };
+        let request = self
+            .api(client.post(GQL_ENDPOINT))
+            .json(&twitch::stream_status_request(channel));
+        let value: serde_json::Value = Retry::ONCE
+            .send(request)
+            .context("Failed to request Twitch stream status")?
+            .error_for_status()
+            .context("Twitch returned an error for the stream status request")?
+            .json()
+            .context("Could not parse Twitch stream status response")?;
+        Ok(twitch::parse_stream_status(&value)?.is_some())
+    }
+
+    /// Waits for an offline channel to go live, checked together with the
+    /// other channels being waited on. Returns false if `stop` was stopped.
+    pub fn wait_until_live(&self, client: &Client, stop: &StopHandle, interval: Duration) -> bool {
+        match &self.target {
+            TwitchTarget::Live { channel } => {
+                monitor::wait_until_live(channel, client, self.user_agent, stop, interval)
+            }
+            TwitchTarget::Vod { .. } => stop.sleep(interval),
+        }
     }
 
     fn fetch_access_token(&self, client: &Client, cache: &Cache) -> Result<AccessToken> {
@@ -124,37 +249,11 @@ impl TwitchSource {
             });
         }
 
-        let variables = match &self.target {
-            TwitchTarget::Live { channel } => json!({
-                "isLive": true,
-                "login": channel,
-                "isVod": false,
-                "vodID": "",
-                "playerType": "embed",
-                "platform": "site",
-            }),
-            TwitchTarget::Vod { id } => json!({
-                "isLive": false,
-                "login": "",
-                "isVod": true,
-                "vodID": id,
-                "playerType": "embed",
-                "platform": "site",
-            }),
-        };
-
-        let payload = json!({
-            "operationName": "PlaybackAccessToken",
-            "extensions": { "persistedQuery": { "version": 1, "sha256Hash": PLAYBACK_HASH } },
-            "variables": variables,
-        });
+        let payload = twitch::access_token_request(&self.target);
 
         info!("Requesting Twitch access token");
-        let response = client
-            .post(GQL_ENDPOINT)
-            .header("Client-ID", CLIENT_ID)
-            .json(&payload)
-            .send()
+        let response = Retry::API
+            .send(self.api(client.post(GQL_ENDPOINT)).json(&payload))
             .context("Failed to request Twitch access token")?
             .error_for_status()
             .context("Twitch returned an error while getting an access token")?;
@@ -163,37 +262,7 @@ impl TwitchSource {
             .json()
             .context("Could not parse Twitch access token response")?;
 
-        if let Some(errors) = value.get("errors").and_then(|v| v.as_array())
-            && let Some(msg) = errors
-                .first()
-                .and_then(|err| err.get("message").and_then(|m| m.as_str()))
-        {
-            bail!("Twitch API error: {msg}");
-        }
-
-        if let (Some(error), Some(message)) = (
-            value.get("error").and_then(|v| v.as_str()),
-            value.get("message").and_then(|v| v.as_str()),
-        ) {
-            bail!("Twitch API error: {error}: {message}");
-        }
-
-        let data: PlaybackData = serde_json::from_value(
-            value
-                .get("data")
-                .cloned()
-                .unwrap_or_else(|| serde_json::json!({})),
-        )
-        .context("Malformed Twitch access token response")?;
-
-        let token = match &self.target {
-            TwitchTarget::Live { .. } => data
-                .streamPlaybackAccessToken
-                .ok_or_else(|| anyhow!("No access token returned for live channel")),
-            TwitchTarget::Vod { .. } => data
-                .videoPlaybackAccessToken
-                .ok_or_else(|| anyhow!("No access token returned for VOD")),
-        }?;
+        let token = twitch::parse_access_token(&self.target, &value)?;
 
         if self.use_cache {
             cache.store_token(&self.target, &token);
@@ -201,44 +270,36 @@ impl TwitchSource {
 
         Ok(token)
     }
-
-    fn build_manifest_url(&self, token: &AccessToken) -> Result<Url> {
-        let encoded = urlencoding::encode(&token.value);
-        let url = match &self.target {
-            TwitchTarget::Live { channel } => format!(
-                "https://usher.ttvnw.net/api/channel/hls/{channel}.m3u8?sig={sig}&token={token}&allow_source=true&allow_audio_only=true&allow_spectre=true&player=twitchweb&client_id={client}{fast_bread}",
-                sig = token.signature,
-                token = encoded,
-                client = CLIENT_ID,
-                fast_bread = if self.low_latency {
-                    "&fast_bread=true"
-                } else {
-                    ""
-                },
-            ),
-            TwitchTarget::Vod { id } => format!(
-                "https://usher.ttvnw.net/vod/{id}.m3u8?sig={sig}&token={token}&allow_source=true&allow_spectre=true&player=twitchweb&client_id={client}",
-                sig = token.signature,
-                token = encoded,
-                client = CLIENT_ID,
-            ),
-        };
-
-        Url::parse(&url).context("Failed to build Twitch manifest URL")
-    }
 }
 
-#[derive(Debug, Deserialize)]
-struct AccessToken {
-    signature: String,
-    value: String,
-}
+/// Lists the VODs of a collection, as URLs in collection order.
+pub fn collection_urls(client: &Client, id: &str) -> Result<Vec<String>> {
+    info!("Requesting Twitch collection {id}");
+    let value: serde_json::Value = Retry::API
+        .send(
+            client
+                .post(GQL_ENDPOINT)
+                .header("Client-ID", CLIENT_ID)
+                .json(&twitch::collection_request(id)),
+        )
+        .context("Failed to request Twitch collection")?
+        .error_for_status()
+        .context("Twitch returned an error for the collection request")?
+        .json()
+        .context("Could not parse Twitch collection response")?;
 
-#[allow(non_snake_case)]
-#[derive(Debug, Deserialize)]
-struct PlaybackData {
-    #[serde(rename = "streamPlaybackAccessToken")]
-    streamPlaybackAccessToken: Option<AccessToken>,
-    #[serde(rename = "videoPlaybackAccessToken")]
-    videoPlaybackAccessToken: Option<AccessToken>,
+    let collection = twitch::parse_collection(&value)?;
+    if collection.video_ids.is_empty() {
+        bail!("Twitch collection {id} has no videos");
+    }
+    info!(
+        "Collection '{}' has {} videos",
+        collection.title,
+        collection.video_ids.len()
+    );
+    Ok(collection
+        .video_ids
+        .iter()
+        .map(|video| format!("https://www.twitch.tv/videos/{video}"))
+        .collect())
 }
@@ -5,6 +5,7 @@ use std::path::PathBuf;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::TwitchTarget;
+use crate::paths;
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 struct CacheFile {
@@ -38,10 +39,7 @@ pub struct Cache {
 
 impl Cache {
     pub fn new() -> Result<Self> {
-        let path = dirs::cache_dir()
-            .unwrap_or_else(std::env::temp_dir)
-            .join("fors")
-            .join("twitch_cache.json");
+        let path = paths::cache_file("twitch_cache.json");
 
         let data = fs::read(&path)
             .ok()
@@ -61,11 +59,7 @@ impl Cache {
             .map(|entry| (entry.signature.clone(), entry.value.clone()))
     }
 
-    pub fn store_token(
-        &self,
-        target: &TwitchTarget,
-        token: &crate::providers::twitch::AccessToken,
-    ) {
+    pub fn store_token(&self, target: &TwitchTarget, token: &fors_core::twitch::AccessToken) {
         if let Some((kind, key)) = cache_key(target) {
             let mut data = self.data.clone();
             let expires_at = now_secs() + CACHE_TTL_TOKEN;
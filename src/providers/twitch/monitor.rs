@@ -0,0 +1,159 @@
+//! Live checks for the Twitch channels `--wait` is waiting on. Rather than
+//! every waiting job asking usher on its own, one thread asks GQL about all
+//! of them in a single batched request per interval, which keeps monitoring
+//! many channels from running into rate limits.
+
+use anyhow::{Context, Result, bail};
+use fors_core::twitch::{self, CLIENT_ID, GQL_BATCH_LIMIT, GQL_ENDPOINT};
+use reqwest::blocking::Client;
+use reqwest::header::USER_AGENT;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Condvar, LazyLock, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::hls::StopHandle;
+use crate::http::Retry;
+use crate::systemd;
+
+#[derive(Default)]
+struct Monitor {
+    /// Channels being waited on, with the number of jobs waiting on each.
+    waiting: HashMap<String, usize>,
+    /// Waited on channels that are live, or whose check failed, so their
+    /// jobs should load the stream again.
+    ready: HashSet<String>,
+    /// Counts the checks, so waiters notice each one.
+    checks: u64,
+    polling: bool,
+}
+
+static MONITOR: LazyLock<Mutex<Monitor>> = LazyLock::new(Mutex::default);
+static CHECKED: Condvar = Condvar::new();
+
+fn lock() -> MutexGuard<'static, Monitor> {
+    MONITOR.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Blocks until a check finds `channel` live. Returns false if `stop` was
+/// stopped first.
+pub fn wait_until_live(
+    channel: &str,
+    client: &Client,
+    user_agent: Option<&'static str>,
+    stop: &StopHandle,
+    interval: Duration,
+) -> bool {
+    let mut monitor = lock();
+    *monitor.waiting.entry(channel.to_string()).or_default() += 1;
+    if !monitor.polling {
+        monitor.polling = true;
+        let client = client.clone();
+        std::thread::spawn(move || poll(&client, user_agent, interval));
+    }
+
+    let mut seen = monitor.checks;
+    systemd::idle_for(interval);
+    let live = loop {
+        if stop.is_stopped() {
+            break false;
+        }
+        monitor = CHECKED
+            .wait_timeout(monitor, Duration::from_millis(250))
+            .unwrap_or_else(|e| e.into_inner())
+            .0;
+        if monitor.checks == seen {
+            continue;
+        }
+        seen = monitor.checks;
+        if monitor.ready.contains(channel) {
+            break true;
+        }
+        debug!("{channel} is still offline");
+        systemd::idle_for(interval);
+    };
+
+    if let Some(count) = monitor.waiting.get_mut(channel) {
+        *count -= 1;
+        if *count == 0 {
+            monitor.waiting.remove(channel);
+            monitor.ready.remove(channel);
+        }
+    }
+    live
+}
+
+/// Checks the waited on channels every `interval` until none are left.
+fn poll(client: &Client, user_agent: Option<&'static str>, interval: Duration) {
+    loop {
+        std::thread::sleep(interval);
+        let channels: Vec<String> = {
+            let mut monitor = lock();
+            if monitor.waiting.is_empty() {
+                monitor.polling = false;
+                return;
+            }
+            monitor
+                .waiting
+                .keys()
+                .filter(|channel| !monitor.ready.contains(*channel))
+                .cloned()
+                .collect()
+        };
+
+        let mut ready = Vec::new();
+        for batch in channels.chunks(GQL_BATCH_LIMIT) {
+            match check(client, user_agent, batch) {
+                Ok(live) => ready.extend(live),
+                Err(err) => {
+                    // The jobs fall back to checking their channel themselves.
+                    warn!("{err:#}");
+                    ready.extend(batch.iter().cloned());
+                }
+            }
+        }
+
+        let mut monitor = lock();
+        monitor.ready.extend(ready);
+        monitor.checks += 1;
+        CHECKED.notify_all();
+    }
+}
+
+/// The channels of `batch` that are live or could not be checked.
+fn check(
+    client: &Client,
+    user_agent: Option<&'static str>,
+    batch: &[String],
+) -> Result<Vec<String>> {
+    debug!("Checking whether {} Twitch channels are live", batch.len());
+    let logins: Vec<&str> = batch.iter().map(String::as_str).collect();
+    let mut request = client
+        .post(GQL_ENDPOINT)
+        .header("Client-ID", CLIENT_ID)
+        .json(&twitch::stream_status_batch_request(&logins));
+    if let Some(agent) = user_agent {
+        request = request.header(USER_AGENT, agent);
+    }
+    let value: serde_json::Value = Retry::ONCE
+        .send(request)
+        .context("Failed to request the status of the waited on Twitch channels")?
+        .error_for_status()
+        .context("Twitch returned an error for the batched stream status request")?
+        .json()
+        .context("Could not parse the batched Twitch stream status response")?;
+    let statuses = twitch::parse_stream_status_batch(&value)?;
+    if statuses.len() != batch.len() {
+        bail!(
+            "Twitch answered {} of {} stream status requests",
+            statuses.len(),
+            batch.len()
+        );
+    }
+    Ok(batch
+        .iter()
+        .zip(statuses)
+        .filter(|(_, status)| !matches!(status, Ok(None)))
+        .map(|(channel, _)| channel.clone())
+        .collect())
+}
@@ -1,64 +1,92 @@
-use anyhow::{Context, Result, anyhow, bail};
-use log::info;
-use regex::Regex;
-use reqwest::blocking::Client;
+use anyhow::{Context, Result, bail};
+use fors_core::provider::StreamMetadata;
+use fors_core::youtube::{self, Format, InnertubeClient};
+use reqwest::blocking::{Client, RequestBuilder, Response};
+use reqwest::header::{COOKIE, ORIGIN, REFERER, USER_AGENT};
+use tracing::{debug, info, warn};
 use url::Url;
 
-use super::StreamSet;
-use crate::hls::parse_master_playlist;
+use super::{ProviderOptions, StreamSet};
+use crate::hls::{
+    QueryPassthrough, parse_audio_renditions, parse_iframe_playlists, parse_master_playlist,
+};
+use crate::http::{Retry, UserAgentProfile};
 
-pub struct YouTubeSource {
-    watch_url: Url,
+mod nsig;
+
+/// The hosts of the YouTube API and pages, which `--youtube-rate-limit`
+/// applies to.
+pub const API_HOSTS: &[&str] = &["www.youtube.com", "youtube.com", "m.youtube.com"];
+
+/// The formats a `--format` spec picked.
+pub struct FormatSelection {
+    /// In the order of the spec, the video first.
+    pub urls: Vec<Url>,
+    pub formats: Vec<Format>,
+    pub metadata: StreamMetadata,
 }
 
-pub fn is_youtube_url(url: &Url) -> bool {
-    url.host_str()
-        .map(|host| host.contains("youtube.com") || host == "youtu.be")
-        .unwrap_or(false)
+pub struct YouTubeSource {
+    video_id: String,
+    watch_url: Url,
+    js_runtime: String,
+    po_token: Option<String>,
+    visitor_data: Option<String>,
+    user_agent: Option<&'static str>,
+    /// The browser whose headers requests carry, if any.
+    browser: Option<UserAgentProfile>,
+    segment_query: QueryPassthrough,
 }
 
 impl YouTubeSource {
-    pub fn from_url(url: Url) -> Result<Self> {
-        let watch_url = canonical_watch_url(&url)
-            .or_else(|| {
-                extract_video_id(&url).and_then(|id| {
-                    Url::parse(&format!("https://www.youtube.com/watch?v={id}")).ok()
-                })
-            })
-            .ok_or_else(|| anyhow!("Unsupported YouTube URL"))?;
+    pub fn new(video_id: String, options: &ProviderOptions) -> Result<Self> {
+        let watch_url = youtube::watch_url(&video_id)?;
+        Ok(YouTubeSource {
+            video_id,
+            watch_url,
+            js_runtime: options.js_runtime.clone(),
+            po_token: options.youtube_po_token.clone(),
+            visitor_data: options.youtube_visitor_data.clone(),
+            // The watch page and web client are meant for browsers.
+            user_agent: options.api_user_agent(Some(UserAgentProfile::Chrome)),
+            browser: options.youtube_browser_headers.then(|| {
+                options
+                    .user_agent_profile
+                    .unwrap_or(UserAgentProfile::Chrome)
+            }),
+            segment_query: options.segment_query,
+        })
+    }
 
-        Ok(YouTubeSource { watch_url })
+    pub fn video_id(&self) -> &str {
+        &self.video_id
     }
 
     pub fn load_streams(&self, client: &Client) -> Result<StreamSet> {
-        info!("Fetching YouTube watch page");
-        let response = client
-            .get(self.watch_url.clone())
-            .send()
-            .context("Failed to request YouTube watch page")?
-            .error_for_status()
-            .context("YouTube watch page request failed")?;
-
-        let final_url = response.url().clone();
-        if final_url
-            .host_str()
-            .map(|h| h.contains("consent.youtube.com"))
-            .unwrap_or(false)
-        {
-            bail!(
-                "YouTube returned a consent page. Try supplying cookies or running in a browser first."
-            );
+        // The player response JSON carries the same fields as the page.
+        let player = if self.use_innertube() {
+            self.innertube_player(client, InnertubeClient::Web)?
+                .to_string()
+        } else {
+            self.watch_page(client)?
+        };
+        let player = if youtube::is_bot_check(&player) {
+            self.fallback_player(client)?.to_string()
+        } else {
+            player
+        };
+        let metadata = serde_json::from_str(&player)
+            .or_else(|_| youtube::extract_player_response(&player))
+            .map(|player| youtube::parse_metadata(&player))
+            .unwrap_or_default();
+        let mut manifest_url = youtube::extract_manifest_url(&player)?;
+        if let Some(po_token) = &self.po_token {
+            manifest_url = youtube::with_po_token(&manifest_url, po_token);
         }
 
-        let body = response
-            .text()
-            .context("Failed to read YouTube watch page")?;
-        let manifest_url = extract_manifest_url(&body)?;
-
         info!("Fetching YouTube HLS manifest");
-        let manifest_response = client
-            .get(manifest_url.clone())
-            .send()
+        let manifest_response = Retry::API
+            .send(client.get(manifest_url.clone()))
             .context("Failed to request YouTube manifest")?
             .error_for_status()
             .context("YouTube returned an error for the manifest request")?;
@@ -68,67 +96,212 @@ impl YouTubeSource {
             .text()
             .context("Failed to read YouTube manifest body")?;
 
-        let variants = parse_master_playlist(&playlist_url, &manifest_body)?;
+        let variants = parse_master_playlist(&playlist_url, &manifest_body, self.segment_query)?;
+        let audio_tracks =
+            parse_audio_renditions(&playlist_url, &manifest_body, self.segment_query)?;
+        let iframe_playlists =
+            parse_iframe_playlists(&playlist_url, &manifest_body, self.segment_query)?;
         Ok(StreamSet {
             variants,
+            audio_tracks,
+            iframe_playlists,
             is_live: true,
             low_latency: false,
+            segment_query: self.segment_query,
+            start_offset: None,
+            metadata,
         })
     }
-}
-
-fn canonical_watch_url(url: &Url) -> Option<Url> {
-    let host = url.host_str()?.to_lowercase();
 
-    if host == "youtu.be" {
-        let id = url.path_segments()?.next()?.to_string();
-        return Url::parse(&format!("https://www.youtube.com/watch?v={id}")).ok();
+    /// Whether the broadcast is still going, from a fresh player response.
+    pub fn is_live(&self, client: &Client) -> Result<bool> {
+        let player = self.innertube_player(client, InnertubeClient::Web)?;
+        youtube::broadcast_live(&player)
+            .context("The YouTube player response does not say whether the stream is live")
     }
 
-    if !host.contains("youtube.com") {
-        return None;
+    fn watch_page(&self, client: &Client) -> Result<String> {
+        info!("Fetching YouTube watch page");
+        let mut response = self.fetch_watch_page(client, None)?;
+        if youtube::is_consent_redirect(response.url()) {
+            info!("Skipping YouTube consent page");
+            response = self.fetch_watch_page(client, Some(youtube::CONSENT_COOKIES))?;
+        }
+        if youtube::is_consent_redirect(response.url()) {
+            bail!(
+                "YouTube returned a consent page. Try supplying cookies or running in a browser first."
+            );
+        }
+
+        response.text().context("Failed to read YouTube watch page")
     }
 
-    extract_video_id(url)
-        .and_then(|id| Url::parse(&format!("https://www.youtube.com/watch?v={id}")).ok())
-}
+    /// The raw formats (itags) of the video.
+    pub fn formats(&self, client: &Client) -> Result<Vec<Format>> {
+        self.page_formats(client).map(|(_, _, formats)| formats)
+    }
 
-fn extract_video_id(url: &Url) -> Option<String> {
-    if let Some(id) = url
-        .query_pairs()
-        .find_map(|(k, v)| if k == "v" { Some(v.to_string()) } else { None })
-    {
-        return Some(id);
+    /// The watch page, the player response and its formats.
+    fn page_formats(&self, client: &Client) -> Result<(String, serde_json::Value, Vec<Format>)> {
+        let body = self.watch_page(client)?;
+        let mut player = if self.use_innertube() {
+            self.innertube_player(client, InnertubeClient::Web)?
+        } else {
+            youtube::extract_player_response(&body)?
+        };
+        if youtube::is_bot_check(&player.to_string()) {
+            player = self.fallback_player(client)?;
+        }
+        let formats = youtube::parse_formats(&player);
+        if formats.is_empty() {
+            bail!("YouTube returned no formats for {}", self.video_id);
+        }
+        Ok((body, player, formats))
     }
 
-    let segments: Vec<String> = url
-        .path_segments()
-        .map(|segments| {
-            segments
-                .filter(|s| !s.is_empty())
-                .map(String::from)
-                .collect()
+    /// Resolves a `--format` spec such as `18` or `299+140` to the URLs of
+    /// its formats, in order.
+    pub fn select_formats(&self, client: &Client, spec: &str) -> Result<FormatSelection> {
+        let (page, player, formats) = self.page_formats(client)?;
+        let itags: Vec<u32> = spec
+            .split('+')
+            .map(|itag| itag.trim().parse())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid --format '{spec}', expected e.g. 18 or 299+140"))?;
+        if itags.is_empty() || itags.len() > 2 {
+            bail!("--format takes one itag, or a video and an audio itag joined by '+'");
+        }
+
+        let selected = itags
+            .iter()
+            .map(|itag| {
+                formats
+                    .iter()
+                    .find(|format| format.itag == *itag)
+                    .cloned()
+                    .with_context(|| format!("Format {itag} is not available (see --list-formats)"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut urls = selected
+            .iter()
+            .map(|format| {
+                format.url.clone().with_context(|| {
+                    format!(
+                        "Format {} needs signature deciphering, which is not supported",
+                        format.itag
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if let Err(err) = nsig::unthrottle(client, &self.js_runtime, &page, &mut urls) {
+            warn!("Download may be throttled: {err:#}");
+        }
+        if let Some(po_token) = &self.po_token {
+            urls = urls
+                .iter()
+                .map(|url| youtube::with_po_token(url, po_token))
+                .collect();
+        }
+        Ok(FormatSelection {
+            urls,
+            formats: selected,
+            metadata: youtube::parse_metadata(&player),
         })
-        .unwrap_or_default();
+    }
+
+    /// With a PO token or visitor data, the player response comes from the
+    /// Innertube API instead of the anonymous watch page.
+    fn use_innertube(&self) -> bool {
+        self.po_token.is_some() || self.visitor_data.is_some()
+    }
 
-    match segments.as_slice() {
-        [prefix, id] if prefix == "live" || prefix == "embed" || prefix == "shorts" => {
-            Some(id.to_string())
+    /// Retries the player request as the Android and iOS apps after the web
+    /// client got a bot check.
+    fn fallback_player(&self, client: &Client) -> Result<serde_json::Value> {
+        warn!("YouTube asked to confirm this is not a bot, trying the app clients");
+        for profile in [InnertubeClient::Android, InnertubeClient::Ios] {
+            match self.innertube_player(client, profile) {
+                Ok(player) if !youtube::is_bot_check(&player.to_string()) => return Ok(player),
+                Ok(_) => debug!("The {} client got a bot check too", profile.name()),
+                Err(err) => debug!("The {} client failed: {err:#}", profile.name()),
+            }
         }
-        _ => None,
+        bail!(
+            "YouTube wants you to sign in to confirm you're not a bot. Try supplying cookies or --youtube-po-token."
+        )
     }
-}
 
-fn extract_manifest_url(body: &str) -> Result<Url> {
-    let re = Regex::new(r#""hlsManifestUrl":"(?P<url>[^"]+)""#).unwrap();
-    let captures = re
-        .captures(body)
-        .ok_or_else(|| anyhow!("No HLS manifest URL found on the page (stream may be offline)"))?;
+    fn innertube_player(
+        &self,
+        client: &Client,
+        profile: InnertubeClient,
+    ) -> Result<serde_json::Value> {
+        info!("Requesting YouTube player response ({})", profile.name());
+        let body = youtube::innertube_player_request(
+            profile,
+            &self.video_id,
+            self.visitor_data.as_deref(),
+            self.po_token.as_deref(),
+        );
+        let mut request = client
+            .post(youtube::INNERTUBE_PLAYER_URL)
+            .header("X-YouTube-Client-Name", profile.id())
+            .header("X-YouTube-Client-Version", profile.version())
+            .json(&body);
+        if let Some(agent) = profile.user_agent().or(self.user_agent) {
+            request = request.header(USER_AGENT, agent);
+        }
+        // The app clients are not browsers.
+        if profile == InnertubeClient::Web && self.browser.is_some() {
+            request = self
+                .with_browser_headers(request, false)
+                .header(ORIGIN, "https://www.youtube.com")
+                .header(REFERER, self.watch_url.as_str());
+        }
+        if let Some(visitor_data) = &self.visitor_data {
+            request = request.header("X-Goog-Visitor-Id", visitor_data);
+        }
+        Retry::API
+            .send(request)
+            .context("Failed to request YouTube player response")?
+            .error_for_status()
+            .context("YouTube returned an error for the player request")?
+            .json()
+            .context("Could not parse YouTube player response")
+    }
 
-    let raw_url = captures.name("url").unwrap().as_str();
-    // Decode JSON-style escaping inside the string
-    let decoded: String = serde_json::from_str(&format!("\"{raw_url}\""))
-        .context("Failed to decode manifest URL from page data")?;
+    /// Adds the headers of `--youtube-browser-headers`.
+    fn with_browser_headers(&self, request: RequestBuilder, navigation: bool) -> RequestBuilder {
+        let Some(browser) = self.browser else {
+            return request;
+        };
+        browser
+            .browser_headers(navigation)
+            .into_iter()
+            .fold(request, |request, (name, value)| {
+                request.header(name, value)
+            })
+    }
 
-    Url::parse(&decoded).context("Invalid YouTube manifest URL")
+    /// Requests the watch page. `cookies` replaces the cookie jar's cookies
+    /// for this request.
+    fn fetch_watch_page(&self, client: &Client, cookies: Option<&str>) -> Result<Response> {
+        let mut request = client.get(self.watch_url.clone());
+        if let Some(agent) = self.user_agent {
+            request = request.header(USER_AGENT, agent);
+        }
+        request = self.with_browser_headers(request, true);
+        if let Some(visitor_data) = &self.visitor_data {
+            request = request.header("X-Goog-Visitor-Id", visitor_data);
+        }
+        if let Some(cookies) = cookies {
+            request = request.header(COOKIE, cookies);
+        }
+        Retry::API
+            .send(request)
+            .context("Failed to request YouTube watch page")?
+            .error_for_status()
+            .context("YouTube watch page request failed")
+    }
 }
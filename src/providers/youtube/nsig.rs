@@ -0,0 +1,177 @@
+use anyhow::{Context, Result, bail};
+use fors_core::youtube;
+use reqwest::blocking::Client;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+use url::Url;
+
+use crate::http::Retry;
+
+/// How long the n parameter transform may run before it is killed.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Replaces the `n` parameter of `urls` with the player's transform of it,
+/// evaluated by the JavaScript runtime `runtime` (e.g. `node` or `deno run`).
+pub fn unthrottle(client: &Client, runtime: &str, page: &str, urls: &mut [Url]) -> Result<()> {
+    let values: Vec<String> = urls.iter().filter_map(n_param).collect();
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    let js_url = youtube::player_js_url(page).context("No player script found on the page")?;
+    debug!("Fetching player script {js_url}");
+    let player_js = Retry::API
+        .send(client.get(js_url))
+        .context("Failed to request the YouTube player script")?
+        .error_for_status()
+        .context("YouTube returned an error for the player script")?
+        .text()
+        .context("Failed to read the YouTube player script")?;
+
+    let script = youtube::nsig_script(&player_js, &values)?;
+    let transformed = evaluate(runtime, &script)?;
+    if transformed.len() != values.len() {
+        bail!(
+            "The n parameter transform returned {} values",
+            transformed.len()
+        );
+    }
+
+    let mut transformed = transformed.into_iter();
+    for url in urls.iter_mut() {
+        if n_param(url).is_none() {
+            continue;
+        }
+        let Some(n) = transformed.next() else { break };
+        let pairs: Vec<(String, String)> = url
+            .query_pairs()
+            .map(|(key, value)| {
+                let value = if key == "n" {
+                    n.clone()
+                } else {
+                    value.into_owned()
+                };
+                (key.into_owned(), value)
+            })
+            .collect();
+        url.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    info!("Applied the YouTube n parameter transform");
+    Ok(())
+}
+
+fn n_param(url: &Url) -> Option<String> {
+    url.query_pairs()
+        .find_map(|(key, value)| (key == "n").then(|| value.into_owned()))
+}
+
+/// Runs `script` and parses the JSON array of strings it prints. Node runs
+/// it with its permission model on, which denies file system access and
+/// child processes; Deno grants none unless `--js-runtime` asks for them.
+fn evaluate(runtime: &str, script: &str) -> Result<Vec<String>> {
+    let mut parts = runtime.split_whitespace();
+    let program = parts.next().context("--js-runtime must not be empty")?;
+    let args: Vec<&str> = parts.collect();
+    let is_node = Path::new(program)
+        .file_stem()
+        .is_some_and(|stem| stem.eq_ignore_ascii_case("node"));
+
+    let output = if is_node {
+        // Node 23.5 renamed `--experimental-permission` to `--permission`,
+        // and older releases reject the new name as a bad option.
+        let output = run(program, Some("--permission"), &args, script)?;
+        if !output.status.success()
+            && String::from_utf8_lossy(&output.stderr).contains("bad option")
+        {
+            run(program, Some("--experimental-permission"), &args, script)?
+        } else {
+            output
+        }
+    } else {
+        run(program, None, &args, script)?
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "JavaScript runtime exited with {}: {}",
+            output.status,
+            stderr.lines().next().unwrap_or_default()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .context("Unexpected output from the n parameter transform")
+}
+
+/// Runs the runtime on `script` from stdin, killing it when it takes longer
+/// than [`TIMEOUT`]: the script comes from the remote player and may never
+/// finish.
+fn run(program: &str, flag: Option<&str>, args: &[&str], script: &str) -> Result<Output> {
+    let mut child = Command::new(program)
+        .args(flag)
+        .args(args)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to start JavaScript runtime '{program}'"))?;
+
+    // Written and read from threads so a runtime that prints early can't
+    // deadlock us.
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("JavaScript runtime has no stdin")?;
+    let script = script.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(script.as_bytes()));
+    let reader = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut buffer = Vec::new();
+            if let Some(mut pipe) = pipe {
+                pipe.read_to_end(&mut buffer).ok();
+            }
+            buffer
+        })
+    };
+    let stdout = reader(child.stdout.take().map(|pipe| Box::new(pipe) as _));
+    let stderr = reader(child.stderr.take().map(|pipe| Box::new(pipe) as _));
+
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .context("Failed to run the JavaScript runtime")?
+        {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            bail!(
+                "The n parameter transform did not finish within {}s",
+                TIMEOUT.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    let written = writer
+        .join()
+        .map_err(|_| anyhow::anyhow!("Script writer panicked"))?;
+    // A runtime that rejects its arguments exits before reading the script.
+    if status.success() {
+        written.context("Failed to pass the script to the JavaScript runtime")?;
+    }
+    let join = |reader: std::thread::JoinHandle<Vec<u8>>| {
+        reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("JavaScript runtime reader panicked"))
+    };
+    Ok(Output {
+        status,
+        stdout: join(stdout)?,
+        stderr: join(stderr)?,
+    })
+}
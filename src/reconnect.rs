@@ -0,0 +1,79 @@
+//! `--reconnect-window`: streamers often drop for a minute and come back with
+//! a new broadcast. Rather than ending the recording, keep asking the
+//! provider for the stream until it is back or the window has passed.
+
+use anyhow::Result;
+use reqwest::blocking::Client;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::hls::StopHandle;
+use crate::providers::{Provider, StreamSet};
+use crate::systemd;
+
+/// How often the provider is asked whether the stream is back.
+const INTERVAL: Duration = Duration::from_secs(10);
+/// The window when only `--reconnect-attempts` is given.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(300);
+
+pub struct Reconnect {
+    window: Duration,
+    /// Reconnections left, unlimited if `None`.
+    attempts_left: Option<u32>,
+}
+
+impl Reconnect {
+    pub fn new(window: Duration, attempts: Option<u32>) -> Self {
+        Reconnect {
+            window,
+            attempts_left: attempts,
+        }
+    }
+
+    /// Waits for the stream to come back. Returns `None` once the window has
+    /// passed, the attempts are used up or fors is stopping.
+    pub fn wait(
+        &mut self,
+        provider: &Provider,
+        client: &Client,
+        stop: &StopHandle,
+    ) -> Result<Option<StreamSet>> {
+        if self.attempts_left == Some(0) {
+            info!("No reconnection attempts left");
+            return Ok(None);
+        }
+        if let Some(left) = &mut self.attempts_left {
+            *left -= 1;
+        }
+
+        info!(
+            "Stream dropped, waiting up to {}s for it to come back",
+            self.window.as_secs()
+        );
+        systemd::status("Stream dropped, waiting for it to come back");
+        let started = Instant::now();
+        loop {
+            match provider.load_streams(client) {
+                Ok(streams) if streams.is_live => {
+                    info!(
+                        "Stream is back after {}s, reconnecting",
+                        started.elapsed().as_secs()
+                    );
+                    return Ok(Some(streams));
+                }
+                Ok(_) => debug!("The stream is no longer live"),
+                Err(err) => debug!("Stream not back yet: {err:#}"),
+            }
+            let left = self.window.saturating_sub(started.elapsed());
+            if left.is_zero() {
+                info!("Stream did not come back within {}s", self.window.as_secs());
+                return Ok(None);
+            }
+            let delay = INTERVAL.min(left);
+            systemd::idle_for(delay);
+            if !stop.sleep(delay) {
+                return Ok(None);
+            }
+        }
+    }
+}
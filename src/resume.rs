@@ -0,0 +1,109 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+use crate::events::{EventSink, SegmentEvent};
+use crate::{paths, persist};
+
+/// Entries older than this are dropped; no playlist reaches back further.
+const MAX_AGE: u64 = 24 * 60 * 60;
+
+/// The last segment written for each live stream (`--resume-live`), so a
+/// restarted recording can carry on without a gap or repeated segments.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct ResumeFile {
+    streams: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    provider: String,
+    id: String,
+    sequence: u64,
+    updated_at: u64,
+}
+
+pub struct ResumePoint {
+    path: PathBuf,
+    provider: String,
+    id: String,
+    last_sequence: Option<u64>,
+    warned: bool,
+}
+
+impl ResumePoint {
+    pub fn load(provider: &str, id: &str) -> Self {
+        let path = paths::cache_file("resume.json");
+
+        let data: ResumeFile = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default();
+        let now = now_secs();
+        let last_sequence = data
+            .streams
+            .iter()
+            .find(|entry| {
+                entry.provider == provider
+                    && entry.id == id
+                    && now.saturating_sub(entry.updated_at) < MAX_AGE
+            })
+            .map(|entry| entry.sequence);
+
+        ResumePoint {
+            path,
+            provider: provider.to_string(),
+            id: id.to_string(),
+            last_sequence,
+            warned: false,
+        }
+    }
+
+    /// The last segment a previous run wrote for this stream.
+    pub fn last_sequence(&self) -> Option<u64> {
+        self.last_sequence
+    }
+
+    /// Saves `sequence` for this stream, keeping what other jobs saved for
+    /// theirs.
+    fn record(&self, sequence: u64) -> Result<()> {
+        let (provider, id) = (&self.provider, &self.id);
+        persist::update_json(&self.path, |data: &mut ResumeFile| {
+            let now = now_secs();
+            data.streams.retain(|entry| {
+                !(&entry.provider == provider && &entry.id == id)
+                    && now.saturating_sub(entry.updated_at) < MAX_AGE
+            });
+            data.streams.push(Entry {
+                provider: provider.clone(),
+                id: id.clone(),
+                sequence,
+                updated_at: now,
+            });
+        })
+    }
+}
+
+impl EventSink for ResumePoint {
+    fn on_segment(&mut self, segment: &SegmentEvent) {
+        if let Err(err) = self.record(segment.sequence)
+            && !self.warned
+        {
+            warn!(
+                "Failed to save resume point to {}: {err:#}",
+                self.path.display()
+            );
+            self.warned = true;
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
@@ -0,0 +1,266 @@
+//! Retention for a recordings directory (`--retention-max-total-size`,
+//! `--retention-max-age`): between jobs, `--url-file` and daemon mode delete
+//! the oldest recordings, along with their sidecar files, so an unattended
+//! recorder does not fill its disk. Only files fors noted as its own
+//! recordings are deleted, and never one a job is still writing.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::units::{format_byte_size, format_duration};
+use crate::{paths, persist};
+
+#[cfg(test)]
+mod tests;
+
+/// Extensions of the files fors records to.
+const RECORDING_EXTENSIONS: &[&str] = &[
+    "ts", "mp4", "m4v", "mkv", "mov", "webm", "flv", "aac", "m4a", "mp3", "opus", "ogg",
+];
+/// Recordings modified this recently may still be written to by another fors
+/// process and are never deleted.
+const IN_USE: Duration = Duration::from_secs(10 * 60);
+
+/// The recordings the jobs of this process are writing.
+static ACTIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Every recording fors wrote, across runs.
+#[derive(Debug, Serialize, Deserialize, Default)]
+struct RecordingsFile {
+    recordings: Vec<PathBuf>,
+}
+
+pub struct Retention {
+    pub dir: PathBuf,
+    pub max_total_size: Option<u64>,
+    pub max_age: Option<Duration>,
+}
+
+/// A recording with the files that belong to it, e.g. its `.nfo` or
+/// `.index.json`.
+struct Recording {
+    path: PathBuf,
+    sidecars: Vec<PathBuf>,
+    modified: SystemTime,
+    size: u64,
+}
+
+/// A recording that was deleted, and which limit it was deleted for.
+pub struct Removed {
+    pub path: PathBuf,
+    pub size: u64,
+    pub reason: &'static str,
+}
+
+/// A recording a job is writing, noted as one of fors' own. Retention leaves
+/// it alone until this is dropped.
+pub struct ActiveRecording {
+    path: PathBuf,
+}
+
+impl ActiveRecording {
+    pub fn start(path: &Path) -> Self {
+        register(&[path.to_path_buf()]);
+        let path = absolute(path);
+        ACTIVE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(path.clone());
+        ActiveRecording { path }
+    }
+}
+
+impl Drop for ActiveRecording {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(index) = active.iter().position(|path| *path == self.path) {
+            active.swap_remove(index);
+        }
+    }
+}
+
+/// Notes `recordings` as written by fors, so the retention limits may delete
+/// them. Recordings that are gone are forgotten.
+pub fn register(recordings: &[PathBuf]) {
+    let recordings: Vec<PathBuf> = recordings.iter().map(|path| absolute(path)).collect();
+    let result = persist::update_json(&registry_path(), |data: &mut RecordingsFile| {
+        data.recordings
+            .retain(|path| path.exists() && !recordings.contains(path));
+        data.recordings.extend(recordings.iter().cloned());
+    });
+    if let Err(err) = result {
+        warn!("Failed to note the recording for the retention limits: {err:#}");
+    }
+}
+
+impl Retention {
+    /// Deletes recordings older than the maximum age, then the oldest ones
+    /// until the directory fits the maximum size.
+    pub fn enforce(&self) -> Result<Vec<Removed>> {
+        let registry = registry_path();
+        let registered: HashSet<PathBuf> = fs::read(&registry)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<RecordingsFile>(&bytes).ok())
+            .map(|data| data.recordings.into_iter().collect())
+            .unwrap_or_default();
+        let removed = self.enforce_among(&registered)?;
+        if !removed.is_empty() {
+            // Forgets the deleted recordings.
+            register(&[]);
+        }
+        Ok(removed)
+    }
+
+    /// [`Retention::enforce`] for the `registered` recordings.
+    fn enforce_among(&self, registered: &HashSet<PathBuf>) -> Result<Vec<Removed>> {
+        let mut recordings = Vec::new();
+        collect(&self.dir, &mut recordings)
+            .with_context(|| format!("Failed to list recordings in {}", self.dir.display()))?;
+        let active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        recordings.retain(|recording| {
+            let path = absolute(&recording.path);
+            registered.contains(&path) && !active.contains(&path)
+        });
+        recordings.sort_by_key(|recording| recording.modified);
+
+        let now = SystemTime::now();
+        let mut total: u64 = recordings.iter().map(|recording| recording.size).sum();
+        let mut removed = Vec::new();
+        for recording in recordings {
+            let age = now.duration_since(recording.modified).unwrap_or_default();
+            if age < IN_USE {
+                continue;
+            }
+            let reason = if self.max_age.is_some_and(|max| age > max) {
+                "max_age"
+            } else if self.max_total_size.is_some_and(|max| total > max) {
+                "max_total_size"
+            } else {
+                continue;
+            };
+            if let Err(err) = recording.remove(&self.dir) {
+                warn!("{err:#}");
+                continue;
+            }
+            info!(
+                "Deleted {} ({}, {} old) to stay within the {}",
+                recording.path.display(),
+                format_byte_size(recording.size),
+                format_duration(age),
+                reason.replace('_', " ")
+            );
+            total -= recording.size;
+            removed.push(Removed {
+                path: recording.path,
+                size: recording.size,
+                reason,
+            });
+        }
+        Ok(removed)
+    }
+}
+
+impl Recording {
+    fn remove(&self, root: &Path) -> Result<()> {
+        fs::remove_file(&self.path)
+            .with_context(|| format!("Failed to delete {}", self.path.display()))?;
+        for sidecar in &self.sidecars {
+            fs::remove_file(sidecar).ok();
+        }
+        // Directories of a --library-layout that are left empty.
+        for dir in self.path.ancestors().skip(1) {
+            if dir == root || !dir.starts_with(root) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn registry_path() -> PathBuf {
+    paths::cache_file("recordings.json")
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// The recordings under `dir`, in its subdirectories too. Symlinks are not
+/// followed, and subdirectories that cannot be read are skipped.
+fn collect(dir: &Path, recordings: &mut Vec<Recording>) -> std::io::Result<()> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)?.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        let path = entry.path();
+        if file_type.is_dir() {
+            if let Err(err) = collect(&path, recordings) {
+                warn!(
+                    "Skipping {} for the retention limits: {err}",
+                    path.display()
+                );
+            }
+        } else if file_type.is_file()
+            && let Ok(metadata) = entry.metadata()
+        {
+            files.push((path, metadata));
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let is_recording = |path: &Path| {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                RECORDING_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+            })
+    };
+    let (found, others): (Vec<_>, Vec<_>) =
+        files.into_iter().partition(|(path, _)| is_recording(path));
+    let first = recordings.len();
+    for (path, metadata) in found {
+        recordings.push(Recording {
+            path,
+            sidecars: Vec::new(),
+            modified: metadata.modified()?,
+            size: metadata.len(),
+        });
+    }
+    let recordings = &mut recordings[first..];
+
+    // Each other file belongs to at most one recording: `<name>.index.json`
+    // and the like to the recording named by the longest prefix, otherwise
+    // `<stem>.nfo` to the first recording with that stem.
+    for (other, metadata) in others {
+        let other_name = other.file_name().unwrap_or_default().to_string_lossy();
+        let owner = recordings
+            .iter()
+            .enumerate()
+            .filter(|(_, recording)| {
+                let name = recording.path.file_name().unwrap_or_default();
+                other_name
+                    .strip_prefix(name.to_string_lossy().as_ref())
+                    .is_some_and(|rest| rest.starts_with('.'))
+            })
+            .max_by_key(|(_, recording)| recording.path.as_os_str().len())
+            .or_else(|| {
+                recordings
+                    .iter()
+                    .enumerate()
+                    .find(|(_, recording)| recording.path.file_stem() == other.file_stem())
+            })
+            .map(|(index, _)| index);
+        if let Some(index) = owner {
+            recordings[index].size += metadata.len();
+            recordings[index].sidecars.push(other);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::{Retention, absolute, collect};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("fors-retention-{}-{name}", std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes `size` bytes to `path`, last modified `age` ago.
+fn write(path: &Path, size: usize, age: Duration) -> PathBuf {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, vec![0u8; size]).unwrap();
+    File::options()
+        .write(true)
+        .open(path)
+        .unwrap()
+        .set_modified(SystemTime::now() - age)
+        .unwrap();
+    absolute(path)
+}
+
+#[test]
+fn recordings_are_collected_with_their_sidecars() {
+    let dir = scratch("collect");
+    write(&dir.join("a.ts"), 100, HOUR);
+    write(&dir.join("a.ts.index.json"), 10, HOUR);
+    write(&dir.join("a.nfo"), 1, HOUR);
+    write(&dir.join("a.mp4"), 50, HOUR);
+    write(&dir.join("x.ts"), 20, HOUR);
+    write(&dir.join("x.ts.mp4"), 30, HOUR);
+    write(&dir.join("notes.txt"), 5, HOUR);
+    write(&dir.join("channel/b.mkv"), 200, HOUR);
+
+    let mut recordings = Vec::new();
+    collect(&dir, &mut recordings).unwrap();
+    recordings.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let found: Vec<(PathBuf, u64, usize)> = recordings
+        .iter()
+        .map(|recording| {
+            let path = recording.path.strip_prefix(&dir).unwrap().to_path_buf();
+            (path, recording.size, recording.sidecars.len())
+        })
+        .collect();
+    assert_eq!(
+        found,
+        [
+            (PathBuf::from("a.mp4"), 51, 1),
+            (PathBuf::from("a.ts"), 110, 1),
+            (PathBuf::from("channel/b.mkv"), 200, 0),
+            (PathBuf::from("x.ts"), 20, 0),
+            (PathBuf::from("x.ts.mp4"), 30, 0),
+        ]
+    );
+    fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn only_registered_recordings_are_deleted() {
+    let dir = scratch("registered");
+    let ours = write(&dir.join("ours.ts"), 100, 48 * HOUR);
+    let theirs = write(&dir.join("theirs.ts"), 100, 48 * HOUR);
+    let retention = Retention {
+        dir: dir.clone(),
+        max_total_size: None,
+        max_age: Some(24 * HOUR),
+    };
+
+    let removed = retention
+        .enforce_among(&HashSet::from([ours.clone()]))
+        .unwrap();
+
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].reason, "max_age");
+    assert!(!ours.exists());
+    assert!(theirs.exists());
+    fs::remove_dir_all(dir).ok();
+}
+
+#[test]
+fn the_oldest_recordings_go_until_the_directory_fits() {
+    let dir = scratch("size");
+    let oldest = write(&dir.join("1.ts"), 100, 3 * HOUR);
+    let older = write(&dir.join("2.ts"), 100, 2 * HOUR);
+    let old = write(&dir.join("3.ts"), 100, HOUR);
+    // Too recent to be sure nothing writes to it any more.
+    let recent = write(&dir.join("4.ts"), 100, Duration::from_secs(60));
+    let retention = Retention {
+        dir: dir.clone(),
+        max_total_size: Some(250),
+        max_age: None,
+    };
+
+    let registered = HashSet::from([oldest.clone(), older.clone(), old.clone(), recent.clone()]);
+    let removed = retention.enforce_among(&registered).unwrap();
+
+    let removed: Vec<&Path> = removed
+        .iter()
+        .map(|removed| removed.path.as_path())
+        .collect();
+    assert_eq!(removed, [dir.join("1.ts"), dir.join("2.ts")]);
+    assert!(old.exists());
+    assert!(recent.exists());
+    fs::remove_dir_all(dir).ok();
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinked_directories_are_not_followed() {
+    let dir = scratch("symlink");
+    write(&dir.join("channel/a.ts"), 100, HOUR);
+    std::os::unix::fs::symlink(&dir, dir.join("channel/loop")).unwrap();
+    std::os::unix::fs::symlink(dir.join("channel/a.ts"), dir.join("b.ts")).unwrap();
+
+    let mut recordings = Vec::new();
+    collect(&dir, &mut recordings).unwrap();
+
+    let found: Vec<&Path> = recordings
+        .iter()
+        .map(|recording| recording.path.as_path())
+        .collect();
+    assert_eq!(found, [dir.join("channel/a.ts")]);
+    fs::remove_dir_all(dir).ok();
+}
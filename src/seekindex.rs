@@ -0,0 +1,39 @@
+//! Sidecar files mapping playback time to byte offsets in a recording, so
+//! players and tools can seek in the raw output without remuxing it first.
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::hls::SeekPoint;
+
+/// Where the index for `output` goes.
+pub fn index_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".index.json");
+    PathBuf::from(name)
+}
+
+pub fn write(output: &Path, points: &[SeekPoint], bytes: u64) -> Result<PathBuf> {
+    let path = index_path(output);
+    fs::write(&path, to_json(points, bytes)?)
+        .with_context(|| format!("Failed to write seek index to {}", path.display()))?;
+    Ok(path)
+}
+
+fn to_json(points: &[SeekPoint], bytes: u64) -> Result<String> {
+    let points: Vec<_> = points
+        .iter()
+        .map(|point| {
+            json!({
+                "time": point.output_time,
+                "offset": point.offset,
+                "sequence": point.sequence,
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(
+        &json!({ "size": bytes, "segments": points }),
+    )?)
+}
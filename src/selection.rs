@@ -0,0 +1,360 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Days, Local, NaiveTime, Timelike};
+use std::cmp::Ordering;
+use std::time::SystemTime;
+
+use crate::hls::{AudioRendition, StreamVariant};
+
+#[cfg(test)]
+mod tests;
+
+/// One entry of `--stream-sorting-excludes`: either a quality name, or a
+/// comparison such as `>720p`, `<=480p30` or `>3000k`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exclude {
+    Name(String),
+    Compare {
+        op: Ordering,
+        or_equal: bool,
+        level: Level,
+    },
+}
+
+/// A point on the quality scale that variants are compared against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Level {
+    /// Height plus optional frame rate, e.g. `720p60`.
+    Height(u64, Option<f64>),
+    /// Bandwidth in bits per second, e.g. `3000k`.
+    Bitrate(u64),
+}
+
+pub fn parse_exclude(input: &str) -> Result<Exclude, String> {
+    let value = input.trim().to_lowercase();
+    let (op, or_equal, rest) = if let Some(rest) = value.strip_prefix(">=") {
+        (Ordering::Greater, true, rest)
+    } else if let Some(rest) = value.strip_prefix("<=") {
+        (Ordering::Less, true, rest)
+    } else if let Some(rest) = value.strip_prefix('>') {
+        (Ordering::Greater, false, rest)
+    } else if let Some(rest) = value.strip_prefix('<') {
+        (Ordering::Less, false, rest)
+    } else if let Some(rest) = value.strip_prefix("==") {
+        (Ordering::Equal, true, rest)
+    } else {
+        if value.is_empty() {
+            return Err("empty quality filter".into());
+        }
+        return Ok(Exclude::Name(value));
+    };
+
+    let level = parse_level(rest.trim()).ok_or_else(|| {
+        format!("invalid quality filter '{input}' (expected e.g. >720p or <3000k)")
+    })?;
+    Ok(Exclude::Compare {
+        op,
+        or_equal,
+        level,
+    })
+}
+
+fn parse_level(value: &str) -> Option<Level> {
+    if let Some(kbps) = value.strip_suffix('k') {
+        return kbps.parse::<u64>().ok().map(|k| Level::Bitrate(k * 1000));
+    }
+    let (height, fps) = value.split_once('p')?;
+    let fps = match fps {
+        "" => None,
+        fps => Some(fps.parse().ok()?),
+    };
+    Some(Level::Height(height.parse().ok()?, fps))
+}
+
+impl Exclude {
+    fn matches(&self, variant: &StreamVariant) -> bool {
+        match self {
+            Exclude::Name(name) => variant.aliases.iter().any(|alias| alias == name),
+            Exclude::Compare {
+                op,
+                or_equal,
+                level,
+            } => {
+                let ordering = match level {
+                    Level::Bitrate(bitrate) => variant.bandwidth.cmp(bitrate),
+                    Level::Height(height, fps) => {
+                        let Some((_, variant_height)) = variant.resolution else {
+                            return false;
+                        };
+                        // Playlists without FRAME-RATE are almost always 30fps.
+                        let variant_fps = variant.frame_rate.unwrap_or(30.0);
+                        variant_height.cmp(height).then_with(|| match fps {
+                            Some(fps) => variant_fps.total_cmp(fps),
+                            None => Ordering::Equal,
+                        })
+                    }
+                };
+                ordering == *op || (*or_equal && ordering == Ordering::Equal)
+            }
+        }
+    }
+}
+
+/// A frame rate requirement, from `--max-fps` or `best[fps<=30]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FpsBound {
+    op: Ordering,
+    or_equal: bool,
+    fps: f64,
+}
+
+impl FpsBound {
+    pub fn at_most(fps: f64) -> Self {
+        FpsBound {
+            op: Ordering::Less,
+            or_equal: true,
+            fps,
+        }
+    }
+
+    /// Parses `fps<=30`, `fps>30`, `fps=60` and so on.
+    fn parse(condition: &str) -> Option<Self> {
+        let rest = condition.trim().strip_prefix("fps")?.trim_start();
+        let (op, or_equal, value) = [
+            ("<=", Ordering::Less, true),
+            (">=", Ordering::Greater, true),
+            ("==", Ordering::Equal, true),
+            ("<", Ordering::Less, false),
+            (">", Ordering::Greater, false),
+            ("=", Ordering::Equal, true),
+        ]
+        .into_iter()
+        .find_map(|(prefix, op, or_equal)| {
+            rest.strip_prefix(prefix).map(|value| (op, or_equal, value))
+        })?;
+        Some(FpsBound {
+            op,
+            or_equal,
+            fps: value.trim().parse().ok()?,
+        })
+    }
+
+    fn allows(&self, variant: &StreamVariant) -> bool {
+        if variant.is_audio_only {
+            return true;
+        }
+        // Playlists without FRAME-RATE are almost always 30fps.
+        let ordering = variant.frame_rate.unwrap_or(30.0).total_cmp(&self.fps);
+        ordering == self.op || (self.or_equal && ordering == Ordering::Equal)
+    }
+}
+
+/// Restrictions on which variants may be picked, from the command line.
+#[derive(Debug, Default, Clone)]
+pub struct Constraints {
+    pub excludes: Vec<Exclude>,
+    pub fps: Vec<FpsBound>,
+    /// Only pick among audio-only variants, whatever `quality` says.
+    pub audio_only: bool,
+}
+
+impl Constraints {
+    fn allows(&self, variant: &StreamVariant) -> bool {
+        !self.excludes.iter().any(|exclude| exclude.matches(variant))
+            && self.fps.iter().all(|bound| bound.allows(variant))
+    }
+}
+
+/// Picks the variant for `quality`. `best` and `worst` only consider variants
+/// the constraints allow; explicit names always work.
+///
+/// `best` and `worst` take extra conditions in brackets, e.g. `best[fps<=30]`.
+pub fn select_variant<'a>(
+    variants: &'a [StreamVariant],
+    quality: &str,
+    constraints: &Constraints,
+) -> Result<&'a StreamVariant> {
+    let mut q = quality.trim().to_lowercase();
+    let mut constraints = constraints.clone();
+    if let Some((base, conditions)) = q.split_once('[') {
+        let conditions = conditions
+            .strip_suffix(']')
+            .with_context(|| format!("Missing ']' in quality '{quality}'"))?;
+        for condition in conditions.split(',') {
+            constraints
+                .fps
+                .push(FpsBound::parse(condition).with_context(|| {
+                    format!("Invalid condition '{condition}' in quality '{quality}'")
+                })?);
+        }
+        q = base.to_string();
+    }
+
+    let named = |variant: &&StreamVariant| variant.aliases.iter().any(|alias| alias == &q);
+    let selected = if constraints.audio_only {
+        let audio = || variants.iter().filter(|v| v.is_audio_only);
+        let allowed = || audio().filter(|v| constraints.allows(v));
+        match q.as_str() {
+            "worst" | "audio_worst" => allowed().min_by_key(|v| v.bandwidth),
+            _ => audio()
+                .find(named)
+                .or_else(|| allowed().max_by_key(|v| v.bandwidth)),
+        }
+    } else {
+        // Audio-only variants are left to `audio`, unless there is no video.
+        let has_video = variants.iter().any(|v| !v.is_audio_only);
+        let candidates = variants
+            .iter()
+            .filter(|v| !(has_video && v.is_audio_only) && constraints.allows(v));
+        match q.as_str() {
+            // The source rendition is the best one even when a transcode
+            // advertises a higher bandwidth.
+            "best" => candidates.max_by_key(|v| v.rank()),
+            "worst" => candidates.min_by_key(|v| v.rank()),
+            _ => variants.iter().find(named),
+        }
+    };
+
+    selected.with_context(|| format!("Quality '{quality}' is not available"))
+}
+
+/// One entry of `--quality-schedule`: the quality to record between two
+/// local times of day, e.g. `18:00-24:00=1080p60`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityWindow {
+    /// Minutes after midnight. A window whose end is not after its start
+    /// runs past midnight.
+    start: u32,
+    end: u32,
+    quality: String,
+}
+
+impl QualityWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+pub fn parse_quality_window(input: &str) -> Result<QualityWindow, String> {
+    let invalid =
+        || format!("invalid schedule entry '{input}' (expected e.g. 18:00-24:00=1080p60)");
+    let (times, quality) = input.trim().split_once('=').ok_or_else(invalid)?;
+    let (start, end) = times.split_once('-').ok_or_else(invalid)?;
+    let minute = |time: &str| match time.trim() {
+        "24:00" => Some(24 * 60),
+        time => NaiveTime::parse_from_str(time, "%H:%M")
+            .ok()
+            .map(|time| time.hour() * 60 + time.minute()),
+    };
+    let (Some(start), Some(end)) = (minute(start), minute(end)) else {
+        return Err(invalid());
+    };
+    let quality = quality.trim();
+    if quality.is_empty() || start % (24 * 60) == end % (24 * 60) {
+        return Err(invalid());
+    }
+    Ok(QualityWindow {
+        start: start % (24 * 60),
+        end: end % (24 * 60),
+        quality: quality.to_string(),
+    })
+}
+
+fn minute_of_day(time: NaiveTime) -> u32 {
+    time.hour() * 60 + time.minute()
+}
+
+/// The quality the schedule asks for at `now`, or `None` outside all of its
+/// windows. The first window that covers `now` wins.
+pub fn scheduled_quality(schedule: &[QualityWindow], now: DateTime<Local>) -> Option<&str> {
+    let minute = minute_of_day(now.time());
+    schedule
+        .iter()
+        .find(|window| window.contains(minute))
+        .map(|window| window.quality.as_str())
+}
+
+/// When the schedule next asks for a different quality than at `now`.
+pub fn next_quality_change(schedule: &[QualityWindow], now: DateTime<Local>) -> Option<SystemTime> {
+    let current = scheduled_quality(schedule, now);
+    let mut boundaries: Vec<u32> = schedule
+        .iter()
+        .flat_map(|window| [window.start, window.end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+    for days in 0..2 {
+        let date = now.date_naive().checked_add_days(Days::new(days))?;
+        for &minute in &boundaries {
+            let Some(at) = date
+                .and_hms_opt(minute / 60, minute % 60, 0)
+                .and_then(|at| at.and_local_timezone(Local).earliest())
+            else {
+                continue;
+            };
+            if at > now && scheduled_quality(schedule, at) != current {
+                return Some(at.into());
+            }
+        }
+    }
+    None
+}
+
+/// Whether `to` is a lower quality than `from`, going by resolution, frame
+/// rate and whether it is the source rendition.
+pub fn is_downgrade(from: &StreamVariant, to: &StreamVariant) -> bool {
+    let rank = |variant: &StreamVariant| {
+        (
+            variant.resolution.map_or(0, |(_, height)| height),
+            variant.frame_rate.map_or(0, |fps| fps.round() as u64),
+            variant.is_source,
+        )
+    };
+    if from.resolution.is_none() && to.resolution.is_none() {
+        return from.label != to.label && to.bandwidth < from.bandwidth;
+    }
+    rank(to) < rank(from)
+}
+
+/// Picks the audio track for `lang` that goes with `variant`. `es` also
+/// matches regional tags such as `es-419`.
+pub fn select_audio_track<'a>(
+    tracks: &'a [AudioRendition],
+    variant: &StreamVariant,
+    lang: &str,
+) -> Result<&'a AudioRendition> {
+    let matching = |track: &&AudioRendition| {
+        variant.audio_group.is_none() || variant.audio_group.as_ref() == Some(&track.group)
+    };
+    let wanted = lang.trim().to_lowercase();
+    tracks
+        .iter()
+        .filter(matching)
+        .filter(|track| {
+            track.language.as_deref().is_some_and(|language| {
+                let language = language.to_lowercase();
+                language == wanted || language.starts_with(&format!("{wanted}-"))
+            })
+        })
+        .max_by_key(|track| track.is_default)
+        .with_context(|| {
+            let mut available: Vec<&str> = tracks
+                .iter()
+                .filter(matching)
+                .filter_map(|track| track.language.as_deref())
+                .collect();
+            available.sort();
+            available.dedup();
+            if available.is_empty() {
+                format!("No '{lang}' audio track: the stream has no alternative audio tracks")
+            } else {
+                format!(
+                    "No '{lang}' audio track (available: {})",
+                    available.join(", ")
+                )
+            }
+        })
+}
@@ -0,0 +1,256 @@
+use chrono::{Local, TimeZone};
+use std::cmp::Ordering;
+use std::time::SystemTime;
+use url::Url;
+
+use super::{
+    Constraints, Exclude, FpsBound, Level, QualityWindow, is_downgrade, next_quality_change,
+    parse_exclude, parse_quality_window, scheduled_quality, select_variant,
+};
+use crate::hls::{QueryPassthrough, StreamVariant, parse_master_playlist};
+
+/// A Twitch-like ladder: the source, a transcode advertising more bandwidth
+/// than it, two 720p variants, one without FRAME-RATE and audio only.
+const MASTER: &str = r#"#EXTM3U
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="chunked",NAME="1080p60 (source)",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=6000000,RESOLUTION=1920x1080,VIDEO="chunked",FRAME-RATE=60.000
+source.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=6500000,RESOLUTION=1920x1080,VIDEO="1080p30",FRAME-RATE=30.000
+1080p30.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=3000000,RESOLUTION=1280x720,VIDEO="720p60",FRAME-RATE=60.000
+720p60.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2000000,RESOLUTION=1280x720,VIDEO="720p30",FRAME-RATE=30.000
+720p30.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=600000,RESOLUTION=640x360,VIDEO="360p30"
+360p30.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=160000,CODECS="mp4a.40.2",VIDEO="audio_only"
+audio_only.m3u8
+"#;
+
+fn variants() -> Vec<StreamVariant> {
+    let base = Url::parse("https://example.com/master.m3u8").unwrap();
+    parse_master_playlist(&base, MASTER, QueryPassthrough::Off).unwrap()
+}
+
+fn excluding(filters: &[&str]) -> Constraints {
+    Constraints {
+        excludes: filters.iter().map(|f| parse_exclude(f).unwrap()).collect(),
+        ..Constraints::default()
+    }
+}
+
+fn pick(variants: &[StreamVariant], quality: &str, constraints: &Constraints) -> String {
+    select_variant(variants, quality, constraints)
+        .unwrap()
+        .label
+        .clone()
+}
+
+#[test]
+fn excludes_parse_names_and_comparisons() {
+    let compare = |op, or_equal, level| Exclude::Compare {
+        op,
+        or_equal,
+        level,
+    };
+
+    assert_eq!(
+        parse_exclude(" Audio_Only "),
+        Ok(Exclude::Name("audio_only".into()))
+    );
+    assert_eq!(
+        parse_exclude(">720p"),
+        Ok(compare(Ordering::Greater, false, Level::Height(720, None)))
+    );
+    assert_eq!(
+        parse_exclude("<=480p30"),
+        Ok(compare(
+            Ordering::Less,
+            true,
+            Level::Height(480, Some(30.0))
+        ))
+    );
+    assert_eq!(
+        parse_exclude(">= 3000k"),
+        Ok(compare(Ordering::Greater, true, Level::Bitrate(3_000_000)))
+    );
+    assert_eq!(
+        parse_exclude("==720p60"),
+        Ok(compare(
+            Ordering::Equal,
+            true,
+            Level::Height(720, Some(60.0))
+        ))
+    );
+}
+
+#[test]
+fn malformed_excludes_are_rejected() {
+    for input in ["", "  ", ">", ">720", ">p", ">720px", "<fastk", "==720p6o"] {
+        assert!(parse_exclude(input).is_err(), "{input}");
+    }
+}
+
+#[test]
+fn best_and_worst_skip_excluded_variants() {
+    let variants = variants();
+
+    assert_eq!(pick(&variants, "best", &excluding(&[">720p"])), "720p60");
+    // Frame rates break ties between equal heights.
+    assert_eq!(pick(&variants, "best", &excluding(&[">=720p60"])), "720p30");
+    assert_eq!(pick(&variants, "worst", &excluding(&["<720p"])), "720p30");
+    assert_eq!(pick(&variants, "best", &excluding(&[">2500k"])), "720p30");
+    assert_eq!(pick(&variants, "best", &excluding(&["source"])), "1080p30");
+}
+
+#[test]
+fn explicit_names_ignore_the_excludes() {
+    let variants = variants();
+
+    assert_eq!(
+        pick(&variants, "1080p60", &excluding(&[">720p"])),
+        "1080p60"
+    );
+}
+
+#[test]
+fn best_prefers_the_source_and_worst_skips_audio() {
+    let variants = variants();
+    let none = Constraints::default();
+
+    assert_eq!(pick(&variants, "best", &none), "1080p60");
+    assert_eq!(pick(&variants, "worst", &none), "360p30");
+    assert_eq!(pick(&variants, "audio", &none), "audio_only");
+    assert_eq!(pick(&variants, " BEST ", &none), "1080p60");
+}
+
+#[test]
+fn the_audio_only_constraint_picks_among_audio_variants() {
+    let variants = variants();
+    let audio_only = Constraints {
+        audio_only: true,
+        ..Constraints::default()
+    };
+
+    assert_eq!(pick(&variants, "best", &audio_only), "audio_only");
+    assert_eq!(pick(&variants, "worst", &audio_only), "audio_only");
+}
+
+#[test]
+fn fps_conditions_narrow_best_and_worst() {
+    let variants = variants();
+    let none = Constraints::default();
+
+    assert_eq!(pick(&variants, "best[fps<=30]", &none), "1080p30");
+    assert_eq!(pick(&variants, "best[fps < 60]", &none), "1080p30");
+    assert_eq!(pick(&variants, "worst[fps>30]", &none), "720p60");
+    assert_eq!(pick(&variants, "worst[fps=60]", &none), "720p60");
+    // Without FRAME-RATE a variant counts as 30fps.
+    assert_eq!(pick(&variants, "worst[fps==30]", &none), "360p30");
+    assert_eq!(pick(&variants, "best[fps>=30,fps<=30]", &none), "1080p30");
+
+    let max_fps = Constraints {
+        fps: vec![FpsBound::at_most(30.0)],
+        ..Constraints::default()
+    };
+    assert_eq!(pick(&variants, "best", &max_fps), "1080p30");
+}
+
+#[test]
+fn malformed_conditions_are_rejected() {
+    let variants = variants();
+    let none = Constraints::default();
+    let error = |quality: &str| {
+        format!(
+            "{:#}",
+            select_variant(&variants, quality, &none).unwrap_err()
+        )
+    };
+
+    assert!(error("best[fps<=30").contains("Missing ']'"));
+    assert!(error("best[height<=720]").contains("Invalid condition"));
+    assert!(error("best[fps<=]").contains("Invalid condition"));
+    assert!(error("best[fps>120]").contains("not available"));
+    assert!(error("4k").contains("not available"));
+}
+
+#[test]
+fn schedule_entries_parse_and_wrap_past_midnight() {
+    let window = |start: u32, end: u32, quality: &str| QualityWindow {
+        start,
+        end,
+        quality: quality.into(),
+    };
+
+    assert_eq!(
+        parse_quality_window("18:00-24:00=1080p60"),
+        Ok(window(18 * 60, 0, "1080p60"))
+    );
+    assert_eq!(
+        parse_quality_window(" 22:30 - 02:00 = 480p "),
+        Ok(window(22 * 60 + 30, 2 * 60, "480p"))
+    );
+    for input in [
+        "18:00=720p",
+        "18:00-19:00",
+        "18:00-19:00=",
+        "18:00-18:00=720p",
+        "00:00-24:00=720p",
+        "25:00-26:00=720p",
+        "6pm-7pm=720p",
+    ] {
+        assert!(parse_quality_window(input).is_err(), "{input}");
+    }
+}
+
+#[test]
+fn the_first_window_covering_a_time_wins() {
+    let schedule: Vec<QualityWindow> = ["22:00-06:00=480p", "18:00-24:00=1080p60"]
+        .into_iter()
+        .map(|entry| parse_quality_window(entry).unwrap())
+        .collect();
+    let at = |hour, minute| {
+        Local
+            .with_ymd_and_hms(2026, 1, 15, hour, minute, 0)
+            .unwrap()
+    };
+
+    assert_eq!(scheduled_quality(&schedule, at(12, 0)), None);
+    assert_eq!(scheduled_quality(&schedule, at(18, 0)), Some("1080p60"));
+    assert_eq!(scheduled_quality(&schedule, at(23, 0)), Some("480p"));
+    assert_eq!(scheduled_quality(&schedule, at(5, 59)), Some("480p"));
+    assert_eq!(scheduled_quality(&schedule, at(6, 0)), None);
+
+    let change = |hour, minute| next_quality_change(&schedule, at(hour, minute));
+    assert_eq!(change(12, 0), Some(SystemTime::from(at(18, 0))));
+    assert_eq!(change(18, 30), Some(SystemTime::from(at(22, 0))));
+    // 24:00 ends a window that the next one already covers.
+    let next_morning = Local.with_ymd_and_hms(2026, 1, 16, 6, 0, 0).unwrap();
+    assert_eq!(change(23, 0), Some(SystemTime::from(next_morning)));
+}
+
+#[test]
+fn downgrades_go_by_height_then_frame_rate_then_source() {
+    let variants = variants();
+    let by_label = |label: &str| variants.iter().find(|v| v.label == label).unwrap();
+
+    assert!(is_downgrade(by_label("1080p60"), by_label("720p60")));
+    assert!(is_downgrade(by_label("1080p60"), by_label("1080p30")));
+    assert!(is_downgrade(by_label("720p60"), by_label("720p30")));
+    assert!(!is_downgrade(by_label("720p30"), by_label("720p60")));
+    assert!(!is_downgrade(by_label("1080p30"), by_label("1080p60")));
+    assert!(!is_downgrade(by_label("720p30"), by_label("720p30")));
+}
+
+#[test]
+fn audio_only_downgrades_go_by_bandwidth() {
+    let mut low = variants().pop().unwrap();
+    let mut high = low.clone();
+    high.label = "audio_high".into();
+    high.bandwidth = 320_000;
+    low.bandwidth = 96_000;
+
+    assert!(is_downgrade(&high, &low));
+    assert!(!is_downgrade(&low, &high));
+    assert!(!is_downgrade(&high, &high.clone()));
+}
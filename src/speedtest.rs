@@ -0,0 +1,71 @@
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+use std::io;
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::hls::{StreamVariant, fetch_media_playlist};
+use crate::http::Retry;
+use crate::units::{format_bitrate, format_byte_size};
+
+/// Number of segments downloaded for a measurement.
+const SAMPLE_SEGMENTS: usize = 4;
+/// Throughput needed per advertised bit/s for a quality to be recommended.
+const HEADROOM: f64 = 1.25;
+
+/// Downloads a few segments of `variant` as fast as possible and reports the
+/// throughput relative to what the variants advertise.
+pub fn run(client: &Client, variant: &StreamVariant, variants: &[StreamVariant]) -> Result<()> {
+    let playlist = fetch_media_playlist(client, &variant.uri)?;
+    // The newest segments are the ones a live viewer would be fetching.
+    let content: Vec<_> = playlist.segments.iter().filter(|s| !s.ad).collect();
+    let segments = &content[content.len().saturating_sub(SAMPLE_SEGMENTS)..];
+    if segments.is_empty() {
+        bail!("The playlist has no segments to measure");
+    }
+
+    let mut bytes = 0u64;
+    let mut elapsed = Duration::ZERO;
+    let mut media_seconds = 0.0;
+    for segment in segments {
+        info!("Downloading segment {}", segment.sequence);
+        let started = Instant::now();
+        let mut response = Retry::SEGMENT
+            .send(client.get(segment.uri.clone()))
+            .with_context(|| format!("Requesting segment {}", segment.uri))?
+            .error_for_status()
+            .with_context(|| format!("Segment download failed: {}", segment.uri))?;
+        bytes += io::copy(&mut response, &mut io::sink()).context("Reading segment failed")?;
+        elapsed += started.elapsed();
+        media_seconds += segment.duration;
+    }
+
+    let throughput = bytes as f64 * 8.0 / elapsed.as_secs_f64().max(0.001);
+    println!(
+        "Tested {} (advertised {})",
+        variant.label,
+        format_bitrate(variant.bandwidth as f64)
+    );
+    println!(
+        "Downloaded {} segments ({}, {:.1}s of media) in {:.1}s",
+        segments.len(),
+        format_byte_size(bytes),
+        media_seconds,
+        elapsed.as_secs_f64()
+    );
+    println!(
+        "Throughput: {} ({:.1}x advertised)",
+        format_bitrate(throughput),
+        throughput / variant.bandwidth.max(1) as f64
+    );
+
+    let recommended = variants
+        .iter()
+        .filter(|v| !v.is_audio_only && v.bandwidth as f64 * HEADROOM <= throughput)
+        .max_by_key(|v| v.bandwidth);
+    match recommended {
+        Some(best) => println!("Recommended quality: {}", best.label),
+        None => println!("Recommended quality: none of the video qualities are sustainable"),
+    }
+    Ok(())
+}
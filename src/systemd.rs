@@ -0,0 +1,112 @@
+//! `Type=notify` support for running fors as a systemd service: readiness
+//! and status through `$NOTIFY_SOCKET`, and watchdog pings while recordings
+//! make progress.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+use crate::hls::StopHandle;
+
+static START: OnceLock<Instant> = OnceLock::new();
+/// Milliseconds after `START` until which the process counts as healthy.
+static ALIVE_UNTIL: AtomicU64 = AtomicU64::new(0);
+
+/// Tells systemd the service is up and starts the watchdog thread if
+/// `WatchdogSec` is set. Does nothing outside of systemd.
+pub fn start(stop: &StopHandle) {
+    if std::env::var_os("NOTIFY_SOCKET").is_none() {
+        return;
+    }
+    heartbeat();
+    notify("READY=1");
+
+    let watchdog = watchdog_interval();
+    if let Some(interval) = watchdog {
+        debug!("Pinging the systemd watchdog every {:?}", interval / 2);
+    }
+    let stop = stop.clone();
+    std::thread::spawn(move || {
+        let tick = watchdog.map_or(Duration::from_secs(1), |interval| {
+            (interval / 2).min(Duration::from_secs(1))
+        });
+        let mut last_ping: Option<Instant> = None;
+        let mut stalled = false;
+        loop {
+            if stop.is_stopped() {
+                notify("STOPPING=1");
+                return;
+            }
+            if let Some(interval) = watchdog
+                && last_ping.is_none_or(|at| at.elapsed() >= interval / 2)
+            {
+                // A stalled recording is left for the watchdog to restart.
+                let healthy = elapsed_ms() <= ALIVE_UNTIL.load(Ordering::Relaxed);
+                if healthy {
+                    notify("WATCHDOG=1");
+                    last_ping = Some(Instant::now());
+                } else if !stalled {
+                    warn!("No progress, no longer pinging the systemd watchdog");
+                }
+                stalled = !healthy;
+            }
+            std::thread::sleep(tick);
+        }
+    });
+}
+
+/// Marks the process as making progress.
+pub fn heartbeat() {
+    idle_for(Duration::ZERO);
+}
+
+/// Marks the process as healthy while it waits for `duration` on purpose.
+pub fn idle_for(duration: Duration) {
+    let grace = watchdog_interval().unwrap_or_default();
+    let until = elapsed_ms() + (duration + grace).as_millis() as u64;
+    ALIVE_UNTIL.fetch_max(until, Ordering::Relaxed);
+}
+
+/// Shows `status` in `systemctl status`.
+pub fn status(status: &str) {
+    notify(&format!("STATUS={status}"));
+}
+
+fn elapsed_ms() -> u64 {
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// `WATCHDOG_USEC`, if it is meant for this process.
+fn watchdog_interval() -> Option<Duration> {
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse() != Ok(std::process::id())) {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let result = UnixDatagram::unbound().and_then(|socket| {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.to_str().and_then(|path| path.strip_prefix('@')) {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(err) = result {
+        debug!("Failed to notify systemd ({state}): {err}");
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
@@ -0,0 +1,109 @@
+use chrono::Local;
+
+#[cfg(test)]
+mod tests;
+
+/// Expands `{name}` placeholders in an output template. Unknown placeholders
+/// are left untouched so typos stay visible in the resulting file name.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    substitute(template, vars, sanitize)
+}
+
+/// Expands `{name}` placeholders in free text, such as a notification.
+pub fn expand(template: &str, vars: &[(&str, &str)]) -> String {
+    substitute(template, vars, str::to_string)
+}
+
+fn substitute(template: &str, vars: &[(&str, &str)], clean: fn(&str) -> String) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end) => {
+                let key = &after[..end];
+                match lookup(key, vars) {
+                    Some(value) => out.push_str(&clean(&value)),
+                    None => out.push_str(&rest[start..start + end + 2]),
+                }
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+pub fn has_placeholders(template: &str) -> bool {
+    template.contains('{') && template.contains('}')
+}
+
+fn lookup(key: &str, vars: &[(&str, &str)]) -> Option<String> {
+    if let Some((_, value)) = vars.iter().find(|(name, _)| *name == key) {
+        return Some(value.to_string());
+    }
+
+    let now = Local::now();
+    match key {
+        "time" => Some(now.format("%Y%m%d-%H%M%S").to_string()),
+        "date" => Some(now.format("%Y-%m-%d").to_string()),
+        "year" => Some(now.format("%Y").to_string()),
+        _ => None,
+    }
+}
+
+/// Longest value substituted into a path, in bytes. Most file systems limit
+/// a file name to 255.
+const MAX_VALUE_LEN: usize = 200;
+
+/// Keeps substituted values from introducing path separators or characters
+/// the file system does not allow, and from making a file name too long.
+pub fn sanitize(value: &str) -> String {
+    // `.` or `..` on its own would name the current or parent directory.
+    let trimmed = value.trim();
+    if !trimmed.is_empty() && trimmed.chars().all(|c| c == '.') {
+        return "_".to_string();
+    }
+    let mut clean: String = value
+        .chars()
+        .map(|c| if is_reserved(c) { '_' } else { c })
+        .collect();
+    if clean.len() > MAX_VALUE_LEN {
+        let mut end = MAX_VALUE_LEN;
+        while !clean.is_char_boundary(end) {
+            end -= 1;
+        }
+        clean.truncate(end);
+    }
+    if cfg!(windows) {
+        // Windows drops trailing dots and spaces of a name, and keeps names
+        // such as CON or COM1 for devices.
+        clean.truncate(clean.trim_end_matches(['.', ' ']).len());
+        let stem = clean.split('.').next().unwrap_or_default();
+        if is_device_name(stem) {
+            clean.insert(0, '_');
+        }
+    }
+    clean
+}
+
+fn is_reserved(c: char) -> bool {
+    matches!(c, '/' | '\\')
+        || c.is_control()
+        || (cfg!(windows) && matches!(c, '<' | '>' | ':' | '"' | '|' | '?' | '*'))
+}
+
+fn is_device_name(name: &str) -> bool {
+    let name = name.trim_end().to_ascii_uppercase();
+    matches!(name.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || ["COM", "LPT"].iter().any(|prefix| {
+            name.strip_prefix(prefix)
+                .is_some_and(|n| n.len() == 1 && n.as_bytes()[0].is_ascii_digit())
+        })
+}
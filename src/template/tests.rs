@@ -0,0 +1,74 @@
+use super::{expand, has_placeholders, render, sanitize};
+
+#[test]
+fn placeholders_are_replaced_and_unknown_ones_kept() {
+    let vars = [("channel", "somechannel"), ("title", "Late night")];
+
+    assert_eq!(
+        render("{channel}/{title} {quality}.ts", &vars),
+        "somechannel/Late night {quality}.ts"
+    );
+    assert_eq!(
+        render("{channel}-{channel}", &vars),
+        "somechannel-somechannel"
+    );
+    assert_eq!(render("unclosed {channel", &vars), "unclosed {channel");
+    assert_eq!(render("{year}", &vars).len(), 4);
+}
+
+#[test]
+fn only_rendered_paths_are_sanitized() {
+    let vars = [("title", "a/b")];
+
+    assert_eq!(render("{title}.ts", &vars), "a_b.ts");
+    assert_eq!(expand("Live: {title}", &vars), "Live: a/b");
+}
+
+#[test]
+fn placeholders_need_both_braces() {
+    assert!(has_placeholders("{channel}.ts"));
+    assert!(!has_placeholders("channel.ts"));
+    assert!(!has_placeholders("{channel.ts"));
+}
+
+#[test]
+fn separators_and_control_characters_are_replaced() {
+    assert_eq!(sanitize("a/b\\c"), "a_b_c");
+    assert_eq!(sanitize("line\nbreak\0"), "line_break_");
+    assert_eq!(sanitize("plain name"), "plain name");
+}
+
+#[test]
+fn dot_only_values_cannot_leave_the_directory() {
+    for value in [".", "..", "...", " .. "] {
+        assert_eq!(sanitize(value), "_", "{value:?}");
+    }
+    assert_eq!(
+        render(
+            "{channel}/{stream}.ts",
+            &[("channel", ".."), ("stream", ".")]
+        ),
+        "_/_.ts"
+    );
+    assert_eq!(sanitize("..hidden"), "..hidden");
+    assert_eq!(sanitize(""), "");
+}
+
+#[test]
+fn long_values_are_cut_on_a_char_boundary() {
+    let long = "é".repeat(150);
+
+    let clean = sanitize(&long);
+
+    assert_eq!(clean.len(), 200);
+    assert!(clean.chars().all(|c| c == 'é'));
+}
+
+#[cfg(windows)]
+#[test]
+fn windows_device_names_and_trailing_dots_are_avoided() {
+    assert_eq!(sanitize("con"), "_con");
+    assert_eq!(sanitize("COM1.txt"), "_COM1.txt");
+    assert_eq!(sanitize("name. "), "name");
+    assert_eq!(sanitize("a:b"), "a_b");
+}
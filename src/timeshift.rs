@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::paths;
+use crate::units::format_byte_size;
+
+const TS_PACKET_SIZE: usize = 188;
+
+/// Keeps the most recent `capacity` bytes of the stream in memory so they can
+/// be saved retroactively.
+#[derive(Clone)]
+pub struct RingBuffer {
+    inner: Arc<Mutex<VecDeque<u8>>>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: u64) -> Self {
+        let capacity = usize::try_from(capacity).unwrap_or(usize::MAX);
+        RingBuffer {
+            inner: Arc::new(Mutex::new(VecDeque::new())),
+            capacity,
+        }
+    }
+
+    /// Writes the buffered bytes to a new file derived from `template` and
+    /// returns its path.
+    pub fn dump(&self, template: &Path) -> Result<PathBuf> {
+        let path = dump_path(template);
+        let buffer = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let (front, back) = buffer.as_slices();
+        let skip = ts_sync_offset(front, back);
+
+        let mut file = BufWriter::new(
+            File::create(paths::long_path(&path))
+                .with_context(|| format!("Failed to create timeshift dump {}", path.display()))?,
+        );
+        if skip < front.len() {
+            file.write_all(&front[skip..])?;
+            file.write_all(back)?;
+        } else {
+            file.write_all(&back[skip - front.len()..])?;
+        }
+        file.flush()?;
+
+        info!(
+            "Saved {} of timeshift buffer to {}",
+            format_byte_size((buffer.len() - skip) as u64),
+            path.display()
+        );
+        Ok(path)
+    }
+
+    /// Dumps the buffer to a new file every time SIGUSR1 is received.
+    #[cfg(unix)]
+    pub fn dump_on_signal(&self, template: PathBuf) -> Result<()> {
+        use signal_hook::consts::SIGUSR1;
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGUSR1]).context("Failed to install SIGUSR1 handler")?;
+        let buffer = self.clone();
+        std::thread::spawn(move || {
+            for _ in signals.forever() {
+                if let Err(err) = buffer.dump(&template) {
+                    warn!("Timeshift dump failed: {err:#}");
+                }
+            }
+        });
+        info!(
+            "Timeshift buffer of {} active; send SIGUSR1 (kill -USR1 {}) to save it",
+            format_byte_size(self.capacity as u64),
+            std::process::id()
+        );
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn dump_on_signal(&self, _template: PathBuf) -> Result<()> {
+        anyhow::bail!("--ringbuffer dumps are triggered by SIGUSR1, which this platform lacks");
+    }
+}
+
+impl Write for RingBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut buffer = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if buf.len() >= self.capacity {
+            buffer.clear();
+            buffer.extend(&buf[buf.len() - self.capacity..]);
+        } else {
+            let overflow = (buffer.len() + buf.len()).saturating_sub(self.capacity);
+            buffer.drain(..overflow);
+            buffer.extend(buf);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Finds the first offset that starts a run of aligned MPEG-TS packets, so the
+/// dump does not begin with a torn packet.
+fn ts_sync_offset(front: &[u8], back: &[u8]) -> usize {
+    let len = front.len() + back.len();
+    let at = |i: usize| {
+        if i < front.len() {
+            front[i]
+        } else {
+            back[i - front.len()]
+        }
+    };
+
+    (0..TS_PACKET_SIZE.min(len))
+        .find(|&start| {
+            (0..3)
+                .map(|n| start + n * TS_PACKET_SIZE)
+                .take_while(|&i| i < len)
+                .all(|i| at(i) == 0x47)
+        })
+        .unwrap_or(0)
+}
+
+fn dump_path(template: &Path) -> PathBuf {
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let stem = template
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "fors-timeshift".into());
+    let name = match template.extension() {
+        Some(ext) => format!("{stem}-{stamp}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{stamp}.ts"),
+    };
+    template.with_file_name(name)
+}
@@ -0,0 +1,128 @@
+//! `--tui`: a status screen for `--url-file` and daemon runs that shows each
+//! recording with its bitrate, distance to the live edge and ad state, and
+//! lets recordings be stopped from the keyboard.
+
+use anyhow::{Context, Result};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::daemon::{Job, JobState, Jobs};
+use crate::units::format_byte_size;
+
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// Draws the jobs until they are done or the user quits, which stops them.
+/// `dir` is where recordings are written, for the free space in the footer.
+pub fn run(jobs: &Jobs, dir: &Path) -> Result<()> {
+    let mut terminal = ratatui::try_init().context("Failed to start the terminal UI")?;
+    let result = draw_loop(&mut terminal, jobs, dir);
+    ratatui::restore();
+    result
+}
+
+fn draw_loop(terminal: &mut DefaultTerminal, jobs: &Jobs, dir: &Path) -> Result<()> {
+    let mut table = TableState::default().with_selected(0);
+    while !jobs.is_done() {
+        let list = jobs.list();
+        if let Some(selected) = table.selected() {
+            table.select(Some(selected.min(list.len().saturating_sub(1))));
+        }
+        terminal.draw(|frame| draw(frame, &list, &mut table, dir))?;
+
+        if !event::poll(REFRESH)? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('q') => jobs.stop_all(),
+            // Raw mode swallows the signal.
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => jobs.stop_all(),
+            KeyCode::Char('s') | KeyCode::Delete => {
+                if let Some(job) = table.selected().and_then(|i| list.get(i)) {
+                    jobs.stop(job.id);
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => table.select_previous(),
+            KeyCode::Down | KeyCode::Char('j') => table.select_next(),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn draw(frame: &mut Frame, jobs: &[Job], table: &mut TableState, dir: &Path) {
+    let [main, footer] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+
+    let header = Row::new([
+        "ID", "STREAM", "STATE", "BITRATE", "BEHIND", "ADS", "WRITTEN",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+    let rows = jobs.iter().map(|job| {
+        let recording = job.state == JobState::Recording;
+        Row::new([
+            job.id.to_string(),
+            job.url.clone(),
+            state(job),
+            job.bitrate
+                .filter(|_| recording)
+                .map_or_else(String::new, |kbps| format!("{kbps} kb/s")),
+            job.behind_live
+                .filter(|_| recording)
+                .map_or_else(String::new, |secs| format!("{secs:.1}s")),
+            match job.ad_remaining {
+                _ if !recording || !job.in_ad_break => String::new(),
+                Some(remaining) => format!("ad {remaining:.0}s"),
+                None => "ad break".into(),
+            },
+            format_byte_size(job.bytes_written),
+        ])
+    });
+    let widths = [
+        Constraint::Length(4),
+        Constraint::Fill(1),
+        Constraint::Length(16),
+        Constraint::Length(12),
+        Constraint::Length(8),
+        Constraint::Length(9),
+        Constraint::Length(10),
+    ];
+    let table_widget = Table::new(rows, widths)
+        .header(header)
+        .row_highlight_style(Style::new().reversed())
+        .block(Block::bordered().title(" fors "));
+    frame.render_stateful_widget(table_widget, main, table);
+
+    let written: u64 = jobs.iter().map(|job| job.bytes_written).sum();
+    let free = fs4::available_space(dir).map_or_else(|_| "?".into(), format_byte_size);
+    frame.render_widget(
+        Line::from(format!(
+            " {} written, {free} free in {} | ↑/↓ select  s stop  q quit",
+            format_byte_size(written),
+            dir.display()
+        )),
+        footer,
+    );
+}
+
+fn state(job: &Job) -> String {
+    match job.state {
+        JobState::Queued if job.attempts > 0 => format!("retry {}", job.attempts + 1),
+        JobState::Queued => "queued".into(),
+        JobState::Recording => "recording".into(),
+        JobState::Finished => "finished".into(),
+        JobState::Failed => "failed".into(),
+        JobState::Stopped => "stopped".into(),
+    }
+}
@@ -0,0 +1,166 @@
+use chrono::{Local, NaiveTime};
+use std::time::{Duration, SystemTime};
+
+#[cfg(test)]
+mod tests;
+
+/// Parses a human friendly byte size such as `512M`, `5G` or `1048576`.
+///
+/// Suffixes are binary multiples (`K` = 1024), with an optional trailing `B`/`iB`.
+pub fn parse_byte_size(input: &str) -> Result<u64, String> {
+    let value = input.trim();
+    let lower = value.to_ascii_lowercase();
+    let trimmed = lower
+        .strip_suffix("ib")
+        .or_else(|| lower.strip_suffix('b'))
+        .unwrap_or(&lower);
+
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1u64 << 10),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1u64 << 20),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 1u64 << 30),
+        Some('t') => (&trimmed[..trimmed.len() - 1], 1u64 << 40),
+        _ => (trimmed, 1),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid size '{value}' (expected e.g. 512M or 5G)"))?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("invalid size '{value}'"));
+    }
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a bitrate such as `3500k`, `3.5M` or `3500000` into bits per second.
+///
+/// Suffixes are decimal multiples, matching playlist BANDWIDTH values.
+pub fn parse_bitrate(input: &str) -> Result<u64, String> {
+    let value = input.trim();
+    let lower = value.to_ascii_lowercase();
+    let trimmed = lower
+        .strip_suffix("bps")
+        .or_else(|| lower.strip_suffix("bit/s"))
+        .unwrap_or(&lower);
+
+    let (number, multiplier) = match trimmed.chars().last() {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 1_000u64),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 1_000_000u64),
+        _ => (trimmed, 1),
+    };
+
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid bitrate '{value}' (expected e.g. 3500k or 6M)"))?;
+    if !number.is_finite() || number <= 0.0 {
+        return Err(format!("invalid bitrate '{value}'"));
+    }
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parses a duration such as `30m`, `1h2m3s`, `7d`, `1:30:00` or `90` (seconds).
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let value = input.trim();
+    let invalid = || format!("invalid duration '{value}' (expected e.g. 30m, 1h2m or 1:30:00)");
+
+    if value.contains(':') {
+        let mut seconds = 0u64;
+        for part in value.split(':') {
+            let part = part.parse::<u64>().map_err(|_| invalid())?;
+            seconds = seconds
+                .checked_mul(60)
+                .and_then(|seconds| seconds.checked_add(part))
+                .ok_or_else(invalid)?;
+        }
+        return Ok(Duration::from_secs(seconds));
+    }
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).map_err(|_| invalid());
+    }
+
+    let mut seconds = 0u64;
+    let mut number = String::new();
+    for c in value.chars() {
+        let unit = match c {
+            '0'..='9' => {
+                number.push(c);
+                continue;
+            }
+            'd' => 24 * 3600,
+            'h' => 3600,
+            'm' => 60,
+            's' => 1,
+            _ => return Err(invalid()),
+        };
+        seconds = number
+            .parse::<u64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit))
+            .and_then(|part| seconds.checked_add(part))
+            .ok_or_else(invalid)?;
+        number.clear();
+    }
+    if !number.is_empty() || value.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Resolves a local wall-clock time such as `02:00` or `23:30:15` to its next
+/// occurrence.
+pub fn parse_clock_time(input: &str) -> Result<SystemTime, String> {
+    let value = input.trim();
+    let time = NaiveTime::parse_from_str(value, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M"))
+        .map_err(|_| format!("invalid time '{value}' (expected HH:MM or HH:MM:SS)"))?;
+
+    let now = Local::now();
+    let mut date = now.date_naive();
+    if time <= now.time() {
+        date = date.succ_opt().ok_or("date out of range")?;
+    }
+
+    let at = date
+        .and_time(time)
+        .and_local_timezone(Local)
+        .earliest()
+        .ok_or_else(|| format!("'{value}' does not exist in the local timezone"))?;
+    Ok(at.into())
+}
+
+pub fn format_byte_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Formats whole seconds as e.g. `1h02m05s` or `42s`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, s) => format!("{h}h{m:02}m{s:02}s"),
+    }
+}
+
+pub fn format_bitrate(bits_per_second: f64) -> String {
+    if bits_per_second >= 1_000_000.0 {
+        format!("{:.1} Mbit/s", bits_per_second / 1_000_000.0)
+    } else {
+        format!("{:.0} kbit/s", bits_per_second / 1000.0)
+    }
+}
@@ -0,0 +1,41 @@
+use super::{parse_bitrate, parse_byte_size, parse_duration};
+use std::time::Duration;
+
+#[test]
+fn durations_in_every_form() {
+    assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+    assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+    assert_eq!(parse_duration("1h2m3s"), Ok(Duration::from_secs(3723)));
+    assert_eq!(parse_duration("7d"), Ok(Duration::from_secs(7 * 86400)));
+    assert_eq!(parse_duration("1:30:00"), Ok(Duration::from_secs(5400)));
+}
+
+#[test]
+fn malformed_durations_are_rejected() {
+    for input in ["", "-5", "NaN", "inf", "5x", "1h30", "1::2", "a:b"] {
+        assert!(parse_duration(input).is_err(), "{input}");
+    }
+}
+
+#[test]
+fn overflowing_durations_are_rejected() {
+    for input in [
+        "1e30",
+        "99999999999999999d",
+        "18446744073709551615h",
+        "18446744073709551615s1s",
+        "99999999999999999:0:0",
+        "18446744073709551615:0",
+    ] {
+        assert!(parse_duration(input).is_err(), "{input}");
+    }
+}
+
+#[test]
+fn sizes_and_bitrates_use_their_own_multiples() {
+    assert_eq!(parse_byte_size("512M"), Ok(512 << 20));
+    assert_eq!(parse_byte_size("1GiB"), Ok(1 << 30));
+    assert_eq!(parse_bitrate("3500k"), Ok(3_500_000));
+    assert_eq!(parse_bitrate("3.5Mbps"), Ok(3_500_000));
+    assert!(parse_bitrate("0").is_err());
+}